@@ -0,0 +1,187 @@
+//! Moving a whole account to a new device in one file: the identity it belongs to, the master
+//! key and keychain, the user's own DAG (settings, devices, keychain entries), and which spaces
+//! the user is in.
+//!
+//! Unlike a [key backup][crate::models::keychain::export_encrypted], an account export brings
+//! the user's settings along, and unlike [pairing][crate::pairing] it doesn't need the old device
+//! to be around. Space contents aren't included: they come down from the space's other members
+//! once the new device starts syncing.
+
+use crate::{
+    error::{Error, Result},
+    models::{
+        keychain::{self, KdfParams, Keychain},
+        operation,
+        space::{MemberID, Role, SpaceID},
+        state::State,
+    },
+    sync::{delta::SyncHello, Inbox},
+};
+use getset::Getters;
+use rasn::{AsnType, Decode, Encode};
+use stamp_core::{
+    crypto::{
+        base::{Sealed, SecretKey},
+        seal,
+    },
+    dag::{Transaction, TransactionID},
+    identity::{Identity, IdentityID},
+    util::Timestamp,
+};
+use std::collections::HashMap;
+use zeroize::Zeroizing;
+
+/// The account export format we write
+pub const ACCOUNT_EXPORT_VERSION: u32 = 1;
+
+/// One space the user belongs to, as of the export.
+#[derive(Clone, AsnType, Encode, Decode, Getters)]
+#[getset(get = "pub")]
+pub struct Membership {
+    #[rasn(tag(explicit(0)))]
+    space_id: SpaceID,
+    /// The user's member record in the space
+    #[rasn(tag(explicit(1)))]
+    member_id: MemberID,
+    #[rasn(tag(explicit(2)))]
+    role: Role,
+    /// The space's title at the time of export, for showing while it syncs
+    #[rasn(tag(explicit(3)))]
+    title: String,
+}
+
+/// The file that gets written out. Only the version and salt are readable without the
+/// passphrase.
+#[derive(AsnType, Encode, Decode)]
+struct AccountFile {
+    #[rasn(tag(explicit(0)))]
+    version: u32,
+    /// The Argon2 salt
+    #[rasn(tag(explicit(1)))]
+    salt: Vec<u8>,
+    /// A sealed [`AccountPayload`]
+    #[rasn(tag(explicit(2)))]
+    sealed: Sealed,
+    /// How the passphrase was stretched. Exports from before this was stored used
+    /// [`KdfParams::legacy`].
+    #[rasn(tag(explicit(3)), default)]
+    kdf: Option<KdfParams>,
+}
+
+/// What's inside an account export
+#[derive(AsnType, Encode, Decode)]
+struct AccountPayload {
+    #[rasn(tag(explicit(0)))]
+    identity_id: IdentityID,
+    #[rasn(tag(explicit(1)))]
+    master_key: SecretKey,
+    #[rasn(tag(explicit(2)))]
+    keychain: Keychain,
+    /// The user's own transactions (the ones not in any space)
+    #[rasn(tag(explicit(3)))]
+    user_transactions: Vec<Transaction>,
+    #[rasn(tag(explicit(4)))]
+    memberships: Vec<Membership>,
+    #[rasn(tag(explicit(5)))]
+    exported: Timestamp,
+}
+
+/// Export an account to a passphrase-protected file.
+///
+/// `transactions` can be everything we have: only the user's own (non-space) transactions are
+/// kept. Memberships are read from `state`.
+pub fn export(identity_id: &IdentityID, master_key: &SecretKey, state: &State, transactions: &[Transaction], passphrase: &str) -> Result<Vec<u8>> {
+    let user_transactions = transactions.iter()
+        .filter(|t| operation::operation_from_transaction(t).map(|(_, e)| e.context().is_none()).unwrap_or(false))
+        .cloned()
+        .collect::<Vec<_>>();
+    let memberships = state.spaces().values()
+        .filter_map(|space| {
+            let member = space.member_by_identity(identity_id)?;
+            Some(Membership {
+                space_id: space.id().clone(),
+                member_id: member.id().clone(),
+                role: member.role().clone(),
+                title: space.title().clone(),
+            })
+        })
+        .collect::<Vec<_>>();
+    let payload = AccountPayload {
+        identity_id: identity_id.clone(),
+        master_key: master_key.clone(),
+        keychain: state.keychain().clone(),
+        user_transactions,
+        memberships,
+        exported: Timestamp::now(),
+    };
+    let mut salt = vec![0u8; 16];
+    getrandom::getrandom(&mut salt[..]).map_err(|e| Error::BackupInvalid(format!("no randomness: {}", e)))?;
+    let serialized = Zeroizing::new(rasn::der::encode(&payload).map_err(|_| Error::ASNSerialize)?);
+    let kdf = KdfParams::current();
+    let sealed = seal::seal(&keychain::backup_key(passphrase, &salt[..], &kdf)?, &serialized[..])?;
+    let file = AccountFile { version: ACCOUNT_EXPORT_VERSION, salt, sealed, kdf: Some(kdf) };
+    rasn::der::encode(&file).map_err(|_| Error::ASNSerialize)
+}
+
+/// Open a file made by [`export`].
+pub fn import(blob: &[u8], passphrase: &str) -> Result<ImportedAccount> {
+    let file: AccountFile = rasn::der::decode(blob).map_err(|_| Error::BackupInvalid("not an account export".into()))?;
+    if file.version != ACCOUNT_EXPORT_VERSION {
+        Err(Error::BackupInvalid(format!("unknown account export version {}", file.version)))?;
+    }
+    let kdf = file.kdf.clone().unwrap_or_else(KdfParams::legacy);
+    let serialized = Zeroizing::new(seal::open(&keychain::backup_key(passphrase, &file.salt[..], &kdf)?, &file.sealed)
+        .map_err(|_| Error::BackupInvalid("wrong passphrase".into()))?);
+    let AccountPayload { identity_id, master_key, keychain, user_transactions, memberships, exported } = rasn::der::decode(&serialized[..])
+        .map_err(|_| Error::ASNDeserialize)?;
+    Ok(ImportedAccount { identity_id, master_key, keychain, user_transactions, memberships, exported })
+}
+
+/// An account, opened from an export.
+#[derive(Getters)]
+#[getset(get = "pub")]
+pub struct ImportedAccount {
+    identity_id: IdentityID,
+    master_key: SecretKey,
+    keychain: Keychain,
+    user_transactions: Vec<Transaction>,
+    memberships: Vec<Membership>,
+    /// When the export was made. Anything that happened after this comes in from sync.
+    exported: Timestamp,
+}
+
+impl ImportedAccount {
+    /// Set up a new device from this account: replay the user's transactions into a fresh
+    /// [`State`], and hand back the inbox that did it (which now knows those transactions, ready
+    /// for whatever arrives from peers).
+    ///
+    /// `identities` needs the user's own identity so the transactions' signatures can be checked.
+    /// User operations are opened with the master key. Transactions that fail are left in the
+    /// inbox's quarantine.
+    pub fn bootstrap(&self, identities: &HashMap<IdentityID, Identity>, now: &Timestamp) -> Result<(State, Inbox)> {
+        let mut state = State::new();
+        let mut inbox = Inbox::new(Vec::<TransactionID>::new());
+        for transaction in &self.user_transactions {
+            inbox.receive(transaction.clone(), identities, now);
+        }
+        let space_keys = self.keychain.current_keys(&self.master_key, state.spaces())?;
+        inbox.apply(&mut state, &self.master_key, &space_keys);
+        Ok((state, inbox))
+    }
+
+    /// The hello to open the first sync with: our position in the user DAG, and an empty
+    /// frontier for each space we belong to, so peers send us everything in those spaces.
+    pub fn sync_hello(&self) -> SyncHello {
+        let mut hello = SyncHello::new(&self.user_transactions);
+        for membership in &self.memberships {
+            hello.add_space(Some(membership.space_id.clone()));
+        }
+        hello
+    }
+
+    /// Consume this account, returning the master key and keychain.
+    pub fn consume(self) -> (SecretKey, Keychain) {
+        let Self { master_key, keychain, .. } = self;
+        (master_key, keychain)
+    }
+}
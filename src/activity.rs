@@ -0,0 +1,247 @@
+//! Turns the operations in a space into a human-readable activity feed ("Alice renamed the
+//! space", "Bob added 3 notes") for showing in an activity panel.
+
+use crate::{
+    crypto::OperationKeys,
+    error::{Error, Result},
+    models::{
+        operation::{self, ObjectRef, Operation, OperationAction},
+        page::DisplayWindow,
+        space::SpaceID,
+        state::State,
+    },
+};
+use getset::Getters;
+use rasn::{AsnType, Decode, Encode};
+use serde::Serialize;
+use stamp_core::{
+    crypto::base::SecretKey,
+    dag::Transaction,
+    identity::IdentityID,
+    util::Timestamp,
+};
+use std::collections::HashMap;
+
+/// The kinds of things that show up in the activity feed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, AsnType, Encode, Decode, Serialize)]
+#[rasn(choice)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivityKind {
+    #[rasn(tag(explicit(0)))]
+    NoteAdded,
+    #[rasn(tag(explicit(1)))]
+    NoteEdited,
+    #[rasn(tag(explicit(2)))]
+    NoteDeleted,
+    #[rasn(tag(explicit(3)))]
+    PageAdded,
+    #[rasn(tag(explicit(4)))]
+    PageEdited,
+    #[rasn(tag(explicit(5)))]
+    PageDeleted,
+    #[rasn(tag(explicit(6)))]
+    FileAdded,
+    #[rasn(tag(explicit(7)))]
+    FileEdited,
+    #[rasn(tag(explicit(8)))]
+    FileDeleted,
+    #[rasn(tag(explicit(9)))]
+    SpaceCreated,
+    #[rasn(tag(explicit(10)))]
+    SpaceRenamed,
+    #[rasn(tag(explicit(11)))]
+    SpaceEdited,
+    #[rasn(tag(explicit(12)))]
+    SpaceDeleted,
+    #[rasn(tag(explicit(13)))]
+    MemberAdded,
+    #[rasn(tag(explicit(14)))]
+    MemberEdited,
+    #[rasn(tag(explicit(15)))]
+    MemberRemoved,
+    #[rasn(tag(explicit(16)))]
+    KeyRotated,
+}
+
+impl ActivityKind {
+    /// Figure out what kind of activity an action is. Returns `None` for things too noisy (or too
+    /// personal) to show in a feed, like file chunks and user settings.
+    pub fn from_action(action: &OperationAction) -> Option<Self> {
+        match action {
+            OperationAction::FileSetV1(..) => Some(Self::FileAdded),
+            OperationAction::FileSetNameV1(..) | OperationAction::FileSetPreviewV1(..) => Some(Self::FileEdited),
+            OperationAction::FileUnsetV1 => Some(Self::FileDeleted),
+            OperationAction::NoteSetV1(..) | OperationAction::NoteSetV2 { .. } => Some(Self::NoteAdded),
+            OperationAction::NoteSetBodySectionV1 { .. } |
+                OperationAction::NoteSetBodySectionIndentV1 { .. } |
+                OperationAction::NoteSetBodySectionOrderV1 { .. } |
+                OperationAction::NoteSetColorV1(..) |
+                OperationAction::NoteSetIconV1(..) |
+                OperationAction::NoteSetTagV1(..) |
+                OperationAction::NoteSetTitleV1(..) |
+                OperationAction::NoteUnsetBodySectionV1(..) |
+                OperationAction::NoteUnsetTagV1(..) => Some(Self::NoteEdited),
+            OperationAction::NoteSetDeletedV1(..) | OperationAction::NoteUnsetV1 => Some(Self::NoteDeleted),
+            OperationAction::PageSetV1(..) => Some(Self::PageAdded),
+            OperationAction::PageSetDeletedV1(..) | OperationAction::PageUnsetV1 => Some(Self::PageDeleted),
+            OperationAction::PageSetDescriptionV1(..) |
+                OperationAction::PageSetDisplayV1(..) |
+                OperationAction::PageSetIconV1(..) |
+                OperationAction::PageSetSliceV1(..) |
+                OperationAction::PageSetSortV1(..) |
+                OperationAction::PageSetTitleV1(..) => Some(Self::PageEdited),
+            OperationAction::SpaceSetV1(..) => Some(Self::SpaceCreated),
+            OperationAction::SpaceSetTitleV1(..) => Some(Self::SpaceRenamed),
+            OperationAction::SpaceSetArchivedV1(..) |
+                OperationAction::SpaceSetColorV1(..) |
+                OperationAction::SpaceSetConvergentChunksV1(..) |
+                OperationAction::SpaceSetDefaultPageV1(..) |
+                OperationAction::SpaceSetDefaultsV1(..) => Some(Self::SpaceEdited),
+            OperationAction::SpaceSetDeletedV1(..) | OperationAction::SpaceUnsetV1 => Some(Self::SpaceDeleted),
+            OperationAction::SpaceSetMemberV1(..) => Some(Self::MemberAdded),
+            OperationAction::SpaceSetMemberPermissionsV1 { .. } |
+                OperationAction::SpaceSetMemberProfileV1 { .. } |
+                OperationAction::SpaceSetMemberRoleV1 { .. } => Some(Self::MemberEdited),
+            OperationAction::SpaceUnsetMemberV1(..) => Some(Self::MemberRemoved),
+            OperationAction::SpaceSetKeyRotatedV1(..) => Some(Self::KeyRotated),
+            _ => None,
+        }
+    }
+
+    /// The verb/noun pair used to describe this kind of activity.
+    pub(crate) fn describe(&self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            Self::NoteAdded => ("added", "a note", "notes"),
+            Self::NoteEdited => ("edited", "a note", "notes"),
+            Self::NoteDeleted => ("deleted", "a note", "notes"),
+            Self::PageAdded => ("added", "a page", "pages"),
+            Self::PageEdited => ("edited", "a page", "pages"),
+            Self::PageDeleted => ("deleted", "a page", "pages"),
+            Self::FileAdded => ("added", "a file", "files"),
+            Self::FileEdited => ("renamed", "a file", "files"),
+            Self::FileDeleted => ("deleted", "a file", "files"),
+            Self::SpaceCreated => ("created", "the space", "the space"),
+            Self::SpaceRenamed => ("renamed", "the space", "the space"),
+            Self::SpaceEdited => ("updated", "the space", "the space"),
+            Self::SpaceDeleted => ("deleted", "the space", "the space"),
+            Self::MemberAdded => ("added", "a member", "members"),
+            Self::MemberEdited => ("updated", "a member", "members"),
+            Self::MemberRemoved => ("removed", "a member", "members"),
+            Self::KeyRotated => ("rotated", "the space key", "the space key"),
+        }
+    }
+}
+
+/// One line in the activity feed. Consecutive operations of the same kind by the same person are
+/// grouped into a single entry.
+#[derive(Getters, Serialize)]
+#[getset(get = "pub")]
+pub struct ActivityEntry {
+    /// Who did the thing
+    author: IdentityID,
+    /// The author's display name in this space, if they set one
+    author_name: Option<String>,
+    /// What they did
+    kind: ActivityKind,
+    /// The (unique) objects they did it to
+    objects: Vec<ObjectRef>,
+    /// When the first operation in this group happened
+    first: Timestamp,
+    /// When the last operation in this group happened
+    last: Timestamp,
+}
+
+impl ActivityEntry {
+    /// Describe this entry in plain English, ie "Bob added 3 notes"
+    pub fn describe(&self) -> String {
+        let name = self.author_name.clone().unwrap_or_else(|| "Someone".into());
+        let (verb, single, plural) = self.kind.describe();
+        match self.objects.len() {
+            0 | 1 => format!("{} {} {}", name, verb, single),
+            num if single == plural => format!("{} {} {} ({} times)", name, verb, plural, num),
+            num => format!("{} {} {} {}", name, verb, num, plural),
+        }
+    }
+}
+
+/// A page of activity, newest first.
+#[derive(Getters, Serialize)]
+#[getset(get = "pub")]
+pub struct ActivityPage {
+    /// The entries in this window
+    entries: Vec<ActivityEntry>,
+    /// How many entries there are in total
+    total: usize,
+    /// The window to ask for next, if there are more entries
+    next: Option<DisplayWindow>,
+}
+
+/// Build the activity feed for a space from its transactions, newest first.
+///
+/// Transactions that aren't for this space, happened before `since`, or can't be decrypted with
+/// the space's key are skipped. `state` is used to look up member display names.
+pub fn feed_for_space(state: &State, space_id: &SpaceID, transactions: &[Transaction], keys: &HashMap<SpaceID, SecretKey>, since: Option<&Timestamp>, window: &DisplayWindow) -> Result<ActivityPage> {
+    let key = keys.get(space_id).ok_or(Error::SpaceKeyMissing)?;
+    let op_keys = OperationKeys::new(key)?;
+    let space = state.spaces().get(space_id);
+
+    let mut items: Vec<(&Timestamp, &IdentityID, Operation)> = Vec::new();
+    for trans in transactions {
+        let created = trans.entry().created();
+        if since.map(|since| created < since).unwrap_or(false) {
+            continue;
+        }
+        let (creator, encrypted) = match operation::operation_from_transaction(trans) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        if encrypted.context().as_ref() != Some(space_id) {
+            continue;
+        }
+        match encrypted.decrypt_with(&op_keys) {
+            Ok(op) => items.push((created, creator, op)),
+            Err(_) => continue,
+        }
+    }
+    items.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut entries: Vec<ActivityEntry> = Vec::new();
+    for (created, creator, op) in items {
+        let kind = match ActivityKind::from_action(op.action()) {
+            Some(k) => k,
+            None => continue,
+        };
+        let object = op.context().object();
+        if let Some(entry) = entries.last_mut() {
+            if &entry.author == creator && entry.kind == kind {
+                if let Some(object) = object {
+                    if !entry.objects.contains(&object) {
+                        entry.objects.push(object);
+                    }
+                }
+                entry.last = created.clone();
+                continue;
+            }
+        }
+        let author_name = space
+            .and_then(|s| s.member_by_identity(creator))
+            .and_then(|m| m.profile().display_name().clone());
+        entries.push(ActivityEntry {
+            author: creator.clone(),
+            author_name,
+            kind,
+            objects: object.into_iter().collect(),
+            first: created.clone(),
+            last: created.clone(),
+        });
+    }
+    entries.reverse();
+
+    let total = entries.len();
+    let entries = entries.into_iter()
+        .skip(*window.offset())
+        .take(*window.limit())
+        .collect::<Vec<_>>();
+    let next = if window.offset() + window.limit() < total { Some(window.next()) } else { None };
+    Ok(ActivityPage { entries, total, next })
+}
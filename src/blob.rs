@@ -0,0 +1,351 @@
+//! Storage for file chunk data.
+//!
+//! Operations only carry chunk metadata (see [`FileChunk`][crate::models::file::FileChunk]). The
+//! actual (encrypted) chunk bytes are kept out of the DAG and go through a [`BlobStore`] instead,
+//! keyed by the hash of the encrypted data (see
+//! [`FileChunk::blob_hash`][crate::models::file::FileChunk::blob_hash]). Keying by the content
+//! hash would let anyone who can see blob keys tell that two spaces hold the same chunk.
+
+use crate::{
+    error::{Error, Result},
+    models::file::FileChunk,
+};
+use data_encoding::HEXLOWER;
+use rasn::{AsnType, Decode, Encode};
+use sha2::{Digest, Sha256};
+use stamp_core::crypto::base::{Hash, Sealed};
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Somewhere to keep chunk data.
+///
+/// Blobs are keyed by a hash of their own (encrypted) contents, so a hash only ever names one
+/// blob.
+pub trait BlobStore {
+    /// Store a blob under the given hash. Storing a blob that's already there is not an error.
+    fn put(&self, hash: &Hash, data: &[u8]) -> Result<()>;
+
+    /// Grab a blob by hash, if we have it.
+    fn get(&self, hash: &Hash) -> Result<Option<Vec<u8>>>;
+
+    /// Whether we have a blob for the given hash.
+    fn has(&self, hash: &Hash) -> Result<bool>;
+
+    /// Remove a blob. Removing a blob we don't have is not an error.
+    fn delete(&self, hash: &Hash) -> Result<()>;
+}
+
+/// Stores an encrypted chunk in a blob store.
+pub fn put_sealed(store: &dyn BlobStore, hash: &Hash, sealed: &Sealed) -> Result<()> {
+    let serialized = rasn::der::encode(sealed).map_err(|_| Error::ASNSerialize)?;
+    store.put(hash, &serialized[..])
+}
+
+/// Grab an encrypted chunk from a blob store, erroring if it's not there.
+pub fn get_sealed(store: &dyn BlobStore, hash: &Hash) -> Result<Sealed> {
+    let serialized = store.get(hash)?.ok_or(Error::BlobMissing)?;
+    rasn::der::decode(&serialized[..]).map_err(|_| Error::ASNDeserialize)
+}
+
+/// A chunk's encrypted data, however it was encrypted.
+#[derive(Clone, AsnType, Encode, Decode)]
+#[rasn(choice)]
+pub enum ChunkData {
+    /// Sealed normally, with a random nonce
+    #[rasn(tag(explicit(0)))]
+    Sealed(Sealed),
+    /// Encrypted deterministically (see [`crypto::seal_convergent`][crate::crypto::seal_convergent])
+    #[rasn(tag(explicit(1)))]
+    Convergent(Vec<u8>),
+}
+
+/// Serialize a chunk's data the way it's kept in a blob store.
+pub fn encode_chunk(data: &ChunkData) -> Result<Vec<u8>> {
+    rasn::der::encode(data).map_err(|_| Error::ASNSerialize)
+}
+
+/// The hash a chunk's data is kept under in a blob store: a hash of the
+/// [serialized][encode_chunk] encrypted data.
+pub fn chunk_blob_hash(data: &ChunkData) -> Result<Hash> {
+    Ok(Hash::new_blake3(&encode_chunk(data)?[..])?)
+}
+
+/// Stores a chunk's data in a blob store, under the hash given (ie, the chunk's
+/// [`blob_hash`][crate::models::file::FileChunk::blob_hash]), which has to be the
+/// [hash of the data][chunk_blob_hash].
+pub fn put_chunk(store: &dyn BlobStore, hash: &Hash, data: &ChunkData) -> Result<()> {
+    let serialized = encode_chunk(data)?;
+    if &Hash::new_blake3(&serialized[..])? != hash {
+        Err(Error::BlobCorrupt("chunk data doesn't match its blob hash".into()))?;
+    }
+    store.put(hash, &serialized[..])
+}
+
+/// Grab a chunk's data from a blob store, erroring if it's not there. Chunks written with
+/// [`put_chunk`] are read back as such, and [legacy][FileChunk::legacy_blob] chunks as the bare
+/// [`Sealed`] that [`put_sealed`] stores.
+pub fn get_chunk(store: &dyn BlobStore, chunk: &FileChunk) -> Result<ChunkData> {
+    if chunk.legacy_blob() {
+        return Ok(ChunkData::Sealed(get_sealed(store, chunk.blob_hash())?));
+    }
+    let serialized = store.get(chunk.blob_hash())?.ok_or(Error::BlobMissing)?;
+    rasn::der::decode(&serialized[..]).map_err(|_| Error::ASNDeserialize)
+}
+
+/// Turn a hash into something we can use as a key (a filename, etc).
+pub fn blob_key(hash: &Hash) -> Result<String> {
+    let serialized = rasn::der::encode(hash).map_err(|_| Error::ASNSerialize)?;
+    Ok(HEXLOWER.encode(&serialized[..]))
+}
+
+/// Make sure a directory's entries (ie, a rename into it) are on disk.
+pub(crate) fn sync_dir(dir: &Path) {
+    // directories can't be opened for syncing everywhere (ie, windows), and there the rename is
+    // as durable as we're going to get
+    if let Ok(dir) = fs::File::open(dir) {
+        let _ = dir.sync_all();
+    }
+}
+
+/// Marks a blob file written with a checksum
+const BLOB_MAGIC: &[u8] = b"TBLB1";
+
+/// How long a checksummed blob file's header is: the magic plus a SHA-256
+const BLOB_HEADER_LEN: usize = 5 + 32;
+
+/// A blob store that keeps each blob in its own file within a directory.
+///
+/// Blobs are sharded into two levels of subdirectories (`ab/cd/<key>`) so no one directory gets
+/// huge. Every hash's DER encoding starts with the same few bytes, so the shard comes from a
+/// digest of the [key][blob_key] rather than the key itself. Each file starts with a SHA-256 of
+/// the blob, which is checked on every read, so a blob that rotted on disk is reported instead
+/// of handed back.
+///
+/// Blobs written before sharding (flat in the root, without a checksum) can still be read and
+/// are moved into place the next time they're written.
+pub struct FsBlobStore {
+    root: PathBuf,
+}
+
+impl FsBlobStore {
+    /// Create a new filesystem blob store, creating its directory if needed.
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    /// The directory blobs are stored in
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn shard_for(&self, key: &str) -> PathBuf {
+        let digest = HEXLOWER.encode(&Sha256::digest(key.as_bytes())[..]);
+        self.root.join(&digest[0..2]).join(&digest[2..4])
+    }
+
+    fn path_for(&self, hash: &Hash) -> Result<PathBuf> {
+        let key = blob_key(hash)?;
+        Ok(self.shard_for(&key).join(key))
+    }
+
+    /// Where a blob lived before sharding
+    fn legacy_path_for(&self, hash: &Hash) -> Result<PathBuf> {
+        Ok(self.root.join(blob_key(hash)?))
+    }
+
+    /// Check a blob file's checksum, returning the blob.
+    fn unwrap_blob(path: &Path, mut contents: Vec<u8>) -> Result<Vec<u8>> {
+        if contents.len() < BLOB_HEADER_LEN || &contents[..BLOB_MAGIC.len()] != BLOB_MAGIC {
+            Err(Error::BlobCorrupt(format!("{} has no checksum header", path.display())))?;
+        }
+        let data = contents.split_off(BLOB_HEADER_LEN);
+        if Sha256::digest(&data[..])[..] != contents[BLOB_MAGIC.len()..] {
+            Err(Error::BlobCorrupt(format!("{} doesn't match its checksum", path.display())))?;
+        }
+        Ok(data)
+    }
+
+    /// Delete a blob, returning how many bytes it took up (zero if we didn't have it).
+    pub fn remove(&self, hash: &Hash) -> Result<u64> {
+        let mut freed = 0;
+        for path in [self.path_for(hash)?, self.legacy_path_for(hash)?] {
+            match fs::metadata(&path) {
+                Ok(meta) => {
+                    fs::remove_file(&path)?;
+                    freed += meta.len();
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => Err(e)?,
+            }
+        }
+        Ok(freed)
+    }
+
+    /// Delete every blob that isn't in `live` (ie, the hashes of every chunk in the state), along
+    /// with any half-written blobs left by a crash. Returns how many files were removed and how
+    /// many bytes they took up.
+    pub fn sweep(&self, live: &[&Hash]) -> Result<(usize, u64)> {
+        let live = live.iter()
+            .map(|h| blob_key(h))
+            .collect::<Result<HashSet<_>>>()?;
+        let mut removed = 0;
+        let mut bytes = 0;
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let path = entry.path();
+                if entry.file_type()?.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.ends_with(".tmp") || !live.contains(&name) {
+                    bytes += entry.metadata()?.len();
+                    fs::remove_file(&path)?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok((removed, bytes))
+    }
+}
+
+impl BlobStore for FsBlobStore {
+    fn put(&self, hash: &Hash, data: &[u8]) -> Result<()> {
+        let path = self.path_for(hash)?;
+        let dir = path.parent().expect("blob paths are sharded");
+        fs::create_dir_all(dir)?;
+        // the hash names the contents, so an intact blob already there is the one we'd write
+        let intact = match fs::read(&path) {
+            Ok(contents) => Self::unwrap_blob(&path, contents).is_ok(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => Err(e)?,
+        };
+        if !intact {
+            // write to a temp file of our own, sync, and move into place so readers never see
+            // half a blob, a crash never leaves one, and two writers of the same blob don't
+            // trip over each other
+            let tmp = path.with_extension(format!("{}.tmp", Uuid::new_v4().simple()));
+            {
+                let mut file = fs::File::create(&tmp)?;
+                file.write_all(BLOB_MAGIC)?;
+                file.write_all(&Sha256::digest(data)[..])?;
+                file.write_all(data)?;
+                file.sync_all()?;
+            }
+            fs::rename(&tmp, &path)?;
+            sync_dir(dir);
+        }
+        match fs::remove_file(self.legacy_path_for(hash)?) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e)?,
+        }
+    }
+
+    fn get(&self, hash: &Hash) -> Result<Option<Vec<u8>>> {
+        let path = self.path_for(hash)?;
+        match fs::read(&path) {
+            Ok(contents) => return Ok(Some(Self::unwrap_blob(&path, contents)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => Err(e)?,
+        }
+        match fs::read(self.legacy_path_for(hash)?) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e)?,
+        }
+    }
+
+    fn has(&self, hash: &Hash) -> Result<bool> {
+        Ok(self.path_for(hash)?.exists() || self.legacy_path_for(hash)?.exists())
+    }
+
+    fn delete(&self, hash: &Hash) -> Result<()> {
+        for path in [self.path_for(hash)?, self.legacy_path_for(hash)?] {
+            match fs::remove_file(path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => Err(e)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use stamp_core::crypto::{base::SecretKey, seal};
+    use std::sync::Arc;
+
+    fn sealed_chunk(data: &[u8]) -> (Hash, ChunkData) {
+        let key = SecretKey::new_xchacha20poly1305().unwrap();
+        let data = ChunkData::Sealed(seal::seal(&key, data).unwrap());
+        (chunk_blob_hash(&data).unwrap(), data)
+    }
+
+    #[test]
+    fn same_content_different_keys_dont_collide() {
+        let store = FsBlobStore::new(test_util::temp_dir("blob-collide")).unwrap();
+        let (hash1, data1) = sealed_chunk(b"shared chunk");
+        let (hash2, data2) = sealed_chunk(b"shared chunk");
+        assert_ne!(hash1, hash2);
+        put_chunk(&store, &hash1, &data1).unwrap();
+        put_chunk(&store, &hash2, &data2).unwrap();
+        assert_eq!(store.get(&hash1).unwrap(), Some(encode_chunk(&data1).unwrap()));
+        assert_eq!(store.get(&hash2).unwrap(), Some(encode_chunk(&data2).unwrap()));
+    }
+
+    #[test]
+    fn put_chunk_checks_the_hash() {
+        let store = FsBlobStore::new(test_util::temp_dir("blob-hash")).unwrap();
+        let (_, data) = sealed_chunk(b"some data");
+        let wrong = Hash::new_blake3(b"some data").unwrap();
+        assert!(matches!(put_chunk(&store, &wrong, &data), Err(Error::BlobCorrupt(..))));
+        assert!(!store.has(&wrong).unwrap());
+    }
+
+    #[test]
+    fn concurrent_puts_of_one_blob() {
+        let store = Arc::new(FsBlobStore::new(test_util::temp_dir("blob-race")).unwrap());
+        let data = vec![7u8; 64 * 1024];
+        let hash = Hash::new_blake3(&data[..]).unwrap();
+        let handles = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                let data = data.clone();
+                let hash = hash.clone();
+                std::thread::spawn(move || store.put(&hash, &data[..]))
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+        assert_eq!(store.get(&hash).unwrap(), Some(data));
+        // nothing half-written is left behind, and the blob itself survives a sweep
+        assert_eq!(store.sweep(&[&hash]).unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn rotted_blobs_are_reported_and_rewritten() {
+        let store = FsBlobStore::new(test_util::temp_dir("blob-rot")).unwrap();
+        let data = b"important bytes".to_vec();
+        let hash = Hash::new_blake3(&data[..]).unwrap();
+        store.put(&hash, &data[..]).unwrap();
+        let path = store.path_for(&hash).unwrap();
+        let mut contents = fs::read(&path).unwrap();
+        *contents.last_mut().unwrap() ^= 0xff;
+        fs::write(&path, contents).unwrap();
+        assert!(matches!(store.get(&hash), Err(Error::BlobCorrupt(..))));
+        store.put(&hash, &data[..]).unwrap();
+        assert_eq!(store.get(&hash).unwrap(), Some(data));
+    }
+}
@@ -0,0 +1,967 @@
+//! Crypto workflows that go beyond sealing and opening a single operation, like rotating a space's
+//! key.
+
+use crate::{
+    error::{Error, Result},
+    models::{
+        Encryptable,
+        file::CompressionAlgo,
+        operation::{self, ObjectRef, Operation, OperationAction, OperationEncrypted},
+        space::{KeyRotation, MemberID, Space, SpaceID, SpaceKeyID},
+        state::State,
+    },
+    provider::{CryptoProvider, LocalProvider},
+};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use getset::Getters;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rasn::{AsnType, Decode, Encode};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use stamp_core::{
+    crypto::{
+        base::{CryptoKeypair, CryptoKeypairMessage, Hash, Sealed, SecretKey},
+        seal,
+    },
+    dag::{Transaction, TransactionID},
+    identity::IdentityID,
+};
+use std::collections::HashMap;
+use zeroize::{Zeroize, Zeroizing};
+
+/// How a payload was encrypted. This gets recorded next to ciphertexts so that when we move to
+/// a new scheme, older payloads can still be opened (and found and upgraded, see
+/// [`migrate_cipher_suite`]).
+#[derive(Clone, Copy, Debug, PartialEq, AsnType, Encode, Decode, Deserialize, Serialize)]
+#[rasn(choice)]
+pub enum CipherSuite {
+    /// Sealed directly with the space key, with nothing binding the ciphertext to its space.
+    /// Deprecated.
+    #[rasn(tag(explicit(0)))]
+    Legacy,
+    /// XChaCha20-Poly1305 with HKDF-SHA256 subkeys (see [`derive_subkey`]) and associated data
+    /// binding (see [`seal_bound`]).
+    #[rasn(tag(explicit(1)))]
+    XChaChaBoundV1,
+}
+
+impl CipherSuite {
+    /// What we encrypt new payloads with
+    pub const CURRENT: Self = Self::XChaChaBoundV1;
+
+    /// Whether payloads using this suite should be re-encrypted
+    pub fn is_deprecated(&self) -> bool {
+        matches!(self, Self::Legacy)
+    }
+
+    /// Whether this suite seals with per-purpose subkeys instead of the space key
+    pub fn uses_subkeys(&self) -> bool {
+        !matches!(self, Self::Legacy)
+    }
+
+    /// Whether this suite binds ciphertexts to their context
+    pub fn is_bound(&self) -> bool {
+        !matches!(self, Self::Legacy)
+    }
+}
+
+impl Default for CipherSuite {
+    fn default() -> Self {
+        Self::Legacy
+    }
+}
+
+/// What a key derived from a space key is used for. Each purpose gets its own subkey, so the
+/// different kinds of data in a space never share a key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyPurpose {
+    /// Sealing operation contexts
+    OperationContext,
+    /// Sealing operation actions
+    OperationAction,
+    /// Sealing file chunks (and inline file data)
+    FileChunk,
+    /// Keying [blind indexes](BlindToken)
+    BlindIndex,
+    /// Sealing [push notification payloads](crate::sync::push)
+    Push,
+    /// Sealing rows in [local storage](crate::storage::StorageCipher)
+    Storage,
+    /// Keying lookup columns in [local storage](crate::storage::StorageCipher)
+    StorageIndex,
+    /// Sealing [state snapshots](crate::storage::backup)
+    Backup,
+}
+
+impl KeyPurpose {
+    fn label(&self) -> &'static [u8] {
+        match self {
+            Self::OperationContext => b"turtl/subkey/op-context/v1",
+            Self::OperationAction => b"turtl/subkey/op-action/v1",
+            Self::FileChunk => b"turtl/subkey/file-chunk/v1",
+            Self::BlindIndex => b"turtl/subkey/blind-index/v1",
+            Self::Push => b"turtl/subkey/push/v1",
+            Self::Storage => b"turtl/subkey/storage/v1",
+            Self::StorageIndex => b"turtl/subkey/storage-index/v1",
+            Self::Backup => b"turtl/subkey/backup/v1",
+        }
+    }
+}
+
+/// Derive a per-purpose subkey from a space key using HKDF-SHA256.
+pub fn derive_subkey(space_key: &SecretKey, purpose: KeyPurpose) -> Result<SecretKey> {
+    let ikm = Zeroizing::new(rasn::der::encode(space_key).map_err(|_| Error::ASNSerialize)?);
+    let mut okm = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(None, &ikm[..])
+        .expand(purpose.label(), &mut okm[..])
+        .map_err(|_| Error::OperationInvalid("subkey derivation failed".into()))?;
+    Ok(SecretKey::new_xchacha20poly1305_from_slice(&okm[..])?)
+}
+
+/// The keys for opening (or sealing) operations in one space: the space key and the subkeys
+/// derived from it. Deriving the subkeys for every operation adds up, so when opening a pile of
+/// operations, derive these once and reuse them.
+pub struct OperationKeys<'k> {
+    /// Missing for [viewers](ViewerKey), who only ever get the subkeys
+    space_key: Option<&'k SecretKey>,
+    context_key: SecretKey,
+    action_key: SecretKey,
+}
+
+impl<'k> OperationKeys<'k> {
+    /// Derive the operation subkeys for a space key
+    pub fn new(space_key: &'k SecretKey) -> Result<Self> {
+        Ok(Self {
+            space_key: Some(space_key),
+            context_key: derive_subkey(space_key, KeyPurpose::OperationContext)?,
+            action_key: derive_subkey(space_key, KeyPurpose::OperationAction)?,
+        })
+    }
+
+    /// Grab the key for a purpose: its subkey, or the space key itself for operations written
+    /// before subkeys existed.
+    pub(crate) fn key_for(&self, purpose: KeyPurpose, subkeys: bool) -> Result<&SecretKey> {
+        match (subkeys, purpose) {
+            (true, KeyPurpose::OperationContext) => Ok(&self.context_key),
+            (true, KeyPurpose::OperationAction) => Ok(&self.action_key),
+            _ => self.space_key.ok_or_else(|| Error::ProviderMissingKey("space".into())),
+        }
+    }
+}
+
+/// Open a batch of sealed payloads (in parallel), returning the results in the same order.
+pub fn open_batch(secret_key: &SecretKey, sealed: &[Sealed]) -> Vec<Result<Zeroizing<Vec<u8>>>> {
+    sealed.par_iter()
+        .map(|s| Ok(Zeroizing::new(seal::open(secret_key, s)?)))
+        .collect()
+}
+
+/// Decrypt a batch of operations from one space (in parallel) with keys that were already
+/// derived, returning the results in the same order. Each operation is opened with whichever of
+/// `keys` opens it, since a space that's had its key rotated has operations under each of its
+/// keys.
+pub fn decrypt_batch(keys: &[OperationKeys], encrypted: &[OperationEncrypted]) -> Vec<Result<Operation>> {
+    encrypted.par_iter()
+        .map(|op| {
+            let mut result = Err(Error::ProviderMissingKey("space".into()));
+            for keys in keys {
+                result = op.decrypt_with(keys);
+                if result.is_ok() {
+                    break;
+                }
+            }
+            result
+        })
+        .collect()
+}
+
+/// Which field a [`BlindToken`] was made from. Tokens from different fields never match each
+/// other, even for the same value.
+#[derive(Clone, Copy, Debug, PartialEq, AsnType, Encode, Decode, Deserialize, Serialize)]
+#[rasn(choice)]
+pub enum BlindField {
+    #[rasn(tag(explicit(0)))]
+    Title,
+    #[rasn(tag(explicit(1)))]
+    Tag,
+}
+
+impl BlindField {
+    fn label(&self) -> &'static [u8] {
+        match self {
+            Self::Title => b"title",
+            Self::Tag => b"tag",
+        }
+    }
+}
+
+/// A blind index entry: a keyed hash of a (normalized) title or tag.
+///
+/// These ride along with encrypted operations so whoever stores them (a server, a local
+/// encrypted database) can answer "which operations mention this tag" without seeing any tags.
+/// Setting and removing a tag give the same token, so a lookup finds both and the caller has to
+/// open the operations to tell which is which. Anybody with the space key can compute the token
+/// for a value and look it up. The catch is that equal values give equal tokens, so the storage
+/// side can see that two notes share a tag (and how popular it is), just not what it is.
+///
+/// Tokens are made with a [`BlindIndexKey`] derived from the space key, so they change when the
+/// space key is rotated: operations from before a rotation only match lookups made with the old
+/// key. [`RevocationPlan::execute_indexed`] re-indexes a space as part of its rotation.
+#[derive(Clone, Debug, PartialEq, AsnType, Encode, Decode, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct BlindToken {
+    #[rasn(tag(explicit(0)))]
+    field: BlindField,
+    /// HMAC-SHA256 of the value
+    #[rasn(tag(explicit(1)))]
+    hash: Vec<u8>,
+}
+
+impl BlindToken {
+    /// Make the token for a value. Values are trimmed and lowercased first, so "Work " and
+    /// "work" give the same token.
+    pub fn new(index_key: &BlindIndexKey, field: BlindField, value: &str) -> Result<Self> {
+        let normalized = Zeroizing::new(value.trim().to_lowercase());
+        let mut mac = Hmac::<Sha256>::new_from_slice(&index_key.0[..])
+            .map_err(|_| Error::OperationInvalid("bad blind index key".into()))?;
+        mac.update(field.label());
+        mac.update(b"\0");
+        mac.update(normalized.as_bytes());
+        Ok(Self { field, hash: mac.finalize().into_bytes().to_vec() })
+    }
+}
+
+/// The key blind index tokens are made with, derived from the space key (so a new one comes
+/// with every key rotation).
+pub struct BlindIndexKey(Zeroizing<Vec<u8>>);
+
+impl BlindIndexKey {
+    /// Derive the blind index key for a space
+    pub fn new(space_key: &SecretKey) -> Result<Self> {
+        let subkey = derive_subkey(space_key, KeyPurpose::BlindIndex)?;
+        Ok(Self(Zeroizing::new(rasn::der::encode(&subkey).map_err(|_| Error::ASNSerialize)?)))
+    }
+}
+
+/// Build the blind index entries for an operation: its note's title and tags, if it touches
+/// them (whether it sets or removes them).
+pub fn blind_index(index_key: &BlindIndexKey, operation: &Operation) -> Result<Vec<BlindToken>> {
+    let mut tokens = Vec::new();
+    match operation.action() {
+        OperationAction::NoteSetV1(note) | OperationAction::NoteSetV2 { note, .. } => {
+            if let Some(title) = note.title() {
+                tokens.push(BlindToken::new(index_key, BlindField::Title, title)?);
+            }
+            for tag in note.tags() {
+                tokens.push(BlindToken::new(index_key, BlindField::Tag, tag.as_str())?);
+            }
+        }
+        OperationAction::NoteSetTitleV1(Some(title)) => {
+            tokens.push(BlindToken::new(index_key, BlindField::Title, title)?);
+        }
+        OperationAction::NoteSetTagV1(tag) | OperationAction::NoteUnsetTagV1(tag) => {
+            tokens.push(BlindToken::new(index_key, BlindField::Tag, tag.as_str())?);
+        }
+        _ => {}
+    }
+    Ok(tokens)
+}
+
+/// Encrypt an operation and attach its blind index entries. Blind indexes are opt-in (see
+/// [`BlindToken`] for what they give away), so plain [`Encryptable::encrypt`] doesn't do this.
+pub fn encrypt_indexed(operation: Operation, space_key: &SecretKey, index_key: &BlindIndexKey) -> Result<OperationEncrypted> {
+    let tokens = blind_index(index_key, &operation)?;
+    let mut encrypted = operation.encrypt(space_key)?;
+    *encrypted.blind_index_mut() = tokens;
+    Ok(encrypted)
+}
+
+/// Where a ciphertext belongs. This gets sealed along with the data it describes and checked
+/// when opening, so a ciphertext can't be lifted out of one space (or one field) and dropped
+/// into another without us noticing.
+#[derive(Clone, Debug, PartialEq, AsnType, Encode, Decode, Getters)]
+#[getset(get = "pub")]
+pub struct AssociatedData {
+    /// The space the ciphertext lives in (`None` for user data)
+    #[rasn(tag(explicit(0)))]
+    space_id: Option<SpaceID>,
+    /// What the ciphertext is, ie "turtl/op/action"
+    #[rasn(tag(explicit(1)))]
+    purpose: String,
+}
+
+impl AssociatedData {
+    /// Create new associated data
+    pub fn new(space_id: Option<SpaceID>, purpose: &str) -> Self {
+        Self { space_id, purpose: purpose.into() }
+    }
+}
+
+/// What actually gets sealed by [`seal_bound`]
+#[derive(AsnType, Encode, Decode)]
+struct BoundPayload {
+    #[rasn(tag(explicit(0)))]
+    associated: AssociatedData,
+    #[rasn(tag(explicit(1)))]
+    data: Vec<u8>,
+}
+
+impl Drop for BoundPayload {
+    fn drop(&mut self) {
+        self.data.zeroize();
+    }
+}
+
+/// Seal some data, binding it to the context it's being stored in.
+pub fn seal_bound(secret_key: &SecretKey, associated: &AssociatedData, data: &[u8]) -> Result<Sealed> {
+    let payload = BoundPayload { associated: associated.clone(), data: data.to_vec() };
+    let serialized = Zeroizing::new(rasn::der::encode(&payload).map_err(|_| Error::ASNSerialize)?);
+    Ok(seal::seal(secret_key, &serialized[..])?)
+}
+
+/// Open data sealed with [`seal_bound`], making sure it was sealed for the context we found it
+/// in.
+pub fn open_bound(secret_key: &SecretKey, associated: &AssociatedData, sealed: &Sealed) -> Result<Vec<u8>> {
+    let opened = Zeroizing::new(seal::open(secret_key, sealed)?);
+    let mut payload: BoundPayload = rasn::der::decode(&opened[..]).map_err(|_| Error::ASNDeserialize)?;
+    if &payload.associated != associated {
+        let space = payload.associated.space_id().as_ref().map(|s| format!("{:?}", s)).unwrap_or_else(|| "user".into());
+        Err(Error::CiphertextContextMismatch(format!("{} in {}", payload.associated.purpose(), space)))?;
+    }
+    Ok(std::mem::take(&mut payload.data))
+}
+
+/// HKDF label for [convergent chunk](seal_convergent) keys
+const CONVERGENT_LABEL: &[u8] = b"turtl/convergent-chunk/v1";
+
+/// How long a [convergent chunk](seal_convergent)'s synthetic nonce is
+const CONVERGENT_NONCE_LEN: usize = 24;
+
+/// Derive the key for one convergently-encrypted chunk from the space's file chunk key, the hash
+/// of the chunk's content, and how the content was compressed before sealing. The compression
+/// is part of the key so the same content compressed two different ways is never sealed under
+/// the same key.
+fn convergent_key(chunk_key: &SecretKey, hash: &Hash, compression: Option<&CompressionAlgo>) -> Result<Zeroizing<[u8; 32]>> {
+    let ikm = Zeroizing::new(rasn::der::encode(chunk_key).map_err(|_| Error::ASNSerialize)?);
+    let salt = rasn::der::encode(hash).map_err(|_| Error::ASNSerialize)?;
+    let mut info = CONVERGENT_LABEL.to_vec();
+    info.extend(rasn::der::encode(&compression.cloned()).map_err(|_| Error::ASNSerialize)?);
+    let mut okm = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(&salt[..]), &ikm[..])
+        .expand(&info[..], &mut okm[..])
+        .map_err(|_| Error::OperationInvalid("convergent key derivation failed".into()))?;
+    Ok(okm)
+}
+
+/// The nonce for a convergently-sealed payload: a keyed hash of the exact bytes being sealed. Two
+/// different payloads never share a nonce under one key, even if they somehow share the key.
+fn convergent_nonce(key: &[u8; 32], payload: &[u8]) -> Result<[u8; CONVERGENT_NONCE_LEN]> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key[..])
+        .map_err(|_| Error::OperationInvalid("convergent nonce derivation failed".into()))?;
+    mac.update(payload);
+    let mut nonce = [0u8; CONVERGENT_NONCE_LEN];
+    nonce.copy_from_slice(&mac.finalize().into_bytes()[..CONVERGENT_NONCE_LEN]);
+    Ok(nonce)
+}
+
+/// Encrypt a chunk deterministically: the same content in the same space always comes out as the
+/// same ciphertext, so a blob store (or server) can dedup chunks that were written separately on
+/// different devices.
+///
+/// `hash` is the chunk's content hash and `payload` is what actually gets sealed: the content
+/// itself, or the content compressed with `compression`. The key is derived from the content
+/// hash, the compression, and `chunk_key` (the space's [`KeyPurpose::FileChunk`] subkey). The
+/// nonce is a keyed hash of the payload (as in SIV), and is stored in front of the ciphertext. So
+/// devices that compress the same content differently get different ciphertexts (and blobs)
+/// rather than reusing a key and nonce on different plaintexts.
+///
+/// The tradeoff is a confirmation attack: anyone who holds the space key and can guess a chunk's
+/// exact content can check whether that chunk exists, and identical chunks can be linked to each
+/// other without knowing the key at all. Only the members of a space can do the former, but it
+/// means convergent encryption should stay off for spaces that hold low-entropy files (forms,
+/// templates with a few fields filled in) that members shouldn't be able to probe for. This is
+/// why it's a per-space toggle and off by default.
+pub fn seal_convergent(chunk_key: &SecretKey, hash: &Hash, compression: Option<&CompressionAlgo>, payload: &[u8]) -> Result<Vec<u8>> {
+    let key = convergent_key(chunk_key, hash, compression)?;
+    let nonce = convergent_nonce(&key, payload)?;
+    let ciphertext = XChaCha20Poly1305::new(Key::from_slice(&key[..]))
+        .encrypt(XNonce::from_slice(&nonce[..]), payload)
+        .map_err(|_| Error::FileInvalid("chunk encryption failed".into()))?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    Ok(sealed)
+}
+
+/// Open a chunk sealed with [`seal_convergent`], returning the (possibly still compressed)
+/// payload. `hash` and `compression` come from the chunk's metadata; the caller should still
+/// check the decompressed data against the hash.
+pub fn open_convergent(chunk_key: &SecretKey, hash: &Hash, compression: Option<&CompressionAlgo>, sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < CONVERGENT_NONCE_LEN {
+        Err(Error::FileInvalid("chunk is too short".into()))?;
+    }
+    let (nonce, ciphertext) = sealed.split_at(CONVERGENT_NONCE_LEN);
+    let key = convergent_key(chunk_key, hash, compression)?;
+    let payload = XChaCha20Poly1305::new(Key::from_slice(&key[..]))
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| Error::FileInvalid("chunk failed to decrypt".into()))?;
+    if convergent_nonce(&key, &payload[..])?[..] != nonce[..] {
+        Err(Error::FileInvalid("chunk nonce doesn't match its contents".into()))?;
+    }
+    Ok(payload)
+}
+
+/// An operation's content key, sealed to one member's identity crypto key.
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct EnvelopeRecipient {
+    /// Who can open this
+    #[rasn(tag(explicit(0)))]
+    member_id: MemberID,
+    /// The content key, sealed to the member's public key
+    #[rasn(tag(explicit(1)))]
+    sealed_key: CryptoKeypairMessage,
+}
+
+/// Pick out the crypto keys for a space's members, given the (public) crypto keys of the
+/// identities we know about. Returns the recipients we found keys for along with the members we
+/// didn't, who won't be able to read anything sealed to the others.
+pub fn envelope_recipients<'a>(space: &Space, identity_keys: &'a HashMap<IdentityID, CryptoKeypair>) -> (Vec<(MemberID, &'a CryptoKeypair)>, Vec<MemberID>) {
+    let mut recipients = Vec::new();
+    let mut missing = Vec::new();
+    for member in space.members() {
+        match identity_keys.get(member.user_id()) {
+            Some(key) => recipients.push((member.id().clone(), key)),
+            None => missing.push(member.id().clone()),
+        }
+    }
+    (recipients, missing)
+}
+
+/// Seal an operation so every given member can read it without having the space key.
+///
+/// The operation is encrypted with a fresh content key, and that key is sealed to each
+/// recipient's identity crypto key and carried along in the operation's envelope. This takes key
+/// distribution out of the picture for new operations: anybody in the envelope can open them with
+/// their own identity key.
+pub fn seal_for_members(operation: Operation, recipients: &[(MemberID, &CryptoKeypair)]) -> Result<OperationEncrypted> {
+    let content_key = SecretKey::new_xchacha20poly1305()?;
+    let serialized_key = Zeroizing::new(rasn::der::encode(&content_key).map_err(|_| Error::ASNSerialize)?);
+    let mut encrypted = operation.encrypt(&content_key)?;
+    let envelope = recipients.iter()
+        .map(|(member_id, keypair)| {
+            Ok(EnvelopeRecipient {
+                member_id: member_id.clone(),
+                sealed_key: keypair.seal_anonymous(&serialized_key[..])?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    *encrypted.envelope_mut() = envelope;
+    Ok(encrypted)
+}
+
+/// Open an operation sealed by [`seal_for_members`] using a member's identity crypto key (and
+/// the master key that unlocks it).
+pub fn open_for_member(encrypted: &OperationEncrypted, member_id: &MemberID, keypair: &CryptoKeypair, master_key: &SecretKey) -> Result<Operation> {
+    open_for_member_with(encrypted, member_id, &LocalProvider::new(master_key).with_crypto_keypair(keypair))
+}
+
+/// Like [`open_for_member`], but lets a [`CryptoProvider`] do the unsealing.
+pub fn open_for_member_with(encrypted: &OperationEncrypted, member_id: &MemberID, provider: &dyn CryptoProvider) -> Result<Operation> {
+    let recipient = encrypted.envelope().iter()
+        .find(|r| r.member_id() == member_id)
+        .ok_or(Error::EnvelopeNotRecipient)?;
+    let serialized_key = Zeroizing::new(provider.open_anonymous(recipient.sealed_key())?);
+    let content_key: SecretKey = rasn::der::decode(&serialized_key[..]).map_err(|_| Error::ASNDeserialize)?;
+    Operation::decrypt(&content_key, encrypted)
+}
+
+/// A read-only capability for a space.
+///
+/// A viewer key holds the subkeys needed to read a space (see [`KeyPurpose`]): the operation
+/// context and action keys, and the file chunk key. It never holds the space key itself, so a
+/// viewer can't derive any of the space's other keys (storage, backups, blind indexes, push), and
+/// can't open [legacy][CipherSuite::Legacy] operations sealed directly with the space key (run
+/// [`migrate_cipher_suite`] first).
+///
+/// The subkeys are still symmetric, though, so a viewer key can seal operations just as well as
+/// it opens them. Read-only access is only enforced by signatures: every operation is signed by
+/// the identity that made it, and the identity a viewer key is given to is registered as a
+/// [`Viewer`][crate::models::space::Viewer] in the space, so anything they create is refused
+/// when applied: [`verify_operation`][crate::models::operation::verify_operation] calls it
+/// [`Verdict::Viewer`][crate::models::operation::Verdict::Viewer], and
+/// [`State::apply_operation_by`][crate::models::state::State::apply_operation_by] refuses it
+/// with [`Error::PermissionDenied`]. Removing a viewer should be followed by a key rotation, same
+/// as removing a member.
+#[derive(AsnType, Encode, Decode, Getters)]
+#[getset(get = "pub")]
+pub struct ViewerKey {
+    /// The space this key can read
+    #[rasn(tag(explicit(0)))]
+    space_id: SpaceID,
+    /// Which of the space's keys this is (`None` if the space hasn't been rotated yet)
+    #[rasn(tag(explicit(1)))]
+    key_id: Option<SpaceKeyID>,
+    #[rasn(tag(explicit(2)))]
+    #[getset(skip)]
+    context_key: SecretKey,
+    #[rasn(tag(explicit(3)))]
+    #[getset(skip)]
+    action_key: SecretKey,
+    /// The key file chunks are sealed with, for reading files (see
+    /// [`FileReader`][crate::models::file::FileReader])
+    #[rasn(tag(explicit(4)))]
+    file_key: SecretKey,
+}
+
+impl ViewerKey {
+    /// Derive a viewer key from a space's current key.
+    pub fn new(space_id: SpaceID, key_id: Option<SpaceKeyID>, space_key: &SecretKey) -> Result<Self> {
+        Ok(Self {
+            space_id,
+            key_id,
+            context_key: derive_subkey(space_key, KeyPurpose::OperationContext)?,
+            action_key: derive_subkey(space_key, KeyPurpose::OperationAction)?,
+            file_key: derive_subkey(space_key, KeyPurpose::FileChunk)?,
+        })
+    }
+
+    /// Decrypt an operation from this key's space.
+    pub fn open(&self, encrypted: &OperationEncrypted) -> Result<Operation> {
+        let keys = OperationKeys {
+            space_key: None,
+            context_key: self.context_key.clone(),
+            action_key: self.action_key.clone(),
+        };
+        encrypted.decrypt_with(&keys)
+    }
+}
+
+/// The result of rotating a space's key.
+#[derive(Getters)]
+#[getset(get = "pub")]
+pub struct RotatedSpaceKey {
+    /// The ID of the new key
+    key_id: SpaceKeyID,
+    /// The new key. This needs to be distributed to every remaining member of the space.
+    secret_key: SecretKey,
+    /// The rotation marker followed by checkpoints of every live object in the space, all
+    /// encrypted under the new key. These should be saved as transactions in order.
+    operations: Vec<OperationEncrypted>,
+}
+
+impl RotatedSpaceKey {
+    /// Consume this rotation, returning the key ID, key, and encrypted operations.
+    pub fn consume(self) -> (SpaceKeyID, SecretKey, Vec<OperationEncrypted>) {
+        let Self { key_id, secret_key, operations } = self;
+        (key_id, secret_key, operations)
+    }
+}
+
+/// Everything that needs to happen to rotate a space's key (optionally kicking out a member in
+/// the process), laid out ahead of time so clients can show what's about to happen before doing
+/// it.
+#[derive(Getters)]
+#[getset(get = "pub")]
+pub struct RevocationPlan {
+    /// The ID the new key will have
+    key_id: SpaceKeyID,
+    /// The member being removed, if any
+    removed_member: Option<MemberID>,
+    /// Every object in the space that will be re-encrypted
+    objects: Vec<ObjectRef>,
+    /// Roughly how many bytes of (unencrypted) data will be re-encrypted
+    estimated_bytes: usize,
+    /// The operations to encrypt under the new key, in order
+    operations: Vec<Operation>,
+}
+
+impl RevocationPlan {
+    /// Plan out a key rotation for a space. If `removed_member` is given, the plan removes them
+    /// from the space as part of the rotation.
+    ///
+    /// `pre_rotation` is the space's current DAG frontier: everything at or before these
+    /// transactions is considered to be encrypted under an old key.
+    pub fn new(state: &State, space_id: &SpaceID, pre_rotation: Vec<TransactionID>, removed_member: Option<&MemberID>) -> Result<Self> {
+        let space = state.spaces().get(space_id).ok_or(Error::SpaceNotFound)?;
+        if let Some(member_id) = removed_member {
+            if space.member(member_id).is_none() {
+                Err(Error::MemberNotFound)?;
+            }
+        }
+        let key_id = SpaceKeyID::new();
+        let rotation = KeyRotation::new(key_id.clone(), pre_rotation);
+
+        let mut operations = Vec::new();
+        if let Some(member_id) = removed_member {
+            operations.push(Operation::space_unset_member(space_id.clone(), member_id.clone()));
+        }
+        operations.push(Operation::space_set_key_rotated(space_id.clone(), rotation.clone()));
+
+        let mut objects = Vec::new();
+        let mut estimated_bytes = 0;
+        for op in state.space_checkpoint(space_id)? {
+            if let Some(object) = op.context().object() {
+                objects.push(object);
+            }
+            estimated_bytes += rasn::der::encode(op.action()).map(|x| x.len()).unwrap_or(0);
+            // the space checkpoint has to reflect the removal and the rotation, otherwise
+            // applying it would undo both
+            let op = match op.consume() {
+                (_, OperationAction::SpaceSetV1(mut space)) => {
+                    if let Some(member_id) = removed_member {
+                        space.members_mut().retain(|m| m.id() != member_id);
+                    }
+                    space.add_key_rotation(rotation.clone());
+                    Operation::space_set(space)
+                }
+                (context, action) => Operation::from_parts(context, action),
+            };
+            operations.push(op);
+        }
+        Ok(Self {
+            key_id,
+            removed_member: removed_member.cloned(),
+            objects,
+            estimated_bytes,
+            operations,
+        })
+    }
+
+    /// Generate the new key and encrypt the plan's operations under it.
+    pub fn execute(self) -> Result<RotatedSpaceKey> {
+        self.execute_with(false)
+    }
+
+    /// Like [`execute`][Self::execute], but for spaces that keep a blind index: the checkpoints
+    /// are [indexed][encrypt_indexed] under the new key, so every live note can be found with
+    /// the new key's tokens.
+    pub fn execute_indexed(self) -> Result<RotatedSpaceKey> {
+        self.execute_with(true)
+    }
+
+    fn execute_with(self, indexed: bool) -> Result<RotatedSpaceKey> {
+        let Self { key_id, operations, .. } = self;
+        let secret_key = SecretKey::new_xchacha20poly1305()?;
+        let index_key = if indexed { Some(BlindIndexKey::new(&secret_key)?) } else { None };
+        let operations = operations.into_iter()
+            .map(|op| match index_key.as_ref() {
+                Some(index_key) => encrypt_indexed(op, &secret_key, index_key),
+                None => op.encrypt(&secret_key),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(RotatedSpaceKey { key_id, secret_key, operations })
+    }
+}
+
+/// Rotate a space's key. This is required whenever a member is removed from a space, otherwise
+/// they can keep reading everything written going forward.
+///
+/// This generates a new key, creates a rotation marker recording the space's current DAG
+/// frontier (`pre_rotation`), and re-encrypts checkpoints of every live object in the space under
+/// the new key. If you're removing a member, use [`Space::revocation_plan`][crate::models::space::Space::revocation_plan]
+/// instead.
+pub fn rotate_space_key(state: &State, space_id: &SpaceID, pre_rotation: Vec<TransactionID>) -> Result<RotatedSpaceKey> {
+    RevocationPlan::new(state, space_id, pre_rotation, None)?.execute()
+}
+
+/// The result of upgrading a space's operations to the current [`CipherSuite`].
+#[derive(Getters)]
+#[getset(get = "pub")]
+pub struct SuiteMigration {
+    /// The transactions in the space that use a deprecated suite. These stay in the log even
+    /// after `operations` are saved, since later transactions still name them as parents; only
+    /// [compaction][crate::storage::compact] removes operations, and only once a key rotation's
+    /// checkpoints have been acknowledged.
+    outdated: Vec<TransactionID>,
+    /// Checkpoints of every live object in the space, encrypted with the current suite. Empty if
+    /// nothing was outdated.
+    operations: Vec<OperationEncrypted>,
+}
+
+impl SuiteMigration {
+    /// Consume this migration, returning the outdated transactions and the new operations.
+    pub fn consume(self) -> (Vec<TransactionID>, Vec<OperationEncrypted>) {
+        let Self { outdated, operations } = self;
+        (outdated, operations)
+    }
+}
+
+/// Find the operations in a space that were encrypted with a deprecated [`CipherSuite`] and
+/// re-encrypt the space's current state with the current one.
+///
+/// Operations live in signed transactions and can't be changed after the fact, so "upgrading"
+/// means writing fresh checkpoints of everything in the space, same as a key rotation does but
+/// under the same key.
+pub fn migrate_cipher_suite(state: &State, space_id: &SpaceID, space_key: &SecretKey, transactions: &[Transaction]) -> Result<SuiteMigration> {
+    let outdated = transactions.iter()
+        .filter_map(|trans| {
+            let (_, encrypted) = operation::operation_from_transaction(trans).ok()?;
+            if encrypted.context().as_ref() == Some(space_id) && encrypted.suite().is_deprecated() {
+                Some(trans.id().clone())
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    let operations = if outdated.is_empty() {
+        Vec::new()
+    } else {
+        state.space_checkpoint(space_id)?
+            .into_iter()
+            .map(|op| op.encrypt(space_key))
+            .collect::<Result<Vec<_>>>()?
+    };
+    Ok(SuiteMigration { outdated, operations })
+}
+
+/// The result of [`audit_stale`].
+#[derive(Debug, Getters)]
+#[getset(get = "pub")]
+pub struct StaleAudit {
+    /// Live objects that have only ever been written under retired keys. Until each of these gets
+    /// a checkpoint under a current key, anyone holding an old key can still read it.
+    stale: Vec<ObjectRef>,
+    /// Transactions in the space we couldn't open (because we don't have the key they were
+    /// written under), so couldn't audit. An object only written by these shows up in `stale`.
+    unreadable: Vec<TransactionID>,
+}
+
+impl StaleAudit {
+    /// Whether re-encryption is finished: every live object has been written under a current key
+    /// and nothing was left unaudited.
+    pub fn is_complete(&self) -> bool {
+        self.stale.is_empty() && self.unreadable.is_empty()
+    }
+}
+
+/// Work out which key each of a space's transactions was written under, going by the space's
+/// key rotations: anything at or before a rotation's `pre_rotation` frontier was written under
+/// the key before it.
+fn transaction_key_ids<'t>(space: &Space, transactions: &[&'t Transaction]) -> HashMap<&'t TransactionID, Option<SpaceKeyID>> {
+    let previous = transactions.iter()
+        .copied()
+        .map(|t| (t.id(), t.entry().previous_transactions()))
+        .collect::<HashMap<_, _>>();
+    let mut key_ids = HashMap::new();
+    let mut prev_key_id: Option<SpaceKeyID> = None;
+    for rotation in space.key_rotations() {
+        let mut stack = rotation.pre_rotation().iter().collect::<Vec<_>>();
+        while let Some(id) = stack.pop() {
+            let (id, prev) = match previous.get_key_value(id) {
+                Some(x) => x,
+                None => continue,
+            };
+            if key_ids.contains_key(id) {
+                continue;
+            }
+            key_ids.insert(*id, prev_key_id.clone());
+            stack.extend(prev.iter());
+        }
+        prev_key_id = Some(rotation.key_id().clone());
+    }
+    for trans in transactions {
+        key_ids.entry(trans.id()).or_insert_with(|| prev_key_id.clone());
+    }
+    key_ids
+}
+
+/// After a key rotation, find the objects in a space whose data is still only encrypted under
+/// retired keys, so clients can drive re-encryption to completion (and so we can verify that
+/// revoked members really have lost access to everything).
+///
+/// `old_key_ids` are the retired keys (`None` being the space's original key) and `keys` is
+/// every key we have for the space, as returned by
+/// [`Keychain::space_keys`][crate::models::keychain::Keychain::space_keys]. Only objects that are
+/// still live in `state` are reported.
+pub fn audit_stale(transactions: &[Transaction], old_key_ids: &[Option<SpaceKeyID>], state: &State, space_id: &SpaceID, keys: &[(Option<SpaceKeyID>, SecretKey)]) -> Result<StaleAudit> {
+    let space = state.spaces().get(space_id).ok_or(Error::SpaceNotFound)?;
+    let op_keys = keys.iter()
+        .map(|(key_id, key)| Ok((key_id.clone(), OperationKeys::new(key)?)))
+        .collect::<Result<HashMap<_, _>>>()?;
+
+    let space_transactions = transactions.iter()
+        .filter_map(|trans| {
+            let (_, encrypted) = operation::operation_from_transaction(trans).ok()?;
+            if encrypted.context().as_ref() == Some(space_id) { Some((trans, encrypted)) } else { None }
+        })
+        .collect::<Vec<_>>();
+    let key_ids = transaction_key_ids(space, &space_transactions.iter().map(|(t, _)| *t).collect::<Vec<_>>());
+
+    // for each object, whether it's been written under a key that isn't retired
+    let mut fresh: HashMap<ObjectRef, bool> = HashMap::new();
+    let mut unreadable = Vec::new();
+    for (trans, encrypted) in &space_transactions {
+        let key_id = key_ids.get(trans.id()).cloned().flatten();
+        let context = match op_keys.get(&key_id).map(|keys| encrypted.get_full_context_with(keys)) {
+            Some(Ok(context)) => context,
+            _ => {
+                unreadable.push(trans.id().clone());
+                continue;
+            }
+        };
+        if let Some(object) = context.object() {
+            let is_fresh = !old_key_ids.contains(&key_id);
+            *fresh.entry(object).or_insert(false) |= is_fresh;
+        }
+    }
+
+    let stale = state.space_checkpoint(space_id)?
+        .iter()
+        .filter_map(|op| op.context().object())
+        .filter(|object| !fresh.get(object).copied().unwrap_or(false))
+        .collect::<Vec<_>>();
+    Ok(StaleAudit { stale, unreadable })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::note::{Note, NoteID, Tag},
+        test_util,
+    };
+
+    #[test]
+    fn blind_tokens_normalize_and_match_set_and_unset() {
+        let key = BlindIndexKey::new(&SecretKey::new_xchacha20poly1305().unwrap()).unwrap();
+        let token = BlindToken::new(&key, BlindField::Tag, "work").unwrap();
+        assert_eq!(BlindToken::new(&key, BlindField::Tag, " Work ").unwrap(), token);
+        assert_ne!(BlindToken::new(&key, BlindField::Title, "work").unwrap(), token);
+        assert_ne!(BlindToken::new(&key, BlindField::Tag, "play").unwrap(), token);
+
+        let space_id = SpaceID::new();
+        let note_id = NoteID::new();
+        let set = blind_index(&key, &Operation::note_set_tag(space_id.clone(), note_id.clone(), Tag::new("work"))).unwrap();
+        let unset = blind_index(&key, &Operation::note_unset_tag(space_id, note_id, Tag::new("work"))).unwrap();
+        assert_eq!(set, vec![token.clone()]);
+        assert_eq!(unset, vec![token]);
+    }
+
+    #[test]
+    fn viewer_keys_read_without_the_space_key() {
+        let space_key = SecretKey::new_xchacha20poly1305().unwrap();
+        let space_id = SpaceID::new();
+        let note_id = NoteID::new();
+        let encrypted = Operation::note_set_title(space_id.clone(), note_id.clone(), Some("hi".into())).encrypt(&space_key).unwrap();
+
+        let viewer_key = ViewerKey::new(space_id.clone(), None, &space_key).unwrap();
+        let der = rasn::der::encode(&viewer_key).unwrap();
+        let space_key_der = rasn::der::encode(&space_key).unwrap();
+        assert!(!der.windows(space_key_der.len()).any(|w| w == &space_key_der[..]));
+        let viewer_key: ViewerKey = rasn::der::decode(&der[..]).unwrap();
+        let opened = viewer_key.open(&encrypted).unwrap();
+        assert!(matches!(opened.action(), OperationAction::NoteSetTitleV1(Some(title)) if title == "hi"));
+        assert_eq!(opened.context().note().as_ref(), Some(&note_id));
+
+        let other = ViewerKey::new(space_id, None, &SecretKey::new_xchacha20poly1305().unwrap()).unwrap();
+        assert!(other.open(&encrypted).is_err());
+    }
+
+    #[test]
+    fn rotation_reindexes_live_notes() {
+        let mut state = State::new();
+        let space = Space::new("indexed".into(), test_util::identity_id());
+        let space_id = space.id().clone();
+        state.apply_operation(Operation::space_set(space)).unwrap();
+        let note = Note::new(space_id.clone(), Some("plans".into()), vec![Tag::new("work")]);
+        state.apply_operation(Operation::note_set(space_id.clone(), note).unwrap()).unwrap();
+
+        let old_key = BlindIndexKey::new(&SecretKey::new_xchacha20poly1305().unwrap()).unwrap();
+        let rotated = RevocationPlan::new(&state, &space_id, vec![], None).unwrap().execute_indexed().unwrap();
+        let new_key = BlindIndexKey::new(rotated.secret_key()).unwrap();
+        let new_token = BlindToken::new(&new_key, BlindField::Tag, "work").unwrap();
+        let old_token = BlindToken::new(&old_key, BlindField::Tag, "work").unwrap();
+        assert_ne!(new_token, old_token);
+        assert_eq!(rotated.operations().iter().filter(|op| op.has_blind_token(&new_token)).count(), 1);
+        assert!(!rotated.operations().iter().any(|op| op.has_blind_token(&old_token)));
+
+        let unindexed = RevocationPlan::new(&state, &space_id, vec![], None).unwrap().execute().unwrap();
+        assert!(unindexed.operations().iter().all(|op| op.blind_index().is_empty()));
+    }
+
+    #[test]
+    fn convergent_is_deterministic_and_opens() {
+        let key = SecretKey::new_xchacha20poly1305().unwrap();
+        let hash = Hash::new_blake3(b"chunk content").unwrap();
+        let sealed = seal_convergent(&key, &hash, None, b"chunk content").unwrap();
+        assert_eq!(sealed, seal_convergent(&key, &hash, None, b"chunk content").unwrap());
+        assert_eq!(open_convergent(&key, &hash, None, &sealed[..]).unwrap(), b"chunk content");
+    }
+
+    #[test]
+    fn convergent_never_reuses_a_nonce() {
+        let key = SecretKey::new_xchacha20poly1305().unwrap();
+        let hash = Hash::new_blake3(b"chunk content").unwrap();
+        let zstd = CompressionAlgo::Zstd;
+        // same content hash, different payloads (ie, compressed by two different devices)
+        let plain = seal_convergent(&key, &hash, None, b"chunk content").unwrap();
+        let compressed = seal_convergent(&key, &hash, Some(&zstd), b"compressed chunk").unwrap();
+        let recompressed = seal_convergent(&key, &hash, Some(&zstd), b"compressed differently").unwrap();
+        assert_ne!(plain[..CONVERGENT_NONCE_LEN], compressed[..CONVERGENT_NONCE_LEN]);
+        assert_ne!(compressed[..CONVERGENT_NONCE_LEN], recompressed[..CONVERGENT_NONCE_LEN]);
+        assert_eq!(open_convergent(&key, &hash, Some(&zstd), &recompressed[..]).unwrap(), b"compressed differently");
+        // the compression is part of the key
+        assert!(open_convergent(&key, &hash, None, &compressed[..]).is_err());
+    }
+
+    #[test]
+    fn convergent_rejects_tampering() {
+        let key = SecretKey::new_xchacha20poly1305().unwrap();
+        let hash = Hash::new_blake3(b"chunk content").unwrap();
+        let sealed = seal_convergent(&key, &hash, None, b"chunk content").unwrap();
+        for i in [0, CONVERGENT_NONCE_LEN, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 1;
+            assert!(open_convergent(&key, &hash, None, &tampered[..]).is_err());
+        }
+        assert!(open_convergent(&key, &hash, None, &sealed[..10]).is_err());
+        let other_key = SecretKey::new_xchacha20poly1305().unwrap();
+        assert!(open_convergent(&other_key, &hash, None, &sealed[..]).is_err());
+    }
+
+    #[test]
+    fn batch_decrypt_matches_one_at_a_time() {
+        let space_id = SpaceID::new();
+        let old_key = SecretKey::new_xchacha20poly1305().unwrap();
+        let new_key = SecretKey::new_xchacha20poly1305().unwrap();
+        let stranger = SecretKey::new_xchacha20poly1305().unwrap();
+        // written across a key rotation, plus some we can't open at all
+        let encrypted = (0..9)
+            .map(|i| {
+                let key = [&old_key, &new_key, &stranger][i % 3];
+                Operation::note_set_title(space_id.clone(), NoteID::new(), Some(format!("note {}", i))).encrypt(key).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let keys = vec![OperationKeys::new(&old_key).unwrap(), OperationKeys::new(&new_key).unwrap()];
+        let batched = decrypt_batch(&keys, &encrypted);
+        assert_eq!(batched.len(), encrypted.len());
+        for (i, (encrypted, batched)) in encrypted.iter().zip(batched).enumerate() {
+            let single = Operation::decrypt(&old_key, encrypted).or_else(|_| Operation::decrypt(&new_key, encrypted));
+            match (batched, single) {
+                (Ok(batched), Ok(single)) => {
+                    assert_eq!(rasn::der::encode(batched.context()).unwrap(), rasn::der::encode(single.context()).unwrap());
+                    assert_eq!(rasn::der::encode(batched.action()).unwrap(), rasn::der::encode(single.action()).unwrap());
+                    assert!(matches!(batched.action(), OperationAction::NoteSetTitleV1(Some(title)) if title == &format!("note {}", i)));
+                }
+                (Err(_), Err(_)) => assert_eq!(i % 3, 2),
+                _ => panic!("batch and single decryption disagree on operation {}", i),
+            }
+        }
+        assert!(decrypt_batch(&[], &encrypted[..1])[0].is_err());
+
+        let sealed = vec![
+            seal::seal(&old_key, b"first").unwrap(),
+            seal::seal(&new_key, b"second").unwrap(),
+            seal::seal(&old_key, b"third").unwrap(),
+        ];
+        let opened = open_batch(&old_key, &sealed);
+        assert_eq!(opened[0].as_ref().unwrap().as_slice(), b"first");
+        assert!(opened[1].is_err());
+        assert_eq!(opened[2].as_ref().unwrap().as_slice(), &seal::open(&old_key, &sealed[2]).unwrap()[..]);
+    }
+}
@@ -1,6 +1,9 @@
 //! Defines our error system.
 
-use crate::models::space::SpaceID;
+use crate::models::{
+    note::NoteIssue,
+    space::SpaceID,
+};
 use stamp_core::{
     dag::TransactionID,
     error::{Error as StampError}
@@ -18,6 +21,59 @@ pub enum Error {
     #[error("ASN serialization error")]
     ASNSerialize,
 
+    /// A key backup is malformed, from a version we don't understand, or the passphrase is wrong
+    #[error("Invalid key backup: {0}")]
+    BackupInvalid(String),
+
+    /// A blob we need isn't in the blob store
+    #[error("Blob missing")]
+    BlobMissing,
+
+    /// A blob in the blob store doesn't match the checksum it was stored with
+    #[error("Blob corrupt: {0}")]
+    BlobCorrupt(String),
+
+    /// A space bundle is malformed or from a version we don't understand
+    #[error("Invalid bundle: {0}")]
+    BundleInvalid(String),
+
+    /// We tried to open a multi-recipient operation that wasn't sealed to us
+    #[error("Not a recipient of this operation")]
+    EnvelopeNotRecipient,
+
+    /// A ciphertext was opened in a different context (space, purpose) than it was sealed in,
+    /// meaning somebody moved it
+    #[error("Ciphertext doesn't belong here (sealed for {0})")]
+    CiphertextContextMismatch(String),
+
+    /// A file can't be removed because it's still used by this many live notes
+    #[error("File is still used by {0} note(s)")]
+    FileInUse(usize),
+
+    /// A file doesn't match its metadata (missing chunks, wrong size, bad hash, etc)
+    #[error("Invalid file: {0}")]
+    FileInvalid(String),
+
+    /// An IO error (reading a file, etc)
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// A record from an old Turtl database doesn't look the way we expect
+    #[error("Invalid legacy data: {0}")]
+    LegacyInvalid(String),
+
+    /// We were asked about a space member that doesn't exist
+    #[error("Member not found")]
+    MemberNotFound,
+
+    /// A note's content doesn't match the hash it came with
+    #[error("Note content hash mismatch")]
+    NoteHashMismatch,
+
+    /// A note (or one of its sections) failed validation
+    #[error("Note invalid: {0:?}")]
+    NoteInvalid(Vec<NoteIssue>),
+
     /// An operation is invalid.
     #[error("Invalid operation: {0}")]
     OperationInvalid(String),
@@ -26,10 +82,67 @@ pub enum Error {
     #[error("Operation: missing context {0}")]
     OperationMissingContext(String),
 
+    /// Pairing a device failed: a bad or expired code, a message that didn't check out, or a
+    /// step taken out of order
+    #[error("Pairing failed: {0}")]
+    PairingFailed(String),
+
+    /// A quorum-gated operation doesn't have enough approvals (has, needs)
+    #[error("Quorum not met: {0} of {1} approvals")]
+    QuorumNotMet(u32, u32),
+
+    /// An operation would push a space over its quota
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    /// Recovery codes (or key shares) are malformed, mistyped, or there aren't enough of them
+    #[error("Recovery failed: {0}")]
+    RecoveryInvalid(String),
+
+    /// We were asked about a profile that isn't in the storage root
+    #[error("Profile not found")]
+    ProfileNotFound,
+
+    /// A crypto provider was asked to use a key it doesn't have (or can't use)
+    #[error("Crypto provider is missing the {0} key")]
+    ProviderMissingKey(String),
+
+    /// Somebody tried to do something in a space they aren't allowed to do
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+
+    /// A TOTP seed couldn't be decoded
+    #[error("Secret: invalid TOTP seed")]
+    SecretInvalidTotpSeed,
+
+    /// Tried to do something with a secret that its kind doesn't support
+    #[error("Secret: wrong kind (need {0})")]
+    SecretWrongKind(String),
+
+    /// A state snapshot can't be opened or doesn't line up with the operation log
+    #[error("Invalid snapshot: {0}")]
+    SnapshotInvalid(String),
+
+    /// A deleted space can't be removed for good until its restore window has passed
+    #[error("Space is still within its restore window")]
+    SpaceInRestoreWindow,
+
+    /// We don't have the key for a space
+    #[error("Space key missing")]
+    SpaceKeyMissing,
+
+    /// We were asked about a space we don't have
+    #[error("Space not found")]
+    SpaceNotFound,
+
     /// An error from the stamp core protocol
     #[error("Stamp error: {0}")]
     Stamp(#[from] StampError),
 
+    /// Local storage couldn't read or write something
+    #[error("Storage error: {0}")]
+    Storage(String),
+
     /// Couldn't deserialize some serialized portion(s) of a transaction.
     #[error("Transaction {0} couldn't be deserialized")]
     TransactionDeserializationError(TransactionID, rasn::error::DecodeError),
@@ -49,6 +162,10 @@ pub enum Error {
     /// The given Stamp transaction was not the right type
     #[error("Transaction {0} is the wrong variant (need ExtV1)")]
     TransactionWrongVariant(TransactionID),
+
+    /// A sync transport couldn't move transactions around
+    #[error("Transport error: {0}")]
+    Transport(String),
 }
 
 /// Wraps `std::result::Result` around our `Error` enum
@@ -0,0 +1,253 @@
+//! A stable event stream for integrations (automations, external backups, chat bridges) to build
+//! on without having to know how operations work.
+//!
+//! Every operation worth telling anyone about becomes an [`Event`], which serializes to JSON
+//! like so:
+//!
+//! ```json
+//! {
+//!   "schema": 1,
+//!   "id": "<transaction id>",
+//!   "type": "note.created",
+//!   "space_id": "<space id>",
+//!   "object": { "type": "note", "id": "<note id>" },
+//!   "author": "<identity id>",
+//!   "at": "2024-01-01T00:00:00Z"
+//! }
+//! ```
+//!
+//! The `type` values are listed on [`EventKind`]. Events only say *what* happened: to see the
+//! object itself, look it up in the state. New event types may be added without bumping
+//! [`EVENT_SCHEMA_VERSION`], so consumers should skip types they don't recognize; renaming or
+//! removing anything bumps it.
+
+use crate::{
+    activity::ActivityKind,
+    error::{Error, Result},
+    models::{
+        operation::{self, ObjectRef, Operation},
+        space::SpaceID,
+    },
+};
+use getset::Getters;
+use serde::Serialize;
+use stamp_core::{
+    dag::Transaction,
+    identity::IdentityID,
+    util::Timestamp,
+};
+use std::collections::HashSet;
+
+/// The version of the event format. Goes out with every event.
+pub const EVENT_SCHEMA_VERSION: u32 = 1;
+
+/// What happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+pub enum EventKind {
+    #[serde(rename = "note.created")]
+    NoteCreated,
+    #[serde(rename = "note.updated")]
+    NoteUpdated,
+    #[serde(rename = "note.deleted")]
+    NoteDeleted,
+    #[serde(rename = "page.created")]
+    PageCreated,
+    #[serde(rename = "page.updated")]
+    PageUpdated,
+    #[serde(rename = "page.deleted")]
+    PageDeleted,
+    #[serde(rename = "file.created")]
+    FileCreated,
+    #[serde(rename = "file.updated")]
+    FileUpdated,
+    #[serde(rename = "file.deleted")]
+    FileDeleted,
+    #[serde(rename = "space.created")]
+    SpaceCreated,
+    #[serde(rename = "space.updated")]
+    SpaceUpdated,
+    #[serde(rename = "space.deleted")]
+    SpaceDeleted,
+    #[serde(rename = "member.added")]
+    MemberAdded,
+    #[serde(rename = "member.updated")]
+    MemberUpdated,
+    #[serde(rename = "member.removed")]
+    MemberRemoved,
+    #[serde(rename = "space.key_rotated")]
+    KeyRotated,
+}
+
+impl From<ActivityKind> for EventKind {
+    fn from(kind: ActivityKind) -> Self {
+        match kind {
+            ActivityKind::NoteAdded => Self::NoteCreated,
+            ActivityKind::NoteEdited => Self::NoteUpdated,
+            ActivityKind::NoteDeleted => Self::NoteDeleted,
+            ActivityKind::PageAdded => Self::PageCreated,
+            ActivityKind::PageEdited => Self::PageUpdated,
+            ActivityKind::PageDeleted => Self::PageDeleted,
+            ActivityKind::FileAdded => Self::FileCreated,
+            ActivityKind::FileEdited => Self::FileUpdated,
+            ActivityKind::FileDeleted => Self::FileDeleted,
+            ActivityKind::SpaceCreated => Self::SpaceCreated,
+            ActivityKind::SpaceRenamed | ActivityKind::SpaceEdited => Self::SpaceUpdated,
+            ActivityKind::SpaceDeleted => Self::SpaceDeleted,
+            ActivityKind::MemberAdded => Self::MemberAdded,
+            ActivityKind::MemberEdited => Self::MemberUpdated,
+            ActivityKind::MemberRemoved => Self::MemberRemoved,
+            ActivityKind::KeyRotated => Self::KeyRotated,
+        }
+    }
+}
+
+/// The kinds of objects events can be about, for filtering.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ObjectType {
+    Space,
+    Page,
+    Note,
+    File,
+}
+
+impl ObjectType {
+    fn of(object: &ObjectRef) -> Self {
+        match object {
+            ObjectRef::Space(..) => Self::Space,
+            ObjectRef::Page(..) => Self::Page,
+            ObjectRef::Note(..) => Self::Note,
+            ObjectRef::File(..) | ObjectRef::FileChunk(..) => Self::File,
+        }
+    }
+}
+
+/// One thing that happened, in the stable format described in the [module docs][self].
+#[derive(Clone, Debug, Getters, Serialize)]
+#[getset(get = "pub")]
+pub struct Event {
+    /// Always [`EVENT_SCHEMA_VERSION`]
+    schema: u32,
+    /// The transaction the event came from. Unique per event, so consumers can dedup on it.
+    id: String,
+    #[serde(rename = "type")]
+    kind: EventKind,
+    space_id: Option<SpaceID>,
+    object: Option<ObjectRef>,
+    author: IdentityID,
+    at: Timestamp,
+}
+
+impl Event {
+    /// Build an event from an operation and the transaction it came in. Returns `None` for
+    /// operations that don't make events (user settings, file chunks, and so on).
+    pub fn from_operation(transaction: &Transaction, operation: &Operation) -> Option<Self> {
+        let kind = ActivityKind::from_action(operation.action())?;
+        let (author, _) = operation::operation_from_transaction(transaction).ok()?;
+        Some(Self {
+            schema: EVENT_SCHEMA_VERSION,
+            id: transaction.id().to_string(),
+            kind: kind.into(),
+            space_id: operation.context().space().clone(),
+            object: operation.context().object(),
+            author: author.clone(),
+            at: transaction.entry().created().clone(),
+        })
+    }
+
+    /// The kind of object this event is about
+    pub fn object_type(&self) -> Option<ObjectType> {
+        self.object.as_ref().map(ObjectType::of)
+    }
+
+    /// Serialize this event as JSON
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string(self).map_err(|e| Error::OperationInvalid(format!("event serialization failed: {}", e)))
+    }
+}
+
+/// Picks which events an integration wants. An empty filter lets everything through.
+#[derive(Clone, Debug, Default)]
+pub struct EventFilter {
+    spaces: Option<HashSet<SpaceID>>,
+    object_types: Option<HashSet<ObjectType>>,
+    kinds: Option<HashSet<EventKind>>,
+}
+
+impl EventFilter {
+    /// A filter that lets everything through
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only let through events in this space (call more than once for more spaces)
+    pub fn space(mut self, space_id: SpaceID) -> Self {
+        self.spaces.get_or_insert_with(HashSet::new).insert(space_id);
+        self
+    }
+
+    /// Only let through events about this type of object (call more than once for more types)
+    pub fn object_type(mut self, object_type: ObjectType) -> Self {
+        self.object_types.get_or_insert_with(HashSet::new).insert(object_type);
+        self
+    }
+
+    /// Only let through this kind of event (call more than once for more kinds)
+    pub fn kind(mut self, kind: EventKind) -> Self {
+        self.kinds.get_or_insert_with(HashSet::new).insert(kind);
+        self
+    }
+
+    /// Whether an event gets through this filter
+    pub fn matches(&self, event: &Event) -> bool {
+        let space_ok = match (&self.spaces, &event.space_id) {
+            (None, _) => true,
+            (Some(spaces), Some(space_id)) => spaces.contains(space_id),
+            (Some(_), None) => false,
+        };
+        let type_ok = match (&self.object_types, event.object_type()) {
+            (None, _) => true,
+            (Some(types), Some(object_type)) => types.contains(&object_type),
+            (Some(_), None) => false,
+        };
+        let kind_ok = self.kinds.as_ref().map(|kinds| kinds.contains(&event.kind)).unwrap_or(true);
+        space_ok && type_ok && kind_ok
+    }
+}
+
+/// Collects events for one integration as operations are applied.
+///
+/// Feed it every operation after it's applied to the state (ie, alongside
+/// [`Inbox::apply`][crate::sync::Inbox::apply] and local commits), then drain it wherever the
+/// events are being delivered.
+#[derive(Debug, Default)]
+pub struct EventStream {
+    filter: EventFilter,
+    pending: Vec<Event>,
+}
+
+impl EventStream {
+    /// Create a stream that only collects events passing `filter`
+    pub fn new(filter: EventFilter) -> Self {
+        Self { filter, pending: Vec::new() }
+    }
+
+    /// Record an applied operation.
+    pub fn observe(&mut self, transaction: &Transaction, operation: &Operation) {
+        if let Some(event) = Event::from_operation(transaction, operation) {
+            if self.filter.matches(&event) {
+                self.pending.push(event);
+            }
+        }
+    }
+
+    /// Grab the events collected since the last drain, oldest first.
+    pub fn drain(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Like [`EventStream::drain`], as JSON lines.
+    pub fn drain_json(&mut self) -> Result<Vec<String>> {
+        self.drain().iter().map(|e| e.to_json()).collect()
+    }
+}
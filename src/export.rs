@@ -0,0 +1,225 @@
+//! Exporting spaces into self-contained bundles that can be written to disk and re-imported later
+//! (see [`import`][crate::import]), for backups or for taking your data with you.
+
+use crate::{
+    error::{Error, Result},
+    models::{
+        file::{File, FileChunk, FileChunkID},
+        note::Note,
+        page::Page,
+        space::{Space, SpaceID, SpaceKeyID},
+        state::State,
+    },
+};
+use getset::Getters;
+use rasn::{AsnType, Decode, Encode};
+use stamp_core::{
+    crypto::base::SecretKey,
+    util::Timestamp,
+};
+
+/// The bundle format version we write. Bumped whenever the bundle layout changes in a way older
+/// importers can't handle.
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// Describes what's in a bundle without having to dig through the whole thing.
+#[derive(Clone, AsnType, Encode, Decode, Getters)]
+#[getset(get = "pub")]
+pub struct BundleManifest {
+    /// The bundle format version
+    #[rasn(tag(explicit(0)))]
+    version: u32,
+    /// The space that was exported
+    #[rasn(tag(explicit(1)))]
+    space_id: SpaceID,
+    /// The space's title at the time of export
+    #[rasn(tag(explicit(2)))]
+    title: String,
+    /// When the export happened
+    #[rasn(tag(explicit(3)))]
+    exported: Timestamp,
+    #[rasn(tag(explicit(4)))]
+    num_pages: u64,
+    #[rasn(tag(explicit(5)))]
+    num_notes: u64,
+    #[rasn(tag(explicit(6)))]
+    num_files: u64,
+    #[rasn(tag(explicit(7)))]
+    num_chunks: u64,
+}
+
+/// One of the keys a space has used. Bundling these lets a restored space still read its
+/// history as it comes in from other members.
+#[derive(AsnType, Encode, Decode, Getters)]
+#[getset(get = "pub")]
+pub struct BundleKey {
+    /// Which key this is (`None` for a space's original key)
+    #[rasn(tag(explicit(0)))]
+    key_id: Option<SpaceKeyID>,
+    #[rasn(tag(explicit(1)))]
+    secret_key: SecretKey,
+}
+
+impl BundleKey {
+    /// Create a new bundle key
+    pub fn new(key_id: Option<SpaceKeyID>, secret_key: SecretKey) -> Self {
+        Self { key_id, secret_key }
+    }
+}
+
+/// The (decrypted) contents of a file chunk.
+#[derive(Clone, AsnType, Encode, Decode, Getters)]
+#[getset(get = "pub")]
+pub struct BundleChunkData {
+    #[rasn(tag(explicit(0)))]
+    chunk_id: FileChunkID,
+    #[rasn(tag(explicit(1)))]
+    data: Vec<u8>,
+}
+
+impl BundleChunkData {
+    /// Consume this chunk data, returning the chunk ID and data.
+    pub fn consume(self) -> (FileChunkID, Vec<u8>) {
+        let Self { chunk_id, data } = self;
+        (chunk_id, data)
+    }
+}
+
+/// A self-contained copy of a space: every live object in it, its file data, and its keys.
+///
+/// Note that bundles are NOT encrypted. If you're writing one somewhere, encrypt it first.
+#[derive(AsnType, Encode, Decode, Getters)]
+#[getset(get = "pub")]
+pub struct SpaceBundle {
+    #[rasn(tag(explicit(0)))]
+    manifest: BundleManifest,
+    #[rasn(tag(explicit(1)))]
+    space: Space,
+    #[rasn(tag(explicit(2)))]
+    pages: Vec<Page>,
+    #[rasn(tag(explicit(3)))]
+    notes: Vec<Note>,
+    #[rasn(tag(explicit(4)))]
+    files: Vec<File>,
+    #[rasn(tag(explicit(5)))]
+    chunks: Vec<FileChunk>,
+    /// File chunk contents. The core only tracks chunk metadata, so callers attach the actual
+    /// data via [`SpaceBundle::add_chunk_data`].
+    #[rasn(tag(explicit(6)))]
+    chunk_data: Vec<BundleChunkData>,
+    #[rasn(tag(explicit(7)))]
+    keys: Vec<BundleKey>,
+}
+
+impl SpaceBundle {
+    /// Attach the contents of one of the bundle's file chunks.
+    pub fn add_chunk_data(&mut self, chunk_id: FileChunkID, data: Vec<u8>) -> Result<()> {
+        if !self.chunks.iter().any(|c| c.id() == &chunk_id) {
+            Err(Error::BundleInvalid(format!("chunk {:?} is not part of this bundle", chunk_id)))?;
+        }
+        self.chunk_data.retain(|c| c.chunk_id() != &chunk_id);
+        self.chunk_data.push(BundleChunkData { chunk_id, data });
+        Ok(())
+    }
+
+    /// Find the data for a chunk, if we have it
+    pub fn chunk_data_for(&self, chunk_id: &FileChunkID) -> Option<&[u8]> {
+        self.chunk_data.iter()
+            .find(|c| c.chunk_id() == chunk_id)
+            .map(|c| c.data().as_slice())
+    }
+
+    /// List the chunks we have metadata for but no data.
+    pub fn missing_chunks(&self) -> Vec<&FileChunkID> {
+        self.chunks.iter()
+            .map(|c| c.id())
+            .filter(|id| self.chunk_data_for(id).is_none())
+            .collect()
+    }
+
+    /// Serialize this bundle so it can be written somewhere.
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        rasn::der::encode(self).map_err(|_| Error::ASNSerialize)
+    }
+
+    /// Read a bundle from its serialized form, making sure we know how to handle its version.
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        let bundle: Self = rasn::der::decode(bytes).map_err(|_| Error::ASNDeserialize)?;
+        if bundle.manifest().version() > &BUNDLE_VERSION {
+            Err(Error::BundleInvalid(format!("unsupported bundle version {}", bundle.manifest().version())))?;
+        }
+        Ok(bundle)
+    }
+
+    /// Break this bundle into its parts.
+    pub fn consume(self) -> (BundleManifest, Space, Vec<Page>, Vec<Note>, Vec<File>, Vec<FileChunk>, Vec<BundleChunkData>, Vec<BundleKey>) {
+        let Self { manifest, space, pages, notes, files, chunks, chunk_data, keys } = self;
+        (manifest, space, pages, notes, files, chunks, chunk_data, keys)
+    }
+}
+
+/// Export a space and everything in it into a bundle, as of `now`. `keys` should be every key the
+/// space has used (see [`BundleKey`]).
+///
+/// Only chunk metadata comes from the state, so use [`SpaceBundle::add_chunk_data`] to fill in
+/// the file contents (and [`SpaceBundle::missing_chunks`] to see what's left) before saving.
+pub fn space_bundle(space_id: &SpaceID, state: &State, keys: Vec<BundleKey>, now: &Timestamp) -> Result<SpaceBundle> {
+    let space = state.spaces().get(space_id).ok_or(Error::SpaceNotFound)?.clone();
+    let pages = state.pages().values()
+        .filter(|p| p.space_id() == space_id)
+        .cloned()
+        .collect::<Vec<_>>();
+    let notes = state.notes().values()
+        .filter(|n| n.space_id() == space_id)
+        .cloned()
+        .collect::<Vec<_>>();
+    let files = state.files().values()
+        .filter(|f| f.space_id() == space_id)
+        .cloned()
+        .collect::<Vec<_>>();
+    let chunks = state.chunks().values()
+        .filter(|c| files.iter().any(|f| f.id() == c.file_id()))
+        .cloned()
+        .collect::<Vec<_>>();
+    let manifest = BundleManifest {
+        version: BUNDLE_VERSION,
+        space_id: space_id.clone(),
+        title: space.title().clone(),
+        exported: now.clone(),
+        num_pages: pages.len() as u64,
+        num_notes: notes.len() as u64,
+        num_files: files.len() as u64,
+        num_chunks: chunks.len() as u64,
+    };
+    Ok(SpaceBundle { manifest, space, pages, notes, files, chunks, chunk_data: Vec::new(), keys })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{note::Note, operation::Operation},
+        test_util,
+    };
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn manifest_describes_the_export() {
+        let space = Space::new("home".into(), test_util::identity_id());
+        let space_id = space.id().clone();
+        let mut state = State::new();
+        state.apply_operation(Operation::space_set(space)).unwrap();
+        state.apply_operation(Operation::note_set(space_id.clone(), Note::new(space_id.clone(), None, vec![])).unwrap()).unwrap();
+        let now = Timestamp::from(Utc.timestamp_opt(1_700_000_000, 0).unwrap());
+
+        let bundle = space_bundle(&space_id, &state, Vec::new(), &now).unwrap();
+        let bundle = SpaceBundle::deserialize(&bundle.serialize().unwrap()).unwrap();
+        let manifest = bundle.manifest();
+        assert_eq!(manifest.space_id(), &space_id);
+        assert_eq!(manifest.title(), "home");
+        assert_eq!(**manifest.exported(), *now);
+        assert_eq!(manifest.num_notes(), &1);
+        assert_eq!(manifest.num_pages(), &0);
+        assert!(matches!(space_bundle(&SpaceID::new(), &state, Vec::new(), &now), Err(Error::SpaceNotFound)));
+    }
+}
@@ -0,0 +1,356 @@
+//! Importing spaces from bundles created by [`export`][crate::export].
+
+use crate::{
+    crypto::{self, KeyPurpose},
+    error::{Error, Result},
+    export::{BundleKey, SpaceBundle},
+    models::{
+        file::{FileChunkID, FileID, FileStorage},
+        note::{NoteID, SectionSpec},
+        operation::Operation,
+        page::{PageID, Slice, SliceFilter},
+        space::{MemberID, SpaceID, Viewer, ViewerID},
+    },
+};
+use getset::Getters;
+use stamp_core::crypto::base::SecretKey;
+use std::collections::HashMap;
+
+/// How to treat the IDs in a bundle we're importing.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ImportMode {
+    /// Keep every ID as-is. Use this when restoring a space from a backup, so the restored space
+    /// lines up with the copies other members have.
+    Restore,
+    /// Give the space and everything in it brand new IDs, creating an independent copy. The
+    /// copy starts over with a fresh key, so the bundle's keys are swapped out for it.
+    Copy,
+}
+
+/// A space recreated from a bundle.
+#[derive(Getters)]
+#[getset(get = "pub")]
+pub struct ImportedSpace {
+    /// The (possibly new) ID of the imported space
+    space_id: SpaceID,
+    /// Operations that build the space from scratch, in order. Encrypt and save these like any
+    /// other operation and the space syncs normally.
+    operations: Vec<Operation>,
+    /// File chunk contents, keyed by (possibly new) chunk ID
+    chunk_data: HashMap<FileChunkID, Vec<u8>>,
+    /// The space's keys. For [`ImportMode::Copy`] this is just the copy's fresh key, which any
+    /// inline file data has already been sealed under.
+    keys: Vec<BundleKey>,
+}
+
+impl ImportedSpace {
+    /// Consume this import, returning the space ID, operations, chunk data, and keys.
+    pub fn consume(self) -> (SpaceID, Vec<Operation>, HashMap<FileChunkID, Vec<u8>>, Vec<BundleKey>) {
+        let Self { space_id, operations, chunk_data, keys } = self;
+        (space_id, operations, chunk_data, keys)
+    }
+}
+
+/// Old ID -> new ID mappings for everything in a bundle.
+#[derive(Default)]
+struct IdMap {
+    pages: HashMap<PageID, PageID>,
+    notes: HashMap<NoteID, NoteID>,
+    files: HashMap<FileID, FileID>,
+    chunks: HashMap<FileChunkID, FileChunkID>,
+}
+
+impl IdMap {
+    fn page(&self, id: &PageID) -> PageID {
+        self.pages.get(id).cloned().unwrap_or_else(|| id.clone())
+    }
+
+    fn note(&self, id: &NoteID) -> NoteID {
+        self.notes.get(id).cloned().unwrap_or_else(|| id.clone())
+    }
+
+    fn file(&self, id: &FileID) -> FileID {
+        self.files.get(id).cloned().unwrap_or_else(|| id.clone())
+    }
+
+    fn chunk(&self, id: &FileChunkID) -> FileChunkID {
+        self.chunks.get(id).cloned().unwrap_or_else(|| id.clone())
+    }
+
+    /// Point any note references in a filter at their new IDs
+    fn remap_filter(&self, filter: &mut SliceFilter) {
+        match filter {
+            SliceFilter::And(filters) | SliceFilter::Or(filters) => {
+                for filter in filters {
+                    self.remap_filter(filter);
+                }
+            }
+            SliceFilter::Not(filter) => self.remap_filter(filter),
+            SliceFilter::LinksTo(note_id) => *note_id = self.note(note_id),
+            _ => {}
+        }
+    }
+}
+
+/// Recreate a space from a serialized [`SpaceBundle`].
+///
+/// References to objects outside of the bundle (ie, a link to a note in another space) are left
+/// alone.
+pub fn space_bundle(bytes: &[u8], mode: ImportMode) -> Result<ImportedSpace> {
+    let (_manifest, mut space, mut pages, mut notes, mut files, mut chunks, chunk_data, keys) = SpaceBundle::deserialize(bytes)?.consume();
+
+    let mut ids = IdMap::default();
+    let keys = if mode == ImportMode::Copy {
+        for page in &pages {
+            ids.pages.insert(page.id().clone(), PageID::new());
+        }
+        for note in &notes {
+            ids.notes.insert(note.id().clone(), NoteID::new());
+        }
+        for file in &files {
+            ids.files.insert(file.id().clone(), FileID::new());
+        }
+        for chunk in &chunks {
+            ids.chunks.insert(chunk.id().clone(), FileChunkID::new());
+        }
+
+        let space_id = SpaceID::new();
+        *space.id_mut() = space_id.clone();
+        *space.default_page_mut() = space.default_page().as_ref().map(|p| ids.page(p));
+        // a copy starts with a fresh key, so the old rotations mean nothing
+        space.key_rotations_mut().clear();
+        for member in space.members_mut() {
+            *member.id_mut() = MemberID::new();
+            *member.space_id_mut() = space_id.clone();
+            // a scope pointing outside the bundle can't be honored, so drop those pages rather
+            // than widen what the member sees
+            let avatar = member.profile().avatar().as_ref().map(|f| ids.file(f));
+            *member.profile_mut().avatar_mut() = avatar;
+            if let Some(scope) = member.scope_mut() {
+                *scope = scope.iter().filter_map(|p| ids.pages.get(p).cloned()).collect();
+            }
+        }
+        let viewers = space.viewers().iter()
+            .map(|v| Viewer::new(ViewerID::new(), v.user_id().clone(), v.label().clone()))
+            .collect::<Vec<_>>();
+        *space.viewers_mut() = viewers;
+
+        for page in pages.iter_mut() {
+            *page.id_mut() = ids.page(page.id());
+            *page.space_id_mut() = space_id.clone();
+            match page.slice_mut() {
+                Slice::Filtered { filter, .. } => ids.remap_filter(filter),
+                Slice::Manual(note_ids) => {
+                    for note_id in note_ids {
+                        *note_id = ids.note(note_id);
+                    }
+                }
+            }
+        }
+        for note in notes.iter_mut() {
+            *note.id_mut() = ids.note(note.id());
+            *note.space_id_mut() = space_id.clone();
+            for section in note.body_mut().sections_mut().values_mut() {
+                match section.spec_mut() {
+                    SectionSpec::NoteLink(note_id) => *note_id = ids.note(note_id),
+                    SectionSpec::PageLink(page_id) => *page_id = ids.page(page_id),
+                    SectionSpec::File { id, .. } => *id = ids.file(id),
+                    _ => {}
+                }
+            }
+        }
+        let copy_key = SecretKey::new_xchacha20poly1305()?;
+        let copy_file_key = crypto::derive_subkey(&copy_key, KeyPurpose::FileChunk)?;
+        let file_keys = keys.iter()
+            .map(|k| crypto::derive_subkey(k.secret_key(), KeyPurpose::FileChunk))
+            .collect::<Result<Vec<_>>>()?;
+        for file in files.iter_mut() {
+            // inline data is sealed under whichever key was current when the file was written
+            if matches!(file.storage(), FileStorage::InlineData(..)) {
+                *file = file_keys.iter()
+                    .find_map(|k| file.reseal(k, &copy_file_key).ok())
+                    .ok_or_else(|| Error::BundleInvalid(format!("none of the bundle's keys open file {:?}", file.id())))?;
+            }
+            *file.id_mut() = ids.file(file.id());
+            *file.space_id_mut() = space_id.clone();
+            *file.preview_chunk_mut() = file.preview_chunk().as_ref().map(|c| ids.chunk(c));
+        }
+        for chunk in chunks.iter_mut() {
+            *chunk.id_mut() = ids.chunk(chunk.id());
+            *chunk.file_id_mut() = ids.file(chunk.file_id());
+            // the data gets sealed again under the copy's key, so the old blob hash is wrong
+            chunk.clear_blob();
+        }
+        vec![BundleKey::new(None, copy_key)]
+    } else {
+        keys
+    };
+
+    let space_id = space.id().clone();
+    let mut operations = vec![Operation::space_set(space)];
+    for page in pages {
+        operations.push(Operation::page_set(space_id.clone(), page));
+    }
+    for note in notes {
+        operations.push(Operation::note_set(space_id.clone(), note)?);
+    }
+    for file in files {
+        operations.push(Operation::file_set(space_id.clone(), file));
+    }
+    for chunk in chunks {
+        let file_id = chunk.file_id().clone();
+        operations.push(Operation::file_set_chunk(space_id.clone(), file_id, chunk));
+    }
+    let chunk_data = chunk_data.into_iter()
+        .map(|c| {
+            let (chunk_id, data) = c.consume();
+            (ids.chunk(&chunk_id), data)
+        })
+        .collect::<HashMap<_, _>>();
+
+    Ok(ImportedSpace { space_id, operations, chunk_data, keys })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blob::FsBlobStore,
+        export,
+        models::{
+            file::{FileReader, FileWriter},
+            page::Page,
+            space::{Member, MemberProfile, Role, Space},
+            state::State,
+        },
+        test_util,
+    };
+    use stamp_core::util::Timestamp;
+
+    fn file_key(space_key: &SecretKey) -> SecretKey {
+        crypto::derive_subkey(space_key, KeyPurpose::FileChunk).unwrap()
+    }
+
+    /// Copy a space out of `state`, returning the copy's state and key.
+    fn copy(state: &State, space_id: &SpaceID, space_key: &SecretKey) -> (State, SecretKey) {
+        let keys = vec![BundleKey::new(None, space_key.clone())];
+        let bytes = export::space_bundle(space_id, state, keys, &Timestamp::now()).unwrap().serialize().unwrap();
+        let (_, operations, _, mut keys) = space_bundle(&bytes, ImportMode::Copy).unwrap().consume();
+        let mut copied = State::new();
+        for op in operations {
+            copied.apply_operation(op).unwrap();
+        }
+        assert_eq!(keys.len(), 1);
+        (copied, keys.pop().unwrap().secret_key().clone())
+    }
+
+    #[test]
+    fn copy_reseals_inline_data_under_the_new_key() {
+        let blobs = FsBlobStore::new(test_util::temp_dir("import-inline")).unwrap();
+        let key = SecretKey::new_xchacha20poly1305().unwrap();
+        let space = Space::new("notes".into(), test_util::identity_id());
+        let space_id = space.id().clone();
+        let mut state = State::new();
+        state.apply_operation(Operation::space_set(space)).unwrap();
+        let mut writer = FileWriter::new(&b"a small file"[..], &file_key(&key), space_id.clone(), "small.txt".into(), None);
+        while writer.next_chunk().unwrap().is_some() {}
+        state.apply_operation(writer.finish().unwrap()).unwrap();
+
+        let (copied, copy_key) = copy(&state, &space_id, &key);
+        let file = copied.files().values().next().unwrap().clone();
+        assert!(matches!(file.storage(), FileStorage::InlineData(..)));
+        let read = |key: &SecretKey| {
+            FileReader::from_store(file.clone(), Vec::new(), key, &blobs)
+                .and_then(|reader| reader.collect::<Result<Vec<_>>>())
+                .map(|parts| parts.concat())
+        };
+        assert_eq!(read(&file_key(&copy_key)).unwrap(), b"a small file");
+        assert!(read(&file_key(&key)).is_err());
+    }
+
+    #[test]
+    fn copy_fails_when_no_key_opens_inline_data() {
+        let key = SecretKey::new_xchacha20poly1305().unwrap();
+        let space = Space::new("notes".into(), test_util::identity_id());
+        let space_id = space.id().clone();
+        let mut state = State::new();
+        state.apply_operation(Operation::space_set(space)).unwrap();
+        let mut writer = FileWriter::new(&b"a small file"[..], &file_key(&key), space_id.clone(), "small.txt".into(), None);
+        while writer.next_chunk().unwrap().is_some() {}
+        state.apply_operation(writer.finish().unwrap()).unwrap();
+
+        let other = BundleKey::new(None, SecretKey::new_xchacha20poly1305().unwrap());
+        let bytes = export::space_bundle(&space_id, &state, vec![other], &Timestamp::now()).unwrap().serialize().unwrap();
+        assert!(matches!(space_bundle(&bytes, ImportMode::Copy), Err(Error::BundleInvalid(..))));
+        assert!(space_bundle(&bytes, ImportMode::Restore).is_ok());
+    }
+
+    #[test]
+    fn copy_remaps_preview_chunks() {
+        let blobs = FsBlobStore::new(test_util::temp_dir("import-preview")).unwrap();
+        let key = SecretKey::new_xchacha20poly1305().unwrap();
+        let space = Space::new("photos".into(), test_util::identity_id());
+        let space_id = space.id().clone();
+        let mut state = State::new();
+        state.apply_operation(Operation::space_set(space)).unwrap();
+        let mut writer = FileWriter::new(&b"not really a png"[..], &file_key(&key), space_id.clone(), "cat.png".into(), None);
+        let file_id = writer.file_id().clone();
+        while writer.next_chunk().unwrap().is_some() {}
+        state.apply_operation(writer.finish().unwrap()).unwrap();
+        let file = state.files().get(&file_id).unwrap().clone();
+        let (preview, set_preview) = file.attach_preview(&file_key(&key), b"thumb".to_vec()).unwrap();
+        state.apply_operation(preview.store(&blobs).unwrap()).unwrap();
+        state.apply_operation(set_preview).unwrap();
+
+        let (copied, _) = copy(&state, &space_id, &key);
+        let file = copied.files().values().next().unwrap();
+        let preview_id = file.preview_chunk().clone().unwrap();
+        assert_ne!(file.id(), &file_id);
+        assert!(!state.chunks().contains_key(&preview_id));
+        assert_eq!(copied.chunks().get(&preview_id).unwrap().file_id(), file.id());
+    }
+
+    #[test]
+    fn copy_remaps_member_scopes() {
+        let key = SecretKey::new_xchacha20poly1305().unwrap();
+        let mut space = Space::new("shared".into(), test_util::identity_id());
+        let space_id = space.id().clone();
+        let page = Page::new(space_id.clone(), "guests".into(), Slice::Manual(vec![]));
+        let page_id = page.id().clone();
+        let mut guest = Member::new(space_id.clone(), test_util::identity_id(), Role::Guest);
+        *guest.scope_mut() = Some(vec![page_id.clone(), PageID::new()]);
+        let guest_user = guest.user_id().clone();
+        space.members_mut().push(guest);
+        let mut state = State::new();
+        state.apply_operation(Operation::space_set(space)).unwrap();
+        state.apply_operation(Operation::page_set(space_id.clone(), page)).unwrap();
+
+        let (copied, _) = copy(&state, &space_id, &key);
+        let space = copied.spaces().values().next().unwrap();
+        let new_page_id = copied.pages().keys().next().unwrap();
+        assert_ne!(new_page_id, &page_id);
+        let guest = space.members().iter().find(|m| m.user_id() == &guest_user).unwrap();
+        assert_eq!(guest.scope(), &Some(vec![new_page_id.clone()]));
+    }
+
+    #[test]
+    fn copy_remaps_member_avatars() {
+        let key = SecretKey::new_xchacha20poly1305().unwrap();
+        let mut space = Space::new("shared".into(), test_util::identity_id());
+        let space_id = space.id().clone();
+        let mut writer = FileWriter::new(&b"me"[..], &file_key(&key), space_id.clone(), "me.png".into(), None);
+        let file_id = writer.file_id().clone();
+        while writer.next_chunk().unwrap().is_some() {}
+        *space.members_mut()[0].profile_mut() = MemberProfile::new(Some("andrew".into()), None, Some(file_id.clone()), None);
+        let mut state = State::new();
+        state.apply_operation(Operation::space_set(space)).unwrap();
+        state.apply_operation(writer.finish().unwrap()).unwrap();
+
+        let (copied, _) = copy(&state, &space_id, &key);
+        let space = copied.spaces().values().next().unwrap();
+        let new_file_id = copied.files().keys().next().unwrap();
+        assert_ne!(new_file_id, &file_id);
+        assert_eq!(space.members()[0].profile().avatar(), &Some(new_file_id.clone()));
+    }
+}
@@ -0,0 +1,106 @@
+//! Bringing data over from Turtl v0.7.
+//!
+//! Old Turtl stored a note's attachment as one big encrypted blob hanging off the note, with the
+//! file's name/type/size living in the note itself. Here we take those records (already
+//! decrypted with the old keys) and re-chunk them into the [`File`][crate::models::file::File] /
+//! [`FileChunk`][crate::models::file::FileChunk] model, so attachments survive the move.
+//!
+//! Reading a whole old database lives in `database` (with the `sqlite` feature).
+
+#[cfg(feature = "sqlite")]
+pub mod database;
+
+use crate::{
+    blob::BlobStore,
+    error::{Error, Result},
+    models::{
+        file::{self, FileID, FileWriter},
+        note::SectionSpec,
+        operation::Operation,
+        space::SpaceID,
+    },
+};
+use getset::Getters;
+use serde::{Deserialize, Serialize};
+use stamp_core::crypto::base::SecretKey;
+
+/// A v0.7 file record, pulled from the old note's `file` field along with the decrypted body.
+#[derive(Clone, Debug, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct LegacyFile {
+    /// The ID of the old note this file was attached to
+    note_id: String,
+    /// The file's name
+    name: String,
+    /// The file's mime type, if the old record had one
+    #[serde(rename = "type")]
+    ty: Option<String>,
+    /// The size the old record claims the file is
+    size: Option<u64>,
+    /// The decrypted file contents
+    #[serde(skip)]
+    data: Vec<u8>,
+}
+
+impl LegacyFile {
+    /// Create a new legacy file record
+    pub fn new(note_id: String, name: String, ty: Option<String>, size: Option<u64>, data: Vec<u8>) -> Self {
+        Self { note_id, name, ty, size, data }
+    }
+}
+
+/// A legacy file after being converted.
+#[derive(Getters)]
+#[getset(get = "pub")]
+pub struct MigratedFile {
+    /// The ID of the old note this file was attached to
+    legacy_note_id: String,
+    /// The new file's ID
+    file_id: FileID,
+    /// The operation that creates the file
+    operation: Operation,
+    /// The chunk operations, which should be saved before `operation`
+    chunk_operations: Vec<Operation>,
+    /// Whether the file should be embedded in its note (images, audio, video) rather than linked
+    embed: bool,
+}
+
+impl MigratedFile {
+    /// The section that puts this file into the migrated note's body, matching the way v0.7
+    /// displayed it.
+    pub fn section_spec(&self) -> SectionSpec {
+        SectionSpec::File { id: self.file_id.clone(), embed: self.embed }
+    }
+
+    /// Consume this migrated file, returning the file operation and its chunk operations.
+    pub fn consume(self) -> (FileID, Operation, Vec<Operation>) {
+        let Self { file_id, operation, chunk_operations, .. } = self;
+        (file_id, operation, chunk_operations)
+    }
+}
+
+/// Convert a v0.7 file into the new model, chunking and encrypting its contents with `secret_key`
+/// and putting the chunks in the blob store.
+pub fn migrate_file(legacy: LegacyFile, space_id: SpaceID, secret_key: &SecretKey, blobs: &dyn BlobStore) -> Result<MigratedFile> {
+    let LegacyFile { note_id, name, ty, size, data } = legacy;
+    if let Some(size) = size {
+        if size != data.len() as u64 {
+            Err(Error::FileInvalid(format!("legacy file for note {} should be {} bytes but is {}", note_id, size, data.len())))?;
+        }
+    }
+    // old clients didn't always record a type, so take a look for ourselves
+    let ty = ty
+        .filter(|t| !t.is_empty())
+        .or_else(|| file::sniff_mime(&data[..data.len().min(16)], &name));
+    let embed = ty.as_ref()
+        .map(|t| t.starts_with("image/") || t.starts_with("audio/") || t.starts_with("video/"))
+        .unwrap_or(false);
+    let mut writer = FileWriter::new(&data[..], secret_key, space_id, name, ty);
+    let file_id = writer.file_id().clone();
+    let mut chunk_operations = Vec::new();
+    while let Some(chunk) = writer.next_chunk()? {
+        chunk_operations.push(chunk.store(blobs)?);
+    }
+    let operation = writer.finish()?;
+    Ok(MigratedFile { legacy_note_id: note_id, file_id, operation, chunk_operations, embed })
+}
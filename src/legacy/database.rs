@@ -0,0 +1,370 @@
+//! Reading a whole v0.7 Turtl database.
+//!
+//! The old desktop and mobile apps kept everything in one SQLite database, as JSON objects in a
+//! `dumpy_objects` table (`id`, `table_name`, `data`) where `table_name` is `spaces`, `boards`,
+//! `notes`, or `keychain`. Old spaces become spaces, boards become pages in their space (a board
+//! whose space is gone becomes a space of its own), and each old note type is turned into
+//! [`Section`]s.
+//!
+//! The old crypto isn't reimplemented here. Whoever has the old account's keys decrypts records
+//! through a [`LegacyDecryptor`], and we take it from there. Each imported space gets a brand new
+//! key and is owned by the importing identity; old members, sharing, and timestamps don't come
+//! along.
+
+use crate::{
+    blob::BlobStore,
+    error::{Error, Result},
+    legacy::{self, LegacyFile},
+    models::{
+        note::{Note, NoteID, RevealPolicy, Secret, SecretKind, Section, SectionSpec, Tag, MAX_INDENT},
+        operation::Operation,
+        page::{Page, Slice},
+        space::{Space, SpaceID},
+    },
+};
+use getset::Getters;
+use rusqlite::{params, Connection, OpenFlags};
+use serde_json::Value;
+use stamp_core::{crypto::base::SecretKey, identity::IdentityID};
+use std::collections::HashMap;
+use std::path::Path;
+use url::Url;
+
+/// Opens the encrypted parts of old records.
+pub trait LegacyDecryptor {
+    /// Decrypt an old space, board, or note, returning the record with its encrypted body
+    /// merged in (ie, a note's `title`, `text`, `tags`, etc). `keychain` is every keychain entry
+    /// in the database.
+    fn decrypt(&self, record: &Value, keychain: &[Value]) -> Result<Value>;
+
+    /// Grab and decrypt the file attached to an old (decrypted) note, if it has one and its
+    /// contents are still around.
+    fn file(&self, note: &Value, keychain: &[Value]) -> Result<Option<Vec<u8>>>;
+}
+
+/// One space brought over from the old database.
+#[derive(Getters)]
+#[getset(get = "pub")]
+pub struct LegacySpace {
+    /// The ID of the old space (or board) this space came from
+    legacy_id: String,
+    /// The new space's ID
+    space_id: SpaceID,
+    /// The new space's key. Add it to the keychain before saving the operations.
+    secret_key: SecretKey,
+    /// Operations that build the space, in order. Encrypt and save these like any other
+    /// operation.
+    operations: Vec<Operation>,
+}
+
+impl LegacySpace {
+    /// Consume this space, returning its ID, key, and operations.
+    pub fn consume(self) -> (SpaceID, SecretKey, Vec<Operation>) {
+        let Self { space_id, secret_key, operations, .. } = self;
+        (space_id, secret_key, operations)
+    }
+}
+
+/// Everything brought over from the old database.
+#[derive(Getters)]
+#[getset(get = "pub")]
+pub struct LegacyImport {
+    /// The imported spaces
+    spaces: Vec<LegacySpace>,
+    /// Records that couldn't be brought over, and why
+    skipped: Vec<String>,
+}
+
+impl LegacyImport {
+    /// Consume this import, returning the spaces and the skipped records.
+    pub fn consume(self) -> (Vec<LegacySpace>, Vec<String>) {
+        let Self { spaces, skipped } = self;
+        (spaces, skipped)
+    }
+}
+
+/// A space being built
+struct SpaceBuilder {
+    space_id: SpaceID,
+    secret_key: SecretKey,
+    operations: Vec<Operation>,
+    /// The boards becoming pages, by legacy board id and title, with the notes on each. Pages
+    /// are added once the notes are in.
+    pages: Vec<(String, String, Vec<NoteID>)>,
+}
+
+impl SpaceBuilder {
+    fn new(title: String, color: Option<String>, owner: &IdentityID) -> Result<Self> {
+        let space = Space::new(title, owner.clone());
+        let space_id = space.id().clone();
+        let mut operations = vec![Operation::space_set(space)];
+        if color.is_some() {
+            operations.push(Operation::space_set_color(space_id.clone(), color));
+        }
+        let secret_key = SecretKey::new_xchacha20poly1305()?;
+        Ok(Self { space_id, secret_key, operations, pages: Vec::new() })
+    }
+
+    fn finish(self, legacy_id: String) -> LegacySpace {
+        let Self { space_id, secret_key, mut operations, pages } = self;
+        for (_, title, note_ids) in pages {
+            let page = Page::new(space_id.clone(), title, Slice::Manual(note_ids));
+            operations.push(Operation::page_set(space_id.clone(), page));
+        }
+        LegacySpace { legacy_id, space_id, secret_key, operations }
+    }
+}
+
+fn db_err(err: rusqlite::Error) -> Error {
+    Error::Storage(format!("legacy database: {}", err))
+}
+
+/// Grab a non-empty string field from an old record
+fn str_field<'a>(record: &'a Value, field: &str) -> Option<&'a str> {
+    record.get(field).and_then(|v| v.as_str()).filter(|s| !s.trim().is_empty())
+}
+
+fn record_id(record: &Value) -> Result<String> {
+    match record.get("id") {
+        Some(Value::String(id)) => Ok(id.clone()),
+        Some(Value::Number(id)) => Ok(id.to_string()),
+        _ => Err(Error::LegacyInvalid("record has no id".into())),
+    }
+}
+
+/// Load (and decrypt) every object in one of the old tables. Records that won't decrypt are noted
+/// in `skipped`.
+fn load_table(conn: &Connection, table: &str, decryptor: Option<(&dyn LegacyDecryptor, &[Value])>, skipped: &mut Vec<String>) -> Result<Vec<Value>> {
+    let mut stmt = conn.prepare("SELECT id, data FROM dumpy_objects WHERE table_name = ?1 ORDER BY id").map_err(db_err)?;
+    let rows = stmt.query_map(params![table], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+        .map_err(db_err)?
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(db_err)?;
+    let mut records = Vec::with_capacity(rows.len());
+    for (id, data) in rows {
+        let record: Value = match serde_json::from_str(&data) {
+            Ok(record) => record,
+            Err(e) => {
+                skipped.push(format!("{} {}: {}", table, id, e));
+                continue;
+            }
+        };
+        match decryptor {
+            Some((decryptor, keychain)) => match decryptor.decrypt(&record, keychain) {
+                Ok(record) => records.push(record),
+                Err(e) => skipped.push(format!("{} {}: {}", table, id, e)),
+            },
+            None => records.push(record),
+        }
+    }
+    Ok(records)
+}
+
+/// Split old markdown-ish note text into sections.
+fn text_sections(text: &str) -> Vec<Section> {
+    let mut sections = Vec::new();
+    let mut paragraph: Vec<&str> = Vec::new();
+    let mut code: Option<Vec<&str>> = None;
+    let flush = |paragraph: &mut Vec<&str>, sections: &mut Vec<Section>| {
+        if !paragraph.is_empty() {
+            sections.push(Section::new(SectionSpec::Paragraph(paragraph.join("\n")), 0));
+            paragraph.clear();
+        }
+    };
+    for line in text.lines() {
+        if let Some(lines) = code.as_mut() {
+            if line.trim_start().starts_with("```") {
+                sections.push(Section::new(SectionSpec::Code(lines.join("\n")), 0));
+                code = None;
+            } else {
+                lines.push(line);
+            }
+            continue;
+        }
+        let trimmed = line.trim_start();
+        let indent = ((line.len() - trimmed.len()) / 2).min(MAX_INDENT as usize) as u8;
+        let spec = if trimmed.starts_with("```") {
+            flush(&mut paragraph, &mut sections);
+            code = Some(Vec::new());
+            continue;
+        } else if trimmed.is_empty() {
+            flush(&mut paragraph, &mut sections);
+            continue;
+        } else if let Some(rest) = trimmed.strip_prefix("### ") {
+            SectionSpec::Heading3(rest.trim().into())
+        } else if let Some(rest) = trimmed.strip_prefix("## ") {
+            SectionSpec::Heading2(rest.trim().into())
+        } else if let Some(rest) = trimmed.strip_prefix("# ") {
+            SectionSpec::Heading1(rest.trim().into())
+        } else if let Some(rest) = trimmed.strip_prefix("- [ ] ").or_else(|| trimmed.strip_prefix("* [ ] ")) {
+            SectionSpec::Checkbox { checked: false, text: rest.into(), due: None }
+        } else if let Some(rest) = ["- [x] ", "- [X] ", "* [x] ", "* [X] "].iter().find_map(|p| trimmed.strip_prefix(p)) {
+            SectionSpec::Checkbox { checked: true, text: rest.into(), due: None }
+        } else if let Some(rest) = trimmed.strip_prefix("- ").or_else(|| trimmed.strip_prefix("* ")) {
+            SectionSpec::Bullet(rest.into())
+        } else if let Some(rest) = trimmed.strip_prefix('>') {
+            SectionSpec::Quote(rest.trim_start().into())
+        } else if trimmed == "---" || trimmed == "***" {
+            SectionSpec::Divider
+        } else if let Some((_, rest)) = trimmed.split_once(". ").filter(|(n, _)| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())) {
+            SectionSpec::Numbered(rest.into())
+        } else {
+            paragraph.push(line);
+            continue;
+        };
+        flush(&mut paragraph, &mut sections);
+        sections.push(Section::new(spec, indent));
+    }
+    // an unclosed code block or a trailing paragraph still counts
+    if let Some(lines) = code {
+        sections.push(Section::new(SectionSpec::Code(lines.join("\n")), 0));
+    }
+    flush(&mut paragraph, &mut sections);
+    sections
+}
+
+/// Convert an old (decrypted) note into a new one, along with the operations for its file (if it
+/// has one), which need to be saved before the note.
+fn convert_note(
+    record: &Value,
+    builder: &SpaceBuilder,
+    decryptor: &dyn LegacyDecryptor,
+    keychain: &[Value],
+    blobs: &dyn BlobStore,
+) -> Result<(Note, Vec<Operation>)> {
+    let legacy_id = record_id(record)?;
+    let tags = record.get("tags")
+        .and_then(|t| t.as_array())
+        .map(|tags| tags.iter().filter_map(|t| t.as_str()).filter(|t| !t.is_empty()).map(Tag::new).collect())
+        .unwrap_or_default();
+    let mut note = Note::new(builder.space_id.clone(), str_field(record, "title").map(String::from), tags);
+    let kind = str_field(record, "type").unwrap_or("text");
+    let url = str_field(record, "url").and_then(|u| Url::parse(u.trim()).ok());
+
+    let mut file_operations = Vec::new();
+    let file_section = match (record.get("file"), decryptor.file(record, keychain)?) {
+        (Some(meta), Some(data)) => {
+            let name = str_field(meta, "name").unwrap_or("file").to_string();
+            let ty = str_field(meta, "type").map(String::from);
+            let size = meta.get("size").and_then(|s| s.as_u64());
+            let legacy_file = LegacyFile::new(legacy_id, name, ty, size, data);
+            let migrated = legacy::migrate_file(legacy_file, builder.space_id.clone(), &builder.secret_key, blobs)?;
+            let section = Section::new(migrated.section_spec(), 0);
+            let (_, operation, chunk_operations) = migrated.consume();
+            file_operations.extend(chunk_operations);
+            file_operations.push(operation);
+            Some(section)
+        }
+        _ => None,
+    };
+
+    match kind {
+        "link" | "bookmark" => {
+            if let Some(url) = url {
+                note.push_section(Section::new(SectionSpec::Bookmark(url), 0));
+            }
+        }
+        "image" if file_section.is_none() => {
+            if let Some(url) = url {
+                note.push_section(Section::new(SectionSpec::Embed(url), 0));
+            }
+        }
+        "password" => {
+            if let Some(username) = str_field(record, "username") {
+                note.push_section(Section::new(SectionSpec::Paragraph(format!("Username: {}", username)), 0));
+            }
+            if let Some(password) = record.get("password").and_then(|p| p.as_str()).filter(|p| !p.is_empty()) {
+                let secret = Secret::new(None, SecretKind::Password, password.into(), RevealPolicy::Manual);
+                note.push_section(Section::new(SectionSpec::Secret(secret), 0));
+            }
+        }
+        _ => {}
+    }
+    if let Some(section) = file_section {
+        note.push_section(section);
+    }
+    for section in text_sections(record.get("text").and_then(|t| t.as_str()).unwrap_or("")) {
+        note.push_section(section);
+    }
+    let issues = note.validate();
+    if !issues.is_empty() {
+        Err(Error::NoteInvalid(issues))?;
+    }
+    Ok((note, file_operations))
+}
+
+/// Read a v0.7 Turtl database and turn everything in it into operations. `owner` is the identity
+/// that will own the imported spaces, and file contents are chunked into `blobs`.
+///
+/// Records that can't be decrypted or converted are skipped (and listed in
+/// [`LegacyImport::skipped`]) rather than failing the whole import.
+pub fn import_database<P: AsRef<Path>>(path: P, owner: &IdentityID, decryptor: &dyn LegacyDecryptor, blobs: &dyn BlobStore) -> Result<LegacyImport> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(db_err)?;
+    let mut skipped = Vec::new();
+    let keychain = load_table(&conn, "keychain", None, &mut skipped)?;
+    let spaces = load_table(&conn, "spaces", Some((decryptor, &keychain[..])), &mut skipped)?;
+    let boards = load_table(&conn, "boards", Some((decryptor, &keychain[..])), &mut skipped)?;
+    let notes = load_table(&conn, "notes", Some((decryptor, &keychain[..])), &mut skipped)?;
+
+    // legacy space (or board) id -> the space it's becoming, in the order they were found
+    let mut order: Vec<String> = Vec::new();
+    let mut builders: HashMap<String, SpaceBuilder> = HashMap::new();
+    for record in &spaces {
+        let id = record_id(record)?;
+        let title = str_field(record, "title").unwrap_or("Imported space").to_string();
+        let builder = SpaceBuilder::new(title, str_field(record, "color").map(String::from), owner)?;
+        builders.insert(id.clone(), builder);
+        order.push(id);
+    }
+
+    // legacy board id -> the legacy id of the space holding its page
+    let mut board_spaces: HashMap<String, String> = HashMap::new();
+    for record in &boards {
+        let id = record_id(record)?;
+        let title = str_field(record, "title").unwrap_or("Imported board").to_string();
+        let space_key = match str_field(record, "space_id") {
+            Some(space_id) if builders.contains_key(space_id) => space_id.to_string(),
+            _ => {
+                builders.insert(id.clone(), SpaceBuilder::new(title.clone(), None, owner)?);
+                order.push(id.clone());
+                id.clone()
+            }
+        };
+        let builder = builders.get_mut(&space_key).expect("space was just checked or added");
+        builder.pages.push((id.clone(), title, Vec::new()));
+        board_spaces.insert(id, space_key);
+    }
+
+    for record in &notes {
+        let legacy_id = record_id(record)?;
+        let board_id = str_field(record, "board_id");
+        let space_key = match (str_field(record, "space_id"), board_id) {
+            (Some(space_id), _) if builders.contains_key(space_id) => space_id.to_string(),
+            (_, Some(board_id)) if board_spaces.contains_key(board_id) => board_spaces[board_id].clone(),
+            _ => {
+                skipped.push(format!("notes {}: its space is gone", legacy_id));
+                continue;
+            }
+        };
+        let builder = builders.get_mut(&space_key).expect("space was just checked");
+        let (note, file_operations) = match convert_note(record, builder, decryptor, &keychain[..], blobs) {
+            Ok(converted) => converted,
+            Err(e) => {
+                skipped.push(format!("notes {}: {}", legacy_id, e));
+                continue;
+            }
+        };
+        if let Some(board_id) = board_id {
+            if let Some((_, _, note_ids)) = builder.pages.iter_mut().find(|(id, ..)| id == board_id) {
+                note_ids.push(note.id().clone());
+            }
+        }
+        builder.operations.extend(file_operations);
+        builder.operations.push(Operation::note_set(builder.space_id.clone(), note)?);
+    }
+
+    let spaces = order.into_iter()
+        .filter_map(|id| builders.remove(&id).map(|builder| builder.finish(id)))
+        .collect();
+    Ok(LegacyImport { spaces, skipped })
+}
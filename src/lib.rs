@@ -1,3 +1,19 @@
+pub mod account;
+pub mod activity;
+pub mod blob;
+pub mod crypto;
 pub mod error;
+pub mod events;
+pub mod export;
+pub mod import;
+pub mod legacy;
 pub mod models;
+pub mod pairing;
+pub mod provider;
+pub mod recovery;
+pub mod storage;
+pub mod sync;
 
+
+#[cfg(test)]
+pub(crate) mod test_util;
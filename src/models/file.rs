@@ -5,14 +5,37 @@
 //! collection of chunks of the file that when put in order and decrypted will allow the full file
 //! to be reconstructed.
 
-use crate::models::{
-    object_id,
-    space::SpaceID,
+use crate::{
+    blob::{self, BlobStore, ChunkData},
+    crypto,
+    error::{Error, Result},
+    models::{
+        object_id,
+        operation::{Operation, OperationAction},
+        space::{Space, SpaceID},
+        state::State,
+        user::SyncPolicy,
+    },
 };
 use getset::{Getters, MutGetters};
 use rasn::{AsnType, Decode, Encode};
 use serde::{Deserialize, Serialize};
-use stamp_core::crypto::base::Hash;
+use stamp_core::crypto::{
+    base::{Hash, Sealed, SecretKey},
+    seal,
+};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{Read, Write};
+use std::ops::Range;
+use zeroize::Zeroizing;
+use std::path::Path;
+
+/// The default size of the chunks [`FileWriter`] splits files into.
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Files this size or smaller are stored inline by [`FileWriter`] by default.
+pub const DEFAULT_INLINE_THRESHOLD: usize = 32 * 1024;
 
 object_id! {
     /// A unique id for files
@@ -24,8 +47,53 @@ object_id! {
     FileChunkID
 }
 
+/// How a chunk's data was compressed before being encrypted
+#[derive(Clone, Debug, PartialEq, AsnType, Encode, Decode, Deserialize, Serialize)]
+#[rasn(choice)]
+pub enum CompressionAlgo {
+    #[rasn(tag(explicit(0)))]
+    #[serde(rename = "zstd")]
+    Zstd,
+}
+
+impl CompressionAlgo {
+    /// Compress some data
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::Zstd => Ok(zstd::encode_all(data, 0)?),
+        }
+    }
+
+    /// Decompress some data
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::Zstd => Ok(zstd::decode_all(data)?),
+        }
+    }
+}
+
+/// Where a file's data lives.
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize)]
+#[rasn(choice)]
+pub enum FileStorage {
+    /// The data is split into [chunks][FileChunk]
+    #[rasn(tag(explicit(0)))]
+    #[serde(rename = "chunked")]
+    Chunked,
+    /// The (encrypted) data is small enough to live right in the file object
+    #[rasn(tag(explicit(1)))]
+    #[serde(rename = "inline")]
+    InlineData(Sealed),
+}
+
+impl Default for FileStorage {
+    fn default() -> Self {
+        Self::Chunked
+    }
+}
+
 /// A single chunk of a file
-#[derive(AsnType, Encode, Decode, Serialize, Deserialize, Getters, MutGetters)]
+#[derive(Clone, AsnType, Encode, Decode, Serialize, Deserialize, Getters, MutGetters)]
 #[getset(get = "pub", get_mut = "pub(crate)")]
 pub struct FileChunk {
     /// The chunk's ID
@@ -40,10 +108,57 @@ pub struct FileChunk {
     /// The zero-based index of this chunk within the file.
     #[rasn(tag(explicit(3)))]
     index: u32,
+    /// The length of this chunk's pre-encrypted content, in bytes
+    #[rasn(tag(explicit(4)), default)]
+    #[serde(default)]
+    len: u32,
+    /// How this chunk's data was compressed (before encryption), if at all. The hash and length
+    /// are always of the uncompressed data.
+    #[rasn(tag(explicit(5)))]
+    compression: Option<CompressionAlgo>,
+    /// The hash of this chunk's encrypted data (see [`blob::chunk_blob_hash`]). Chunks written
+    /// before this was recorded don't have it.
+    #[rasn(tag(explicit(6)), default)]
+    #[serde(default)]
+    #[getset(skip)]
+    blob: Option<Hash>,
+}
+
+impl FileChunk {
+    /// The hash this chunk's data is kept under in a [`BlobStore`]. This is the hash of the
+    /// encrypted data, so the same content in two spaces never shares a blob. Older chunks are
+    /// kept under their content hash.
+    pub fn blob_hash(&self) -> &Hash {
+        self.blob.as_ref().unwrap_or(&self.hash)
+    }
+
+    /// Whether this chunk's data is kept the way older chunks' was: a bare [`Sealed`] under its
+    /// content hash (see [`blob::put_sealed`]) rather than an encoded [`ChunkData`].
+    pub fn legacy_blob(&self) -> bool {
+        self.blob.is_none()
+    }
+
+    /// Re-encrypt this chunk's data from one file chunk key to another (ie, when moving it to
+    /// another space), storing the new data in `blobs`. Returns the chunk pointing at its new
+    /// blob; the old blob is left alone for anything still using it.
+    pub fn reseal(&self, from_key: &SecretKey, to_key: &SecretKey, convergent: bool, blobs: &dyn BlobStore) -> Result<Self> {
+        let payload = Zeroizing::new(open_chunk_data(from_key, self, &blob::get_chunk(blobs, self)?)?);
+        let data = seal_chunk_data(to_key, &self.hash, self.compression.as_ref(), &payload[..], convergent)?;
+        let blob = blob::chunk_blob_hash(&data)?;
+        blob::put_chunk(blobs, &blob, &data)?;
+        let mut chunk = self.clone();
+        chunk.blob = Some(blob);
+        Ok(chunk)
+    }
+
+    /// Forget this chunk's blob hash, so it's kept under its content hash like older chunks.
+    pub(crate) fn clear_blob(&mut self) {
+        self.blob = None;
+    }
 }
 
 /// A file that can be linked to or embeded into a note.
-#[derive(AsnType, Encode, Decode, Serialize, Deserialize, Getters, MutGetters)]
+#[derive(Clone, AsnType, Encode, Decode, Serialize, Deserialize, Getters, MutGetters)]
 #[getset(get = "pub", get_mut = "pub(crate)")]
 pub struct File {
     /// The file's ID
@@ -61,5 +176,774 @@ pub struct File {
     /// The number of chunks this file has
     #[rasn(tag(explicit(4)))]
     num_chunks: u32,
+    /// The file's total size in bytes
+    #[rasn(tag(explicit(5)), default)]
+    #[serde(default)]
+    size: u64,
+    /// A small preview of the file (ie, an image thumbnail) generated by the client. The preview
+    /// chunk isn't counted in `num_chunks`.
+    #[rasn(tag(explicit(6)))]
+    preview_chunk: Option<FileChunkID>,
+    /// A hash of all the file's chunk hashes, in order (see [`File::hash_chunks`]). Catches
+    /// truncated files and swapped chunks, which per-chunk hashes alone can't.
+    #[rasn(tag(explicit(7)))]
+    hash: Option<Hash>,
+    /// Whether the file's data is chunked or inline
+    #[rasn(tag(explicit(8)), default)]
+    #[serde(default)]
+    storage: FileStorage,
+}
+
+
+impl File {
+    /// Build a whole-file hash from each chunk's hash, in order.
+    pub fn hash_chunks<'a, I: IntoIterator<Item = &'a Hash>>(chunk_hashes: I) -> Result<Hash> {
+        let mut serialized = Vec::new();
+        for hash in chunk_hashes {
+            serialized.extend(rasn::der::encode(hash).map_err(|_| Error::ASNSerialize)?);
+        }
+        Ok(Hash::new_blake3(&serialized[..])?)
+    }
+
+    /// Re-encrypt this file's inline data (if it has any) from one file chunk key to another.
+    /// Chunked files are left as they are: their chunks get [resealed][FileChunk::reseal]
+    /// separately.
+    pub fn reseal(&self, from_key: &SecretKey, to_key: &SecretKey) -> Result<Self> {
+        let mut file = self.clone();
+        if let FileStorage::InlineData(sealed) = &self.storage {
+            let data = Zeroizing::new(seal::open(from_key, sealed)?);
+            file.storage = FileStorage::InlineData(seal::seal(to_key, &data[..])?);
+        }
+        Ok(file)
+    }
+
+    /// Encrypt a preview for this file. Returns the preview chunk (which needs to be stored via
+    /// [`WrittenChunk::store`] and its operation saved) and the operation that points the file at
+    /// it.
+    pub fn attach_preview(&self, secret_key: &SecretKey, data: Vec<u8>) -> Result<(WrittenChunk, Operation)> {
+        let chunk = WrittenChunk::seal(secret_key, &self.space_id, &self.id, 0, data, None, false)?;
+        let chunk_id = match chunk.operation().action() {
+            OperationAction::FileSetChunkV1(c) => c.id().clone(),
+            _ => Err(Error::OperationInvalid("expected a chunk operation".into()))?,
+        };
+        let op = Operation::file_set_preview(self.space_id.clone(), self.id.clone(), Some(chunk_id));
+        Ok((chunk, op))
+    }
+
+    /// Fetch and decrypt this file's preview, if it has one (and we have it).
+    pub fn read_preview(&self, state: &State, secret_key: &SecretKey, blobs: &dyn BlobStore) -> Result<Option<Vec<u8>>> {
+        let chunk = match self.preview_chunk.as_ref().and_then(|id| state.chunks().get(id)) {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        if !blobs.has(chunk.blob_hash())? {
+            return Ok(None);
+        }
+        let data = open_chunk_data(secret_key, chunk, &blob::get_chunk(blobs, chunk)?)?;
+        if Hash::new_blake3(&data[..])? != chunk.hash {
+            Err(Error::FileInvalid("preview has the wrong hash".into()))?;
+        }
+        Ok(Some(data))
+    }
+
+    /// The indexes of the chunks we don't have metadata for yet, in order.
+    pub fn missing_chunks(&self, state: &State) -> Vec<u32> {
+        let have = self.chunks(state).iter()
+            .map(|c| *c.index())
+            .collect::<BTreeSet<_>>();
+        (0..self.num_chunks)
+            .filter(|i| !have.contains(i))
+            .collect()
+    }
+
+    /// Figure out which chunks (in order) are needed to serve the given byte range of this file,
+    /// and which part of each chunk to use. This lets media players seek around in a large file
+    /// without pulling down all of it.
+    ///
+    /// The range is clamped to the file's size. Inline files have no chunks, so their plan is
+    /// always empty.
+    pub fn chunk_plan(&self, state: &State, range: Range<u64>) -> Result<Vec<PlannedChunk>> {
+        if matches!(self.storage, FileStorage::InlineData(..)) {
+            return Ok(Vec::new());
+        }
+        let chunks = self.chunks(state);
+        if chunks.len() != self.num_chunks as usize {
+            Err(Error::FileInvalid("missing chunk metadata".into()))?;
+        }
+        let end = range.end.min(self.size);
+        let mut plan = Vec::new();
+        let mut chunk_start = 0u64;
+        for chunk in chunks {
+            let chunk_end = chunk_start + chunk.len as u64;
+            if chunk_end > range.start && chunk_start < end {
+                let from = range.start.max(chunk_start);
+                let to = end.min(chunk_end);
+                plan.push(PlannedChunk {
+                    chunk_id: chunk.id.clone(),
+                    index: chunk.index,
+                    offset: (from - chunk_start) as u32,
+                    len: (to - from) as u32,
+                });
+            }
+            if chunk_end >= end {
+                break;
+            }
+            chunk_start = chunk_end;
+        }
+        Ok(plan)
+    }
+
+    /// Grab this file's chunks from the state, in order.
+    pub fn chunks<'a>(&self, state: &'a State) -> Vec<&'a FileChunk> {
+        let mut chunks = state.chunks().values()
+            .filter(|c| c.file_id() == &self.id && Some(c.id()) != self.preview_chunk.as_ref())
+            .collect::<Vec<_>>();
+        chunks.sort_by_key(|c| c.index);
+        chunks
+    }
+}
+
+/// A broad category of mime types, for searching files.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MimeClass {
+    Image,
+    Audio,
+    Video,
+    Pdf,
+    Text,
+}
+
+impl MimeClass {
+    /// Whether a mime type falls into this class
+    pub fn matches(&self, mime: &str) -> bool {
+        match self {
+            Self::Image => mime.starts_with("image/"),
+            Self::Audio => mime.starts_with("audio/"),
+            Self::Video => mime.starts_with("video/"),
+            Self::Pdf => mime == "application/pdf",
+            Self::Text => mime.starts_with("text/"),
+        }
+    }
+}
+
+/// What to look for when searching files. Every set criterion has to match.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct FileQuery {
+    /// Case-insensitive substring of the file name
+    name: Option<String>,
+    class: Option<MimeClass>,
+    /// Smallest size (inclusive), in bytes
+    min_size: Option<u64>,
+    /// Largest size (inclusive), in bytes
+    max_size: Option<u64>,
+}
+
+impl FileQuery {
+    /// Create a new file query
+    pub fn new(name: Option<String>, class: Option<MimeClass>, min_size: Option<u64>, max_size: Option<u64>) -> Self {
+        Self { name, class, min_size, max_size }
+    }
+
+    /// Whether a file matches this query
+    pub fn matches(&self, file: &File) -> bool {
+        if let Some(name) = self.name.as_ref() {
+            if !file.name.to_lowercase().contains(&name.to_lowercase()) {
+                return false;
+            }
+        }
+        if let Some(class) = self.class.as_ref() {
+            if !file.ty.as_ref().map(|t| class.matches(t)).unwrap_or(false) {
+                return false;
+            }
+        }
+        self.min_size.map(|min| file.size >= min).unwrap_or(true) &&
+            self.max_size.map(|max| file.size <= max).unwrap_or(true)
+    }
+}
+
+/// One chunk needed to serve a byte range, and which part of it the range covers.
+#[derive(Clone, Debug, Getters)]
+#[getset(get = "pub")]
+pub struct PlannedChunk {
+    chunk_id: FileChunkID,
+    /// The chunk's index within the file
+    index: u32,
+    /// Where the range starts within this chunk's (decrypted) data
+    offset: u32,
+    /// How many bytes of this chunk the range covers
+    len: u32,
+}
+
+/// Tracks where an upload or download of a file's chunk data is at, so an interrupted transfer
+/// can pick up where it left off. Clients are expected to save this somewhere between runs.
+#[derive(Clone, Debug, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct FileTransfer {
+    /// The file being transferred
+    file_id: FileID,
+    /// How many chunks the file has
+    num_chunks: u32,
+    /// The chunks we have locally
+    local: BTreeSet<u32>,
+    /// The chunks the other side has confirmed they have
+    acknowledged: BTreeSet<u32>,
+}
+
+impl FileTransfer {
+    /// Start tracking a transfer for a file, seeing which of its chunks we already have in the
+    /// blob store.
+    pub fn new(file: &File, state: &State, blobs: &dyn BlobStore) -> Result<Self> {
+        let mut local = BTreeSet::new();
+        for chunk in file.chunks(state) {
+            if blobs.has(chunk.blob_hash())? {
+                local.insert(*chunk.index());
+            }
+        }
+        Ok(Self {
+            file_id: file.id().clone(),
+            num_chunks: *file.num_chunks(),
+            local,
+            acknowledged: BTreeSet::new(),
+        })
+    }
+
+    /// Record that we now have a chunk locally (ie, it finished downloading)
+    pub fn mark_local(&mut self, index: u32) {
+        self.local.insert(index);
+    }
+
+    /// Record that the other side has a chunk (ie, it finished uploading)
+    pub fn mark_acknowledged(&mut self, index: u32) {
+        self.acknowledged.insert(index);
+    }
+
+    /// The chunks we still need to download, in order
+    pub fn pending_download(&self) -> Vec<u32> {
+        (0..self.num_chunks).filter(|i| !self.local.contains(i)).collect()
+    }
+
+    /// The chunks we still need to download, in order, going by a device's sync policy: if the
+    /// policy doesn't want this file's data, nothing is pending.
+    pub fn pending_download_for(&self, policy: &SyncPolicy, space: &Space, file: &File) -> Vec<u32> {
+        if file.id() != &self.file_id || !policy.wants_file_data(space, file) {
+            return Vec::new();
+        }
+        self.pending_download()
+    }
+
+    /// The chunks we have but the other side hasn't confirmed yet, in order
+    pub fn pending_upload(&self) -> Vec<u32> {
+        self.local.difference(&self.acknowledged).cloned().collect()
+    }
+
+    /// Whether we have every chunk locally
+    pub fn download_complete(&self) -> bool {
+        self.pending_download().is_empty()
+    }
+
+    /// Whether the other side has confirmed every chunk
+    pub fn upload_complete(&self) -> bool {
+        (0..self.num_chunks).all(|i| self.acknowledged.contains(&i))
+    }
+}
+
+/// One chunk produced by a [`FileWriter`]: the operation recording the chunk, and the chunk's
+/// encrypted data.
+#[derive(Getters)]
+#[getset(get = "pub")]
+pub struct WrittenChunk {
+    /// The chunk's [blob store key][FileChunk::blob_hash]
+    hash: Hash,
+    operation: Operation,
+    data: ChunkData,
+}
+
+impl WrittenChunk {
+    /// Hash, (optionally) compress, and encrypt a chunk's worth of data.
+    fn seal(secret_key: &SecretKey, space_id: &SpaceID, file_id: &FileID, index: u32, data: Vec<u8>, compression: Option<&CompressionAlgo>, convergent: bool) -> Result<Self> {
+        let len = u32::try_from(data.len())
+            .map_err(|_| Error::FileInvalid("chunk is too large".into()))?;
+        let hash = Hash::new_blake3(&data[..])?;
+        let compressed = match compression {
+            Some(algo) => {
+                let compressed = algo.compress(&data[..])?;
+                if compressed.len() < data.len() { Some((algo.clone(), compressed)) } else { None }
+            }
+            None => None,
+        };
+        let (compression, payload) = match compressed {
+            Some((algo, compressed)) => (Some(algo), compressed),
+            None => (None, data),
+        };
+        let data = seal_chunk_data(secret_key, &hash, compression.as_ref(), &payload[..], convergent)?;
+        let blob = blob::chunk_blob_hash(&data)?;
+        let chunk = FileChunk {
+            id: FileChunkID::new(),
+            file_id: file_id.clone(),
+            hash,
+            index,
+            len,
+            compression,
+            blob: Some(blob.clone()),
+        };
+        let operation = Operation::file_set_chunk(space_id.clone(), file_id.clone(), chunk);
+        Ok(Self { hash: blob, operation, data })
+    }
+
+    /// Put this chunk's data in a blob store, returning the chunk's operation. The operation only
+    /// carries the chunk's metadata.
+    pub fn store(self, blobs: &dyn BlobStore) -> Result<Operation> {
+        blob::put_chunk(blobs, &self.hash, &self.data)?;
+        Ok(self.operation)
+    }
+
+    /// Consume this chunk, returning its operation and encrypted data.
+    pub fn consume(self) -> (Operation, ChunkData) {
+        let Self { operation, data, .. } = self;
+        (operation, data)
+    }
+}
+
+/// Turns anything readable into a file: splits it into chunks, hashes and encrypts each one, and
+/// hands back the chunk operations (and encrypted data) one at a time so the whole file never
+/// has to sit in memory. Small files are stored inline in the file object instead, in which
+/// case no chunks are produced at all.
+///
+/// Iterate the writer (or call [`FileWriter::next_chunk`]) until it runs dry, then call
+/// [`FileWriter::finish`] to get the operation that creates the file itself.
+pub struct FileWriter<'k, R> {
+    reader: R,
+    secret_key: &'k SecretKey,
+    space_id: SpaceID,
+    file_id: FileID,
+    name: String,
+    ty: Option<String>,
+    chunk_size: usize,
+    inline_threshold: usize,
+    inline: Option<Sealed>,
+    compression: Option<CompressionAlgo>,
+    convergent: bool,
+    num_chunks: u32,
+    chunk_hashes: Vec<Hash>,
+    size: u64,
+    done: bool,
+}
+
+impl<'k, R: Read> FileWriter<'k, R> {
+    /// Create a new file writer. `secret_key` is what the chunks get sealed with: the
+    /// [`KeyPurpose::FileChunk`][crate::crypto::KeyPurpose::FileChunk] subkey of the space the
+    /// file is going into, so file keys can be handled separately from operation keys.
+    pub fn new(reader: R, secret_key: &'k SecretKey, space_id: SpaceID, name: String, ty: Option<String>) -> Self {
+        Self {
+            reader,
+            secret_key,
+            space_id,
+            file_id: FileID::new(),
+            name,
+            ty,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            inline_threshold: DEFAULT_INLINE_THRESHOLD,
+            inline: None,
+            compression: None,
+            convergent: false,
+            num_chunks: 0,
+            chunk_hashes: Vec::new(),
+            size: 0,
+            done: false,
+        }
+    }
+
+    /// Use a chunk size other than [`DEFAULT_CHUNK_SIZE`].
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.clamp(1, u32::MAX as usize);
+        self
+    }
+
+    /// Store files this size or smaller inline instead of chunking them (see
+    /// [`DEFAULT_INLINE_THRESHOLD`]). Use 0 to always chunk.
+    pub fn with_inline_threshold(mut self, inline_threshold: usize) -> Self {
+        self.inline_threshold = inline_threshold;
+        self
+    }
+
+    /// Compress chunks before encrypting them. Chunks that don't get any smaller are stored as-is.
+    pub fn with_compression(mut self, compression: CompressionAlgo) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Encrypt chunks convergently (see [`crypto::seal_convergent`]) so identical chunks can be
+    /// deduped. Pass the space's
+    /// [`convergent_chunks`][crate::models::space::Space::convergent_chunks] setting here. Inline
+    /// data is always sealed normally, since it lives in the file object and can't be deduped
+    /// anyway.
+    pub fn with_convergent(mut self, convergent: bool) -> Self {
+        self.convergent = convergent;
+        self
+    }
+
+    /// The ID of the file being written
+    pub fn file_id(&self) -> &FileID {
+        &self.file_id
+    }
+
+    /// Fill a buffer with up to one chunk's worth of data, returning how much we got. Anything
+    /// short of a full chunk means we hit the end.
+    fn read_chunk(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.reader.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e)?,
+            }
+        }
+        Ok(filled)
+    }
+
+    /// Read, hash, and encrypt the next chunk. Returns `None` once the reader is used up.
+    pub fn next_chunk(&mut self) -> Result<Option<WrittenChunk>> {
+        if self.done {
+            return Ok(None);
+        }
+        let mut buf = vec![0u8; self.chunk_size];
+        let len = self.read_chunk(&mut buf)?;
+        if len < buf.len() {
+            self.done = true;
+        }
+        if len == 0 {
+            return Ok(None);
+        }
+        buf.truncate(len);
+        // small enough to skip chunking entirely
+        if self.num_chunks == 0 && self.done && len <= self.inline_threshold {
+            self.chunk_hashes.push(Hash::new_blake3(&buf[..])?);
+            self.inline = Some(seal::seal(self.secret_key, &buf[..])?);
+            self.size = len as u64;
+            return Ok(None);
+        }
+        let chunk = WrittenChunk::seal(self.secret_key, &self.space_id, &self.file_id, self.num_chunks, buf, self.compression.as_ref(), self.convergent)?;
+        self.num_chunks += 1;
+        self.chunk_hashes.push(chunk.hash().clone());
+        self.size += len as u64;
+        Ok(Some(chunk))
+    }
+
+    /// Finish writing, returning the operation that creates the file. Errors if there are still
+    /// chunks left to read.
+    pub fn finish(self) -> Result<Operation> {
+        if !self.done {
+            Err(Error::OperationInvalid("file writer still has unread chunks".into()))?;
+        }
+        let file = File {
+            id: self.file_id,
+            space_id: self.space_id.clone(),
+            name: self.name,
+            ty: self.ty,
+            num_chunks: self.num_chunks,
+            size: self.size,
+            preview_chunk: None,
+            hash: Some(File::hash_chunks(&self.chunk_hashes)?),
+            storage: match self.inline {
+                Some(sealed) => FileStorage::InlineData(sealed),
+                None => FileStorage::Chunked,
+            },
+        };
+        Ok(Operation::file_set(self.space_id, file))
+    }
+}
+
+impl<'k, R: Read> Iterator for FileWriter<'k, R> {
+    type Item = Result<WrittenChunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().transpose()
+    }
+}
+
+/// Reassembles a file from its chunks, decrypting each one and making sure it matches what the
+/// file and chunk metadata say it should be. The whole-file hash is checked against the chunk
+/// metadata up front, and each chunk's data is checked against its own hash as it's read.
+///
+/// `fetch` grabs a chunk's encrypted data from wherever it's stored (see [`blob::get_chunk`]).
+pub struct FileReader<'k, F> {
+    file: File,
+    chunks: Vec<FileChunk>,
+    secret_key: &'k SecretKey,
+    fetch: F,
+    next: usize,
+    size: u64,
+    inline_read: bool,
 }
 
+impl<'k, F: FnMut(&FileChunk) -> Result<ChunkData>> FileReader<'k, F> {
+    /// Create a new file reader. `chunks` can be in any order, but must be every chunk the file
+    /// has. `secret_key` is the key the file was written with (see [`FileWriter::new`]).
+    pub fn new(file: File, mut chunks: Vec<FileChunk>, secret_key: &'k SecretKey, fetch: F) -> Result<Self> {
+        chunks.retain(|c| c.file_id() == file.id() && Some(c.id()) != file.preview_chunk().as_ref());
+        chunks.sort_by_key(|c| c.index);
+        if chunks.len() != file.num_chunks as usize || chunks.iter().enumerate().any(|(i, c)| c.index as usize != i) {
+            Err(Error::FileInvalid(format!("expected chunks 0..{}", file.num_chunks)))?;
+        }
+        let chunked = matches!(file.storage(), FileStorage::Chunked);
+        if let (Some(expected), true) = (file.hash(), chunked) {
+            if &File::hash_chunks(chunks.iter().map(|c| c.hash()))? != expected {
+                Err(Error::FileInvalid("chunks don't match the file hash".into()))?;
+            }
+        }
+        Ok(Self { file, chunks, secret_key, fetch, next: 0, size: 0, inline_read: false })
+    }
+
+    /// The file being read
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Fetch, decrypt, and verify the next chunk. Returns `None` once the whole file has been
+    /// read.
+    pub fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        if let FileStorage::InlineData(sealed) = &self.file.storage {
+            if self.inline_read {
+                return Ok(None);
+            }
+            let data = seal::open(self.secret_key, sealed)?;
+            if data.len() as u64 != self.file.size {
+                Err(Error::FileInvalid("file has the wrong size".into()))?;
+            }
+            if let Some(expected) = self.file.hash.as_ref() {
+                if &File::hash_chunks(&[Hash::new_blake3(&data[..])?])? != expected {
+                    Err(Error::FileInvalid("inline data doesn't match the file hash".into()))?;
+                }
+            }
+            self.inline_read = true;
+            return Ok(Some(data));
+        }
+        let chunk = match self.chunks.get(self.next) {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        let fetched = (self.fetch)(chunk)?;
+        let opened = open_chunk_data(self.secret_key, chunk, &fetched)?;
+        let data = match chunk.compression.as_ref() {
+            Some(algo) => algo.decompress(&opened[..])?,
+            None => opened,
+        };
+        if data.len() != chunk.len as usize {
+            Err(Error::FileInvalid(format!("chunk {} has the wrong length", chunk.index)))?;
+        }
+        if Hash::new_blake3(&data[..])? != chunk.hash {
+            Err(Error::FileInvalid(format!("chunk {} has the wrong hash", chunk.index)))?;
+        }
+        self.next += 1;
+        self.size += data.len() as u64;
+        if self.next == self.chunks.len() && self.size != self.file.size {
+            Err(Error::FileInvalid("file has the wrong size".into()))?;
+        }
+        Ok(Some(data))
+    }
+}
+
+impl<'k, 'b> FileReader<'k, Box<dyn FnMut(&FileChunk) -> Result<ChunkData> + 'b>> {
+    /// Create a file reader that pulls chunk data from a blob store.
+    pub fn from_store(file: File, chunks: Vec<FileChunk>, secret_key: &'k SecretKey, blobs: &'b dyn BlobStore) -> Result<Self> {
+        let fetch: Box<dyn FnMut(&FileChunk) -> Result<ChunkData> + 'b> = Box::new(move |chunk: &FileChunk| blob::get_chunk(blobs, chunk));
+        Self::new(file, chunks, secret_key, fetch)
+    }
+}
+
+impl<'k, F: FnMut(&FileChunk) -> Result<ChunkData>> Iterator for FileReader<'k, F> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().transpose()
+    }
+}
+
+/// Encrypt a chunk's (possibly compressed) data, convergently or not.
+fn seal_chunk_data(secret_key: &SecretKey, hash: &Hash, compression: Option<&CompressionAlgo>, payload: &[u8], convergent: bool) -> Result<ChunkData> {
+    if convergent {
+        Ok(ChunkData::Convergent(crypto::seal_convergent(secret_key, hash, compression, payload)?))
+    } else {
+        Ok(ChunkData::Sealed(seal::seal(secret_key, payload)?))
+    }
+}
+
+/// Decrypt a chunk's data (still compressed, if it was compressed), however it was encrypted.
+fn open_chunk_data(secret_key: &SecretKey, chunk: &FileChunk, data: &ChunkData) -> Result<Vec<u8>> {
+    match data {
+        ChunkData::Sealed(sealed) => Ok(seal::open(secret_key, sealed)?),
+        ChunkData::Convergent(ciphertext) => crypto::open_convergent(secret_key, &chunk.hash, chunk.compression.as_ref(), &ciphertext[..]),
+    }
+}
+
+/// Guess a file's mime type, first from its leading bytes and then from its extension.
+pub fn sniff_mime(head: &[u8], name: &str) -> Option<String> {
+    let magic: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"OggS", "audio/ogg"),
+        (b"ID3", "audio/mpeg"),
+        (b"fLaC", "audio/flac"),
+        (b"PK\x03\x04", "application/zip"),
+    ];
+    for (prefix, mime) in magic {
+        if head.starts_with(prefix) {
+            return Some(mime.to_string());
+        }
+    }
+    if head.len() >= 12 && &head[0..4] == b"RIFF" {
+        match &head[8..12] {
+            b"WEBP" => return Some("image/webp".into()),
+            b"WAVE" => return Some("audio/wav".into()),
+            _ => {}
+        }
+    }
+    if head.len() >= 8 && &head[4..8] == b"ftyp" {
+        return Some("video/mp4".into());
+    }
+    let ext = Path::new(name).extension()?.to_str()?.to_lowercase();
+    let mime = match ext.as_str() {
+        "txt" => "text/plain",
+        "md" => "text/markdown",
+        "html" | "htm" => "text/html",
+        "csv" => "text/csv",
+        "json" => "application/json",
+        "svg" => "image/svg+xml",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "pdf" => "application/pdf",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        _ => return None,
+    };
+    Some(mime.into())
+}
+
+/// Read a file from disk into a space: sniffs its mime type, chunks and encrypts it, and puts
+/// the chunk data in the blob store. Returns the operation that creates the file along with the
+/// chunk operations, which should be saved first.
+pub fn import_path(path: &Path, space_id: SpaceID, secret_key: &SecretKey, blobs: &dyn BlobStore) -> Result<(Operation, Vec<Operation>)> {
+    let name = path.file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| Error::FileInvalid("path has no file name".into()))?
+        .to_string();
+    let mut reader = fs::File::open(path)?;
+    let mut head = Vec::with_capacity(16);
+    (&mut reader).take(16).read_to_end(&mut head)?;
+    let ty = sniff_mime(&head[..], &name);
+    let mut writer = FileWriter::new(std::io::Cursor::new(head).chain(reader), secret_key, space_id, name, ty);
+    let mut chunk_ops = Vec::new();
+    while let Some(chunk) = writer.next_chunk()? {
+        chunk_ops.push(chunk.store(blobs)?);
+    }
+    Ok((writer.finish()?, chunk_ops))
+}
+
+/// Write a file out to disk, pulling its chunks from the blob store. The file is written next to
+/// `path` and moved into place once it's verified, so a failed export doesn't leave a partial
+/// file behind.
+pub fn export_to_path(file: &File, state: &State, path: &Path, secret_key: &SecretKey, blobs: &dyn BlobStore) -> Result<()> {
+    let chunks = file.chunks(state).into_iter().cloned().collect::<Vec<_>>();
+    let reader = FileReader::from_store(file.clone(), chunks, secret_key, blobs)?;
+    let tmp = path.with_extension("turtl-export");
+    let result = (|| -> Result<()> {
+        let mut out = fs::File::create(&tmp)?;
+        for data in reader {
+            out.write_all(&data?[..])?;
+        }
+        out.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{blob::FsBlobStore, test_util};
+
+    /// Write some data as a file, storing its chunks, and hand back the file and chunks.
+    fn write_file(key: &SecretKey, blobs: &dyn BlobStore, data: &[u8], compression: Option<CompressionAlgo>, convergent: bool) -> (File, Vec<FileChunk>) {
+        let mut writer = FileWriter::new(data, key, SpaceID::new(), "test.bin".into(), None)
+            .with_chunk_size(1024)
+            .with_inline_threshold(0)
+            .with_convergent(convergent);
+        if let Some(algo) = compression {
+            writer = writer.with_compression(algo);
+        }
+        let mut chunks = Vec::new();
+        while let Some(chunk) = writer.next_chunk().unwrap() {
+            match chunk.store(blobs).unwrap().action() {
+                OperationAction::FileSetChunkV1(chunk) => chunks.push(chunk.clone()),
+                _ => panic!("expected a chunk operation"),
+            }
+        }
+        let file = match writer.finish().unwrap().action() {
+            OperationAction::FileSetV1(file) => file.clone(),
+            _ => panic!("expected a file operation"),
+        };
+        (file, chunks)
+    }
+
+    fn test_data() -> Vec<u8> {
+        // some compressible chunks and some that aren't
+        let mut data = b"turtle ".repeat(400);
+        data.extend((0..3000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8));
+        data
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        let key = SecretKey::new_xchacha20poly1305().unwrap();
+        let blobs = FsBlobStore::new(test_util::temp_dir("file-round-trip")).unwrap();
+        let data = test_data();
+        for (compression, convergent) in [(None, false), (Some(CompressionAlgo::Zstd), false), (None, true), (Some(CompressionAlgo::Zstd), true)] {
+            let (file, chunks) = write_file(&key, &blobs, &data[..], compression, convergent);
+            assert!(chunks.len() > 1);
+            let read = FileReader::from_store(file, chunks, &key, &blobs).unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap()
+                .concat();
+            assert_eq!(read, data);
+        }
+    }
+
+    #[test]
+    fn convergent_chunks_dedup_only_when_sealed_the_same_way() {
+        let key = SecretKey::new_xchacha20poly1305().unwrap();
+        let blobs = FsBlobStore::new(test_util::temp_dir("file-convergent")).unwrap();
+        let data = test_data();
+        let blob_hashes = |chunks: &[FileChunk]| chunks.iter().map(|c| c.blob_hash().clone()).collect::<Vec<_>>();
+
+        let (_, plain1) = write_file(&key, &blobs, &data[..], None, true);
+        let (_, plain2) = write_file(&key, &blobs, &data[..], None, true);
+        assert_eq!(blob_hashes(&plain1), blob_hashes(&plain2));
+
+        // the first chunk compresses, so it has to come out different
+        let (file, compressed) = write_file(&key, &blobs, &data[..], Some(CompressionAlgo::Zstd), true);
+        assert!(compressed[0].compression().is_some());
+        assert_ne!(compressed[0].blob_hash(), plain1[0].blob_hash());
+        let read = FileReader::from_store(file, compressed, &key, &blobs).unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap()
+            .concat();
+        assert_eq!(read, data);
+
+        // a different key never dedups
+        let other_key = SecretKey::new_xchacha20poly1305().unwrap();
+        let (_, other) = write_file(&other_key, &blobs, &data[..], None, true);
+        assert_ne!(blob_hashes(&other), blob_hashes(&plain2));
+    }
+}
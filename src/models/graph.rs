@@ -0,0 +1,107 @@
+//! Builds the data behind the [graph display][crate::models::page::Display::Graph]: a set of nodes
+//! (notes and pages) and the links between them.
+
+use crate::models::{
+    note::{Note, NoteID, SectionSpec},
+    page::{Page, PageID},
+    space::SpaceID,
+};
+use getset::Getters;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+/// Identifies a node in the graph, which is either a note or a page.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(tag = "type", content = "id", rename_all = "lowercase")]
+pub enum GraphNodeID {
+    Note(NoteID),
+    Page(PageID),
+}
+
+/// A single node in the graph
+#[derive(Getters, Serialize)]
+#[getset(get = "pub")]
+pub struct GraphNode {
+    /// The note or page this node represents
+    id: GraphNodeID,
+    /// The title of the note/page
+    label: Option<String>,
+    /// How many links point to this node (backlinks)
+    in_degree: usize,
+    /// How many links come out of this node
+    out_degree: usize,
+}
+
+/// A link from a note to another note or page
+#[derive(Getters, Serialize)]
+#[getset(get = "pub")]
+pub struct GraphEdge {
+    from: GraphNodeID,
+    to: GraphNodeID,
+}
+
+/// The full graph of links for a space.
+#[derive(Getters, Serialize)]
+#[getset(get = "pub")]
+pub struct NoteGraph {
+    /// Every (non-deleted) note in the space, plus any pages they link to
+    nodes: Vec<GraphNode>,
+    /// The links between nodes. Linking to the same thing more than once only counts once.
+    edges: Vec<GraphEdge>,
+}
+
+impl NoteGraph {
+    /// Build the link graph for a space. Links to notes/pages that don't exist (or live in another
+    /// space) are left out.
+    pub fn build(space_id: &SpaceID, notes: &HashMap<NoteID, Note>, pages: &HashMap<PageID, Page>) -> Self {
+        let live_note = |id: &NoteID| notes.get(id).map(|n| n.space_id() == space_id && !n.deleted()).unwrap_or(false);
+        let live_page = |id: &PageID| pages.get(id).map(|p| p.space_id() == space_id && !p.deleted()).unwrap_or(false);
+
+        let mut edges: Vec<GraphEdge> = Vec::new();
+        let mut seen: HashSet<(NoteID, GraphNodeID)> = HashSet::new();
+        let mut in_degree: HashMap<GraphNodeID, usize> = HashMap::new();
+        let mut out_degree: HashMap<GraphNodeID, usize> = HashMap::new();
+        let mut linked_pages: Vec<PageID> = Vec::new();
+
+        let space_notes = notes.values()
+            .filter(|n| live_note(n.id()))
+            .collect::<Vec<_>>();
+        for note in &space_notes {
+            for section_id in note.body().order() {
+                let to = match note.body().sections().get(section_id).map(|s| s.spec()) {
+                    Some(SectionSpec::NoteLink(id)) if live_note(id) => GraphNodeID::Note(id.clone()),
+                    Some(SectionSpec::PageLink(id)) if live_page(id) => GraphNodeID::Page(id.clone()),
+                    _ => continue,
+                };
+                if !seen.insert((note.id().clone(), to.clone())) {
+                    continue;
+                }
+                if let GraphNodeID::Page(page_id) = &to {
+                    if !in_degree.contains_key(&to) {
+                        linked_pages.push(page_id.clone());
+                    }
+                }
+                let from = GraphNodeID::Note(note.id().clone());
+                *out_degree.entry(from.clone()).or_insert(0) += 1;
+                *in_degree.entry(to.clone()).or_insert(0) += 1;
+                edges.push(GraphEdge { from, to });
+            }
+        }
+
+        let node = |id: GraphNodeID, label: Option<String>| GraphNode {
+            in_degree: in_degree.get(&id).copied().unwrap_or(0),
+            out_degree: out_degree.get(&id).copied().unwrap_or(0),
+            id,
+            label,
+        };
+        let mut nodes = space_notes.into_iter()
+            .map(|n| node(GraphNodeID::Note(n.id().clone()), n.title().clone()))
+            .collect::<Vec<_>>();
+        nodes.extend(
+            linked_pages.into_iter()
+                .filter_map(|id| pages.get(&id))
+                .map(|p| node(GraphNodeID::Page(p.id().clone()), Some(p.title().clone())))
+        );
+        Self { nodes, edges }
+    }
+}
@@ -0,0 +1,367 @@
+//! The keychain is where a user keeps the keys for every space they belong to.
+//!
+//! Each space key is wrapped (sealed) under the user's master key before it goes into the
+//! keychain, so the keychain can be synced through the user's own DAG like any other setting
+//! without the space keys ever being stored in the clear.
+
+use crate::{
+    error::{Error, Result},
+    models::{
+        operation::Operation,
+        space::{Space, SpaceID, SpaceKeyID},
+    },
+    provider::{CryptoProvider, LocalProvider},
+};
+use argon2::{Algorithm, Argon2, Params, Version};
+use getset::{Getters, MutGetters};
+use rasn::{AsnType, Decode, Encode};
+use serde::{Deserialize, Serialize};
+use stamp_core::{
+    crypto::{
+        base::{Sealed, SecretKey},
+        seal,
+    },
+    util::HashMapAsn1,
+};
+use std::collections::HashMap;
+use zeroize::Zeroizing;
+
+/// A single space key, sealed under the user's master key.
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Getters, Serialize)]
+#[getset(get = "pub")]
+pub struct KeychainEntry {
+    /// Which of the space's keys this is (`None` for the key the space was created with)
+    #[rasn(tag(explicit(0)))]
+    key_id: Option<SpaceKeyID>,
+    /// The space key, sealed under the master key
+    #[rasn(tag(explicit(1)))]
+    wrapped: Sealed,
+}
+
+impl KeychainEntry {
+    /// Wrap a space key under the user's master key.
+    pub fn wrap(master_key: &SecretKey, key_id: Option<SpaceKeyID>, secret_key: &SecretKey) -> Result<Self> {
+        Self::wrap_with(&LocalProvider::new(master_key), key_id, secret_key)
+    }
+
+    /// Wrap a space key using a [`CryptoProvider`].
+    pub fn wrap_with(provider: &dyn CryptoProvider, key_id: Option<SpaceKeyID>, secret_key: &SecretKey) -> Result<Self> {
+        let serialized = Zeroizing::new(rasn::der::encode(secret_key).map_err(|_| Error::ASNSerialize)?);
+        let wrapped = provider.wrap(&serialized[..])?;
+        Ok(Self { key_id, wrapped })
+    }
+
+    /// Unwrap this entry's space key using the user's master key.
+    pub fn unwrap(&self, master_key: &SecretKey) -> Result<SecretKey> {
+        self.unwrap_with(&LocalProvider::new(master_key))
+    }
+
+    /// Unwrap this entry's space key using a [`CryptoProvider`].
+    pub fn unwrap_with(&self, provider: &dyn CryptoProvider) -> Result<SecretKey> {
+        let serialized = Zeroizing::new(provider.unwrap(&self.wrapped)?);
+        rasn::der::decode(&serialized[..]).map_err(|_| Error::ASNDeserialize)
+    }
+}
+
+/// Holds the wrapped keys for each space the user belongs to. A space can have more than one key
+/// once it's been rotated, and the old keys are kept so older transactions can still be read.
+/// Which key is current comes from the space's [key rotations][Space::current_key_id], not from
+/// the order keys were added in: keys can arrive in any order (ie, from a backup).
+#[derive(Clone, Default, AsnType, Encode, Decode, Deserialize, Getters, MutGetters, Serialize)]
+#[getset(get = "pub", get_mut = "pub(crate)")]
+pub struct Keychain {
+    #[rasn(tag(explicit(0)))]
+    entries: HashMapAsn1<SpaceID, Vec<KeychainEntry>>,
+}
+
+impl Keychain {
+    /// Create an empty keychain
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a key to the keychain. Adding a key with an ID that's already in the keychain for that
+    /// space replaces it where it is.
+    pub(crate) fn add(&mut self, space_id: SpaceID, entry: KeychainEntry) {
+        let keys = self.entries.entry(space_id).or_insert_with(Vec::new);
+        match keys.iter_mut().find(|e| e.key_id() == entry.key_id()) {
+            Some(existing) => *existing = entry,
+            None => keys.push(entry),
+        }
+    }
+
+    /// The entry for a space's current key: the one its latest rotation rotated to, or its
+    /// original key if it was never rotated. If we don't know about the space yet (ie, while
+    /// bootstrapping a new device), we go with the key added last. If we know the space but
+    /// don't have its current key, there's no current entry: encrypting under an older key
+    /// would let removed members read it.
+    fn current_entry(&self, space_id: &SpaceID, spaces: &HashMap<SpaceID, Space>) -> Option<&KeychainEntry> {
+        let keys = self.entries.get(space_id)?;
+        match spaces.get(space_id) {
+            Some(space) => keys.iter().find(|e| e.key_id().as_ref() == space.current_key_id()),
+            None => keys.last(),
+        }
+    }
+
+    /// Remove all of a space's keys from the keychain
+    pub(crate) fn remove(&mut self, space_id: &SpaceID) {
+        self.entries.remove(space_id);
+    }
+
+    /// Unwrap every key we have for a space, in the order they were added.
+    pub fn space_keys(&self, master_key: &SecretKey, space_id: &SpaceID) -> Result<Vec<(Option<SpaceKeyID>, SecretKey)>> {
+        self.space_keys_with(&LocalProvider::new(master_key), space_id)
+    }
+
+    /// Like [`Keychain::space_keys`], unwrapping with a [`CryptoProvider`].
+    pub fn space_keys_with(&self, provider: &dyn CryptoProvider, space_id: &SpaceID) -> Result<Vec<(Option<SpaceKeyID>, SecretKey)>> {
+        self.entries.get(space_id)
+            .map(|keys| keys.iter().map(|e| Ok((e.key_id().clone(), e.unwrap_with(provider)?))).collect())
+            .unwrap_or_else(|| Ok(Vec::new()))
+    }
+
+    /// Unwrap the current key for each space (see [`Space::current_key_id`]), giving the map that
+    /// [`Inbox::apply`][crate::sync::Inbox::apply] and friends want. `spaces` are the spaces as
+    /// we know them (ie, [`State::spaces`][crate::models::state::State::spaces]).
+    pub fn current_keys(&self, master_key: &SecretKey, spaces: &HashMap<SpaceID, Space>) -> Result<HashMap<SpaceID, SecretKey>> {
+        self.current_keys_with(&LocalProvider::new(master_key), spaces)
+    }
+
+    /// Like [`Keychain::current_keys`], unwrapping with a [`CryptoProvider`].
+    pub fn current_keys_with(&self, provider: &dyn CryptoProvider, spaces: &HashMap<SpaceID, Space>) -> Result<HashMap<SpaceID, SecretKey>> {
+        self.entries.keys()
+            .filter_map(|space_id| self.current_entry(space_id, spaces).map(|e| (space_id, e)))
+            .map(|(space_id, entry)| Ok((space_id.clone(), entry.unwrap_with(provider)?)))
+            .collect()
+    }
+}
+
+/// The current key backup format
+const BACKUP_VERSION: u32 = 1;
+
+/// The most memory (in KiB) we'll let a backup ask Argon2 for: 1 GiB
+const MAX_KDF_M_COST: u32 = 1024 * 1024;
+
+/// The most passes we'll let a backup ask Argon2 for
+const MAX_KDF_T_COST: u32 = 64;
+
+/// The most QR chunks a backup can be split into
+const MAX_QR_CHUNKS: usize = 256;
+
+/// The Argon2id cost parameters a passphrase was stretched with. These are stored next to the
+/// salt so the costs can go up for new backups without breaking older ones.
+#[derive(Clone, Debug, PartialEq, AsnType, Encode, Decode)]
+pub(crate) struct KdfParams {
+    /// Memory, in KiB
+    #[rasn(tag(explicit(0)))]
+    m_cost: u32,
+    /// Number of passes
+    #[rasn(tag(explicit(1)))]
+    t_cost: u32,
+    /// Degree of parallelism
+    #[rasn(tag(explicit(2)))]
+    p_cost: u32,
+}
+
+impl KdfParams {
+    /// The parameters backups made before we stored any were stretched with (argon2's defaults)
+    pub(crate) fn legacy() -> Self {
+        Self { m_cost: Params::DEFAULT_M_COST, t_cost: Params::DEFAULT_T_COST, p_cost: Params::DEFAULT_P_COST }
+    }
+
+    /// The parameters new backups are stretched with
+    pub(crate) fn current() -> Self {
+        Self { m_cost: 64 * 1024, t_cost: 3, p_cost: 1 }
+    }
+
+    fn argon2(&self) -> Result<Argon2<'static>> {
+        if self.m_cost > MAX_KDF_M_COST || self.t_cost > MAX_KDF_T_COST {
+            Err(Error::BackupInvalid("key derivation parameters are too expensive".into()))?;
+        }
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32))
+            .map_err(|e| Error::BackupInvalid(format!("bad key derivation parameters: {}", e)))?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+}
+
+/// A passphrase-protected key backup: the master key and the whole keychain, sealed under a key
+/// stretched from the passphrase with Argon2id. This is the portable thing that gets written to
+/// a file or split into QR codes.
+#[derive(AsnType, Encode, Decode)]
+struct KeyBackupFile {
+    #[rasn(tag(explicit(0)))]
+    version: u32,
+    /// The Argon2 salt
+    #[rasn(tag(explicit(1)))]
+    salt: Vec<u8>,
+    /// A sealed [`KeyBackupPayload`]
+    #[rasn(tag(explicit(2)))]
+    sealed: Sealed,
+    /// How the passphrase was stretched. Backups from before this was stored used
+    /// [`KdfParams::legacy`].
+    #[rasn(tag(explicit(3)), default)]
+    kdf: Option<KdfParams>,
+}
+
+/// What's inside a key backup
+#[derive(AsnType, Encode, Decode)]
+struct KeyBackupPayload {
+    #[rasn(tag(explicit(0)))]
+    master_key: SecretKey,
+    #[rasn(tag(explicit(1)))]
+    keychain: Keychain,
+}
+
+/// The keys restored from a backup.
+#[derive(Getters)]
+#[getset(get = "pub")]
+pub struct RestoredKeys {
+    master_key: SecretKey,
+    keychain: Keychain,
+}
+
+impl RestoredKeys {
+    /// The operations that put the restored keychain back into the user's DAG.
+    pub fn operations(&self) -> Vec<Operation> {
+        let mut ops = Vec::new();
+        for (space_id, entries) in self.keychain.entries().iter() {
+            for entry in entries {
+                ops.push(Operation::user_set_keychain_entry(space_id.clone(), entry.clone()));
+            }
+        }
+        ops
+    }
+
+    /// Consume these keys, returning the master key and keychain.
+    pub fn consume(self) -> (SecretKey, Keychain) {
+        let Self { master_key, keychain } = self;
+        (master_key, keychain)
+    }
+}
+
+/// Stretch a passphrase into a sealing key.
+pub(crate) fn backup_key(passphrase: &str, salt: &[u8], kdf: &KdfParams) -> Result<SecretKey> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    kdf.argon2()?
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key[..])
+        .map_err(|e| Error::BackupInvalid(format!("key derivation failed: {}", e)))?;
+    Ok(SecretKey::new_xchacha20poly1305_from_slice(&key[..])?)
+}
+
+/// Export the master key and keychain as a passphrase-protected blob.
+pub fn export_encrypted(master_key: &SecretKey, keychain: &Keychain, passphrase: &str) -> Result<Vec<u8>> {
+    export_encrypted_with(master_key, keychain, passphrase, KdfParams::current())
+}
+
+fn export_encrypted_with(master_key: &SecretKey, keychain: &Keychain, passphrase: &str, kdf: KdfParams) -> Result<Vec<u8>> {
+    let mut salt = vec![0u8; 16];
+    getrandom::getrandom(&mut salt[..]).map_err(|e| Error::BackupInvalid(format!("no randomness: {}", e)))?;
+    let payload = KeyBackupPayload { master_key: master_key.clone(), keychain: keychain.clone() };
+    let serialized = Zeroizing::new(rasn::der::encode(&payload).map_err(|_| Error::ASNSerialize)?);
+    let sealed = seal::seal(&backup_key(passphrase, &salt[..], &kdf)?, &serialized[..])?;
+    let file = KeyBackupFile { version: BACKUP_VERSION, salt, sealed, kdf: Some(kdf) };
+    rasn::der::encode(&file).map_err(|_| Error::ASNSerialize)
+}
+
+/// Open a blob made by [`export_encrypted`].
+pub fn import_encrypted(blob: &[u8], passphrase: &str) -> Result<RestoredKeys> {
+    let file: KeyBackupFile = rasn::der::decode(blob).map_err(|_| Error::BackupInvalid("not a key backup".into()))?;
+    if file.version != BACKUP_VERSION {
+        Err(Error::BackupInvalid(format!("unknown backup version {}", file.version)))?;
+    }
+    let kdf = file.kdf.clone().unwrap_or_else(KdfParams::legacy);
+    let serialized = Zeroizing::new(seal::open(&backup_key(passphrase, &file.salt[..], &kdf)?, &file.sealed)
+        .map_err(|_| Error::BackupInvalid("wrong passphrase".into()))?);
+    let KeyBackupPayload { master_key, keychain } = rasn::der::decode(&serialized[..]).map_err(|_| Error::ASNDeserialize)?;
+    Ok(RestoredKeys { master_key, keychain })
+}
+
+/// Split a backup blob into text chunks small enough for QR codes. Each chunk is tagged with its
+/// position (`2/5:...`) so they can be scanned in any order.
+pub fn backup_to_qr_chunks(blob: &[u8], max_chunk_len: usize) -> Vec<String> {
+    let encoded = data_encoding::BASE32_NOPAD.encode(blob);
+    let chunks = encoded.as_bytes().chunks(max_chunk_len.max(1)).collect::<Vec<_>>();
+    let total = chunks.len();
+    chunks.into_iter()
+        .enumerate()
+        .map(|(i, chunk)| format!("{}/{}:{}", i + 1, total, std::str::from_utf8(chunk).unwrap_or_default()))
+        .collect()
+}
+
+/// Put a backup blob back together from its QR chunks.
+pub fn backup_from_qr_chunks<S: AsRef<str>>(chunks: &[S]) -> Result<Vec<u8>> {
+    let mut parts: Vec<Option<&str>> = Vec::new();
+    for chunk in chunks {
+        let invalid = || Error::BackupInvalid("malformed QR chunk".into());
+        let (header, data) = chunk.as_ref().split_once(':').ok_or_else(invalid)?;
+        let (index, total) = header.split_once('/').ok_or_else(invalid)?;
+        let index: usize = index.parse().map_err(|_| invalid())?;
+        let total: usize = total.parse().map_err(|_| invalid())?;
+        if total == 0 || total > MAX_QR_CHUNKS {
+            Err(invalid())?;
+        }
+        if total > chunks.len() {
+            Err(Error::BackupInvalid(format!("missing {} QR chunk(s)", total - chunks.len())))?;
+        }
+        if parts.is_empty() {
+            parts = vec![None; total];
+        }
+        if index == 0 || index > parts.len() || total != parts.len() {
+            Err(invalid())?;
+        }
+        parts[index - 1] = Some(data);
+    }
+    let missing = parts.iter().filter(|p| p.is_none()).count();
+    if missing > 0 || parts.is_empty() {
+        Err(Error::BackupInvalid(format!("missing {} QR chunk(s)", missing)))?;
+    }
+    let encoded = parts.into_iter().flatten().collect::<String>();
+    data_encoding::BASE32_NOPAD.decode(encoded.as_bytes()).map_err(|_| Error::BackupInvalid("malformed QR chunk".into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cheap_kdf() -> KdfParams {
+        KdfParams { m_cost: 64, t_cost: 1, p_cost: 1 }
+    }
+
+    #[test]
+    fn backup_round_trips_with_its_own_kdf_params() {
+        let master_key = SecretKey::new_xchacha20poly1305().unwrap();
+        let blob = export_encrypted_with(&master_key, &Keychain::default(), "correct horse", cheap_kdf()).unwrap();
+        let file: KeyBackupFile = rasn::der::decode(&blob[..]).unwrap();
+        assert_eq!(file.kdf, Some(cheap_kdf()));
+        let restored = import_encrypted(&blob[..], "correct horse").unwrap();
+        assert_eq!(rasn::der::encode(restored.master_key()).unwrap(), rasn::der::encode(&master_key).unwrap());
+        assert!(matches!(import_encrypted(&blob[..], "wrong horse"), Err(Error::BackupInvalid(..))));
+    }
+
+    #[test]
+    fn expensive_kdf_params_are_refused() {
+        let master_key = SecretKey::new_xchacha20poly1305().unwrap();
+        let blob = export_encrypted_with(&master_key, &Keychain::default(), "pass", cheap_kdf()).unwrap();
+        let mut file: KeyBackupFile = rasn::der::decode(&blob[..]).unwrap();
+        file.kdf = Some(KdfParams { m_cost: u32::MAX, t_cost: 1, p_cost: 1 });
+        let blob = rasn::der::encode(&file).unwrap();
+        assert!(matches!(import_encrypted(&blob[..], "pass"), Err(Error::BackupInvalid(..))));
+    }
+
+    #[test]
+    fn qr_chunks_round_trip_in_any_order() {
+        let blob = (0..500u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut chunks = backup_to_qr_chunks(&blob[..], 100);
+        assert!(chunks.len() > 1);
+        chunks.reverse();
+        assert_eq!(backup_from_qr_chunks(&chunks[..]).unwrap(), blob);
+        assert!(backup_from_qr_chunks(&chunks[1..]).is_err());
+    }
+
+    #[test]
+    fn qr_chunk_totals_are_bounded() {
+        for bad in ["1/0:AAAA", "1/18446744073709551615:AAAA", "1/100000:AAAA", "1/2:AAAA", "0/1:AAAA"] {
+            assert!(matches!(backup_from_qr_chunks(&[bad]), Err(Error::BackupInvalid(..))), "{}", bad);
+        }
+    }
+}
@@ -10,9 +10,12 @@ use stamp_core::crypto::base::SecretKey;
 use uuid::Uuid;
 
 pub mod file;
+pub mod graph;
+pub mod keychain;
 pub mod note;
 pub mod operation;
 pub mod page;
+pub mod permission;
 pub mod space;
 pub mod state;
 pub mod user;
@@ -37,6 +40,19 @@ pub trait Encryptable: Sized {
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub struct ObjectID(Uuid);
 
+impl ObjectID {
+    /// Create a new, random object ID
+    pub fn new() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+impl Default for ObjectID {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl AsnType for ObjectID {
     const TAG: Tag = Tag::UTF8_STRING;
 }
@@ -67,7 +83,40 @@ macro_rules! object_id {
         #[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize, rasn::AsnType, rasn::Encode, rasn::Decode)]
         #[rasn(delegate)]
         pub struct $name(crate::models::ObjectID);
+
+        impl $name {
+            /// Create a new, random ID
+            pub fn new() -> Self {
+                Self(crate::models::ObjectID::new())
+            }
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
     }
 }
 pub(crate) use object_id;
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::note::NoteID;
+
+    #[test]
+    fn object_id_default_is_random() {
+        assert_ne!(ObjectID::default(), ObjectID::default());
+        assert_ne!(NoteID::default(), NoteID::default());
+    }
+
+    #[test]
+    fn object_id_der_roundtrip() {
+        let id = NoteID::default();
+        let der = rasn::der::encode(&id).unwrap();
+        let decoded: NoteID = rasn::der::decode(&der[..]).unwrap();
+        assert_eq!(decoded, id);
+    }
+}
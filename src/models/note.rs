@@ -2,22 +2,34 @@
 //! which altogether create the body of the note.
 
 use crate::{
+    error::{Error, Result},
     models::{
         object_id,
         file::FileID,
+        operation::Operation,
         page::PageID,
-        space::SpaceID,
+        space::{Space, SpaceID},
     },
 };
 use getset::{Getters, MutGetters};
+use hmac::{Hmac, Mac};
 use rasn::{AsnType, Encode, Decode};
 use serde::{Deserialize, Serialize};
+use sha1::Sha1;
 use stamp_core::{
+    crypto::base::Hash,
     util::{
         HashMapAsn1,
+        Timestamp,
         Url,
     },
 };
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+use zeroize::{Zeroize, Zeroizing};
+
+/// How deep a section is allowed to be indented.
+pub const MAX_INDENT: u8 = 8;
 
 object_id! {
     /// A unique id for our note
@@ -35,11 +47,23 @@ object_id! {
 }
 
 /// Represents a tag that can be attached to a note
-#[derive(AsnType, Encode, Decode, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, AsnType, Encode, Decode, Deserialize, Serialize)]
 #[rasn(delegate)]
 pub struct Tag(String);
 
-#[derive(PartialEq, Eq, Hash, Deserialize, Serialize, AsnType, Encode, Decode, Getters)]
+impl Tag {
+    /// Create a tag
+    pub fn new<S: Into<String>>(tag: S) -> Self {
+        Self(tag.into())
+    }
+
+    /// Grab the tag as a string
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Deserialize, Serialize, AsnType, Encode, Decode, Getters)]
 #[getset(get = "pub")]
 pub struct TableCoord {
     #[rasn(tag(explicit(0)))]
@@ -48,8 +72,142 @@ pub struct TableCoord {
     col: u8,
 }
 
+/// A hint for how a table column's values should be treated. Values are always stored as strings,
+/// this just lets clients display/edit/sort them sensibly.
+#[derive(Clone, PartialEq, AsnType, Encode, Decode, Deserialize, Serialize)]
+#[rasn(choice)]
+pub enum ColumnType {
+    #[rasn(tag(explicit(0)))]
+    #[serde(rename = "text")]
+    Text,
+    #[rasn(tag(explicit(1)))]
+    #[serde(rename = "number")]
+    Number,
+    #[rasn(tag(explicit(2)))]
+    #[serde(rename = "date")]
+    Date,
+    #[rasn(tag(explicit(3)))]
+    #[serde(rename = "checkbox")]
+    Checkbox,
+}
+
+/// Describes one column in a table section
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct TableColumn {
+    /// What kind of values live in this column
+    #[rasn(tag(explicit(0)))]
+    ty: ColumnType,
+    /// An optional width hint, in characters
+    #[rasn(tag(explicit(1)))]
+    width: Option<u16>,
+}
+
+impl TableColumn {
+    /// Create a new table column spec
+    pub fn new(ty: ColumnType, width: Option<u16>) -> Self {
+        Self { ty, width }
+    }
+}
+
+/// What kind of value a [`Secret`] holds. Mostly this helps the UI decide how to present it.
+#[derive(Clone, PartialEq, AsnType, Encode, Decode, Deserialize, Serialize)]
+#[rasn(choice)]
+pub enum SecretKind {
+    /// A run-of-the-mill password
+    #[rasn(tag(explicit(0)))]
+    #[serde(rename = "password")]
+    Password,
+    /// An API key or token
+    #[rasn(tag(explicit(1)))]
+    #[serde(rename = "api-key")]
+    ApiKey,
+    /// A base32-encoded TOTP seed, used to generate one-time codes
+    #[rasn(tag(explicit(2)))]
+    #[serde(rename = "totp-seed")]
+    TotpSeed,
+}
+
+/// Determines how a [`Secret`] is allowed to be revealed once it's obscured.
+#[derive(Clone, PartialEq, AsnType, Encode, Decode, Deserialize, Serialize)]
+#[rasn(choice)]
+pub enum RevealPolicy {
+    /// Stays hidden until the user reveals it, then stays revealed.
+    #[rasn(tag(explicit(0)))]
+    #[serde(rename = "manual")]
+    Manual,
+    /// Once revealed, goes back into hiding after the given number of seconds.
+    #[rasn(tag(explicit(1)))]
+    #[serde(rename = "timed")]
+    Timed(u32),
+    /// Can never be shown, only copied.
+    #[rasn(tag(explicit(2)))]
+    #[serde(rename = "copy-only")]
+    CopyOnly,
+}
+
+/// A secret value living in a note: a password, API key, TOTP seed, etc.
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct Secret {
+    /// What this secret is for ("Bank login")
+    #[rasn(tag(explicit(0)))]
+    label: Option<String>,
+    /// The kind of secret we're storing
+    #[rasn(tag(explicit(1)))]
+    kind: SecretKind,
+    /// The secret itself. Shhh.
+    #[rasn(tag(explicit(2)))]
+    value: String,
+    /// How this secret can be revealed
+    #[rasn(tag(explicit(3)))]
+    reveal: RevealPolicy,
+}
+
+impl Secret {
+    /// Create a new secret
+    pub fn new(label: Option<String>, kind: SecretKind, value: String, reveal: RevealPolicy) -> Self {
+        Self { label, kind, value, reveal }
+    }
+
+    /// Generate the current six-digit TOTP code (RFC 6238, 30 second steps) for this secret.
+    ///
+    /// Fails if this secret isn't a [`SecretKind::TotpSeed`].
+    pub fn totp(&self) -> Result<String> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        self.totp_at(now)
+    }
+
+    /// Generate the TOTP code for this secret at the given unix time (in seconds).
+    pub fn totp_at(&self, unix_time: u64) -> Result<String> {
+        if self.kind != SecretKind::TotpSeed {
+            Err(Error::SecretWrongKind("totp-seed".into()))?;
+        }
+        // seeds get copy/pasted in all sorts of creative formats, so normalize before decoding
+        let seed: Zeroizing<String> = Zeroizing::new(self.value.chars()
+            .filter(|c| !c.is_whitespace() && *c != '=' && *c != '-')
+            .map(|c| c.to_ascii_uppercase())
+            .collect());
+        let key = Zeroizing::new(data_encoding::BASE32_NOPAD.decode(seed.as_bytes())
+            .map_err(|_| Error::SecretInvalidTotpSeed)?);
+        let mut mac = Hmac::<Sha1>::new_from_slice(&key[..])
+            .map_err(|_| Error::SecretInvalidTotpSeed)?;
+        mac.update(&(unix_time / 30).to_be_bytes());
+        let digest = mac.finalize().into_bytes();
+        let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+        let code = u32::from_be_bytes([digest[offset] & 0x7f, digest[offset + 1], digest[offset + 2], digest[offset + 3]]);
+        Ok(format!("{:06}", code % 1_000_000))
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.value.zeroize();
+    }
+}
+
 /// A section is a paragraph, bullet list, etc...any piece or component of a note's body.
-#[derive(AsnType, Encode, Decode, Deserialize, Serialize)]
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize)]
 #[rasn(choice)]
 pub enum SectionSpec {
     /// A link to a note
@@ -83,6 +241,9 @@ pub enum SectionSpec {
         checked: bool,
         #[rasn(tag(explicit(1)))]
         text: String,
+        /// When this task is due, if ever
+        #[rasn(tag(explicit(2)))]
+        due: Option<Timestamp>,
     },
     /// A Quote
     #[rasn(tag(explicit(9)))]
@@ -96,9 +257,13 @@ pub enum SectionSpec {
     /// Embed a photo/video/etc by URL (hotlinking...tsk tsk...)
     #[rasn(tag(explicit(12)))]
     Embed(Url),
-    /// A secret value (obscured from view by default)
+    /// A plain-text secret, as written before secrets had kinds and reveal policies. Still read,
+    /// but [upgraded][SectionSpec::upgrade] to [`SectionSpec::Secret`] whenever it's written.
     #[rasn(tag(explicit(13)))]
-    Secret(String),
+    SecretText(String),
+    /// A secret value (obscured from view by default)
+    #[rasn(tag(explicit(17)))]
+    Secret(Secret),
     /// Ohhh look at me I'm a divider gee whizz guess I'll divide things don't mind me
     #[rasn(tag(explicit(14)))]
     Divider,
@@ -119,11 +284,48 @@ pub enum SectionSpec {
         cols: u8,
         #[rasn(tag(explicit(2)))]
         values: HashMapAsn1<TableCoord, String>,
+        /// Per-column display hints, indexed by column. Columns without an entry are text.
+        #[rasn(tag(explicit(3)), default)]
+        #[serde(default)]
+        columns: Vec<TableColumn>,
+        /// Whether the first row is a header
+        #[rasn(tag(explicit(4)), default)]
+        #[serde(default)]
+        header_row: bool,
     },
 }
 
+
+impl SectionSpec {
+    /// Grab the searchable text from this section, if it has any. Secrets are intentionally left
+    /// out.
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            Self::Heading1(text) |
+                Self::Heading2(text) |
+                Self::Heading3(text) |
+                Self::Paragraph(text) |
+                Self::Bullet(text) |
+                Self::Numbered(text) |
+                Self::Quote(text) |
+                Self::Code(text) |
+                Self::Checkbox { text, .. } => Some(text.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Convert any legacy section types into their current form, ie a plain-text
+    /// [`SectionSpec::SecretText`] becomes a manually-revealed password [`SectionSpec::Secret`].
+    pub fn upgrade(self) -> Self {
+        match self {
+            Self::SecretText(value) => Self::Secret(Secret::new(None, SecretKind::Password, value, RevealPolicy::Manual)),
+            spec => spec,
+        }
+    }
+}
+
 /// A body section.
-#[derive(AsnType, Encode, Decode, Getters, MutGetters, Deserialize, Serialize)]
+#[derive(Clone, AsnType, Encode, Decode, Getters, MutGetters, Deserialize, Serialize)]
 #[getset(get = "pub", get_mut = "pub(crate)")]
 pub struct Section {
     /// The actual section content
@@ -135,8 +337,70 @@ pub struct Section {
     indent: u8,
 }
 
+impl Section {
+    /// Create a new section
+    pub fn new(spec: SectionSpec, indent: u8) -> Self {
+        Self { spec, indent }
+    }
+
+    /// Convert this section's spec into its current form (see [`SectionSpec::upgrade`]).
+    pub fn upgrade(self) -> Self {
+        Self { spec: self.spec.upgrade(), indent: self.indent }
+    }
+
+    /// Check this section for problems, ie indented too far, table values living outside of the
+    /// table, etc.
+    pub fn issues(&self, section_id: &SectionID) -> Vec<NoteIssue> {
+        let mut issues = Vec::new();
+        if self.indent > MAX_INDENT {
+            issues.push(NoteIssue::IndentTooDeep { section_id: section_id.clone(), indent: self.indent });
+        }
+        if let SectionSpec::Table { rows, cols, values, columns, .. } = &self.spec {
+            if columns.len() > *cols as usize {
+                issues.push(NoteIssue::TableTooManyColumnSpecs { section_id: section_id.clone(), specs: columns.len(), cols: *cols });
+            }
+            for coord in values.keys() {
+                if coord.row() >= rows || coord.col() >= cols {
+                    issues.push(NoteIssue::TableCoordOutOfBounds {
+                        section_id: section_id.clone(),
+                        row: *coord.row(),
+                        col: *coord.col(),
+                    });
+                }
+            }
+        }
+        issues
+    }
+}
+
+/// A single problem found when [validating][Note::validate] a note.
+#[derive(Debug)]
+pub enum NoteIssue {
+    /// A section is indented deeper than [`MAX_INDENT`]
+    IndentTooDeep {
+        section_id: SectionID,
+        indent: u8,
+    },
+    /// A table has more column specs than it has columns
+    TableTooManyColumnSpecs {
+        section_id: SectionID,
+        specs: usize,
+        cols: u8,
+    },
+    /// A table has a value outside of its rows/cols
+    TableCoordOutOfBounds {
+        section_id: SectionID,
+        row: u32,
+        col: u8,
+    },
+    /// The body's order references a section that doesn't exist
+    SectionMissing(SectionID),
+    /// The same tag is on the note more than once
+    DuplicateTag(Tag),
+}
+
 /// The body of a note, made from an ordered set of [`Section`]s
-#[derive(AsnType, Encode, Decode, Getters, MutGetters, Deserialize, Serialize)]
+#[derive(Clone, Default, AsnType, Encode, Decode, Getters, MutGetters, Deserialize, Serialize)]
 #[getset(get = "pub", get_mut = "pub(crate)")]
 pub struct NoteBody {
     /// Our heroic body sections
@@ -147,8 +411,33 @@ pub struct NoteBody {
     order: Vec<SectionID>,
 }
 
+/// Tracks when a note was created and last modified. These aren't stored in the note itself, but
+/// are derived from the timestamps of the operations that built the note.
+#[derive(Clone, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct NoteDates {
+    /// When the note's first operation happened
+    created: Timestamp,
+    /// When the note's most recent operation happened
+    modified: Timestamp,
+}
+
+impl NoteDates {
+    /// Create a new set of note dates
+    pub fn new(created: Timestamp, modified: Timestamp) -> Self {
+        Self { created, modified }
+    }
+
+    /// Bump our modified date, if the given timestamp is newer
+    pub(crate) fn touch(&mut self, timestamp: &Timestamp) {
+        if timestamp > &self.modified {
+            self.modified = timestamp.clone();
+        }
+    }
+}
+
 /// Represents a single note.
-#[derive(AsnType, Encode, Decode, Serialize, Deserialize, Getters, MutGetters)]
+#[derive(Clone, AsnType, Encode, Decode, Serialize, Deserialize, Getters, MutGetters)]
 #[getset(get = "pub", get_mut = "pub(crate)")]
 pub struct Note {
     /// Our ID
@@ -169,5 +458,349 @@ pub struct Note {
     /// Whether or not the note is marked as deleted
     #[rasn(tag(explicit(5)))]
     deleted: bool,
+    /// An optional emoji or icon name to show next to the note
+    #[rasn(tag(explicit(6)), default)]
+    #[serde(default)]
+    icon: Option<String>,
+    /// An optional color to highlight the note with
+    #[rasn(tag(explicit(7)), default)]
+    #[serde(default)]
+    color: Option<String>,
+}
+
+
+/// Determines how [`Note::merge_from`] combines the body sections of two notes.
+pub enum MergeStrategy {
+    /// Tack the other note's sections onto the end of ours.
+    Append,
+    /// Alternate between our sections and the other note's sections, one at a time.
+    Interleave,
+}
+
+/// The pieces of another note that need to be folded into ours in order to merge the two.
+struct NoteMerge {
+    /// The incoming sections (with fresh IDs), along with the section each one comes after.
+    sections: Vec<(SectionID, Section, Option<SectionID>)>,
+    /// The final section order of the merged note.
+    order: Vec<SectionID>,
+    /// Tags we don't already have.
+    tags: Vec<Tag>,
+    /// The new title, if the other note's title wins.
+    title: Option<String>,
+}
+
+impl NoteBody {
+    /// Consume this body and return its sections in order, each with a brand new [`SectionID`].
+    fn into_remapped_sections(self) -> Vec<(SectionID, Section)> {
+        let Self { mut sections, order } = self;
+        order.into_iter()
+            .filter_map(|id| sections.remove(&id))
+            .map(|section| (SectionID::new(), section))
+            .collect()
+    }
+}
+
+impl Note {
+    /// Create a new, empty note
+    pub fn new(space_id: SpaceID, title: Option<String>, tags: Vec<Tag>) -> Self {
+        Self { id: NoteID::new(), space_id, title, body: NoteBody::default(), tags, deleted: false, icon: None, color: None }
+    }
+
+    /// Create a new, empty note in the given space, with the space's [defaults][crate::models::space::SpaceDefaults]
+    /// applied.
+    pub fn new_in(space: &Space, title: Option<String>, tags: Vec<Tag>) -> Self {
+        let mut note = Self::new(space.id().clone(), title, tags);
+        space.defaults().apply_to_note(&mut note);
+        note
+    }
+
+    /// Add a section to the end of this note's body, returning its ID.
+    pub fn push_section(&mut self, section: Section) -> SectionID {
+        let section_id = SectionID::new();
+        self.body.sections.insert(section_id.clone(), section);
+        self.body.order.push(section_id.clone());
+        section_id
+    }
+
+    /// Convert any legacy sections in this note's body into their current form (see
+    /// [`SectionSpec::upgrade`]).
+    pub fn upgrade_sections(&mut self) {
+        for section in self.body.sections.values_mut() {
+            if matches!(section.spec, SectionSpec::SecretText(..)) {
+                let spec = std::mem::replace(&mut section.spec, SectionSpec::Divider);
+                section.spec = spec.upgrade();
+            }
+        }
+    }
+
+    /// Check this note's invariants, returning every problem found. An empty list means the note
+    /// is valid.
+    pub fn validate(&self) -> Vec<NoteIssue> {
+        let mut issues = Vec::new();
+        for section_id in self.body.order() {
+            match self.body.sections().get(section_id) {
+                Some(section) => issues.extend(section.issues(section_id)),
+                None => issues.push(NoteIssue::SectionMissing(section_id.clone())),
+            }
+        }
+        for (idx, tag) in self.tags.iter().enumerate() {
+            if self.tags[..idx].contains(tag) {
+                issues.push(NoteIssue::DuplicateTag(tag.clone()));
+            }
+        }
+        issues
+    }
+
+    /// Hash a canonical encoding of this note. Two devices that converged on identical note
+    /// content will always come up with the same hash.
+    ///
+    /// The note's DER encoding isn't canonical on its own: body sections (and table values) live
+    /// in hash maps, which encode in whatever order the map iterates in. So we hash each field's
+    /// DER in turn (DER is self-delimiting, so the concatenation is unambiguous), with map entries
+    /// sorted by their encoded keys.
+    pub fn content_hash(&self) -> Result<Hash> {
+        fn der<T: Encode>(val: &T) -> Result<Vec<u8>> {
+            rasn::der::encode(val).map_err(|_| Error::ASNSerialize)
+        }
+        fn sorted_entries<'a, K: Encode + 'a, V: Encode + 'a>(entries: impl Iterator<Item = (&'a K, &'a V)>) -> Result<Vec<u8>> {
+            let mut encoded = entries
+                .map(|(k, v)| Ok((der(k)?, der(v)?)))
+                .collect::<Result<Vec<_>>>()?;
+            encoded.sort();
+            Ok(encoded.into_iter().flat_map(|(k, v)| k.into_iter().chain(v)).collect())
+        }
+        let mut canonical = Vec::new();
+        canonical.extend(der(&self.id)?);
+        canonical.extend(der(&self.space_id)?);
+        canonical.extend(der(&self.title)?);
+        // icon and color came later, so leave them out when unset to keep older hashes stable
+        if self.icon.is_some() || self.color.is_some() {
+            canonical.extend(der(&self.icon)?);
+            canonical.extend(der(&self.color)?);
+        }
+        canonical.extend(der(&self.body.order)?);
+        let mut sections = self.body.sections.iter()
+            .map(|(section_id, section)| {
+                let mut encoded = der(section_id)?;
+                match &section.spec {
+                    SectionSpec::Table { rows, cols, values, columns, header_row } => {
+                        encoded.extend(der(rows)?);
+                        encoded.extend(der(cols)?);
+                        encoded.extend(sorted_entries(values.iter())?);
+                        encoded.extend(der(columns)?);
+                        encoded.extend(der(header_row)?);
+                    }
+                    spec => encoded.extend(der(spec)?),
+                }
+                encoded.extend(der(&section.indent)?);
+                Ok(encoded)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // every entry starts with its section id, so this sorts by id
+        sections.sort();
+        canonical.extend(sections.into_iter().flatten());
+        canonical.extend(der(&self.tags)?);
+        canonical.extend(der(&self.deleted)?);
+        Ok(Hash::new_blake3(&canonical[..])?)
+    }
+
+    /// The files this note embeds or links to.
+    pub fn file_refs(&self) -> HashSet<&FileID> {
+        self.body.sections().values()
+            .filter_map(|section| match section.spec() {
+                SectionSpec::File { id, .. } => Some(id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Merge another note into this one. The other note's sections are given new IDs and either
+    /// appended or interleaved (based on `strategy`), its tags are unioned with ours, and whichever
+    /// title is longer wins.
+    ///
+    /// Mainly useful for cleaning up after importing duplicates.
+    pub fn merge_from(&mut self, other: Note, strategy: MergeStrategy) {
+        let NoteMerge { sections, order, tags, title } = self.merge_plan(other, strategy);
+        for (section_id, section, _) in sections {
+            self.body.sections.insert(section_id, section);
+        }
+        self.body.order = order;
+        self.tags.extend(tags);
+        if title.is_some() {
+            self.title = title;
+        }
+    }
+
+    /// Like [`Note::merge_from`], but instead of mutating this note, returns the operations that,
+    /// when applied in order, merge the other note into this one.
+    pub fn merge_operations(&self, other: Note, strategy: MergeStrategy) -> Vec<Operation> {
+        let NoteMerge { sections, tags, title, .. } = self.merge_plan(other, strategy);
+        let mut ops = Vec::with_capacity(sections.len() + tags.len() + 1);
+        for (section_id, section, after) in sections {
+            ops.push(Operation::note_set_body_section(self.space_id.clone(), self.id.clone(), section_id, section, after));
+        }
+        for tag in tags {
+            ops.push(Operation::note_set_tag(self.space_id.clone(), self.id.clone(), tag));
+        }
+        if title.is_some() {
+            ops.push(Operation::note_set_title(self.space_id.clone(), self.id.clone(), title));
+        }
+        ops
+    }
+
+    /// Figure out what needs to change in this note to merge the other into it.
+    fn merge_plan(&self, other: Note, strategy: MergeStrategy) -> NoteMerge {
+        let Note { title: other_title, body: other_body, tags: other_tags, .. } = other;
+        let incoming = other_body.into_remapped_sections();
+        let ours = self.body.order();
+        let mut order = Vec::with_capacity(ours.len() + incoming.len());
+        match strategy {
+            MergeStrategy::Append => {
+                order.extend(ours.iter().cloned());
+                order.extend(incoming.iter().map(|(id, _)| id.clone()));
+            }
+            MergeStrategy::Interleave => {
+                let mut ours = ours.iter();
+                let mut theirs = incoming.iter().map(|(id, _)| id);
+                loop {
+                    match (ours.next(), theirs.next()) {
+                        (None, None) => break,
+                        (a, b) => {
+                            order.extend(a.cloned());
+                            order.extend(b.cloned());
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut incoming: HashMap<SectionID, Section> = incoming.into_iter().collect();
+        let mut sections = Vec::with_capacity(incoming.len());
+        let mut prev: Option<SectionID> = None;
+        for section_id in &order {
+            if let Some(section) = incoming.remove(section_id) {
+                sections.push((section_id.clone(), section, prev.clone()));
+            }
+            prev = Some(section_id.clone());
+        }
+
+        let mut tags: Vec<Tag> = Vec::new();
+        for tag in other_tags {
+            if !self.tags.contains(&tag) && !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+
+        let our_len = self.title.as_ref().map(|t| t.chars().count()).unwrap_or(0);
+        let title = other_title.filter(|t| t.chars().count() > our_len);
+
+        NoteMerge { sections, order, tags, title }
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn totp_seed() -> Secret {
+        // RFC 6238 appendix B uses the ascii seed "12345678901234567890"
+        let seed = data_encoding::BASE32_NOPAD.encode(b"12345678901234567890");
+        Secret::new(None, SecretKind::TotpSeed, seed, RevealPolicy::Manual)
+    }
+
+    #[test]
+    fn totp_rfc6238_vectors() {
+        let secret = totp_seed();
+        // the RFC's eight-digit SHA1 codes, trimmed to our six digits
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+        for (time, code) in vectors {
+            assert_eq!(secret.totp_at(time).unwrap(), code, "time {}", time);
+        }
+    }
+
+    #[test]
+    fn totp_normalizes_seed() {
+        let seed = data_encoding::BASE32_NOPAD.encode(b"12345678901234567890").to_lowercase();
+        let spaced = seed.as_bytes().chunks(4)
+            .map(|c| std::str::from_utf8(c).unwrap())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let secret = Secret::new(None, SecretKind::TotpSeed, format!("{}==", spaced), RevealPolicy::Manual);
+        assert_eq!(secret.totp_at(59).unwrap(), "287082");
+    }
+
+    #[test]
+    fn totp_rejects_wrong_kind_and_bad_seed() {
+        let password = Secret::new(None, SecretKind::Password, "GEZDGNBV".into(), RevealPolicy::Manual);
+        assert!(matches!(password.totp_at(59), Err(Error::SecretWrongKind(..))));
+        let bad = Secret::new(None, SecretKind::TotpSeed, "not base32!".into(), RevealPolicy::Manual);
+        assert!(matches!(bad.totp_at(59), Err(Error::SecretInvalidTotpSeed)));
+    }
+
+    #[test]
+    fn legacy_secret_text_decodes_and_upgrades() {
+        let der = rasn::der::encode(&SectionSpec::SecretText("hunter2".into())).unwrap();
+        let spec: SectionSpec = rasn::der::decode(&der[..]).unwrap();
+        assert!(matches!(&spec, SectionSpec::SecretText(value) if value == "hunter2"));
+        match spec.upgrade() {
+            SectionSpec::Secret(secret) => {
+                assert_eq!(secret.value(), "hunter2");
+                assert!(*secret.kind() == SecretKind::Password);
+            }
+            _ => panic!("secret text wasn't upgraded"),
+        }
+    }
+
+    #[test]
+    fn content_hash_ignores_map_order() {
+        let space_id = SpaceID::new();
+        let mut note = Note::new(space_id, Some("hash me".into()), vec![Tag::new("a")]);
+        for i in 0..20 {
+            note.push_section(Section::new(SectionSpec::Paragraph(format!("paragraph {}", i)), 0));
+        }
+        let mut values = HashMapAsn1::<TableCoord, String>::default();
+        for row in 0..5 {
+            values.insert(TableCoord { row, col: 0 }, format!("cell {}", row));
+        }
+        note.push_section(Section::new(SectionSpec::Table { rows: 5, cols: 1, values, columns: vec![], header_row: false }, 0));
+        let hash = note.content_hash().unwrap();
+
+        // rebuild the same note with its maps filled in a different order
+        let mut reordered = note.clone();
+        let mut sections = note.body().sections().iter().map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>();
+        sections.reverse();
+        reordered.body_mut().sections_mut().clear();
+        for (id, section) in sections {
+            reordered.body_mut().sections_mut().insert(id, section);
+        }
+        assert_eq!(reordered.content_hash().unwrap(), hash);
+
+        // and a decoded copy hashes the same too
+        let der = rasn::der::encode(&note).unwrap();
+        let decoded: Note = rasn::der::decode(&der[..]).unwrap();
+        assert_eq!(decoded.content_hash().unwrap(), hash);
+
+        *reordered.title_mut() = Some("changed".into());
+        assert_ne!(reordered.content_hash().unwrap(), hash);
+    }
+
+    #[test]
+    fn structured_secret_roundtrips() {
+        let spec = SectionSpec::Secret(Secret::new(Some("bank".into()), SecretKind::ApiKey, "abc".into(), RevealPolicy::Timed(30)));
+        let der = rasn::der::encode(&spec).unwrap();
+        match rasn::der::decode::<SectionSpec>(&der[..]).unwrap() {
+            SectionSpec::Secret(secret) => {
+                assert_eq!(secret.label().as_deref(), Some("bank"));
+                assert!(*secret.reveal() == RevealPolicy::Timed(30));
+            }
+            _ => panic!("wrong section type"),
+        }
+    }
+}
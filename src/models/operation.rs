@@ -10,30 +10,40 @@
 //! rescue!
 
 use crate::{
+    crypto::{self, AssociatedData, BlindToken, CipherSuite, EnvelopeRecipient, KeyPurpose, OperationKeys},
     error::{Error, Result},
     models::{
         Encryptable, ObjectID,
 
         file::{File, FileChunk, FileChunkID, FileID},
-        note::{Note, NoteID, Section, SectionID, Tag},
-        page::{Display, Page, PageID, Slice},
-        space::{Member, MemberID, Role, Space, SpaceID},
-        user::{UserSettings},
+        note::{MAX_INDENT, Note, NoteID, NoteIssue, Section, SectionID, Tag},
+        page::{Display, Page, PageID, Slice, SortEntry},
+        permission::Permissions,
+        space::{Approval, KeyRotation, Member, MemberID, MemberProfile, MemberScope, Role, Space, SpaceDefaults, SpaceID, SpaceQuota, Tombstone, Viewer, ViewerID},
+        keychain::KeychainEntry,
+        user::{Device, DeviceID, Favorite, NoteReadState, NotificationLevel, RecentView, SettingsBlob, SpaceOverride, SyncPolicy, TagStyle, Theme, UserProfile, UserSettings},
     },
 };
-use getset::Getters;
+use getset::{Getters, MutGetters};
 use rasn::{AsnType, Decode, Encode};
 use serde::{Deserialize, Serialize};
 use stamp_core::{
     crypto::{
-        base::{HashAlgo, Sealed, SecretKey},
+        base::{Hash, HashAlgo, Sealed, SecretKey},
         seal,
     },
     dag::{Dag, Transaction, TransactionBody, TransactionID, Transactions},
+    identity::{Identity, IdentityID},
     util::Timestamp,
 };
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::ops::Deref;
+use zeroize::Zeroizing;
+
+/// The highest [`OperationAction`] tag this build knows about. Bump this whenever a variant is
+/// added, so [sync negotiation][crate::sync::handshake] can tell peers what we understand.
+pub const MAX_ACTION_TAG: u32 = 71;
 
 /// Defines an operation that runs at an acceptable level of granularity such that, for each
 /// object, when run *in order* the operations can construct the object in its entirety.
@@ -50,6 +60,9 @@ pub enum OperationAction {
     /// Create a file chunk
     #[rasn(tag(explicit(1)))]
     FileSetChunkV1(FileChunk),
+    /// Point a file at its preview chunk (or remove the preview with `None`)
+    #[rasn(tag(explicit(46)))]
+    FileSetPreviewV1(Option<FileChunkID>),
     /// Set a file's name
     #[rasn(tag(explicit(2)))]
     FileSetNameV1(String),
@@ -59,6 +72,15 @@ pub enum OperationAction {
     /// Create a full note.
     #[rasn(tag(explicit(4)))]
     NoteSetV1(Note),
+    /// Create a full note, along with the note's [content hash][Note::content_hash]. This is
+    /// what checkpoints use so devices can verify they converged on the same note.
+    #[rasn(tag(explicit(27)))]
+    NoteSetV2 {
+        #[rasn(tag(explicit(0)))]
+        note: Note,
+        #[rasn(tag(explicit(1)))]
+        hash: Hash,
+    },
     /// Add a new section to this note
     #[rasn(tag(explicit(5)))]
     NoteSetBodySectionV1 {
@@ -88,6 +110,12 @@ pub enum OperationAction {
     /// Mark a note as deleted. This is effectively putting it into the trash as opposed to
     /// deleting it outright. Full deletion is done via `NoteUnsetV1`.
     NoteSetDeletedV1(bool),
+    /// Set (or clear) a note's color
+    #[rasn(tag(explicit(71)))]
+    NoteSetColorV1(Option<String>),
+    /// Set (or clear) a note's icon
+    #[rasn(tag(explicit(70)))]
+    NoteSetIconV1(Option<String>),
     /// Add a tag to this note
     #[rasn(tag(explicit(8)))]
     NoteSetTagV1(Tag),
@@ -108,13 +136,22 @@ pub enum OperationAction {
     PageSetV1(Page),
     /// Mark a page as deleted. This moves it to the trash as opposed to deleting it outright. A
     /// full delete happens via `PageUnsetV1`.
-    PageSetDeleted(bool),
+    PageSetDeletedV1(bool),
+    /// Set (or clear) a page's description
+    #[rasn(tag(explicit(30)))]
+    PageSetDescriptionV1(Option<String>),
     /// Set a page's display
     #[rasn(tag(explicit(14)))]
     PageSetDisplayV1(Display),
+    /// Set (or clear) a page's icon
+    #[rasn(tag(explicit(29)))]
+    PageSetIconV1(Option<String>),
     /// Set a page's slice
     #[rasn(tag(explicit(15)))]
     PageSetSliceV1(Slice),
+    /// Set a page's sort order
+    #[rasn(tag(explicit(32)))]
+    PageSetSortV1(Vec<SortEntry>),
     /// Set a page's title
     #[rasn(tag(explicit(16)))]
     PageSetTitleV1(String),
@@ -124,12 +161,59 @@ pub enum OperationAction {
     /// Set a space into existence
     #[rasn(tag(explicit(18)))]
     SpaceSetV1(Space),
+    /// Archive (or un-archive) a space
+    #[rasn(tag(explicit(35)))]
+    SpaceSetArchivedV1(bool),
+    /// Approve a quorum-gated operation
+    #[rasn(tag(explicit(42)))]
+    SpaceSetApprovalV1(Approval),
     /// Set the space's color
     #[rasn(tag(explicit(19)))]
     SpaceSetColorV1(Option<String>),
+    /// Turn convergent chunk encryption on or off for the space
+    #[rasn(tag(explicit(67)))]
+    SpaceSetConvergentChunksV1(bool),
+    /// Set the defaults for new notes and pages in the space
+    #[rasn(tag(explicit(45)))]
+    SpaceSetDefaultsV1(SpaceDefaults),
+    /// Set (or clear) the page shown when the space is opened
+    #[rasn(tag(explicit(31)))]
+    SpaceSetDefaultPageV1(Option<PageID>),
     /// Sets a full member object
     #[rasn(tag(explicit(20)))]
     SpaceSetMemberV1(Member),
+    /// Override a member's permissions, or go back to their role's presets with `None`
+    #[rasn(tag(explicit(33)))]
+    SpaceSetMemberPermissionsV1 {
+        #[rasn(tag(explicit(0)))]
+        member_id: MemberID,
+        #[rasn(tag(explicit(1)))]
+        permissions: Option<Permissions>,
+    },
+    /// Set a member's profile (display name, avatar, etc)
+    #[rasn(tag(explicit(36)))]
+    SpaceSetMemberProfileV1 {
+        #[rasn(tag(explicit(0)))]
+        member_id: MemberID,
+        #[rasn(tag(explicit(1)))]
+        profile: MemberProfile,
+    },
+    /// Limit a member to the notes in a set of pages, or lift the limit with `None`
+    #[rasn(tag(explicit(40)))]
+    SpaceSetMemberScopeV1 {
+        #[rasn(tag(explicit(0)))]
+        member_id: MemberID,
+        #[rasn(tag(explicit(1)))]
+        scope: Option<Vec<PageID>>,
+    },
+    /// Record the space frontier a member has acknowledged receiving
+    #[rasn(tag(explicit(43)))]
+    SpaceSetMemberSyncMarkerV1 {
+        #[rasn(tag(explicit(0)))]
+        member_id: MemberID,
+        #[rasn(tag(explicit(1)))]
+        frontier: Vec<TransactionID>,
+    },
     /// Set a member's role
     #[rasn(tag(explicit(21)))]
     SpaceSetMemberRoleV1 {
@@ -138,21 +222,162 @@ pub enum OperationAction {
         #[rasn(tag(explicit(1)))]
         role: Role,
     },
+    /// Marks the point where the space's key was rotated
+    #[rasn(tag(explicit(34)))]
+    SpaceSetKeyRotatedV1(KeyRotation),
+    /// Set (or remove) how many admin approvals destructive operations need
+    #[rasn(tag(explicit(41)))]
+    SpaceSetQuorumV1(Option<u32>),
+    /// Set (or remove) the space's quota
+    #[rasn(tag(explicit(37)))]
+    SpaceSetQuotaV1(Option<SpaceQuota>),
+    /// Give someone read-only access to the space
+    #[rasn(tag(explicit(38)))]
+    SpaceSetViewerV1(Viewer),
+    /// Move the space into (or out of) the trash
+    #[rasn(tag(explicit(44)))]
+    SpaceSetDeletedV1(bool),
     /// Set the space's title
     #[rasn(tag(explicit(22)))]
     SpaceSetTitleV1(String),
+    /// Record that an unset object's history can be purged
+    #[rasn(tag(explicit(69)))]
+    SpaceSetTombstoneV1(Tombstone),
     /// Delete a space.
     #[rasn(tag(explicit(23)))]
     SpaceUnsetV1,
     /// Remove a member from this space
     #[rasn(tag(explicit(24)))]
     SpaceUnsetMemberV1(MemberID),
+    /// Take away a viewer's access to the space
+    #[rasn(tag(explicit(39)))]
+    SpaceUnsetViewerV1(ViewerID),
     /// Set all settings
     #[rasn(tag(explicit(25)))]
-    UserSetSettingsV1(UserSettings),
+    UserSetSettingsV1(SettingsBlob),
     /// Set the default space in the user's settings LOL
     #[rasn(tag(explicit(26)))]
     UserSetSettingsDefaultSpaceV1(Option<SpaceID>),
+    /// Set (or clear) the user's reading state for a note
+    #[rasn(tag(explicit(28)))]
+    UserSetNoteReadStateV1 {
+        #[rasn(tag(explicit(0)))]
+        note_id: NoteID,
+        #[rasn(tag(explicit(1)))]
+        read_state: Option<NoteReadState>,
+    },
+    /// Set the user's color scheme
+    #[rasn(tag(explicit(47)))]
+    UserSetSettingsThemeV1(Theme),
+    /// Set (or clear) the user's locale
+    #[rasn(tag(explicit(48)))]
+    UserSetSettingsLocaleV1(Option<String>),
+    /// Set the default note sort
+    #[rasn(tag(explicit(49)))]
+    UserSetSettingsNoteSortV1(Vec<SortEntry>),
+    /// Turn the editor's spellcheck on/off (or back to the platform default)
+    #[rasn(tag(explicit(50)))]
+    UserSetSettingsEditorSpellcheckV1(Option<bool>),
+    /// Set (or clear) the editor's font size
+    #[rasn(tag(explicit(51)))]
+    UserSetSettingsEditorFontSizeV1(Option<u8>),
+    /// Set (or clear) how long notes stay in the trash
+    #[rasn(tag(explicit(52)))]
+    UserSetSettingsTrashPurgeDaysV1(Option<u32>),
+    /// Register a device
+    #[rasn(tag(explicit(53)))]
+    UserSetDeviceV1(Device),
+    /// Rename a device
+    #[rasn(tag(explicit(54)))]
+    UserSetDeviceNameV1 {
+        #[rasn(tag(explicit(0)))]
+        device_id: DeviceID,
+        #[rasn(tag(explicit(1)))]
+        name: String,
+    },
+    /// Record the user DAG frontier a device has synced up to
+    #[rasn(tag(explicit(55)))]
+    UserSetDeviceSyncedV1 {
+        #[rasn(tag(explicit(0)))]
+        device_id: DeviceID,
+        #[rasn(tag(explicit(1)))]
+        frontier: Vec<TransactionID>,
+    },
+    /// Set what a device syncs
+    #[rasn(tag(explicit(68)))]
+    UserSetDeviceSyncPolicyV1 {
+        #[rasn(tag(explicit(0)))]
+        device_id: DeviceID,
+        #[rasn(tag(explicit(1)))]
+        policy: SyncPolicy,
+    },
+    /// Revoke a device
+    #[rasn(tag(explicit(56)))]
+    UserUnsetDeviceV1(DeviceID),
+    /// Add a (wrapped) space key to the user's keychain
+    #[rasn(tag(explicit(57)))]
+    UserSetKeychainEntryV1 {
+        #[rasn(tag(explicit(0)))]
+        space_id: SpaceID,
+        #[rasn(tag(explicit(1)))]
+        entry: KeychainEntry,
+    },
+    /// Remove all of a space's keys from the user's keychain
+    #[rasn(tag(explicit(58)))]
+    UserUnsetKeychainEntryV1(SpaceID),
+    /// Add a favorite, or move it if it's already there. A `None` index adds to the end (and
+    /// leaves existing favorites where they are).
+    #[rasn(tag(explicit(59)))]
+    UserSetFavoriteV1 {
+        #[rasn(tag(explicit(0)))]
+        favorite: Favorite,
+        #[rasn(tag(explicit(1)))]
+        index: Option<u32>,
+    },
+    /// Remove a favorite
+    #[rasn(tag(explicit(60)))]
+    UserUnsetFavoriteV1(Favorite),
+    /// Record a batch of note/page views
+    #[rasn(tag(explicit(61)))]
+    UserSetRecentViewsV1(Vec<RecentView>),
+    /// Set (or clear) the user's styling for a tag
+    #[rasn(tag(explicit(62)))]
+    UserSetTagStyleV1 {
+        #[rasn(tag(explicit(0)))]
+        tag: Tag,
+        #[rasn(tag(explicit(1)))]
+        style: Option<TagStyle>,
+    },
+    /// Set (or clear) the user's global profile
+    #[rasn(tag(explicit(63)))]
+    UserSetProfileV1(Option<UserProfile>),
+    /// Set the default notification level
+    #[rasn(tag(explicit(64)))]
+    UserSetNotificationsDefaultV1(NotificationLevel),
+    /// Set (or clear) a space's notification level
+    #[rasn(tag(explicit(65)))]
+    UserSetNotificationsSpaceV1 {
+        #[rasn(tag(explicit(0)))]
+        space_id: SpaceID,
+        #[rasn(tag(explicit(1)))]
+        level: Option<NotificationLevel>,
+    },
+    /// Set (or clear) the user's override for a space
+    #[rasn(tag(explicit(66)))]
+    UserSetSpaceOverrideV1 {
+        #[rasn(tag(explicit(0)))]
+        space_id: SpaceID,
+        #[rasn(tag(explicit(1)))]
+        space_override: Option<SpaceOverride>,
+    },
+}
+
+impl OperationAction {
+    /// A hash of this action, used to tie [approvals][Approval] to the exact action they approve.
+    pub fn approval_hash(&self) -> Result<Hash> {
+        let serialized = rasn::der::encode(self).map_err(|_| Error::ASNSerialize)?;
+        Ok(Hash::new_blake3(&serialized[..])?)
+    }
 }
 
 /// Defines a context an operation belongs to. Allows an application to determine which ops it cares
@@ -176,6 +401,39 @@ impl OperationContext {
     fn new(space: Option<SpaceID>, chunk: Option<FileChunkID>, file: Option<FileID>, note: Option<NoteID>, page: Option<PageID>) -> Self {
         Self { chunk, file, note, page, space }
     }
+
+    /// Grab the most specific object this context points to (ie, a chunk context also has a file
+    /// and space, but the object is the chunk). Returns `None` for user-level contexts.
+    pub fn object(&self) -> Option<ObjectRef> {
+        if let Some(id) = self.chunk.as_ref() {
+            Some(ObjectRef::FileChunk(id.clone()))
+        } else if let Some(id) = self.file.as_ref() {
+            Some(ObjectRef::File(id.clone()))
+        } else if let Some(id) = self.note.as_ref() {
+            Some(ObjectRef::Note(id.clone()))
+        } else if let Some(id) = self.page.as_ref() {
+            Some(ObjectRef::Page(id.clone()))
+        } else {
+            self.space.as_ref().map(|id| ObjectRef::Space(id.clone()))
+        }
+    }
+}
+
+/// Points to a single object of any type.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, AsnType, Encode, Decode, Deserialize, Serialize)]
+#[rasn(choice)]
+#[serde(tag = "type", content = "id", rename_all = "snake_case")]
+pub enum ObjectRef {
+    #[rasn(tag(explicit(0)))]
+    Space(SpaceID),
+    #[rasn(tag(explicit(1)))]
+    Page(PageID),
+    #[rasn(tag(explicit(2)))]
+    Note(NoteID),
+    #[rasn(tag(explicit(3)))]
+    File(FileID),
+    #[rasn(tag(explicit(4)))]
+    FileChunk(FileChunkID),
 }
 
 /// Defines an operation, and the context(s) it runs within.
@@ -198,6 +456,33 @@ impl Operation {
         (context, action)
     }
 
+    /// Build an operation from its parts. The inverse of [`Operation::consume`].
+    pub fn from_parts(context: OperationContext, action: OperationAction) -> Self {
+        Self { context, action }
+    }
+
+    /// Pre-flight validation. Checks that the data this operation carries is sane before we turn
+    /// it into a transaction (see [`State::check_local_operation`]).
+    ///
+    /// Only ever run on operations we create: a signed operation from a peer is applied as-is,
+    /// or devices that disagree about what's valid would end up with different states.
+    ///
+    /// [`State::check_local_operation`]: crate::models::state::State::check_local_operation
+    pub fn validate(&self) -> Result<()> {
+        let issues = match &self.action {
+            OperationAction::NoteSetV1(note) | OperationAction::NoteSetV2 { note, .. } => note.validate(),
+            OperationAction::NoteSetBodySectionV1 { section_id, section, .. } => section.issues(section_id),
+            OperationAction::NoteSetBodySectionIndentV1 { section_id, indent } if *indent > MAX_INDENT => {
+                vec![NoteIssue::IndentTooDeep { section_id: section_id.clone(), indent: *indent }]
+            }
+            _ => Vec::new(),
+        };
+        if !issues.is_empty() {
+            Err(Error::NoteInvalid(issues))?;
+        }
+        Ok(())
+    }
+
     /// Create a file
     pub fn file_set(space_id: SpaceID, file: File) -> Self {
         Self {
@@ -222,6 +507,14 @@ impl Operation {
         }
     }
 
+    /// Set (or remove) a file's preview chunk. See [`File::attach_preview`].
+    pub fn file_set_preview(space_id: SpaceID, file_id: FileID, chunk_id: Option<FileChunkID>) -> Self {
+        Self {
+            context: OperationContext::new(Some(space_id), None, Some(file_id), None, None),
+            action: OperationAction::FileSetPreviewV1(chunk_id),
+        }
+    }
+
     /// Delete a file
     pub fn file_unset(space_id: SpaceID, file_id: FileID) -> Self {
         Self {
@@ -232,11 +525,16 @@ impl Operation {
 
     /// Set/create a whole note. Mainly useful for moving notes across space lines, or for creating
     /// checkpoints.
-    pub fn note_set(space_id: SpaceID, note: Note) -> Self {
-        Self {
+    ///
+    /// The note's content hash is stored alongside it. Any legacy sections are
+    /// [upgraded][Note::upgrade_sections] first.
+    pub fn note_set(space_id: SpaceID, mut note: Note) -> Result<Self> {
+        note.upgrade_sections();
+        let hash = note.content_hash()?;
+        Ok(Self {
             context: OperationContext::new(Some(space_id), None, None, Some(note.id().clone()), None),
-            action: OperationAction::NoteSetV1(note),
-        }
+            action: OperationAction::NoteSetV2 { note, hash },
+        })
     }
 
     /// Create a body section in a note
@@ -245,20 +543,36 @@ impl Operation {
             context: OperationContext::new(Some(space_id), None, None, Some(note_id), None),
             action: OperationAction::NoteSetBodySectionV1 {
                 section_id,
-                section,
+                section: section.upgrade(),
                 after,
             },
         }
     }
 
     /// Mark a note as (un)deleted
-    pub fn note_set_deleted(space_id: SpaceID, node_id: NoteID, deleted: bool) -> Self {
+    pub fn note_set_deleted(space_id: SpaceID, note_id: NoteID, deleted: bool) -> Self {
         Self {
             context: OperationContext::new(Some(space_id), None, None, Some(note_id), None),
             action: OperationAction::NoteSetDeletedV1(deleted),
         }
     }
 
+    /// Set a note's color
+    pub fn note_set_color(space_id: SpaceID, note_id: NoteID, color: Option<String>) -> Self {
+        Self {
+            context: OperationContext::new(Some(space_id), None, None, Some(note_id), None),
+            action: OperationAction::NoteSetColorV1(color),
+        }
+    }
+
+    /// Set a note's icon
+    pub fn note_set_icon(space_id: SpaceID, note_id: NoteID, icon: Option<String>) -> Self {
+        Self {
+            context: OperationContext::new(Some(space_id), None, None, Some(note_id), None),
+            action: OperationAction::NoteSetIconV1(icon),
+        }
+    }
+
     /// Attach a tag to a note
     pub fn note_set_tag(space_id: SpaceID, note_id: NoteID, tag: Tag) -> Self {
         Self {
@@ -315,6 +629,14 @@ impl Operation {
         }
     }
 
+    /// Set a page's description
+    pub fn page_set_description(space_id: SpaceID, page_id: PageID, description: Option<String>) -> Self {
+        Self {
+            context: OperationContext::new(Some(space_id), None, None, None, Some(page_id)),
+            action: OperationAction::PageSetDescriptionV1(description),
+        }
+    }
+
     /// Set a page's view
     pub fn page_set_display(space_id: SpaceID, page_id: PageID, display: Display) -> Self {
         Self {
@@ -323,6 +645,14 @@ impl Operation {
         }
     }
 
+    /// Set a page's icon
+    pub fn page_set_icon(space_id: SpaceID, page_id: PageID, icon: Option<String>) -> Self {
+        Self {
+            context: OperationContext::new(Some(space_id), None, None, None, Some(page_id)),
+            action: OperationAction::PageSetIconV1(icon),
+        }
+    }
+
     /// Set a page's slice
     pub fn page_set_slice(space_id: SpaceID, page_id: PageID, slice: Slice) -> Self {
         Self {
@@ -331,6 +661,14 @@ impl Operation {
         }
     }
 
+    /// Set a page's sort order, without having to touch its slice
+    pub fn page_set_sort(space_id: SpaceID, page_id: PageID, sort: Vec<SortEntry>) -> Self {
+        Self {
+            context: OperationContext::new(Some(space_id), None, None, None, Some(page_id)),
+            action: OperationAction::PageSetSortV1(sort),
+        }
+    }
+
     /// Set a page's title
     pub fn page_set_title(space_id: SpaceID, page_id: PageID, title: String) -> Self {
         Self {
@@ -355,6 +693,41 @@ impl Operation {
         }
     }
 
+    /// Archive or un-archive a space.
+    pub fn space_set_archived(space_id: SpaceID, archived: bool) -> Self {
+        Self {
+            context: OperationContext::new(Some(space_id), None, None, None, None),
+            action: OperationAction::SpaceSetArchivedV1(archived),
+        }
+    }
+
+    /// Approve a quorum-gated action (see [`Space::requires_quorum`]) as the given member.
+    pub fn space_set_approval(space_id: SpaceID, member_id: MemberID, action: &OperationAction) -> Result<Self> {
+        let approval = Approval::new(member_id, action.approval_hash()?);
+        Ok(Self {
+            context: OperationContext::new(Some(space_id), None, None, None, None),
+            action: OperationAction::SpaceSetApprovalV1(approval),
+        })
+    }
+
+    /// Set the defaults applied to new notes and pages in this space.
+    pub fn space_set_defaults(space_id: SpaceID, defaults: SpaceDefaults) -> Self {
+        Self {
+            context: OperationContext::new(Some(space_id), None, None, None, None),
+            action: OperationAction::SpaceSetDefaultsV1(defaults),
+        }
+    }
+
+    /// Move a space into (or out of) the trash. A deleted space can only be removed for good once
+    /// its restore window has passed (see
+    /// [`purge::plan_space_unset`][crate::sync::purge::plan_space_unset]).
+    pub fn space_set_deleted(space_id: SpaceID, deleted: bool) -> Self {
+        Self {
+            context: OperationContext::new(Some(space_id), None, None, None, None),
+            action: OperationAction::SpaceSetDeletedV1(deleted),
+        }
+    }
+
     /// Set a space's color, although the only color allowed is black. Like my soul.
     pub fn space_set_color(space_id: SpaceID, color: Option<String>) -> Self {
         Self {
@@ -363,6 +736,23 @@ impl Operation {
         }
     }
 
+    /// Turn convergent encryption of file chunks on or off for a space. Only affects chunks
+    /// written from here on.
+    pub fn space_set_convergent_chunks(space_id: SpaceID, convergent: bool) -> Self {
+        Self {
+            context: OperationContext::new(Some(space_id), None, None, None, None),
+            action: OperationAction::SpaceSetConvergentChunksV1(convergent),
+        }
+    }
+
+    /// Set the page that shows when this space is opened.
+    pub fn space_set_default_page(space_id: SpaceID, page_id: Option<PageID>) -> Self {
+        Self {
+            context: OperationContext::new(Some(space_id), None, None, None, None),
+            action: OperationAction::SpaceSetDefaultPageV1(page_id),
+        }
+    }
+
     /// Create a new member in this space.
     pub fn space_set_member(member: Member) -> Self {
         Self {
@@ -371,6 +761,45 @@ impl Operation {
         }
     }
 
+    /// Override a member's permissions. Passing `None` resets them to their role's presets.
+    pub fn space_set_member_permissions(space_id: SpaceID, member_id: MemberID, permissions: Option<Permissions>) -> Self {
+        Self {
+            context: OperationContext::new(Some(space_id), None, None, None, None),
+            action: OperationAction::SpaceSetMemberPermissionsV1 {
+                member_id,
+                permissions,
+            },
+        }
+    }
+
+    /// Set a member's profile. Members can always set their own.
+    pub fn space_set_member_profile(space_id: SpaceID, member_id: MemberID, profile: MemberProfile) -> Self {
+        Self {
+            context: OperationContext::new(Some(space_id), None, None, None, None),
+            action: OperationAction::SpaceSetMemberProfileV1 {
+                member_id,
+                profile,
+            },
+        }
+    }
+
+    /// Limit a member to only seeing the notes in the given pages (or lift the limit with `None`).
+    pub fn space_set_member_scope(space_id: SpaceID, member_id: MemberID, scope: Option<Vec<PageID>>) -> Self {
+        Self {
+            context: OperationContext::new(Some(space_id), None, None, None, None),
+            action: OperationAction::SpaceSetMemberScopeV1 { member_id, scope },
+        }
+    }
+
+    /// Record that a member has caught up to the given space frontier. Members generally send
+    /// this for themselves after syncing.
+    pub fn space_set_member_sync_marker(space_id: SpaceID, member_id: MemberID, frontier: Vec<TransactionID>) -> Self {
+        Self {
+            context: OperationContext::new(Some(space_id), None, None, None, None),
+            action: OperationAction::SpaceSetMemberSyncMarkerV1 { member_id, frontier },
+        }
+    }
+
     /// Set a new role for a member.
     pub fn space_set_member_role(space_id: SpaceID, member_id: MemberID, role: Role) -> Self {
         Self {
@@ -382,6 +811,40 @@ impl Operation {
         }
     }
 
+    /// Mark that the space's key has been rotated.
+    pub fn space_set_key_rotated(space_id: SpaceID, rotation: KeyRotation) -> Self {
+        Self {
+            context: OperationContext::new(Some(space_id), None, None, None, None),
+            action: OperationAction::SpaceSetKeyRotatedV1(rotation),
+        }
+    }
+
+    /// Require approvals from this many admins before destructive operations can be applied (or
+    /// remove the requirement with `None`).
+    pub fn space_set_quorum(space_id: SpaceID, quorum: Option<u32>) -> Self {
+        Self {
+            context: OperationContext::new(Some(space_id), None, None, None, None),
+            action: OperationAction::SpaceSetQuorumV1(quorum),
+        }
+    }
+
+    /// Set limits on how much can live in this space, or remove them with `None`.
+    pub fn space_set_quota(space_id: SpaceID, quota: Option<SpaceQuota>) -> Self {
+        Self {
+            context: OperationContext::new(Some(space_id), None, None, None, None),
+            action: OperationAction::SpaceSetQuotaV1(quota),
+        }
+    }
+
+    /// Give someone read-only access to this space. They still need to be handed a
+    /// [`ViewerKey`][crate::crypto::ViewerKey] to actually read anything.
+    pub fn space_set_viewer(space_id: SpaceID, viewer: Viewer) -> Self {
+        Self {
+            context: OperationContext::new(Some(space_id), None, None, None, None),
+            action: OperationAction::SpaceSetViewerV1(viewer),
+        }
+    }
+
     /// Set this space's title
     pub fn space_set_title(space_id: SpaceID, title: String) -> Self {
         Self {
@@ -390,7 +853,18 @@ impl Operation {
         }
     }
 
-    /// Remove this space, including all data held within it. Careful!
+    /// Record a tombstone for a note or file that every member has seen unset, so its old
+    /// operations and chunk blobs can be purged. See [`sync::purge`][crate::sync::purge].
+    pub fn space_set_tombstone(space_id: SpaceID, tombstone: Tombstone) -> Self {
+        Self {
+            context: OperationContext::new(Some(space_id), None, None, None, None),
+            action: OperationAction::SpaceSetTombstoneV1(tombstone),
+        }
+    }
+
+    /// Remove this space, including all data held within it. Careful! This doesn't check the
+    /// space's restore window: use
+    /// [`purge::plan_space_unset`][crate::sync::purge::plan_space_unset] for that.
     pub fn space_unset(space_id: SpaceID) -> Self {
         Self {
             context: OperationContext::new(Some(space_id), None, None, None, None),
@@ -406,14 +880,26 @@ impl Operation {
         }
     }
 
-    /// Sets all user settings
-    pub fn user_set_settings(settings: UserSettings) -> Self {
+    /// Remove a viewer from this space. Like removing a member, this should be followed by a key
+    /// rotation.
+    pub fn space_unset_viewer(space_id: SpaceID, viewer_id: ViewerID) -> Self {
         Self {
-            context: OperationContext::new(None, None, None, None, None),
-            action: OperationAction::UserSetSettingsV1(settings),
+            context: OperationContext::new(Some(space_id), None, None, None, None),
+            action: OperationAction::SpaceUnsetViewerV1(viewer_id),
         }
     }
 
+    /// Sets all user settings. Pass
+    /// [`State::newer_user_settings`][crate::models::state::State::newer_user_settings] as `newer`, so fields written
+    /// by clients newer than us aren't lost.
+    pub fn user_set_settings(mut settings: UserSettings, newer: Option<&SettingsBlob>) -> Result<Self> {
+        settings.stamp_version();
+        Ok(Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetSettingsV1(SettingsBlob::new(&settings, newer)?),
+        })
+    }
+
     /// Set the user's default space.
     pub fn user_set_settings_default_space(space_id: Option<SpaceID>) -> Self {
         Self {
@@ -421,6 +907,197 @@ impl Operation {
             action: OperationAction::UserSetSettingsDefaultSpaceV1(space_id),
         }
     }
+
+    /// Save where the user left off in a note, or clear it by passing `None`.
+    pub fn user_set_note_read_state(note_id: NoteID, read_state: Option<NoteReadState>) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetNoteReadStateV1 { note_id, read_state },
+        }
+    }
+
+    /// Set the user's color scheme.
+    pub fn user_set_settings_theme(theme: Theme) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetSettingsThemeV1(theme),
+        }
+    }
+
+    /// Set the user's locale, or pass `None` to follow the system.
+    pub fn user_set_settings_locale(locale: Option<String>) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetSettingsLocaleV1(locale),
+        }
+    }
+
+    /// Set how notes are sorted by default.
+    pub fn user_set_settings_note_sort(sort: Vec<SortEntry>) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetSettingsNoteSortV1(sort),
+        }
+    }
+
+    /// Set whether the editor spellchecks.
+    pub fn user_set_settings_editor_spellcheck(spellcheck: Option<bool>) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetSettingsEditorSpellcheckV1(spellcheck),
+        }
+    }
+
+    /// Set the editor's font size.
+    pub fn user_set_settings_editor_font_size(font_size: Option<u8>) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetSettingsEditorFontSizeV1(font_size),
+        }
+    }
+
+    /// Set how many days notes stay in the trash before being purged.
+    pub fn user_set_settings_trash_purge_days(days: Option<u32>) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetSettingsTrashPurgeDaysV1(days),
+        }
+    }
+
+    /// Register a new device with the user.
+    pub fn user_set_device(device: Device) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetDeviceV1(device),
+        }
+    }
+
+    /// Rename one of the user's devices.
+    pub fn user_set_device_name(device_id: DeviceID, name: String) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetDeviceNameV1 { device_id, name },
+        }
+    }
+
+    /// Mark a device as synced up to the given frontier.
+    pub fn user_set_device_synced(device_id: DeviceID, frontier: Vec<TransactionID>) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetDeviceSyncedV1 { device_id, frontier },
+        }
+    }
+
+    /// Set what one of the user's devices syncs.
+    pub fn user_set_device_sync_policy(device_id: DeviceID, policy: SyncPolicy) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetDeviceSyncPolicyV1 { device_id, policy },
+        }
+    }
+
+    /// Revoke one of the user's devices.
+    pub fn user_unset_device(device_id: DeviceID) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserUnsetDeviceV1(device_id),
+        }
+    }
+
+    /// Add a space key to the user's keychain. Wrap the key first with
+    /// [`KeychainEntry::wrap`].
+    pub fn user_set_keychain_entry(space_id: SpaceID, entry: KeychainEntry) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetKeychainEntryV1 { space_id, entry },
+        }
+    }
+
+    /// Drop a space's keys from the user's keychain, ie after leaving the space.
+    pub fn user_unset_keychain_entry(space_id: SpaceID) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserUnsetKeychainEntryV1(space_id),
+        }
+    }
+
+    /// Star a note/page/space, adding it to the end of the user's favorites.
+    pub fn user_set_favorite(favorite: Favorite) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetFavoriteV1 { favorite, index: None },
+        }
+    }
+
+    /// Move a favorite to a new spot in the list.
+    pub fn user_set_favorite_order(favorite: Favorite, index: u32) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetFavoriteV1 { favorite, index: Some(index) },
+        }
+    }
+
+    /// Un-star a note/page/space.
+    pub fn user_unset_favorite(favorite: Favorite) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserUnsetFavoriteV1(favorite),
+        }
+    }
+
+    /// Record that the user opened some notes/pages. Views are meant to be batched up and sent
+    /// every so often rather than creating an operation every time something is opened.
+    pub fn user_set_recent_views(views: Vec<RecentView>) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetRecentViewsV1(views),
+        }
+    }
+
+    /// Set how a tag looks for this user, or pass `None` to go back to the plain tag.
+    pub fn user_set_tag_style(tag: Tag, style: Option<TagStyle>) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetTagStyleV1 { tag, style },
+        }
+    }
+
+    /// Set the user's global profile. This doesn't change what any space shows: publish it to
+    /// a space with [`Operation::space_set_member_profile`] and
+    /// [`UserProfile::to_member_profile`].
+    pub fn user_set_profile(profile: Option<UserProfile>) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetProfileV1(profile),
+        }
+    }
+
+    /// Set the notification level used by spaces without their own.
+    pub fn user_set_notifications_default(level: NotificationLevel) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetNotificationsDefaultV1(level),
+        }
+    }
+
+    /// Set a space's notification level, or pass `None` to go back to the default. Note that
+    /// this is a user operation: the space ID is part of the action, not the context, so the
+    /// space's other members never see it.
+    pub fn user_set_notifications_space(space_id: SpaceID, level: Option<NotificationLevel>) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetNotificationsSpaceV1 { space_id, level },
+        }
+    }
+
+    /// Rename/recolor/mute a space for this user only, or pass `None` to see the space as
+    /// everyone else does.
+    pub fn user_set_space_override(space_id: SpaceID, space_override: Option<SpaceOverride>) -> Self {
+        Self {
+            context: OperationContext::new(None, None, None, None, None),
+            action: OperationAction::UserSetSpaceOverrideV1 { space_id, space_override },
+        }
+    }
 }
 
 impl Encryptable for Operation {
@@ -430,32 +1107,31 @@ impl Encryptable for Operation {
         let Self { context, action } = self;
         let OperationContext { chunk, file, note, page, space } = context;
         let context_no_space = OperationContext::new(None, chunk, file, note, page);
-        let serialized_context = rasn::der::encode(&context_no_space).map_err(|_| Error::ASNSerialize)?;
-        let serialized_action = rasn::der::encode(&action).map_err(|_| Error::ASNSerialize)?;
-        let sealed_context = seal::seal(secret_key, &serialized_context[..])?;
-        let sealed_action = seal::seal(secret_key, &serialized_action[..])?;
+        let serialized_context = Zeroizing::new(rasn::der::encode(&context_no_space).map_err(|_| Error::ASNSerialize)?);
+        let serialized_action = Zeroizing::new(rasn::der::encode(&action).map_err(|_| Error::ASNSerialize)?);
+        let keys = OperationKeys::new(secret_key)?;
+        let sealed_context = crypto::seal_bound(keys.key_for(KeyPurpose::OperationContext, true)?, &AssociatedData::new(space.clone(), PURPOSE_CONTEXT), &serialized_context[..])?;
+        let sealed_action = crypto::seal_bound(keys.key_for(KeyPurpose::OperationAction, true)?, &AssociatedData::new(space.clone(), PURPOSE_ACTION), &serialized_action[..])?;
         Ok(Self::Output {
             context: space,
             ciphertext_context: sealed_context,
             ciphertext_action: sealed_action,
+            envelope: Vec::new(),
+            suite: CipherSuite::CURRENT,
+            blind_index: Vec::new(),
         })
     }
 
     fn decrypt(secret_key: &SecretKey, encrypted: &Self::Output) -> crate::error::Result<Self> {
-        let Self::Output { context: ref context_space, ref ciphertext_context, ref ciphertext_action } = encrypted;
-        let opened_context = seal::open(secret_key, ciphertext_context)?;
-        let opened_action = seal::open(secret_key, ciphertext_action)?;
-        let OperationContext { chunk, file, note, page, .. } = rasn::der::decode(&opened_context[..]).map_err(|_| crate::error::Error::ASNDeserialize)?;
-        let action: OperationAction = rasn::der::decode(&opened_action[..]).map_err(|_| crate::error::Error::ASNDeserialize)?;
-
-        let context = OperationContext::new(context_space.clone(), chunk, file, note, page);
-        Ok(Self {
-            context,
-            action,
-        })
+        encrypted.decrypt_with(&OperationKeys::new(secret_key)?)
     }
 }
 
+/// What an operation's sealed context is bound to
+const PURPOSE_CONTEXT: &str = "turtl/op/context";
+/// What an operation's sealed action is bound to
+const PURPOSE_ACTION: &str = "turtl/op/action";
+
 /// Basically, a [`Operation`] but with the `action` field serialized and encrypted, and the `context`
 /// field also encrypted, but only after lifting `space` out of the context and shoving it into the
 /// `context` field as a `Option<SpaceID>`.
@@ -468,7 +1144,7 @@ impl Encryptable for Operation {
 /// ```
 ///
 /// Make sure you have [`Encryptable`] imported.
-#[derive(AsnType, Encode, Decode, Deserialize, Serialize, Getters)]
+#[derive(AsnType, Encode, Decode, Deserialize, Serialize, Getters, MutGetters)]
 #[getset(get = "pub")]
 pub struct OperationEncrypted {
     /// The space context(s) this operation happens within.
@@ -487,15 +1163,253 @@ pub struct OperationEncrypted {
     #[rasn(tag(explicit(2)))]
     #[getset(skip)]
     ciphertext_action: Sealed,
+    /// If this operation was sealed with its own content key (see
+    /// [`crypto::seal_for_members`][crate::crypto::seal_for_members]), that key sealed to each
+    /// member. Empty for operations sealed with the space key.
+    #[rasn(tag(explicit(3)), default)]
+    #[serde(default)]
+    #[getset(get = "pub", get_mut = "pub(crate)")]
+    envelope: Vec<EnvelopeRecipient>,
+    /// How the ciphertexts were sealed. Operations from before we tracked this are
+    /// [`CipherSuite::Legacy`].
+    #[rasn(tag(explicit(4)), default)]
+    #[serde(default)]
+    suite: CipherSuite,
+    /// Blind index entries for this operation, if it was encrypted with
+    /// [`crypto::encrypt_indexed`]
+    #[rasn(tag(explicit(5)), default)]
+    #[serde(default)]
+    #[getset(get = "pub", get_mut = "pub(crate)")]
+    blind_index: Vec<BlindToken>,
 }
 
 impl OperationEncrypted {
+    /// Whether this operation's blind index has the given token
+    pub fn has_blind_token(&self, token: &BlindToken) -> bool {
+        self.blind_index.contains(token)
+    }
+
+    /// Open one of our ciphertexts according to its cipher suite.
+    fn open_part(&self, keys: &OperationKeys, key_purpose: KeyPurpose, purpose: &str, sealed: &Sealed) -> Result<Vec<u8>> {
+        let secret_key = keys.key_for(key_purpose, self.suite.uses_subkeys())?;
+        if self.suite.is_bound() {
+            crypto::open_bound(secret_key, &AssociatedData::new(self.context.clone(), purpose), sealed)
+        } else {
+            Ok(seal::open(secret_key, sealed)?)
+        }
+    }
+
     /// Decrypts this operation's full context and returns it on a platter with french fried potatoes.
     pub fn get_full_context(&self, secret_key: &SecretKey) -> Result<OperationContext> {
-        let opened_context = seal::open(secret_key, &self.ciphertext_context)?;
+        self.get_full_context_with(&OperationKeys::new(secret_key)?)
+    }
+
+    /// Like [`get_full_context`][Self::get_full_context], but with keys that were already
+    /// derived, for when we're opening a lot of operations from the same space.
+    pub fn get_full_context_with(&self, keys: &OperationKeys) -> Result<OperationContext> {
+        let opened_context = Zeroizing::new(self.open_part(keys, KeyPurpose::OperationContext, PURPOSE_CONTEXT, &self.ciphertext_context)?);
         let OperationContext { chunk, file, note, page, .. } = rasn::der::decode(&opened_context[..]).map_err(|_| crate::error::Error::ASNDeserialize)?;
         Ok(OperationContext::new(self.context.clone(), chunk, file, note, page))
     }
+
+    /// Decrypt this operation with keys that were already derived. This is what
+    /// [`Operation::decrypt`] does after deriving the keys itself.
+    pub fn decrypt_with(&self, keys: &OperationKeys) -> Result<Operation> {
+        let context = self.get_full_context_with(keys)?;
+        let opened_action = Zeroizing::new(self.open_part(keys, KeyPurpose::OperationAction, PURPOSE_ACTION, &self.ciphertext_action)?);
+        let action: OperationAction = rasn::der::decode(&opened_action[..]).map_err(|_| crate::error::Error::ASNDeserialize)?;
+        Ok(Operation { context, action })
+    }
+}
+
+/// Pull the encrypted operation out of a Stamp transaction, filling in its (unencrypted) space
+/// context from the transaction's context. Returns the transaction's creator along with the
+/// operation.
+pub fn operation_from_transaction(trans: &Transaction) -> Result<(&IdentityID, OperationEncrypted)> {
+    match trans.entry().body() {
+        TransactionBody::ExtV1 { ref creator, ref ty, ref context, ref payload, .. } => {
+            if ty.as_ref().map(|x| x.deref().as_slice()) != Some(b"turtl/op/v1") {
+                Err(Error::TransactionWrongType(trans.id().clone()))?;
+            }
+            let space_id = match context.as_ref().and_then(|map| map.get(&b"space".to_vec().into())) {
+                Some(ser) => {
+                    Some(rasn::der::decode::<SpaceID>(ser.as_slice()).map_err(|e| Error::TransactionDeserializationError(trans.id().clone(), e))?)
+                }
+                None => None,
+            };
+            let mut operation = rasn::der::decode::<OperationEncrypted>(payload.as_slice())
+                .map_err(|e| Error::TransactionDeserializationError(trans.id().clone(), e))?;
+            operation.context = space_id;
+            Ok((creator, operation))
+        }
+        _ => Err(Error::TransactionWrongVariant(trans.id().clone())),
+    }
+}
+
+/// What [`verify_operation`] thinks of a transaction.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    /// Signed by its creator, who was a member of the space
+    Valid,
+    /// The signature doesn't hold up (or wasn't made by the identity we were given)
+    BadSignature(String),
+    /// Not a Turtl operation at all
+    NotAnOperation,
+    /// The operation belongs to a different space (or to no space)
+    WrongSpace,
+    /// The creator is only a viewer of the space, and viewers can't make changes
+    Viewer,
+    /// The creator wasn't a member of the space (ie, they were removed before making this)
+    NotAMember,
+}
+
+impl Verdict {
+    /// Whether the transaction should be accepted
+    pub fn is_valid(&self) -> bool {
+        matches!(self, Self::Valid)
+    }
+}
+
+/// Check a space transaction before it goes anywhere near [`State`][crate::models::state::State]:
+/// that it's properly signed by `creator`, and that its creator was a member of the space at that
+/// point in the DAG.
+///
+/// `space` is the space as it stood just before this transaction (ie, built from the
+/// transaction's ancestors), not as it is now: a removed member's old operations are still
+/// valid, but anything they make afterwards isn't. `creator` is the identity that created the
+/// transaction. This doesn't decrypt the operation, so it doesn't check the creator's
+/// permissions; use [`Space::authorize`] for that once the operation is opened.
+pub fn verify_operation(transaction: &Transaction, space: &Space, creator: &Identity) -> Verdict {
+    let (creator_id, encrypted) = match operation_from_transaction(transaction) {
+        Ok(x) => x,
+        Err(_) => return Verdict::NotAnOperation,
+    };
+    if creator_id != creator.id() {
+        return Verdict::BadSignature("transaction was not created by the given identity".into());
+    }
+    if let Err(e) = transaction.verify(Some(creator)) {
+        return Verdict::BadSignature(format!("{}", e));
+    }
+    if encrypted.context().as_ref() != Some(space.id()) {
+        return Verdict::WrongSpace;
+    }
+    if space.member_by_identity(creator_id).is_some() {
+        Verdict::Valid
+    } else if space.viewer_by_identity(creator_id).is_some() {
+        Verdict::Viewer
+    } else {
+        Verdict::NotAMember
+    }
+}
+
+/// Given a space's transactions, pick out the ones that should be shared with a scoped member.
+/// Transactions we can't read are left out (and returned as errors), since we can't tell whether
+/// they're in scope.
+pub fn filter_transactions_for_scope<'a>(transactions: &[&'a Transaction], space_key: &SecretKey, scope: &MemberScope) -> (Vec<&'a Transaction>, Vec<Error>) {
+    let mut errors = Vec::new();
+    let mut shared = Vec::new();
+    for trans in transactions {
+        let context = match operation_from_transaction(trans).and_then(|(_, op)| op.get_full_context(space_key)) {
+            Ok(x) => x,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+        if scope.allows(&context) {
+            shared.push(*trans);
+        }
+    }
+    (shared, errors)
+}
+
+/// Map each transaction to the transactions that came directly before it. Used for walking a
+/// space's history (see [`Member::received`]).
+pub fn transaction_parents(transactions: &[Transaction]) -> HashMap<TransactionID, Vec<TransactionID>> {
+    transactions.iter()
+        .map(|t| (t.id().clone(), t.entry().previous_transactions().clone()))
+        .collect()
+}
+
+/// Put a batch of transactions in the order every device applies them in: parents before
+/// children, otherwise oldest first, with ties broken by ID. Two devices with the same batch
+/// get the same order no matter what order the transactions arrived in. Parents that aren't in
+/// the batch are taken to have been applied already.
+pub fn order_transactions(transactions: Vec<Transaction>) -> Vec<Transaction> {
+    let ids = transactions.iter().map(|t| t.id().clone()).collect::<HashSet<_>>();
+    let mut waiting = Vec::with_capacity(transactions.len());
+    let mut children: HashMap<TransactionID, Vec<usize>> = HashMap::new();
+    for (idx, trans) in transactions.iter().enumerate() {
+        let parents = trans.entry().previous_transactions().iter()
+            .filter(|p| ids.contains(p))
+            .collect::<HashSet<_>>();
+        for parent in &parents {
+            children.entry((*parent).clone()).or_default().push(idx);
+        }
+        waiting.push(parents.len());
+    }
+    let key = |idx: usize| Reverse((**transactions[idx].entry().created(), transactions[idx].id().to_string(), idx));
+    let mut next = (0..transactions.len())
+        .filter(|idx| waiting[*idx] == 0)
+        .map(key)
+        .collect::<BinaryHeap<_>>();
+    let mut order = Vec::with_capacity(transactions.len());
+    while let Some(Reverse((_, _, idx))) = next.pop() {
+        order.push(idx);
+        for child in children.get(transactions[idx].id()).into_iter().flatten() {
+            waiting[*child] -= 1;
+            if waiting[*child] == 0 {
+                next.push(key(*child));
+            }
+        }
+    }
+    let mut slots = transactions.into_iter().map(Some).collect::<Vec<_>>();
+    let mut ordered = order.into_iter()
+        .filter_map(|idx| slots[idx].take())
+        .collect::<Vec<_>>();
+    // a batch with a cycle in it isn't a DAG, but don't lose anything over it
+    ordered.extend(slots.into_iter().flatten());
+    ordered
+}
+
+/// Put a batch of transactions in [the order they're applied in][order_transactions] and open
+/// their operations, in parallel, a space at a time (see [`crypto::decrypt_batch`]). `keys` are
+/// the keys to try for each space, or for `None`, the user's own operations; derive them once and
+/// pass them in for every batch.
+pub fn open_ordered(transactions: Vec<Transaction>, keys: &HashMap<Option<SpaceID>, Vec<OperationKeys>>) -> Vec<(Transaction, Result<Operation>)> {
+    let ordered = order_transactions(transactions);
+    let mut opened = ordered.iter().map(|_| None).collect::<Vec<Option<Result<Operation>>>>();
+    let mut groups: HashMap<Option<SpaceID>, (Vec<usize>, Vec<OperationEncrypted>)> = HashMap::new();
+    for (idx, trans) in ordered.iter().enumerate() {
+        match operation_from_transaction(trans) {
+            Ok((_, encrypted)) => {
+                let group = groups.entry(encrypted.context().clone()).or_default();
+                group.0.push(idx);
+                group.1.push(encrypted);
+            }
+            Err(e) => opened[idx] = Some(Err(e)),
+        }
+    }
+    for (space_id, (idxs, encrypted)) in groups {
+        match (keys.get(&space_id), space_id) {
+            (Some(space_keys), _) => {
+                for (idx, result) in idxs.into_iter().zip(crypto::decrypt_batch(space_keys, &encrypted)) {
+                    opened[idx] = Some(result);
+                }
+            }
+            (None, Some(space_id)) => {
+                for idx in idxs {
+                    opened[idx] = Some(Err(Error::TransactionMissingSpaceKey(ordered[idx].id().clone(), space_id.clone())));
+                }
+            }
+            (None, None) => {
+                for idx in idxs {
+                    opened[idx] = Some(Err(Error::ProviderMissingKey("user".into())));
+                }
+            }
+        }
+    }
+    ordered.into_iter().zip(opened.into_iter().flatten()).collect()
 }
 
 /// Takes a flat list of stamp transactions, segments them by space, then converts them to DAGs.
@@ -541,105 +1455,64 @@ pub fn group_operations_by_space<'a>(transactions: &'a Vec<Transaction>) -> (Has
     (result, errors)
 }
 
-/*
-/// Takes a flat list of stamp transactions, segments them by space, then orders them, then
-/// segments by object ID.
-pub fn order_operations_(space_keys: &HashMap<SpaceID, SecretKey>, transactions: &Vec<Transaction>) -> (HashMap<Option<SpaceID>, Vec<Vec<OperationEncrypted>>>, Vec<Error>) {
-    #[derive(Getters)]
-    #[getset(get = "pub(crate)")]
-    struct OperationTransaction<'t> {
-        id: &'t TransactionID,
-        created: &'t Timestamp,
-        previous_transactions: &'t Vec<TransactionID>,
-        context: OperationContext,
-        operation: OperationEncrypted,
-    }
-
-    impl<'t> OperationTransaction<'t> {
-        fn try_from_parts(space_key: &SecretKey, transaction: &'t Transaction, operation: OperationEncrypted) -> Result<Self> {
-            let context = operation.get_full_context(space_key)?;
-            Ok(Self {
-                id: transaction.id(),
-                created: transaction.entry().created(),
-                previous_transactions: transaction.entry().previous_transactions(),
-                context,
-                operation,
-            })
-        }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn title_op(space_id: &SpaceID, note_id: &NoteID) -> Operation {
+        Operation::note_set_title(space_id.clone(), note_id.clone(), Some("a title".into()))
     }
 
-    let mut errors = Vec::new();
-    let mut personal_transactions: Vec<OperationTransaction> = Vec::new();
-    let mut space_group: HashMap<SpaceID, Vec<OperationTransaction>> = HashMap::new();
+    fn same_op(a: &Operation, b: &Operation) -> bool {
+        rasn::der::encode(a.context()).unwrap() == rasn::der::encode(b.context()).unwrap() &&
+            rasn::der::encode(a.action()).unwrap() == rasn::der::encode(b.action()).unwrap()
+    }
 
-    for trans in transactions {
-        match trans.entry().body() {
-            TransactionBody::ExtV1 { ref creator, ref ty, ref context, ref payload, .. } => {
-                if ty.as_ref().map(|x| x.deref().as_slice()) != Some(b"turtl/op/v1") {
-                    errors.push(Error::TransactionWrongType(trans.id().clone()));
-                    continue;
-                }
-                let space_id_ser = context.as_ref()
-                    .and_then(|map| map.get(&b"space".to_vec().into()));
-                let space_id = match space_id_ser {
-                    Some(ser) => {
-                        match rasn::der::decode::<SpaceID>(ser.as_slice()) {
-                            Ok(s) => Some(s),
-                            Err(e) => {
-                                errors.push(Error::TransactionDeserializationError(trans.id().clone(), e));
-                                continue;
-                            }
-                        }
-                    },
-                    None => None,
-                };
-                let mut operation_enc = match rasn::der::decode::<OperationEncrypted>(payload.as_slice()) {
-                    Ok(x) => x,
-                    Err(e) => {
-                        errors.push(Error::TransactionDeserializationError(trans.id().clone(), e));
-                        continue;
-                    }
-                };
-                operation_enc.context = space_id.clone();
-                let optrans = if let Some(space_id) = operation_enc.context.as_ref() {
-                    let space_key = match space_keys.get(space_id) {
-                        Some(k) => k,
-                        None => {
-                            errors.push(Error::TransactionMissingSpaceKey(trans.id().clone(), space_id.clone()));
-                            continue;
-                        }
-                    };
-                    match OperationTransaction::try_from_parts(space_key, trans, operation_enc) {
-                        Ok(x) => x,
-                        Err(e) => {
-                            errors.push(Error::TransactionStampError(trans.id().clone(), Box::new(e)));
-                            continue;
-                        },
-                    }
-                } else {
-                    OperationTransaction {
-                        id: trans.id(),
-                        created: trans.entry().created(),
-                        previous_transactions: trans.entry().previous_transactions(),
-                        context: OperationContext::default(),
-                        operation: operation_enc,
-                    }
-                };
-                if let Some(space_id) = space_id {
-                    space_group.entry(space_id).or_insert(Vec::new()).push(optrans);
-                } else {
-                    personal_transactions.push(optrans);
-                }
-            }
-            _ => errors.push(Error::TransactionWrongVariant(trans.id().clone())),
-        }
+    #[test]
+    fn encrypt_round_trips_with_the_current_suite() {
+        let key = SecretKey::new_xchacha20poly1305().unwrap();
+        let space_id = SpaceID::new();
+        let note_id = NoteID::new();
+        let op = title_op(&space_id, &note_id);
+        let encrypted = title_op(&space_id, &note_id).encrypt(&key).unwrap();
+        assert_eq!(encrypted.suite(), &CipherSuite::CURRENT);
+
+        // the suite survives serialization
+        let der = rasn::der::encode(&encrypted).unwrap();
+        let decoded: OperationEncrypted = rasn::der::decode(&der[..]).unwrap();
+        assert_eq!(decoded.suite(), &CipherSuite::CURRENT);
+        assert!(same_op(&Operation::decrypt(&key, &decoded).unwrap(), &op));
+
+        let other_key = SecretKey::new_xchacha20poly1305().unwrap();
+        assert!(Operation::decrypt(&other_key, &decoded).is_err());
     }
 
-    let mut result = HashMap::new();
+    #[test]
+    fn bound_ciphertexts_dont_open_in_another_space() {
+        let key = SecretKey::new_xchacha20poly1305().unwrap();
+        let mut encrypted = title_op(&SpaceID::new(), &NoteID::new()).encrypt(&key).unwrap();
+        encrypted.context = Some(SpaceID::new());
+        assert!(Operation::decrypt(&key, &encrypted).is_err());
+    }
 
-    let personal_ordered = order_operations_inner(&personal_transactions);
-    result.insert(None, personal_ordered);
-    (result, errors)
+    #[test]
+    fn legacy_operations_still_open() {
+        let key = SecretKey::new_xchacha20poly1305().unwrap();
+        let space_id = SpaceID::new();
+        let op = title_op(&space_id, &NoteID::new());
+        let context = OperationContext::new(None, None, None, op.context().note().clone(), None);
+        let legacy = OperationEncrypted {
+            context: Some(space_id),
+            ciphertext_context: seal::seal(&key, &rasn::der::encode(&context).unwrap()[..]).unwrap(),
+            ciphertext_action: seal::seal(&key, &rasn::der::encode(op.action()).unwrap()[..]).unwrap(),
+            envelope: Vec::new(),
+            suite: CipherSuite::Legacy,
+            blind_index: Vec::new(),
+        };
+        let der = rasn::der::encode(&legacy).unwrap();
+        let decoded: OperationEncrypted = rasn::der::decode(&der[..]).unwrap();
+        assert!(decoded.suite().is_deprecated());
+        assert!(same_op(&Operation::decrypt(&key, &decoded).unwrap(), &op));
+    }
 }
-*/
-
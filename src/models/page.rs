@@ -6,12 +6,17 @@
 
 use crate::models::{
     object_id,
-    note::{NoteID, Tag},
-    space::SpaceID,
+    file::{File, FileID},
+    note::{Note, NoteDates, NoteID, SectionSpec, Tag},
+    space::{Space, SpaceID},
 };
-use getset::Getters;
+use chrono::NaiveDate;
+use getset::{Getters, MutGetters};
 use rasn::{AsnType, Encode, Decode};
 use serde::{Deserialize, Serialize};
+use stamp_core::util::Timestamp;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 
 object_id! {
     /// A unique ID for a page
@@ -19,7 +24,7 @@ object_id! {
 }
 
 /// Describes a slice of notes given a filter criteria
-#[derive(AsnType, Encode, Decode, Deserialize, Serialize)]
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize)]
 #[rasn(choice)]
 pub enum SliceFilter {
     /// An intersection of filters
@@ -40,10 +45,128 @@ pub enum SliceFilter {
     /// Filter notes that link to a specific note
     #[rasn(tag(explicit(5)))]
     LinksTo(NoteID),
+    /// Filter notes that do NOT match the given filter
+    #[rasn(tag(explicit(6)))]
+    Not(Box<SliceFilter>),
+    /// Filter notes that have at least one of the given tags
+    #[rasn(tag(explicit(8)))]
+    TagAnyOf(Vec<Tag>),
+    /// Filter notes that have every one of the given tags
+    #[rasn(tag(explicit(9)))]
+    TagAllOf(Vec<Tag>),
+    /// Filter notes with an attached file whose mime type starts with the given string (ie
+    /// `image/` or `application/pdf`)
+    #[rasn(tag(explicit(10)))]
+    HasFileOfType(String),
+    /// Filter notes by whether or not they're in the trash.
+    ///
+    /// Deleted notes are excluded from slices unless the filter explicitly uses this.
+    #[rasn(tag(explicit(7)))]
+    Deleted(bool),
+}
+
+impl SliceFilter {
+    /// Determine if a note matches this filter.
+    ///
+    /// File type filters need to look up file metadata, so they never match a note unless given
+    /// `files`.
+    pub fn matches(&self, note: &Note, files: Option<&HashMap<FileID, File>>) -> bool {
+        let sections = || note.body().sections().values().map(|s| s.spec());
+        match self {
+            Self::And(filters) => filters.iter().all(|f| f.matches(note, files)),
+            Self::Or(filters) => filters.iter().any(|f| f.matches(note, files)),
+            Self::Tag(tag) => note.tags().contains(tag),
+            Self::TagAnyOf(tags) => tags.iter().any(|t| note.tags().contains(t)),
+            Self::TagAllOf(tags) => tags.iter().all(|t| note.tags().contains(t)),
+            Self::Search(search) => {
+                let search = search.to_lowercase();
+                let in_title = note.title().as_ref()
+                    .map(|t| t.to_lowercase().contains(&search))
+                    .unwrap_or(false);
+                in_title || sections()
+                    .filter_map(|spec| spec.text())
+                    .any(|text| text.to_lowercase().contains(&search))
+            }
+            Self::HasFile(has_file) => {
+                sections().any(|spec| matches!(spec, SectionSpec::File { .. })) == *has_file
+            }
+            Self::HasFileOfType(ty) => {
+                let files = match files {
+                    Some(f) => f,
+                    None => return false,
+                };
+                note.file_refs().into_iter()
+                    .filter_map(|id| files.get(id))
+                    .any(|file| file.ty().as_ref().map(|t| t.starts_with(ty.as_str())).unwrap_or(false))
+            }
+            Self::LinksTo(note_id) => {
+                sections().any(|spec| matches!(spec, SectionSpec::NoteLink(id) if id == note_id))
+            }
+            Self::Not(filter) => !filter.matches(note, files),
+            Self::Deleted(deleted) => note.deleted() == deleted,
+        }
+    }
+
+    /// Use a tag index to narrow down which notes could possibly match this filter. Returns
+    /// `None` if the filter can't be narrowed (ie, it needs to look at every note).
+    ///
+    /// This doesn't replace [`SliceFilter::matches`], it just keeps us from running it against
+    /// notes that can't match.
+    pub fn candidates(&self, notes_by_tag: &HashMap<Tag, HashSet<NoteID>>) -> Option<HashSet<NoteID>> {
+        let tagged = |tag: &Tag| notes_by_tag.get(tag).cloned().unwrap_or_default();
+        match self {
+            Self::Tag(tag) => Some(tagged(tag)),
+            Self::TagAnyOf(tags) => {
+                Some(tags.iter().flat_map(|t| tagged(t)).collect())
+            }
+            Self::TagAllOf(tags) => {
+                let mut tags = tags.iter();
+                let first = tagged(tags.next()?);
+                Some(tags.fold(first, |acc, t| {
+                    let set = tagged(t);
+                    acc.into_iter().filter(|id| set.contains(id)).collect()
+                }))
+            }
+            Self::And(filters) => {
+                filters.iter()
+                    .filter_map(|f| f.candidates(notes_by_tag))
+                    .reduce(|acc, set| acc.into_iter().filter(|id| set.contains(id)).collect())
+            }
+            Self::Or(filters) => {
+                filters.iter()
+                    .map(|f| f.candidates(notes_by_tag))
+                    .collect::<Option<Vec<_>>>()
+                    .map(|sets| sets.into_iter().flatten().collect())
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether this filter (or any of its children) looks at file types, meaning its results can
+    /// change when a file does.
+    pub fn mentions_file_type(&self) -> bool {
+        match self {
+            Self::And(filters) | Self::Or(filters) => filters.iter().any(|f| f.mentions_file_type()),
+            Self::Not(filter) => filter.mentions_file_type(),
+            Self::HasFileOfType(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether this filter (or any of its children) explicitly asks about deleted notes. If not,
+    /// deleted notes are left out of the results.
+    pub fn mentions_deleted(&self) -> bool {
+        match self {
+            Self::And(filters) | Self::Or(filters) => filters.iter().any(|f| f.mentions_deleted()),
+            Self::Not(filter) => filter.mentions_deleted(),
+            Self::Deleted(_) => true,
+            _ => false,
+        }
+    }
 }
 
 /// Defines sort order ascending or descending
-#[derive(AsnType, Encode, Decode, Deserialize, Serialize)]
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize)]
 #[rasn(choice)]
 pub enum AscDesc {
     #[rasn(tag(explicit(0)))]
@@ -53,7 +176,7 @@ pub enum AscDesc {
 }
 
 /// Allows sorting a set of notes.
-#[derive(AsnType, Encode, Decode, Deserialize, Serialize)]
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize)]
 #[rasn(choice)]
 pub enum Sort {
     #[rasn(tag(explicit(0)))]
@@ -67,7 +190,7 @@ pub enum Sort {
 }
 
 /// Specifies a sort order
-#[derive(AsnType, Encode, Decode, Deserialize, Serialize, Getters)]
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize, Getters)]
 #[getset(get = "pub")]
 pub struct SortEntry {
     #[rasn(tag(explicit(0)))]
@@ -76,17 +199,57 @@ pub struct SortEntry {
     asc: AscDesc,
 }
 
+impl SortEntry {
+    /// Compare two notes using this sort entry.
+    ///
+    /// Created/modified sorts need `note_dates`: without them (or for notes with no dates) those
+    /// sorts leave the notes in the order they came in.
+    pub fn compare(&self, a: &Note, b: &Note, note_dates: Option<&HashMap<NoteID, NoteDates>>) -> Ordering {
+        let dates = |note: &Note| note_dates.and_then(|d| d.get(note.id()));
+        fn has_file(note: &Note) -> bool {
+            note.body().sections().values().any(|s| matches!(s.spec(), SectionSpec::File { .. }))
+        }
+        let ord = match self.sort {
+            Sort::Title => {
+                let a = a.title().as_ref().map(|t| t.to_lowercase());
+                let b = b.title().as_ref().map(|t| t.to_lowercase());
+                a.cmp(&b)
+            }
+            Sort::HasFile => has_file(a).cmp(&has_file(b)),
+            Sort::Created => {
+                match (dates(a), dates(b)) {
+                    (Some(a), Some(b)) => a.created().cmp(b.created()),
+                    _ => Ordering::Equal,
+                }
+            }
+            Sort::Modified => {
+                match (dates(a), dates(b)) {
+                    (Some(a), Some(b)) => a.modified().cmp(b.modified()),
+                    _ => Ordering::Equal,
+                }
+            }
+        };
+        match self.asc {
+            AscDesc::Ascending => ord,
+            AscDesc::Descending => ord.reverse(),
+        }
+    }
+}
+
 /// A page slice is a sorted view of the notes in a space. It can be a manually created list,
 /// or an automatically filtered list based on text, tag, etc.
-#[derive(AsnType, Encode, Decode, Deserialize, Serialize)]
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize)]
 #[rasn(choice)]
 pub enum Slice {
-    /// An automated view of notes in a space by some filtering and sorting criteria.
+    /// An automated view of notes in a space by some filtering criteria.
     #[rasn(tag(explicit(0)))]
     Filtered {
         #[rasn(tag(explicit(0)))]
         filter: SliceFilter,
-        #[rasn(tag(explicit(1)))]
+        /// Where filtered slices kept their sort order before it moved onto the page (see
+        /// [`Page::sort`]). Only read so older pages keep their order, new slices leave it empty.
+        #[rasn(tag(explicit(1)), default)]
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
         sort: Vec<SortEntry>,
     },
     /// A manually-created list of notes with a manually-set sort order.
@@ -94,9 +257,188 @@ pub enum Slice {
     Manual(Vec<NoteID>),
 }
 
+/// Everything a slice needs in order to be evaluated: the space it's running in, the notes it can
+/// pick from, and (optionally) a tag index to speed things up.
+pub struct SliceContext<'a> {
+    space_id: &'a SpaceID,
+    notes: &'a HashMap<NoteID, Note>,
+    notes_by_tag: Option<&'a HashMap<Tag, HashSet<NoteID>>>,
+    note_dates: Option<&'a HashMap<NoteID, NoteDates>>,
+    files: Option<&'a HashMap<FileID, File>>,
+    /// Whether the space is archived (see [`SliceContext::with_space`])
+    archived: bool,
+    /// Whether to evaluate slices in an archived space anyway
+    include_archived: bool,
+}
+
+impl<'a> SliceContext<'a> {
+    /// Create a new slice context. Only notes in `space_id` will be considered.
+    pub fn new(space_id: &'a SpaceID, notes: &'a HashMap<NoteID, Note>) -> Self {
+        Self { space_id, notes, notes_by_tag: None, note_dates: None, files: None, archived: false, include_archived: false }
+    }
+
+    /// Let the context know about the space it's running in. Slices in an archived space come
+    /// back empty, unless [`SliceContext::including_archived`] is used.
+    pub fn with_space(mut self, space: &Space) -> Self {
+        self.archived = *space.archived();
+        self
+    }
+
+    /// Evaluate slices even if the space is archived.
+    pub fn including_archived(mut self) -> Self {
+        self.include_archived = true;
+        self
+    }
+
+    /// Use a tag index to skip over notes that can't possibly match.
+    pub fn with_tag_index(mut self, notes_by_tag: &'a HashMap<Tag, HashSet<NoteID>>) -> Self {
+        self.notes_by_tag = Some(notes_by_tag);
+        self
+    }
+
+    /// Give the slice access to file metadata, used for filtering by file type.
+    pub fn with_files(mut self, files: &'a HashMap<FileID, File>) -> Self {
+        self.files = Some(files);
+        self
+    }
+
+    /// Give the slice access to note created/modified dates, used for sorting.
+    pub fn with_note_dates(mut self, note_dates: &'a HashMap<NoteID, NoteDates>) -> Self {
+        self.note_dates = Some(note_dates);
+        self
+    }
+}
+
+/// A window into the results of a slice, so large pages can be rendered a bit at a time.
+#[derive(Clone, Debug, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct DisplayWindow {
+    /// How many results to skip
+    offset: usize,
+    /// The max number of results to return
+    limit: usize,
+}
+
+impl DisplayWindow {
+    /// Create a new window
+    pub fn new(offset: usize, limit: usize) -> Self {
+        Self { offset, limit }
+    }
+
+    /// The window directly after this one
+    pub fn next(&self) -> Self {
+        Self::new(self.offset + self.limit, self.limit)
+    }
+}
+
+/// One window's worth of results from [`Slice::page`].
+#[derive(Getters)]
+#[getset(get = "pub")]
+pub struct SlicePage<'a> {
+    /// The notes in this window, in order
+    notes: Vec<&'a Note>,
+    /// How many notes matched the slice in total
+    total: usize,
+    /// The window to ask for next, if there are more results
+    next: Option<DisplayWindow>,
+}
+
+impl Slice {
+    /// Run this slice against a set of notes, returning the matching notes sorted by `sort`. If
+    /// `sort` is empty, manual slices keep their manual order (and filtered slices come back in
+    /// no particular order).
+    ///
+    /// Deleted notes are skipped unless the filter specifically asks for them via
+    /// [`SliceFilter::Deleted`]. Manual slices never include deleted notes.
+    pub fn evaluate<'a>(&self, ctx: &SliceContext<'a>, sort: &[SortEntry]) -> Vec<&'a Note> {
+        let mut matched = self.matching(ctx);
+        if !sort.is_empty() {
+            matched.sort_by(|a, b| compare_notes(sort, a, b, ctx.note_dates));
+        }
+        matched
+    }
+
+    /// Like [`Slice::evaluate`], but only returns the notes within the given window.
+    ///
+    /// We still have to find every matching note in order to know where the window starts, but
+    /// we only fully sort as many notes as we need to. A window with a limit of zero returns no
+    /// notes and no next window (otherwise paging through it would never end).
+    pub fn page<'a>(&self, ctx: &SliceContext<'a>, sort: &[SortEntry], window: &DisplayWindow) -> SlicePage<'a> {
+        let mut matched = self.matching(ctx);
+        let total = matched.len();
+        let end = window.offset.saturating_add(window.limit).min(total);
+        if !sort.is_empty() {
+            if end < total {
+                matched.select_nth_unstable_by(end, |a, b| compare_notes(sort, a, b, ctx.note_dates));
+                matched.truncate(end);
+            }
+            matched.sort_by(|a, b| compare_notes(sort, a, b, ctx.note_dates));
+        }
+        let notes = matched.into_iter()
+            .skip(window.offset)
+            .take(window.limit)
+            .collect();
+        let next = if window.limit > 0 && end < total { Some(window.next()) } else { None };
+        SlicePage { notes, total, next }
+    }
+
+    /// Determine if a single note belongs in this slice. This follows the same rules as
+    /// [`Slice::evaluate`], but for one note at a time.
+    pub fn includes(&self, space_id: &SpaceID, note: &Note, files: Option<&HashMap<FileID, File>>) -> bool {
+        if note.space_id() != space_id {
+            return false;
+        }
+        match self {
+            Self::Filtered { filter, .. } => {
+                (filter.mentions_deleted() || !note.deleted()) && filter.matches(note, files)
+            }
+            Self::Manual(note_ids) => !note.deleted() && note_ids.contains(note.id()),
+        }
+    }
+
+    /// Find the notes matching this slice. Filtered results are *not* sorted, manual results are
+    /// in their manual order.
+    pub(crate) fn matching<'a>(&self, ctx: &SliceContext<'a>) -> Vec<&'a Note> {
+        if ctx.archived && !ctx.include_archived {
+            return Vec::new();
+        }
+        let notes = ctx.notes;
+        let in_space = |note: &&'a Note| note.space_id() == ctx.space_id;
+        match self {
+            Self::Filtered { filter, .. } => {
+                let include_deleted = filter.mentions_deleted();
+                let candidates: Box<dyn Iterator<Item = &'a Note>> = match ctx.notes_by_tag.and_then(|idx| filter.candidates(idx)) {
+                    Some(ids) => Box::new(ids.into_iter().filter_map(move |id| notes.get(&id))),
+                    None => Box::new(notes.values()),
+                };
+                candidates
+                    .filter(in_space)
+                    .filter(|note| include_deleted || !note.deleted())
+                    .filter(|note| filter.matches(note, ctx.files))
+                    .collect()
+            }
+            Self::Manual(note_ids) => {
+                note_ids.iter()
+                    .filter_map(|id| notes.get(id))
+                    .filter(in_space)
+                    .filter(|note| !note.deleted())
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Compare two notes using a list of sort entries, falling through to the next entry on ties.
+fn compare_notes(sort: &[SortEntry], a: &Note, b: &Note, note_dates: Option<&HashMap<NoteID, NoteDates>>) -> Ordering {
+    sort.iter()
+        .map(|entry| entry.compare(a, b, note_dates))
+        .find(|ord| ord != &Ordering::Equal)
+        .unwrap_or(Ordering::Equal)
+}
+
 /// A view determines how notes will be displayed within a page: a list, a grid, a masonry layout,
 /// etc.
-#[derive(AsnType, Encode, Decode, Deserialize, Serialize)]
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize)]
 #[rasn(choice)]
 pub enum Display {
     #[rasn(tag(explicit(0)))]
@@ -109,6 +451,259 @@ pub enum Display {
     Masonry,
     #[rasn(tag(explicit(4)))]
     Graph,
+    /// A kanban-style board, with notes sorted into columns
+    #[rasn(tag(explicit(5)))]
+    Board {
+        #[rasn(tag(explicit(0)))]
+        group_by: BoardGrouping,
+    },
+    /// A calendar, with notes placed on days (journals, planners, etc)
+    #[rasn(tag(explicit(6)))]
+    Calendar {
+        #[rasn(tag(explicit(0)))]
+        date_source: DateSource,
+    },
+    /// A spreadsheet-like table, with one row per note
+    #[rasn(tag(explicit(7)))]
+    Table {
+        #[rasn(tag(explicit(0)))]
+        columns: Vec<ColumnSpec>,
+    },
+}
+
+/// A note field that a [table display][Display::Table] column can show.
+#[derive(Clone, PartialEq, AsnType, Encode, Decode, Deserialize, Serialize)]
+#[rasn(choice)]
+pub enum NoteField {
+    #[rasn(tag(explicit(0)))]
+    Title,
+    #[rasn(tag(explicit(1)))]
+    Tags,
+    #[rasn(tag(explicit(2)))]
+    Created,
+    #[rasn(tag(explicit(3)))]
+    Modified,
+    #[rasn(tag(explicit(4)))]
+    HasFile,
+}
+
+/// A single column in a [table display][Display::Table]
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct ColumnSpec {
+    /// The note field this column shows
+    #[rasn(tag(explicit(0)))]
+    field: NoteField,
+    /// An optional width hint, in characters
+    #[rasn(tag(explicit(1)))]
+    width: Option<u16>,
+}
+
+impl ColumnSpec {
+    /// Create a new column spec
+    pub fn new(field: NoteField, width: Option<u16>) -> Self {
+        Self { field, width }
+    }
+
+    /// Pull this column's value out of a note.
+    pub fn value<'a>(&self, note: &'a Note, note_dates: &'a HashMap<NoteID, NoteDates>) -> CellValue<'a> {
+        match self.field {
+            NoteField::Title => CellValue::Text(note.title().as_deref()),
+            NoteField::Tags => CellValue::Tags(note.tags()),
+            NoteField::Created => CellValue::Date(note_dates.get(note.id()).map(|d| d.created())),
+            NoteField::Modified => CellValue::Date(note_dates.get(note.id()).map(|d| d.modified())),
+            NoteField::HasFile => CellValue::Bool(note.body().sections().values().any(|s| matches!(s.spec(), SectionSpec::File { .. }))),
+        }
+    }
+}
+
+/// A single cell's value in a table display
+#[derive(Serialize)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum CellValue<'a> {
+    Text(Option<&'a str>),
+    Tags(&'a Vec<Tag>),
+    Date(Option<&'a Timestamp>),
+    Bool(bool),
+}
+
+/// A row in a table display: a note, and the value for each of the table's columns (in column
+/// order).
+#[derive(Getters, Serialize)]
+#[getset(get = "pub")]
+pub struct TableRow<'a> {
+    note: &'a Note,
+    cells: Vec<CellValue<'a>>,
+}
+
+/// Turn a list of notes (generally the result of evaluating a slice) into table rows.
+pub fn table_rows<'a>(columns: &[ColumnSpec], notes: Vec<&'a Note>, note_dates: &'a HashMap<NoteID, NoteDates>) -> Vec<TableRow<'a>> {
+    notes.into_iter()
+        .map(|note| TableRow {
+            note,
+            cells: columns.iter().map(|c| c.value(note, note_dates)).collect(),
+        })
+        .collect()
+}
+
+/// Which date a [calendar][Display::Calendar] uses to place a note.
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize)]
+#[rasn(choice)]
+pub enum DateSource {
+    /// When the note was created
+    #[rasn(tag(explicit(0)))]
+    Created,
+    /// When the note was last modified
+    #[rasn(tag(explicit(1)))]
+    Modified,
+    /// The earliest due date of any unchecked checkbox in the note
+    #[rasn(tag(explicit(2)))]
+    Due,
+}
+
+/// A single day in a calendar, and the notes that land on it.
+#[derive(Getters, Serialize)]
+#[getset(get = "pub")]
+pub struct CalendarDay<'a> {
+    /// The (UTC) day
+    date: NaiveDate,
+    /// The notes on this day, in slice order
+    notes: Vec<&'a Note>,
+}
+
+/// Notes bucketed by day, for calendar displays.
+#[derive(Getters, Serialize)]
+#[getset(get = "pub")]
+pub struct Calendar<'a> {
+    /// The days that have notes, in chronological order
+    days: Vec<CalendarDay<'a>>,
+    /// Notes that have no date for the given [`DateSource`]
+    undated: Vec<&'a Note>,
+}
+
+impl DateSource {
+    /// Grab the date for a note, if it has one.
+    pub fn date_for<'a>(&self, note: &'a Note, note_dates: &'a HashMap<NoteID, NoteDates>) -> Option<&'a Timestamp> {
+        match self {
+            Self::Created => note_dates.get(note.id()).map(|d| d.created()),
+            Self::Modified => note_dates.get(note.id()).map(|d| d.modified()),
+            Self::Due => {
+                note.body().sections().values()
+                    .filter_map(|s| match s.spec() {
+                        SectionSpec::Checkbox { checked: false, due, .. } => due.as_ref(),
+                        _ => None,
+                    })
+                    .min()
+            }
+        }
+    }
+
+    /// Sort a list of notes (generally the result of evaluating a slice) into days.
+    pub fn bucket<'a>(&self, notes: Vec<&'a Note>, note_dates: &'a HashMap<NoteID, NoteDates>) -> Calendar<'a> {
+        let mut days: Vec<CalendarDay<'a>> = Vec::new();
+        let mut undated = Vec::new();
+        for note in notes {
+            match self.date_for(note, note_dates) {
+                Some(ts) => {
+                    let date = ts.date_naive();
+                    match days.iter_mut().find(|d| d.date == date) {
+                        Some(day) => day.notes.push(note),
+                        None => days.push(CalendarDay { date, notes: vec![note] }),
+                    }
+                }
+                None => undated.push(note),
+            }
+        }
+        days.sort_by_key(|d| d.date);
+        Calendar { days, undated }
+    }
+}
+
+/// How a [board][Display::Board] decides which column a note lives in.
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize)]
+#[rasn(choice)]
+pub enum BoardGrouping {
+    /// One column per tag, in the order given. A note goes in the column for the first of these
+    /// tags it has, and notes with none of them go into [`BoardColumnKey::Other`].
+    #[rasn(tag(explicit(0)))]
+    Tags(Vec<Tag>),
+    /// Group by the state of the note's checkboxes: all checked is done, anything less is todo.
+    /// Notes without checkboxes go into [`BoardColumnKey::Other`].
+    #[rasn(tag(explicit(1)))]
+    Checkbox,
+}
+
+/// Identifies a single column in a board
+#[derive(Debug, PartialEq, Serialize)]
+pub enum BoardColumnKey {
+    /// The column for a specific tag
+    Tag(Tag),
+    /// Notes with unchecked checkboxes
+    Todo,
+    /// Notes where every checkbox is checked
+    Done,
+    /// Notes that don't fit into any other column
+    Other,
+}
+
+/// A column of notes in a board
+#[derive(Getters, Serialize)]
+#[getset(get = "pub")]
+pub struct BoardColumn<'a> {
+    /// Which column this is
+    key: BoardColumnKey,
+    /// The notes in this column, in slice order
+    notes: Vec<&'a Note>,
+}
+
+impl BoardGrouping {
+    /// Sort a list of notes (generally the result of evaluating a slice) into board columns.
+    /// Notes keep their relative order within each column. The [`BoardColumnKey::Other`] column is
+    /// always last.
+    pub fn bucket<'a>(&self, notes: Vec<&'a Note>) -> Vec<BoardColumn<'a>> {
+        let mut columns = match self {
+            Self::Tags(tags) => tags.iter().map(|t| BoardColumnKey::Tag(t.clone())).collect::<Vec<_>>(),
+            Self::Checkbox => vec![BoardColumnKey::Todo, BoardColumnKey::Done],
+        }
+            .into_iter()
+            .chain(std::iter::once(BoardColumnKey::Other))
+            .map(|key| BoardColumn { key, notes: Vec::new() })
+            .collect::<Vec<_>>();
+        for note in notes {
+            let key = self.column_for(note);
+            if let Some(column) = columns.iter_mut().find(|c| c.key == key) {
+                column.notes.push(note);
+            }
+        }
+        columns
+    }
+
+    /// Figure out which column a note belongs in.
+    fn column_for(&self, note: &Note) -> BoardColumnKey {
+        match self {
+            Self::Tags(tags) => {
+                tags.iter()
+                    .find(|t| note.tags().contains(t))
+                    .map(|t| BoardColumnKey::Tag(t.clone()))
+                    .unwrap_or(BoardColumnKey::Other)
+            }
+            Self::Checkbox => {
+                let checked = note.body().sections().values()
+                    .filter_map(|s| match s.spec() {
+                        SectionSpec::Checkbox { checked, .. } => Some(*checked),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                if checked.is_empty() {
+                    BoardColumnKey::Other
+                } else if checked.iter().all(|c| *c) {
+                    BoardColumnKey::Done
+                } else {
+                    BoardColumnKey::Todo
+                }
+            }
+        }
+    }
 }
 
 /// A space is a siloed container of notes and pages. It offers a way to keep these sets of data
@@ -117,8 +712,8 @@ pub enum Display {
 /// For instance, you might have a space for home, for work, for family, etc.
 ///
 /// Spaces are also the mechanism for sharing data with other Turtl users.
-#[derive(AsnType, Encode, Decode, Serialize, Deserialize, Getters)]
-#[getset(get = "pub")]
+#[derive(Clone, AsnType, Encode, Decode, Serialize, Deserialize, Getters, MutGetters)]
+#[getset(get = "pub", get_mut = "pub(crate)")]
 pub struct Page {
     /// The pages's unique ID
     #[rasn(tag(explicit(0)))]
@@ -136,8 +731,121 @@ pub struct Page {
     /// Determines how notes in this page are displayed.
     #[rasn(tag(explicit(4)))]
     view: Display,
+    /// How the notes in this page are sorted. Applies to both filtered and manual slices, although
+    /// leaving this empty keeps a manual slice in its manual order.
+    #[rasn(tag(explicit(8)), default)]
+    #[serde(default)]
+    sort: Vec<SortEntry>,
     /// Whether or not the page is marked as deleted.
     #[rasn(tag(explicit(5)))]
     deleted: bool,
+    /// An optional emoji or icon name to show next to the title
+    #[rasn(tag(explicit(6)))]
+    icon: Option<String>,
+    /// A longer description of what this page is for
+    #[rasn(tag(explicit(7)))]
+    description: Option<String>,
+}
+
+impl Page {
+    /// Create a new page showing a slice of the notes in a space
+    pub fn new(space_id: SpaceID, title: String, slice: Slice) -> Self {
+        Self {
+            id: PageID::new(),
+            space_id,
+            title,
+            slice,
+            view: Display::ListSingleCol,
+            sort: Vec::new(),
+            deleted: false,
+            icon: None,
+            description: None,
+        }
+    }
+
+    /// Create a new page in the given space, with the space's
+    /// [defaults][crate::models::space::SpaceDefaults] applied.
+    pub fn new_in(space: &Space, title: String, slice: Slice) -> Self {
+        let mut page = Self::new(space.id().clone(), title, slice);
+        space.defaults().apply_to_page(&mut page);
+        page
+    }
+
+    /// Move a sort order left on this page's slice by an older client onto the page itself,
+    /// unless the page already has its own sort.
+    pub(crate) fn migrate_slice_sort(&mut self) {
+        if let Slice::Filtered { sort, .. } = &mut self.slice {
+            if self.sort.is_empty() {
+                self.sort = std::mem::take(sort);
+            } else {
+                sort.clear();
+            }
+        }
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notes(space_id: &SpaceID, count: usize) -> (Vec<NoteID>, HashMap<NoteID, Note>) {
+        let notes = (0..count)
+            .map(|i| Note::new(space_id.clone(), Some(format!("note {}", i)), vec![]))
+            .map(|note| (note.id().clone(), note))
+            .collect::<HashMap<_, _>>();
+        (notes.keys().cloned().collect(), notes)
+    }
+
+    #[test]
+    fn page_walks_every_window() {
+        let space_id = SpaceID::new();
+        let (ids, notes) = notes(&space_id, 5);
+        let slice = Slice::Manual(ids.clone());
+        let ctx = SliceContext::new(&space_id, &notes);
+        let mut seen = Vec::new();
+        let mut window = Some(DisplayWindow::new(0, 2));
+        while let Some(win) = window {
+            let page = slice.page(&ctx, &[], &win);
+            assert_eq!(*page.total(), 5);
+            seen.extend(page.notes().iter().map(|n| n.id().clone()));
+            window = page.next().clone();
+        }
+        assert_eq!(seen, ids);
+    }
+
+    #[test]
+    fn page_zero_limit_ends() {
+        let space_id = SpaceID::new();
+        let (ids, notes) = notes(&space_id, 3);
+        let slice = Slice::Manual(ids);
+        let ctx = SliceContext::new(&space_id, &notes);
+        let page = slice.page(&ctx, &[], &DisplayWindow::new(1, 0));
+        assert!(page.notes().is_empty());
+        assert_eq!(*page.total(), 3);
+        assert!(page.next().is_none());
+    }
+
+    #[test]
+    fn archived_space_slices_are_empty() {
+        let mut space = Space::new("old project".into(), crate::test_util::identity_id());
+        *space.archived_mut() = true;
+        let (ids, notes) = notes(space.id(), 3);
+        let slice = Slice::Manual(ids);
+        let ctx = SliceContext::new(space.id(), &notes).with_space(&space);
+        assert!(slice.evaluate(&ctx, &[]).is_empty());
+        let ctx = ctx.including_archived();
+        assert_eq!(slice.evaluate(&ctx, &[]).len(), 3);
+    }
+
+    #[test]
+    fn legacy_slice_sort_decodes_and_migrates() {
+        let sort = vec![SortEntry { sort: Sort::Title, asc: AscDesc::Ascending }];
+        let slice = Slice::Filtered { filter: SliceFilter::Deleted(false), sort: sort.clone() };
+        let der = rasn::der::encode(&slice).unwrap();
+        let decoded: Slice = rasn::der::decode(&der[..]).unwrap();
+        let mut page = Page::new(SpaceID::new(), "old".into(), decoded);
+        page.migrate_slice_sort();
+        assert_eq!(page.sort().len(), 1);
+        assert!(matches!(page.slice(), Slice::Filtered { sort, .. } if sort.is_empty()));
+    }
+}
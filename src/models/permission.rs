@@ -0,0 +1,216 @@
+//! Permissions are the fine-grained version of [roles][crate::models::space::Role]. Every role
+//! maps to a preset set of permissions, but a member's permissions can be overridden so that, for
+//! instance, somebody can add notes but not delete them.
+
+use crate::models::{
+    operation::{ObjectRef, OperationAction},
+    space::Role,
+};
+use rasn::{AsnType, Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+/// A single thing a member is allowed to do in a space.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    /// Create new notes
+    AddNote = 0,
+    /// Edit existing notes (sections, tags, title, etc)
+    EditNote = 1,
+    /// Trash or fully remove notes
+    DeleteNote = 2,
+    /// Create, edit, and remove pages
+    ManagePages = 3,
+    /// Upload, rename, and remove files
+    ManageFiles = 4,
+    /// Add/remove members and change their roles/permissions
+    ManageMembers = 5,
+    /// Change the space's own settings (title, color, etc)
+    ManageSpace = 6,
+    /// Delete the space entirely
+    DeleteSpace = 7,
+}
+
+impl Permission {
+    /// Every permission we know about
+    pub const ALL: [Permission; 8] = [
+        Self::AddNote,
+        Self::EditNote,
+        Self::DeleteNote,
+        Self::ManagePages,
+        Self::ManageFiles,
+        Self::ManageMembers,
+        Self::ManageSpace,
+        Self::DeleteSpace,
+    ];
+
+    /// Which permission (if any) a member needs in order to run an operation. Operations that
+    /// don't happen in a space (user settings and such) don't need any permission.
+    pub fn required_for(action: &OperationAction) -> Option<Self> {
+        match action {
+            OperationAction::FileSetV1(..) |
+                OperationAction::FileSetChunkV1(..) |
+                OperationAction::FileSetNameV1(..) |
+                OperationAction::FileSetPreviewV1(..) |
+                OperationAction::FileUnsetV1 => Some(Self::ManageFiles),
+            OperationAction::NoteSetV1(..) |
+                OperationAction::NoteSetV2 { .. } => Some(Self::AddNote),
+            OperationAction::NoteSetBodySectionV1 { .. } |
+                OperationAction::NoteSetBodySectionIndentV1 { .. } |
+                OperationAction::NoteSetBodySectionOrderV1 { .. } |
+                OperationAction::NoteSetColorV1(..) |
+                OperationAction::NoteSetIconV1(..) |
+                OperationAction::NoteSetTagV1(..) |
+                OperationAction::NoteSetTitleV1(..) |
+                OperationAction::NoteUnsetBodySectionV1(..) |
+                OperationAction::NoteUnsetTagV1(..) => Some(Self::EditNote),
+            OperationAction::NoteSetDeletedV1(..) |
+                OperationAction::NoteUnsetV1 => Some(Self::DeleteNote),
+            OperationAction::PageSetV1(..) |
+                OperationAction::PageSetDeletedV1(..) |
+                OperationAction::PageSetDescriptionV1(..) |
+                OperationAction::PageSetDisplayV1(..) |
+                OperationAction::PageSetIconV1(..) |
+                OperationAction::PageSetSliceV1(..) |
+                OperationAction::PageSetSortV1(..) |
+                OperationAction::PageSetTitleV1(..) |
+                OperationAction::PageUnsetV1 => Some(Self::ManagePages),
+            OperationAction::SpaceSetV1(..) |
+                OperationAction::SpaceSetArchivedV1(..) |
+                OperationAction::SpaceSetColorV1(..) |
+                OperationAction::SpaceSetConvergentChunksV1(..) |
+                OperationAction::SpaceSetDefaultPageV1(..) |
+                OperationAction::SpaceSetDefaultsV1(..) |
+                OperationAction::SpaceSetApprovalV1(..) |
+                OperationAction::SpaceSetQuorumV1(..) |
+                OperationAction::SpaceSetQuotaV1(..) |
+                OperationAction::SpaceSetTitleV1(..) => Some(Self::ManageSpace),
+            OperationAction::SpaceSetTombstoneV1(tombstone) => match tombstone.object() {
+                ObjectRef::Note(..) => Some(Self::DeleteNote),
+                ObjectRef::File(..) => Some(Self::ManageFiles),
+                // these can't be tombstoned, and applying the tombstone fails
+                ObjectRef::Space(..) |
+                    ObjectRef::Page(..) |
+                    ObjectRef::FileChunk(..) => None,
+            },
+            OperationAction::SpaceSetKeyRotatedV1(..) |
+                OperationAction::SpaceSetMemberV1(..) |
+                OperationAction::SpaceSetMemberPermissionsV1 { .. } |
+                OperationAction::SpaceSetMemberProfileV1 { .. } |
+                OperationAction::SpaceSetMemberRoleV1 { .. } |
+                OperationAction::SpaceSetMemberScopeV1 { .. } |
+                OperationAction::SpaceSetMemberSyncMarkerV1 { .. } |
+                OperationAction::SpaceSetViewerV1(..) |
+                OperationAction::SpaceUnsetMemberV1(..) |
+                OperationAction::SpaceUnsetViewerV1(..) => Some(Self::ManageMembers),
+            OperationAction::SpaceSetDeletedV1(..) |
+                OperationAction::SpaceUnsetV1 => Some(Self::DeleteSpace),
+            OperationAction::UserSetSettingsV1(..) |
+                OperationAction::UserSetSettingsDefaultSpaceV1(..) |
+                OperationAction::UserSetNoteReadStateV1 { .. } |
+                OperationAction::UserSetSettingsThemeV1(..) |
+                OperationAction::UserSetSettingsLocaleV1(..) |
+                OperationAction::UserSetSettingsNoteSortV1(..) |
+                OperationAction::UserSetSettingsEditorSpellcheckV1(..) |
+                OperationAction::UserSetSettingsEditorFontSizeV1(..) |
+                OperationAction::UserSetSettingsTrashPurgeDaysV1(..) |
+                OperationAction::UserSetDeviceV1(..) |
+                OperationAction::UserSetDeviceNameV1 { .. } |
+                OperationAction::UserSetDeviceSyncedV1 { .. } |
+                OperationAction::UserSetDeviceSyncPolicyV1 { .. } |
+                OperationAction::UserUnsetDeviceV1(..) |
+                OperationAction::UserSetKeychainEntryV1 { .. } |
+                OperationAction::UserUnsetKeychainEntryV1(..) |
+                OperationAction::UserSetFavoriteV1 { .. } |
+                OperationAction::UserUnsetFavoriteV1(..) |
+                OperationAction::UserSetRecentViewsV1(..) |
+                OperationAction::UserSetTagStyleV1 { .. } |
+                OperationAction::UserSetProfileV1(..) |
+                OperationAction::UserSetNotificationsDefaultV1(..) |
+                OperationAction::UserSetNotificationsSpaceV1 { .. } |
+                OperationAction::UserSetSpaceOverrideV1 { .. } => None,
+        }
+    }
+
+    fn bit(&self) -> u32 {
+        1 << (*self as u32)
+    }
+}
+
+/// A set of [`Permission`]s, stored as a bitset.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, AsnType, Encode, Decode, Deserialize, Serialize)]
+#[rasn(delegate)]
+pub struct Permissions(u32);
+
+impl Permissions {
+    /// A set with no permissions at all
+    pub fn none() -> Self {
+        Self(0)
+    }
+
+    /// A set with every permission
+    pub fn all() -> Self {
+        Self::from_list(&Permission::ALL)
+    }
+
+    /// Create a set from a list of permissions
+    pub fn from_list(permissions: &[Permission]) -> Self {
+        Self(permissions.iter().fold(0, |acc, p| acc | p.bit()))
+    }
+
+    /// The preset permissions for a role.
+    pub fn for_role(role: &Role) -> Self {
+        match role {
+            Role::Owner => Self::all(),
+            Role::Admin => Self::from_list(&[
+                Permission::AddNote,
+                Permission::EditNote,
+                Permission::DeleteNote,
+                Permission::ManagePages,
+                Permission::ManageFiles,
+                Permission::ManageMembers,
+                Permission::ManageSpace,
+            ]),
+            Role::Moderator => Self::from_list(&[
+                Permission::AddNote,
+                Permission::EditNote,
+                Permission::DeleteNote,
+                Permission::ManagePages,
+                Permission::ManageFiles,
+                Permission::ManageMembers,
+            ]),
+            Role::Member => Self::from_list(&[
+                Permission::AddNote,
+                Permission::EditNote,
+                Permission::DeleteNote,
+                Permission::ManagePages,
+                Permission::ManageFiles,
+            ]),
+            Role::Guest => Self::none(),
+        }
+    }
+
+    /// Whether this set has the given permission
+    pub fn contains(&self, permission: Permission) -> bool {
+        self.0 & permission.bit() != 0
+    }
+
+    /// Add a permission to this set
+    pub fn insert(&mut self, permission: Permission) {
+        self.0 |= permission.bit();
+    }
+
+    /// Remove a permission from this set
+    pub fn remove(&mut self, permission: Permission) {
+        self.0 &= !permission.bit();
+    }
+
+    /// Whether every permission in this set is also in `other`
+    pub fn is_subset(&self, other: &Permissions) -> bool {
+        self.0 & !other.0 == 0
+    }
+
+    /// List the permissions in this set
+    pub fn list(&self) -> Vec<Permission> {
+        Permission::ALL.iter().copied().filter(|p| self.contains(*p)).collect()
+    }
+}
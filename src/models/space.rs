@@ -4,24 +4,53 @@
 //! Things in a space ONLY live in that space, which means spaces are how the routing layer of tp2p
 //! knows which transactions go to which people.
 
-use crate::models::object_id;
-use getset::Getters;
+use crate::{
+    blob::BlobStore,
+    crypto::{self, KeyPurpose, RevocationPlan},
+    error::{Error, Result},
+    models::{
+        object_id,
+        file::FileID,
+        note::{Note, NoteID, Tag},
+        operation::{ObjectRef, Operation, OperationAction, OperationContext},
+        page::{Display, Page, PageID},
+        permission::{Permission, Permissions},
+        state::State,
+    },
+};
+use getset::{Getters, MutGetters};
 use rasn::{AsnType, Decode, Encode};
 use serde::{Deserialize, Serialize};
-use stamp_core::identity::IdentityID;
+use stamp_core::{
+    crypto::base::{Hash, SecretKey},
+    dag::TransactionID,
+    identity::IdentityID,
+    util::Timestamp,
+};
+use std::collections::{HashMap, HashSet};
 
 object_id! {
     /// A unique space id
     SpaceID
 }
 
+object_id! {
+    /// Identifies one of the keys a space has used over its lifetime
+    SpaceKeyID
+}
+
 object_id! {
     /// A unique ID for space members. In space, nobody hears you scream...
     MemberID
 }
 
+object_id! {
+    /// A unique ID for a space's read-only viewers
+    ViewerID
+}
+
 /// Defines a role a user can have within a space
-#[derive(PartialEq, AsnType, Encode, Decode, Deserialize, Serialize)]
+#[derive(Clone, PartialEq, AsnType, Encode, Decode, Deserialize, Serialize)]
 #[rasn(choice)]
 pub enum Role {
     #[rasn(tag(explicit(0)))]
@@ -41,9 +70,47 @@ pub enum Role {
     Owner,
 }
 
+impl Role {
+    /// Where this role sits relative to the others, from guests (lowest) up to owners.
+    pub fn rank(&self) -> u8 {
+        match self {
+            Self::Guest => 0,
+            Self::Member => 1,
+            Self::Moderator => 2,
+            Self::Admin => 3,
+            Self::Owner => 4,
+        }
+    }
+}
+
+/// The human-friendly side of a member, so shared spaces can show names instead of identity IDs.
+#[derive(Clone, Default, AsnType, Encode, Decode, Deserialize, Getters, MutGetters, Serialize)]
+#[getset(get = "pub", get_mut = "pub(crate)")]
+pub struct MemberProfile {
+    /// What this member wants to be called in this space
+    #[rasn(tag(explicit(0)))]
+    display_name: Option<String>,
+    /// An image file (living in the space) to show for this member
+    #[rasn(tag(explicit(1)))]
+    avatar: Option<FileID>,
+    /// When this member joined the space
+    #[rasn(tag(explicit(2)))]
+    joined: Option<Timestamp>,
+    /// This member's pronouns
+    #[rasn(tag(explicit(3)))]
+    pronouns: Option<String>,
+}
+
+impl MemberProfile {
+    /// Create a new member profile
+    pub fn new(display_name: Option<String>, pronouns: Option<String>, avatar: Option<FileID>, joined: Option<Timestamp>) -> Self {
+        Self { display_name, avatar, joined, pronouns }
+    }
+}
+
 /// A user that has access to a space
-#[derive(AsnType, Encode, Decode, Deserialize, Getters, Serialize)]
-#[getset(get = "pub")]
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Getters, MutGetters, Serialize)]
+#[getset(get = "pub", get_mut = "pub(crate)")]
 pub struct Member {
     /// This member's unique ID
     #[rasn(tag(explicit(0)))]
@@ -57,6 +124,337 @@ pub struct Member {
     /// This member's role within the space
     #[rasn(tag(explicit(3)))]
     role: Role,
+    /// Overrides the preset permissions for this member's role. If `None`, the role's presets are
+    /// used.
+    #[rasn(tag(explicit(4)))]
+    permissions: Option<Permissions>,
+    /// This member's name, avatar, etc
+    #[rasn(tag(explicit(5)), default)]
+    #[serde(default)]
+    profile: MemberProfile,
+    /// Limits this member (generally a guest) to the notes shown in these pages. `None` means
+    /// the member sees the whole space.
+    #[rasn(tag(explicit(6)))]
+    scope: Option<Vec<PageID>>,
+    /// The space's DAG frontier as of the last time this member told us they'd caught up
+    #[rasn(tag(explicit(7)), default)]
+    #[serde(default)]
+    sync_marker: Vec<TransactionID>,
+}
+
+impl Member {
+    /// Create a new member of a space
+    pub fn new(space_id: SpaceID, user_id: IdentityID, role: Role) -> Self {
+        Self {
+            id: MemberID::new(),
+            space_id,
+            user_id,
+            role,
+            permissions: None,
+            profile: MemberProfile::default(),
+            scope: None,
+            sync_marker: Vec::new(),
+        }
+    }
+
+    /// The permissions this member actually has: their override if they have one, otherwise
+    /// their role's presets.
+    pub fn effective_permissions(&self) -> Permissions {
+        self.permissions.unwrap_or_else(|| Permissions::for_role(&self.role))
+    }
+
+    /// Whether this member has the given permission
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.effective_permissions().contains(permission)
+    }
+
+    /// Every transaction this member has acknowledged: their sync marker and everything before
+    /// it. `parents` maps each transaction to its previous transactions (see
+    /// [`transaction_parents`][crate::models::operation::transaction_parents]).
+    pub fn received<'a>(&'a self, parents: &'a HashMap<TransactionID, Vec<TransactionID>>) -> HashSet<&'a TransactionID> {
+        let mut seen = HashSet::new();
+        let mut queue = self.sync_marker.iter().collect::<Vec<_>>();
+        while let Some(id) = queue.pop() {
+            if seen.insert(id) {
+                if let Some(prev) = parents.get(id) {
+                    queue.extend(prev.iter());
+                }
+            }
+        }
+        seen
+    }
+
+    /// Whether this member has acknowledged receiving the given transaction.
+    pub fn has_received(&self, transaction_id: &TransactionID, parents: &HashMap<TransactionID, Vec<TransactionID>>) -> bool {
+        self.received(parents).contains(transaction_id)
+    }
+}
+
+/// Someone who has been given a [`ViewerKey`][crate::crypto::ViewerKey] for a space. Viewers can
+/// read everything in the space, but any operations they create are rejected when applied (see
+/// [`Space::authorize`]).
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Getters, Serialize)]
+#[getset(get = "pub")]
+pub struct Viewer {
+    /// This viewer's unique ID
+    #[rasn(tag(explicit(0)))]
+    id: ViewerID,
+    /// The identity holding the viewer key
+    #[rasn(tag(explicit(1)))]
+    user_id: IdentityID,
+    /// A reminder of who this is (ie "my accountant")
+    #[rasn(tag(explicit(2)))]
+    label: Option<String>,
+}
+
+impl Viewer {
+    /// Create a new viewer
+    pub fn new(id: ViewerID, user_id: IdentityID, label: Option<String>) -> Self {
+        Self { id, user_id, label }
+    }
+}
+
+/// The objects a scoped member is allowed to see, built via
+/// [`State::member_scope`][crate::models::state::State::member_scope].
+#[derive(Clone, Debug, Default, Getters)]
+#[getset(get = "pub")]
+pub struct MemberScope {
+    pages: HashSet<PageID>,
+    notes: HashSet<NoteID>,
+    files: HashSet<FileID>,
+}
+
+impl MemberScope {
+    /// Create a new member scope
+    pub fn new(pages: HashSet<PageID>, notes: HashSet<NoteID>, files: HashSet<FileID>) -> Self {
+        Self { pages, notes, files }
+    }
+
+    /// Whether an operation with the given context should be shared with the scoped member.
+    /// Space-level operations (members, title, etc) are always shared.
+    pub fn allows(&self, context: &OperationContext) -> bool {
+        if let Some(file_id) = context.file().as_ref() {
+            self.files.contains(file_id)
+        } else if let Some(note_id) = context.note().as_ref() {
+            self.notes.contains(note_id)
+        } else if let Some(page_id) = context.page().as_ref() {
+            self.pages.contains(page_id)
+        } else {
+            true
+        }
+    }
+}
+
+/// A member's sign-off on a destructive operation in a space that requires a quorum.
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct Approval {
+    /// The member approving
+    #[rasn(tag(explicit(0)))]
+    member_id: MemberID,
+    /// The [approval hash][crate::models::operation::OperationAction::approval_hash] of the
+    /// action being approved
+    #[rasn(tag(explicit(1)))]
+    action_hash: Hash,
+}
+
+impl Approval {
+    /// Create a new approval
+    pub fn new(member_id: MemberID, action_hash: Hash) -> Self {
+        Self { member_id, action_hash }
+    }
+}
+
+/// Records that a note or file was unset and every member has seen it go, so the operations that
+/// built it (and its chunk blobs) can be thrown away. Once a space has a tombstone for an object,
+/// applying an operation that sets it again (or adds chunks to it) fails, so it can't come back
+/// from a peer that never purged.
+///
+/// A tombstone doesn't say what to throw away: each device works that out from its own log (see
+/// [`purge::purge_set`][crate::sync::purge::purge_set]), so a bad tombstone can't get anything
+/// else deleted.
+#[derive(Clone, Debug, AsnType, Encode, Decode, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct Tombstone {
+    /// What was unset
+    #[rasn(tag(explicit(0)))]
+    object: ObjectRef,
+    /// The transaction that unset it
+    #[rasn(tag(explicit(1)))]
+    unset: TransactionID,
+}
+
+impl Tombstone {
+    /// Create a new tombstone
+    pub fn new(object: ObjectRef, unset: TransactionID) -> Self {
+        Self { object, unset }
+    }
+}
+
+/// Records that a space's key was rotated.
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct KeyRotation {
+    /// The ID of the key we rotated to
+    #[rasn(tag(explicit(0)))]
+    key_id: SpaceKeyID,
+    /// The space's DAG frontier at the time of rotation. Everything at or before these
+    /// transactions was encrypted with a previous key.
+    #[rasn(tag(explicit(1)))]
+    pre_rotation: Vec<TransactionID>,
+}
+
+impl KeyRotation {
+    /// Create a new rotation record
+    pub fn new(key_id: SpaceKeyID, pre_rotation: Vec<TransactionID>) -> Self {
+        Self { key_id, pre_rotation }
+    }
+}
+
+/// Limits on how much a space can hold. Any limit left as `None` is unlimited.
+#[derive(Clone, Default, AsnType, Encode, Decode, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct SpaceQuota {
+    #[rasn(tag(explicit(0)))]
+    max_notes: Option<u64>,
+    #[rasn(tag(explicit(1)))]
+    max_pages: Option<u64>,
+    #[rasn(tag(explicit(2)))]
+    max_files: Option<u64>,
+    /// Limits the total (encoded) size of everything in the space
+    #[rasn(tag(explicit(3)))]
+    max_bytes: Option<u64>,
+}
+
+impl SpaceQuota {
+    /// Create a new quota
+    pub fn new(max_notes: Option<u64>, max_pages: Option<u64>, max_files: Option<u64>, max_bytes: Option<u64>) -> Self {
+        Self { max_notes, max_pages, max_files, max_bytes }
+    }
+}
+
+/// The kinds of object a [`SpaceQuota`] puts a count on.
+enum Counted {
+    Notes,
+    Pages,
+    Files,
+}
+
+impl Counted {
+    fn limit(&self, quota: &SpaceQuota) -> Option<u64> {
+        match self {
+            Self::Notes => quota.max_notes,
+            Self::Pages => quota.max_pages,
+            Self::Files => quota.max_files,
+        }
+    }
+
+    fn current(&self, usage: &SpaceUsage) -> u64 {
+        match self {
+            Self::Notes => usage.notes,
+            Self::Pages => usage.pages,
+            Self::Files => usage.files,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Notes => "notes",
+            Self::Pages => "pages",
+            Self::Files => "files",
+        }
+    }
+}
+
+/// Defaults for new content in a space, so things in (say) a project space stay consistently
+/// organized.
+#[derive(Clone, Default, AsnType, Encode, Decode, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct SpaceDefaults {
+    /// Tags given to every new note
+    #[rasn(tag(explicit(0)))]
+    note_tags: Vec<Tag>,
+    /// How new pages are displayed
+    #[rasn(tag(explicit(1)))]
+    page_display: Option<Display>,
+    /// The icon given to new notes
+    #[rasn(tag(explicit(2)), default)]
+    #[serde(default)]
+    note_icon: Option<String>,
+    /// The color given to new notes
+    #[rasn(tag(explicit(3)), default)]
+    #[serde(default)]
+    note_color: Option<String>,
+}
+
+impl SpaceDefaults {
+    /// Create a new set of space defaults
+    pub fn new(note_tags: Vec<Tag>, page_display: Option<Display>, note_icon: Option<String>, note_color: Option<String>) -> Self {
+        Self { note_tags, page_display, note_icon, note_color }
+    }
+
+    /// Apply these defaults to a new note, adding any default tags it doesn't already have and
+    /// filling in its icon/color if it doesn't have its own.
+    pub fn apply_to_note(&self, note: &mut Note) {
+        for tag in &self.note_tags {
+            if !note.tags().contains(tag) {
+                note.tags_mut().push(tag.clone());
+            }
+        }
+        if note.icon().is_none() {
+            *note.icon_mut() = self.note_icon.clone();
+        }
+        if note.color().is_none() {
+            *note.color_mut() = self.note_color.clone();
+        }
+    }
+
+    /// Apply these defaults to a new page.
+    pub fn apply_to_page(&self, page: &mut Page) {
+        if let Some(display) = self.page_display.as_ref() {
+            *page.view_mut() = display.clone();
+        }
+    }
+}
+
+/// How much stuff is in a space
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct SpaceUsage {
+    notes: u64,
+    pages: u64,
+    files: u64,
+    chunks: u64,
+    /// The total (encoded) size of every object in the space, including file data
+    bytes: u64,
+}
+
+impl SpaceUsage {
+    fn count_mut(&mut self, object: &ObjectRef) -> Option<&mut u64> {
+        match object {
+            ObjectRef::Space(..) => None,
+            ObjectRef::Page(..) => Some(&mut self.pages),
+            ObjectRef::Note(..) => Some(&mut self.notes),
+            ObjectRef::File(..) => Some(&mut self.files),
+            ObjectRef::FileChunk(..) => Some(&mut self.chunks),
+        }
+    }
+
+    /// Count an object (of the given size) as living in the space.
+    pub(crate) fn add(&mut self, object: &ObjectRef, bytes: u64) {
+        if let Some(count) = self.count_mut(object) {
+            *count += 1;
+        }
+        self.bytes += bytes;
+    }
+
+    /// Stop counting an object previously [added][SpaceUsage::add] with the given size.
+    pub(crate) fn remove(&mut self, object: &ObjectRef, bytes: u64) {
+        if let Some(count) = self.count_mut(object) {
+            *count = count.saturating_sub(1);
+        }
+        self.bytes = self.bytes.saturating_sub(bytes);
+    }
 }
 
 /// A space is a siloed container of notes and pages. It offers a way to keep these sets of data
@@ -65,8 +463,8 @@ pub struct Member {
 /// For instance, you might have a space for home, for work, for family, etc.
 ///
 /// Spaces are also the mechanism for sharing data with other Turtl users.
-#[derive(AsnType, Encode, Decode, Serialize, Deserialize, Getters)]
-#[getset(get = "pub")]
+#[derive(Clone, AsnType, Encode, Decode, Serialize, Deserialize, Getters, MutGetters)]
+#[getset(get = "pub", get_mut = "pub(crate)")]
 pub struct Space {
     /// The space's unique ID
     #[rasn(tag(explicit(0)))]
@@ -80,5 +478,452 @@ pub struct Space {
     /// Sets the mood
     #[rasn(tag(explicit(3)))]
     color: Option<String>,
+    /// The page to show when this space is opened
+    #[rasn(tag(explicit(4)))]
+    default_page: Option<PageID>,
+    /// Every key rotation this space has gone through, oldest first
+    #[rasn(tag(explicit(5)), default)]
+    #[serde(default)]
+    key_rotations: Vec<KeyRotation>,
+    /// Archived spaces are hidden away, but unlike deleted spaces keep all their data (and shared
+    /// history).
+    #[rasn(tag(explicit(6)), default)]
+    #[serde(default)]
+    archived: bool,
+    /// Optional limits on how much stuff can live in this space
+    #[rasn(tag(explicit(7)))]
+    quota: Option<SpaceQuota>,
+    /// People who can read this space but not change it
+    #[rasn(tag(explicit(8)), default)]
+    #[serde(default)]
+    viewers: Vec<Viewer>,
+    /// If set, destructive operations (deleting the space, rotating its key, replacing the whole
+    /// space, or changing this setting) need approvals from this many admins before they're
+    /// applied. Can't be more than the number of members who can approve.
+    #[rasn(tag(explicit(9)))]
+    quorum: Option<u32>,
+    /// Outstanding approvals for quorum-gated operations
+    #[rasn(tag(explicit(10)), default)]
+    #[serde(default)]
+    approvals: Vec<Approval>,
+    /// Whether or not the space is in the trash. Deleted spaces can be restored until their
+    /// restore window runs out, after which they can be removed for good.
+    #[rasn(tag(explicit(11)), default)]
+    #[serde(default)]
+    deleted: bool,
+    /// Defaults applied to new notes and pages in this space
+    #[rasn(tag(explicit(12)), default)]
+    #[serde(default)]
+    defaults: SpaceDefaults,
+    /// Whether file chunks in this space are encrypted convergently so identical chunks can be
+    /// deduped. Off by default: see [`crypto::seal_convergent`][crate::crypto::seal_convergent]
+    /// for what it gives away.
+    #[rasn(tag(explicit(13)), default)]
+    #[serde(default)]
+    convergent_chunks: bool,
+    /// Notes and files whose history has been purged
+    #[rasn(tag(explicit(14)), default)]
+    #[serde(default)]
+    tombstones: Vec<Tombstone>,
 }
 
+impl Space {
+    /// Create a new space, owned by the given identity
+    pub fn new(title: String, owner: IdentityID) -> Self {
+        let id = SpaceID::new();
+        Self {
+            members: vec![Member::new(id.clone(), owner, Role::Owner)],
+            id,
+            title,
+            color: None,
+            default_page: None,
+            key_rotations: Vec::new(),
+            archived: false,
+            quota: None,
+            viewers: Vec::new(),
+            quorum: None,
+            approvals: Vec::new(),
+            deleted: false,
+            defaults: SpaceDefaults::default(),
+            convergent_chunks: false,
+            tombstones: Vec::new(),
+        }
+    }
+
+    /// The ID of the key this space currently uses. `None` means the space is still on its
+    /// original key.
+    pub fn current_key_id(&self) -> Option<&SpaceKeyID> {
+        self.key_rotations.last().map(|r| r.key_id())
+    }
+
+    /// Record a key rotation. Recording the same rotation twice does nothing.
+    pub(crate) fn add_key_rotation(&mut self, rotation: KeyRotation) {
+        if !self.key_rotations.iter().any(|r| r.key_id() == rotation.key_id()) {
+            self.key_rotations.push(rotation);
+        }
+    }
+
+    /// Plan out everything needed to remove a member and rotate the space's key out from under
+    /// them: all the objects that need re-encrypting, a rough estimate of the work involved, and
+    /// the operations to encrypt under the new key. Call [`RevocationPlan::execute`] to run it.
+    ///
+    /// `pre_rotation` is the space's current DAG frontier.
+    pub fn revocation_plan(&self, state: &State, removed_member: &MemberID, pre_rotation: Vec<TransactionID>) -> Result<RevocationPlan> {
+        RevocationPlan::new(state, &self.id, pre_rotation, Some(removed_member))
+    }
+
+    /// How much stuff lives in this space (see [`State::space_usage`]).
+    pub fn usage(&self, state: &State) -> SpaceUsage {
+        state.space_usage(&self.id)
+    }
+
+    /// Make sure an operation we're creating won't push this space over its quota. Only
+    /// operations that add new things are checked, so a space that's already over quota can still
+    /// be cleaned up.
+    ///
+    /// Quotas are only enforced on our own operations (see [`State::check_local_operation`]): a
+    /// peer's operation is applied even if it pushes the space over, since devices that briefly
+    /// disagree about usage would otherwise end up with different states.
+    pub fn check_quota(&self, state: &State, operation: &Operation) -> Result<()> {
+        let quota = match self.quota.as_ref() {
+            Some(q) => q,
+            None => return Ok(()),
+        };
+        let (is_new, counted) = match operation.action() {
+            OperationAction::NoteSetV1(note) | OperationAction::NoteSetV2 { note, .. } => {
+                (!state.notes().contains_key(note.id()), Some(Counted::Notes))
+            }
+            OperationAction::PageSetV1(page) => (!state.pages().contains_key(page.id()), Some(Counted::Pages)),
+            OperationAction::FileSetV1(file) => (!state.files().contains_key(file.id()), Some(Counted::Files)),
+            OperationAction::FileSetChunkV1(chunk) => (!state.chunks().contains_key(chunk.id()), None),
+            OperationAction::NoteSetBodySectionV1 { .. } => (true, None),
+            _ => return Ok(()),
+        };
+        if !is_new {
+            return Ok(());
+        }
+        let usage = self.usage(state);
+        if let Some(counted) = counted {
+            if let Some(limit) = counted.limit(quota) {
+                if counted.current(&usage) >= limit {
+                    Err(Error::QuotaExceeded(format!("space can only have {} {}", limit, counted.name())))?;
+                }
+            }
+        }
+        if let Some(max_bytes) = quota.max_bytes {
+            let mut added = rasn::der::encode(operation.action()).map(|x| x.len() as u64).unwrap_or(0);
+            if let OperationAction::FileSetChunkV1(chunk) = operation.action() {
+                added += *chunk.len() as u64;
+            }
+            if usage.bytes + added > max_bytes {
+                Err(Error::QuotaExceeded(format!("space can only hold {} bytes", max_bytes)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the given action needs a quorum of approvals in this space
+    pub fn requires_quorum(&self, action: &OperationAction) -> bool {
+        if self.quorum.unwrap_or(0) == 0 {
+            return false;
+        }
+        // replacing the whole space could drop the quorum (or anything else), so it's gated too
+        matches!(action, OperationAction::SpaceSetV1(..) | OperationAction::SpaceUnsetV1 | OperationAction::SpaceSetKeyRotatedV1(..) | OperationAction::SpaceSetQuorumV1(..))
+    }
+
+    /// How many members can approve quorum-gated actions (see [`Space::check_quorum`])
+    pub fn approver_count(&self) -> u32 {
+        self.members.iter().filter(|m| m.has_permission(Permission::ManageSpace)).count() as u32
+    }
+
+    /// Make sure a quorum can be met by this space's members. A quorum bigger than the number of
+    /// members who can approve would lock the space's gated actions away for good.
+    pub fn check_quorum_reachable(&self, quorum: Option<u32>) -> Result<()> {
+        let quorum = quorum.unwrap_or(0);
+        let approvers = self.approver_count();
+        if quorum > approvers {
+            Err(Error::OperationInvalid(format!("quorum of {} is more than the {} members who can approve", quorum, approvers)))?;
+        }
+        Ok(())
+    }
+
+    /// Make sure a quorum-gated action has enough approvals. Only approvals from members who can
+    /// currently manage the space count, and each member counts once.
+    ///
+    /// Returns the action's approval hash if it was gated, so the approvals can be cleared once
+    /// it's applied.
+    pub fn check_quorum(&self, action: &OperationAction) -> Result<Option<Hash>> {
+        if !self.requires_quorum(action) {
+            return Ok(None);
+        }
+        let required = self.quorum.unwrap_or(0);
+        let hash = action.approval_hash()?;
+        let approvers = self.approvals.iter()
+            .filter(|a| a.action_hash() == &hash)
+            .filter(|a| self.member(a.member_id()).map(|m| m.has_permission(Permission::ManageSpace)).unwrap_or(false))
+            .map(|a| a.member_id())
+            .collect::<HashSet<_>>();
+        if (approvers.len() as u32) < required {
+            Err(Error::QuorumNotMet(approvers.len() as u32, required))?;
+        }
+        Ok(Some(hash))
+    }
+
+    /// List the members who haven't acknowledged the given transaction yet (ie "Bob has not
+    /// received your latest edits").
+    pub fn members_behind(&self, transaction_id: &TransactionID, parents: &HashMap<TransactionID, Vec<TransactionID>>) -> Vec<&Member> {
+        self.members.iter()
+            .filter(|m| !m.has_received(transaction_id, parents))
+            .collect()
+    }
+
+    /// The transactions every member of this space has acknowledged. Anything in here is safe to
+    /// compact.
+    pub fn received_by_all<'a>(&'a self, parents: &'a HashMap<TransactionID, Vec<TransactionID>>) -> HashSet<&'a TransactionID> {
+        let mut members = self.members.iter();
+        let mut common = match members.next() {
+            Some(m) => m.received(parents),
+            None => return HashSet::new(),
+        };
+        for member in members {
+            let received = member.received(parents);
+            common.retain(|id| received.contains(id));
+        }
+        common
+    }
+
+    /// Find a member by ID
+    pub fn member(&self, member_id: &MemberID) -> Option<&Member> {
+        self.members.iter().find(|m| m.id() == member_id)
+    }
+
+    /// Find a member by their identity
+    pub fn member_by_identity(&self, identity_id: &IdentityID) -> Option<&Member> {
+        self.members.iter().find(|m| m.user_id() == identity_id)
+    }
+
+    /// Whether a member has the given permission in this space. This is the same role/permission
+    /// matrix [`Space::authorize`] enforces, so UIs can use it to grey out buttons.
+    pub fn can(&self, member_id: &MemberID, permission: Permission) -> bool {
+        self.member(member_id)
+            .map(|m| m.has_permission(permission))
+            .unwrap_or(false)
+    }
+
+    /// Whether a member is allowed to perform a specific action in this space. Unlike
+    /// [`Space::can`], this takes into account things members can always do to themselves (like
+    /// editing their own profile).
+    pub fn can_perform(&self, member_id: &MemberID, action: &OperationAction) -> bool {
+        match self.member(member_id) {
+            Some(member) => self.check_member(member, action).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Authorization hook: make sure the given identity is a member of this space and has the
+    /// permission needed to run the operation. [`State::apply_operation_by`][crate::models::state::State::apply_operation_by]
+    /// runs this on every operation before applying it.
+    pub fn authorize(&self, identity_id: &IdentityID, operation: &Operation) -> Result<()> {
+        if self.member_by_identity(identity_id).is_none() && self.viewer_by_identity(identity_id).is_some() {
+            Err(Error::PermissionDenied("viewers cannot make changes".into()))?;
+        }
+        if Permission::required_for(operation.action()).is_none() {
+            return Ok(());
+        }
+        let member = self.member_by_identity(identity_id)
+            .ok_or_else(|| Error::PermissionDenied("not a member of this space".into()))?;
+        self.check_member(member, operation.action())
+    }
+
+    /// The actual permission check for a member performing an action.
+    fn check_member(&self, member: &Member, action: &OperationAction) -> Result<()> {
+        let permission = match Permission::required_for(action) {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        // nobody gets to approve on someone else's behalf
+        if let OperationAction::SpaceSetApprovalV1(approval) = action {
+            if approval.member_id() != member.id() {
+                Err(Error::PermissionDenied("cannot approve for another member".into()))?;
+            }
+        }
+        // everyone gets to edit their own profile and sync marker
+        match action {
+            OperationAction::SpaceSetMemberProfileV1 { member_id, .. } |
+                OperationAction::SpaceSetMemberSyncMarkerV1 { member_id, .. } => {
+                if member_id == member.id() {
+                    return Ok(());
+                }
+            }
+            _ => {}
+        }
+        if !member.has_permission(permission) {
+            Err(Error::PermissionDenied(format!("missing permission {:?}", permission)))?;
+        }
+        self.check_grant(member, action)
+    }
+
+    /// Make sure a member managing other members isn't handing out more than they have: nobody
+    /// can grant a permission they don't hold themselves, or give out (or take away) a role at or
+    /// above their own. Owners can do anything, so ownership can be handed on.
+    fn check_grant(&self, member: &Member, action: &OperationAction) -> Result<()> {
+        if member.role() == &Role::Owner {
+            return Ok(());
+        }
+        let granter = member.effective_permissions();
+        let outranks = |role: &Role| role.rank() < member.role().rank();
+        let target_outranked = |member_id: &MemberID| self.member(member_id).map(|m| outranks(m.role())).unwrap_or(true);
+        match action {
+            OperationAction::SpaceSetMemberV1(new_member) => {
+                if !outranks(new_member.role()) || !target_outranked(new_member.id()) {
+                    Err(Error::PermissionDenied("cannot grant a role at or above your own".into()))?;
+                }
+                if !new_member.effective_permissions().is_subset(&granter) {
+                    Err(Error::PermissionDenied("cannot grant permissions you don't have".into()))?;
+                }
+            }
+            OperationAction::SpaceSetMemberPermissionsV1 { member_id, permissions } => {
+                let target = self.member(member_id).ok_or(Error::MemberNotFound)?;
+                if !outranks(target.role()) {
+                    Err(Error::PermissionDenied("cannot change a member at or above your own role".into()))?;
+                }
+                let granted = permissions.unwrap_or_else(|| Permissions::for_role(target.role()));
+                if !granted.is_subset(&granter) {
+                    Err(Error::PermissionDenied("cannot grant permissions you don't have".into()))?;
+                }
+            }
+            OperationAction::SpaceSetMemberRoleV1 { member_id, role } => {
+                let target = self.member(member_id).ok_or(Error::MemberNotFound)?;
+                if !outranks(target.role()) || !outranks(role) {
+                    Err(Error::PermissionDenied("cannot grant a role at or above your own".into()))?;
+                }
+                let granted = target.permissions().unwrap_or_else(|| Permissions::for_role(role));
+                if !granted.is_subset(&granter) {
+                    Err(Error::PermissionDenied("cannot grant permissions you don't have".into()))?;
+                }
+            }
+            // removing yourself doesn't need to outrank anyone
+            OperationAction::SpaceUnsetMemberV1(member_id) if member_id != member.id() => {
+                if !target_outranked(member_id) {
+                    Err(Error::PermissionDenied("cannot remove a member at or above your own role".into()))?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Find a viewer by their identity
+    pub fn viewer_by_identity(&self, identity_id: &IdentityID) -> Option<&Viewer> {
+        self.viewers.iter().find(|v| v.user_id() == identity_id)
+    }
+
+    /// Find a member by ID, mutably
+    pub(crate) fn member_mut(&mut self, member_id: &MemberID) -> Option<&mut Member> {
+        self.members.iter_mut().find(|m| m.id() == member_id)
+    }
+}
+
+
+/// Merge one space into another: every page, note, and file (and its chunks) in `src` is moved
+/// into `dest`, keeping its ID, and then `src` is moved to the trash.
+///
+/// File data is sealed under the space's file chunk key, so it can't just be moved: `src_key` and
+/// `dest_key` are the two spaces' current keys, and each chunk is re-encrypted under `dest`'s key
+/// and stored in `blobs` (inline file data is re-encrypted in place). Chunks we don't have the
+/// data for can't be re-encrypted, so they stop the merge with [`Error::BlobMissing`].
+///
+/// The returned operations are meant to be applied in order. The moves come first so that
+/// deleting `src` doesn't take anything with it.
+pub fn merge(dest: &SpaceID, src: &SpaceID, state: &State, src_key: &SecretKey, dest_key: &SecretKey, blobs: &dyn BlobStore) -> Result<Vec<Operation>> {
+    if dest == src {
+        Err(Error::OperationInvalid("cannot merge a space into itself".into()))?;
+    }
+    let dest_space = state.spaces().get(dest).ok_or(Error::SpaceNotFound)?;
+    if !state.spaces().contains_key(src) {
+        Err(Error::SpaceNotFound)?;
+    }
+    let src_file_key = crypto::derive_subkey(src_key, KeyPurpose::FileChunk)?;
+    let dest_file_key = crypto::derive_subkey(dest_key, KeyPurpose::FileChunk)?;
+    let mut ops = Vec::new();
+    for page in state.pages().values().filter(|p| p.space_id() == src) {
+        let mut page = page.clone();
+        *page.space_id_mut() = dest.clone();
+        ops.push(Operation::page_set(dest.clone(), page));
+    }
+    for note in state.notes().values().filter(|n| n.space_id() == src) {
+        let mut note = note.clone();
+        *note.space_id_mut() = dest.clone();
+        ops.push(Operation::note_set(dest.clone(), note)?);
+    }
+    for file in state.files().values().filter(|f| f.space_id() == src) {
+        let mut moved = file.reseal(&src_file_key, &dest_file_key)?;
+        *moved.space_id_mut() = dest.clone();
+        ops.push(Operation::file_set(dest.clone(), moved));
+        for chunk in state.chunks().values().filter(|c| c.file_id() == file.id()) {
+            let chunk = chunk.reseal(&src_file_key, &dest_file_key, *dest_space.convergent_chunks(), blobs)?;
+            ops.push(Operation::file_set_chunk(dest.clone(), file.id().clone(), chunk));
+        }
+    }
+    ops.push(Operation::space_set_deleted(src.clone(), true));
+    Ok(ops)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blob::FsBlobStore,
+        models::file::{FileReader, FileWriter},
+        test_util,
+    };
+
+    #[test]
+    fn merge_reseals_file_data_under_the_destination_key() {
+        let blobs = FsBlobStore::new(test_util::temp_dir("space-merge")).unwrap();
+        let mut state = State::new();
+        let src = Space::new("src".into(), test_util::identity_id());
+        let dest = Space::new("dest".into(), test_util::identity_id());
+        let (src_id, dest_id) = (src.id().clone(), dest.id().clone());
+        state.apply_operation(Operation::space_set(src)).unwrap();
+        state.apply_operation(Operation::space_set(dest)).unwrap();
+        let src_key = SecretKey::new_xchacha20poly1305().unwrap();
+        let dest_key = SecretKey::new_xchacha20poly1305().unwrap();
+        let src_file_key = crypto::derive_subkey(&src_key, KeyPurpose::FileChunk).unwrap();
+        let dest_file_key = crypto::derive_subkey(&dest_key, KeyPurpose::FileChunk).unwrap();
+
+        let data = (0..5000u32).map(|i| (i % 253) as u8).collect::<Vec<_>>();
+        let mut writer = FileWriter::new(&data[..], &src_file_key, src_id.clone(), "big.bin".into(), None)
+            .with_chunk_size(1024)
+            .with_inline_threshold(0);
+        let file_id = writer.file_id().clone();
+        let mut chunk_ops = Vec::new();
+        while let Some(chunk) = writer.next_chunk().unwrap() {
+            chunk_ops.push(chunk.store(&blobs).unwrap());
+        }
+        state.apply_operation(writer.finish().unwrap()).unwrap();
+        for op in chunk_ops {
+            state.apply_operation(op).unwrap();
+        }
+        let mut small = FileWriter::new(&b"tiny"[..], &src_file_key, src_id.clone(), "tiny.txt".into(), None);
+        let small_id = small.file_id().clone();
+        assert!(small.next_chunk().unwrap().is_none());
+        state.apply_operation(small.finish().unwrap()).unwrap();
+
+        for op in merge(&dest_id, &src_id, &state, &src_key, &dest_key, &blobs).unwrap() {
+            state.apply_operation(op).unwrap();
+        }
+        let read = |file_id: &FileID, key: &SecretKey| {
+            let file = state.files().get(file_id).unwrap().clone();
+            let chunks = state.chunks().values().filter(|c| c.file_id() == file_id).cloned().collect::<Vec<_>>();
+            FileReader::from_store(file, chunks, key, &blobs)
+                .and_then(|reader| reader.collect::<Result<Vec<_>>>())
+                .map(|parts| parts.concat())
+        };
+        assert_eq!(state.files().get(&file_id).unwrap().space_id(), &dest_id);
+        assert_eq!(read(&file_id, &dest_file_key).unwrap(), data);
+        assert!(read(&file_id, &src_file_key).is_err());
+        assert_eq!(read(&small_id, &dest_file_key).unwrap(), b"tiny");
+        assert!(read(&small_id, &src_file_key).is_err());
+    }
+}
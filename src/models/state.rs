@@ -2,19 +2,139 @@
 //! in order.
 
 use crate::{
+    crypto::OperationKeys,
     error::{Error, Result},
     models::{
-        file::{File, FileChunk, FileChunkID, FileID},
-        note::{Note, NoteID},
-        operation::{Operation, OperationAction},
-        page::{Page, PageID},
-        space::{Space, SpaceID},
-        user::UserSettings,
+        file::{File, FileChunk, FileChunkID, FileID, FileQuery},
+        graph::NoteGraph,
+        keychain::Keychain,
+        note::{Note, NoteDates, NoteID, Tag},
+        operation::{self, ObjectRef, Operation, OperationAction},
+        page::{self, BoardColumn, Calendar, Display, DisplayWindow, Page, PageID, SliceContext, SlicePage, TableRow},
+        space::{MemberID, MemberScope, Role, Space, SpaceID, SpaceUsage},
+        user::{RecentView, SettingsBlob, SpaceView, UserSettings, ViewTarget, USER_SETTINGS_VERSION},
     },
+    storage::{NoteMeta, NoteQuery, Storage},
 };
 use getset::{Getters, MutGetters};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use stamp_core::{
+    crypto::base::{Hash, SecretKey},
+    dag::{Transaction, TransactionID},
+    identity::IdentityID,
+    util::Timestamp,
+};
+use std::collections::{HashMap, HashSet};
+
+/// How many days a deleted space sticks around before it can be removed for good, unless
+/// otherwise configured via [`State::set_space_restore_days`] (see
+/// [`purge::plan_space_unset`][crate::sync::purge::plan_space_unset]).
+pub const DEFAULT_SPACE_RESTORE_DAYS: u32 = 30;
+
+/// Record kinds a [`State`] is persisted under (see [`State::persist_dirty`])
+const RECORD_SPACE: &str = "state/space";
+const RECORD_ACTIVITY: &str = "state/space-activity";
+const RECORD_PAGE: &str = "state/page";
+const RECORD_NOTE: &str = "state/note";
+const RECORD_FILE: &str = "state/file";
+const RECORD_USER: &str = "state/user";
+
+/// The parts of a [`State`] that changed since it was last
+/// [persisted][State::persist_dirty].
+#[derive(Clone, Default, Getters)]
+#[getset(get = "pub")]
+pub struct Dirty {
+    spaces: HashSet<SpaceID>,
+    /// Spaces where the [last active][State::member_last_active] times changed
+    activity: HashSet<SpaceID>,
+    pages: HashSet<PageID>,
+    notes: HashSet<NoteID>,
+    /// Files, along with their chunks
+    files: HashSet<FileID>,
+    /// Whether the user's settings or keychain changed
+    user: bool,
+}
+
+impl Dirty {
+    /// Whether nothing has changed
+    pub fn is_empty(&self) -> bool {
+        self.spaces.is_empty() && self.activity.is_empty() && self.pages.is_empty() && self.notes.is_empty() && self.files.is_empty() && !self.user
+    }
+
+    fn merge(&mut self, other: Dirty) {
+        self.spaces.extend(other.spaces);
+        self.activity.extend(other.activity);
+        self.pages.extend(other.pages);
+        self.notes.extend(other.notes);
+        self.files.extend(other.files);
+        self.user |= other.user;
+    }
+}
+
+/// How a space is persisted
+#[derive(Serialize, Deserialize)]
+struct SpaceRecord {
+    space: Space,
+}
+
+/// How a space's member activity is persisted
+#[derive(Serialize, Deserialize)]
+struct ActivityRecord {
+    space_id: SpaceID,
+    last_active: Vec<(IdentityID, Timestamp)>,
+}
+
+/// How a note is persisted
+#[derive(Serialize, Deserialize)]
+struct NoteRecord {
+    note: Note,
+    dates: Option<NoteDates>,
+}
+
+/// How a file (and its chunks) is persisted. Chunks can arrive before their file does.
+#[derive(Serialize, Deserialize)]
+struct FileRecord {
+    file_id: FileID,
+    file: Option<File>,
+    chunks: Vec<FileChunk>,
+}
+
+/// How everything outside the spaces is persisted
+#[derive(Serialize, Deserialize)]
+struct UserRecord {
+    space_restore_days: Option<u32>,
+    user_settings: UserSettings,
+    #[serde(default)]
+    newer_user_settings: Option<SettingsBlob>,
+    keychain: Keychain,
+}
+
+fn record_id<T: rasn::Encode>(id: &T) -> Result<Vec<u8>> {
+    rasn::der::encode(id).map_err(|_| Error::ASNSerialize)
+}
+
+fn to_record<T: Serialize>(record: &T) -> Result<Vec<u8>> {
+    serde_json::to_vec(record).map_err(|e| Error::Storage(format!("couldn't serialize state: {}", e)))
+}
+
+fn from_record<T: for<'de> Deserialize<'de>>(data: &[u8]) -> Result<T> {
+    serde_json::from_slice(data).map_err(|e| Error::Storage(format!("couldn't deserialize state: {}", e)))
+}
+
+/// Serializes [`State::object_sizes`] as a list, since object refs can't be map keys in JSON.
+mod object_sizes {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(sizes: &HashMap<ObjectRef, (SpaceID, u64)>, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_seq(sizes.iter().map(|(object, (space_id, bytes))| (object, space_id, bytes)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<HashMap<ObjectRef, (SpaceID, u64)>, D::Error> {
+        let sizes: Vec<(ObjectRef, SpaceID, u64)> = Vec::deserialize(deserializer)?;
+        Ok(sizes.into_iter().map(|(object, space_id, bytes)| (object, (space_id, bytes))).collect())
+    }
+}
 
 /// An object that represents application state. This is built by applying operations in order.
 #[derive(Default, Serialize, Deserialize, Getters, MutGetters)]
@@ -23,9 +143,39 @@ pub struct State {
     chunks: HashMap<FileChunkID, FileChunk>,
     files: HashMap<FileID, File>,
     notes: HashMap<NoteID, Note>,
+    /// An index of which notes have which tags
+    notes_by_tag: HashMap<Tag, HashSet<NoteID>>,
+    /// Which notes reference each file
+    file_refs: HashMap<FileID, HashSet<NoteID>>,
+    /// When each note was created/modified, taken from the operations applied to it
+    note_dates: HashMap<NoteID, NoteDates>,
+    /// How many notes are in each page, kept up to date as operations are applied
+    #[getset(skip)]
+    page_counts: HashMap<PageID, usize>,
+    /// The encoded size of each object in a space, and the space it counts against
+    #[getset(skip)]
+    #[serde(with = "object_sizes")]
+    object_sizes: HashMap<ObjectRef, (SpaceID, u64)>,
+    /// How much is in each space, kept up to date as operations are applied
+    #[getset(skip)]
+    space_usage: HashMap<SpaceID, SpaceUsage>,
     pages: HashMap<PageID, Page>,
     spaces: HashMap<SpaceID, Space>,
+    /// The last time each identity did anything in each space
+    last_active: HashMap<SpaceID, HashMap<IdentityID, Timestamp>>,
+    /// How many days a deleted space can be restored for. `None` uses
+    /// [`DEFAULT_SPACE_RESTORE_DAYS`]. This is a setting on this device, and only decides when
+    /// it schedules removals: applying a removal never looks at it.
+    space_restore_days: Option<u32>,
     user_settings: UserSettings,
+    /// The last settings written by a client newer than us, so the fields we don't know about can
+    /// be written back out (see [`SettingsBlob`])
+    newer_user_settings: Option<SettingsBlob>,
+    /// The keys for the spaces we belong to
+    keychain: Keychain,
+    /// What's changed since we were last persisted
+    #[serde(skip)]
+    dirty: Dirty,
 }
 
 impl State {
@@ -34,8 +184,901 @@ impl State {
         Self::default()
     }
 
+    /// Set how many days a deleted space can be restored for before
+    /// [`purge::plan_space_unset`][crate::sync::purge::plan_space_unset] lets it be removed.
+    pub fn set_space_restore_days(&mut self, days: u32) {
+        self.space_restore_days = Some(days);
+        self.dirty.user = true;
+    }
+
+    /// List our spaces, leaving out archived and deleted ones.
+    pub fn list_spaces(&self) -> Vec<&Space> {
+        self.spaces().values().filter(|s| !s.archived() && !s.deleted()).collect()
+    }
+
+    /// Grab a space as the user sees it, with their overrides applied.
+    pub fn space_view(&self, space_id: &SpaceID) -> Option<SpaceView<'_>> {
+        self.spaces().get(space_id).map(|space| self.user_settings().space_view(space))
+    }
+
+    /// List only our archived spaces.
+    pub fn list_archived_spaces(&self) -> Vec<&Space> {
+        self.spaces().values().filter(|s| *s.archived() && !s.deleted()).collect()
+    }
+
+    /// List the spaces in the trash.
+    pub fn list_deleted_spaces(&self) -> Vec<&Space> {
+        self.spaces().values().filter(|s| *s.deleted()).collect()
+    }
+
+    /// How long a deleted space can be restored for on this device.
+    pub fn space_restore_window(&self) -> chrono::Duration {
+        chrono::Duration::days(self.space_restore_days.unwrap_or(DEFAULT_SPACE_RESTORE_DAYS) as i64)
+    }
+
+    /// Grab the notes that belong in a page, in the page's sort order.
+    pub fn page_notes(&self, page_id: &PageID) -> Vec<&Note> {
+        match self.pages().get(page_id) {
+            Some(page) => page.slice().evaluate(&self.slice_context(page.space_id()), page.sort()),
+            None => Vec::new(),
+        }
+    }
+
+    /// Find the notes in a page by querying the [note metadata][crate::storage::meta] in
+    /// storage rather than the notes in memory, optionally just one window's worth. Returns
+    /// `None` if the page doesn't exist or its slice needs more than the metadata has (ie, a
+    /// text search), in which case use [`State::page_notes`].
+    ///
+    /// Storage only knows about notes as of the last [`State::persist_dirty`].
+    pub fn query_page_notes(&self, storage: &dyn Storage, page_id: &PageID, window: Option<&DisplayWindow>) -> Result<Option<Vec<NoteID>>> {
+        let page = match self.pages().get(page_id) {
+            Some(page) => page,
+            None => return Ok(None),
+        };
+        if self.space_archived(page.space_id()) {
+            return Ok(Some(Vec::new()));
+        }
+        let query = match NoteQuery::from_slice(page.space_id().clone(), page.slice(), page.sort()) {
+            Some(query) => query,
+            None => return Ok(None),
+        };
+        let query = match window {
+            Some(window) => query.with_window(window.clone()),
+            None => query,
+        };
+        Ok(Some(storage.query_notes(&query)?))
+    }
+
+    /// Grab one window's worth of the notes in a page. Use [`SlicePage::next`] to get the
+    /// following window.
+    pub fn page_notes_window(&self, page_id: &PageID, window: &DisplayWindow) -> Option<SlicePage<'_>> {
+        self.pages().get(page_id)
+            .map(|page| page.slice().page(&self.slice_context(page.space_id()), page.sort(), window))
+    }
+
+    /// Grab the notes in a page, bucketed into board columns. Returns `None` if the page doesn't
+    /// exist or isn't displayed as a board.
+    pub fn page_board(&self, page_id: &PageID) -> Option<Vec<BoardColumn<'_>>> {
+        let page = self.pages().get(page_id)?;
+        match page.view() {
+            Display::Board { group_by } => Some(group_by.bucket(self.page_notes(page_id))),
+            _ => None,
+        }
+    }
+
+    /// Grab the notes in a page, bucketed into days. Returns `None` if the page doesn't exist or
+    /// isn't displayed as a calendar.
+    pub fn page_calendar(&self, page_id: &PageID) -> Option<Calendar<'_>> {
+        let page = self.pages().get(page_id)?;
+        match page.view() {
+            Display::Calendar { date_source } => Some(date_source.bucket(self.page_notes(page_id), self.note_dates())),
+            _ => None,
+        }
+    }
+
+    /// Grab the notes in a page as table rows. Returns `None` if the page doesn't exist or isn't
+    /// displayed as a table.
+    pub fn page_table(&self, page_id: &PageID) -> Option<Vec<TableRow<'_>>> {
+        let page = self.pages().get(page_id)?;
+        match page.view() {
+            Display::Table { columns } => Some(page::table_rows(columns, self.page_notes(page_id), self.note_dates())),
+            _ => None,
+        }
+    }
+
+    /// Get the note count for every page in a space, ie for showing "Inbox (12)" in a sidebar.
+    /// Archived spaces have no counts.
+    ///
+    /// These are maintained as operations are applied, so this is cheap to call.
+    pub fn page_counts(&self, space_id: &SpaceID) -> HashMap<&PageID, usize> {
+        if self.space_archived(space_id) {
+            return HashMap::new();
+        }
+        self.pages().values()
+            .filter(|p| p.space_id() == space_id)
+            .map(|p| (p.id(), self.page_counts.get(p.id()).copied().unwrap_or(0)))
+            .collect()
+    }
+
+    /// Build the graph of links between the notes (and pages) in a space.
+    pub fn note_graph(&self, space_id: &SpaceID) -> NoteGraph {
+        NoteGraph::build(space_id, self.notes(), self.pages())
+    }
+
+    /// Find all the pages a note currently shows up in.
+    fn pages_including(&self, note_id: &NoteID) -> HashSet<PageID> {
+        match self.notes.get(note_id) {
+            Some(note) => {
+                self.pages.values()
+                    .filter(|p| p.slice().includes(p.space_id(), note, Some(&self.files)))
+                    .map(|p| p.id().clone())
+                    .collect()
+            }
+            None => HashSet::new(),
+        }
+    }
+
+    /// Count a page's notes from scratch. Pages in archived spaces are counted too, so their
+    /// counts are ready when the space is unarchived.
+    fn recount_page(&mut self, page_id: &PageID) {
+        let count = self.pages.get(page_id)
+            .map(|p| p.slice().matching(&self.slice_context(p.space_id()).including_archived()).len());
+        match count {
+            Some(count) => { self.page_counts.insert(page_id.clone(), count); }
+            None => { self.page_counts.remove(page_id); }
+        }
+    }
+
+    /// Create a context for evaluating slices against the notes in a space. Slices in an archived
+    /// space come back empty.
+    fn slice_context<'a>(&'a self, space_id: &'a SpaceID) -> SliceContext<'a> {
+        let ctx = SliceContext::new(space_id, self.notes())
+            .with_tag_index(self.notes_by_tag())
+            .with_note_dates(self.note_dates())
+            .with_files(self.files());
+        match self.spaces.get(space_id) {
+            Some(space) => ctx.with_space(space),
+            None => ctx,
+        }
+    }
+
+    /// Whether a space is archived (and so left out of queries by default)
+    fn space_archived(&self, space_id: &SpaceID) -> bool {
+        self.spaces.get(space_id).map(|s| *s.archived()).unwrap_or(false)
+    }
+
+    /// Add (or replace) a note, keeping our indexes up to date.
+    fn set_note(&mut self, note: Note) {
+        if let Some(existing) = self.notes.remove(note.id()) {
+            for tag in existing.tags() {
+                self.unindex_tag(tag, existing.id());
+            }
+        }
+        for tag in note.tags() {
+            self.notes_by_tag.entry(tag.clone()).or_default().insert(note.id().clone());
+        }
+        self.notes.insert(note.id().clone(), note);
+    }
+
+    /// Remove a note, keeping our indexes up to date.
+    fn unset_note(&mut self, note_id: &NoteID) -> Option<Note> {
+        let note = self.notes.remove(note_id)?;
+        for tag in note.tags() {
+            self.unindex_tag(tag, note_id);
+        }
+        self.note_dates.remove(note_id);
+        Some(note)
+    }
+
+    /// The files a note currently references (none if we don't have it).
+    fn note_file_refs(&self, note_id: &NoteID) -> HashSet<FileID> {
+        self.notes.get(note_id)
+            .map(|note| note.file_refs().into_iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Update a note's entries in the file reference index, given the files it referenced before
+    /// it changed. Only the files it picked up or dropped are touched.
+    fn update_file_refs(&mut self, note_id: &NoteID, before: HashSet<FileID>) {
+        let after = self.note_file_refs(note_id);
+        for file_id in before.difference(&after) {
+            if let Some(note_ids) = self.file_refs.get_mut(file_id) {
+                note_ids.remove(note_id);
+                if note_ids.is_empty() {
+                    self.file_refs.remove(file_id);
+                }
+            }
+        }
+        for file_id in after.difference(&before) {
+            self.file_refs.entry(file_id.clone()).or_default().insert(note_id.clone());
+        }
+    }
+
+    /// Search the files in a space. Archived spaces have no results.
+    pub fn find_files(&self, space_id: &SpaceID, query: &FileQuery) -> Vec<&File> {
+        if self.space_archived(space_id) {
+            return Vec::new();
+        }
+        let mut files = self.files().values()
+            .filter(|f| f.space_id() == space_id && query.matches(f))
+            .collect::<Vec<_>>();
+        files.sort_by(|a, b| a.name().cmp(b.name()));
+        files
+    }
+
+    /// The notes/pages the user opened recently, most recent first, leaving out anything that's
+    /// since been deleted, that lives in an archived space, or that we don't have.
+    pub fn recently_viewed(&self, limit: usize) -> Vec<&RecentView> {
+        self.user_settings().recent_views().iter()
+            .filter(|view| match view.target() {
+                ViewTarget::Note(note_id) => self.notes.get(note_id).map(|n| !n.deleted() && !self.space_archived(n.space_id())).unwrap_or(false),
+                ViewTarget::Page(page_id) => self.pages.get(page_id).map(|p| !p.deleted() && !self.space_archived(p.space_id())).unwrap_or(false),
+            })
+            .take(limit)
+            .collect()
+    }
+
+    /// List the live (not deleted) notes that reference a file.
+    pub fn file_referenced_by(&self, file_id: &FileID) -> Vec<&Note> {
+        self.file_refs.get(file_id)
+            .map(|note_ids| {
+                note_ids.iter()
+                    .filter_map(|id| self.notes.get(id))
+                    .filter(|n| !n.deleted())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Remove a note from the tag index for a single tag
+    fn unindex_tag(&mut self, tag: &Tag, note_id: &NoteID) {
+        if let Some(note_ids) = self.notes_by_tag.get_mut(tag) {
+            note_ids.remove(note_id);
+            if note_ids.is_empty() {
+                self.notes_by_tag.remove(tag);
+            }
+        }
+    }
+
+    /// Figure out what a member of a space is allowed to see. Returns `None` if the member isn't
+    /// scoped (they see everything).
+    ///
+    /// A scoped member sees their pages, every note those pages currently show, and any files
+    /// those notes reference.
+    pub fn member_scope(&self, space_id: &SpaceID, member_id: &MemberID) -> Result<Option<MemberScope>> {
+        let space = self.spaces().get(space_id).ok_or(Error::SpaceNotFound)?;
+        let member = space.member(member_id).ok_or(Error::MemberNotFound)?;
+        let page_ids = match member.scope() {
+            Some(pages) => pages,
+            None => return Ok(None),
+        };
+        let mut pages = HashSet::new();
+        let mut notes = HashSet::new();
+        let mut files = HashSet::new();
+        for page_id in page_ids {
+            let page = match self.pages().get(page_id) {
+                Some(p) if p.space_id() == space_id => p,
+                _ => continue,
+            };
+            pages.insert(page_id.clone());
+            for note in page.slice().matching(&self.slice_context(space_id)) {
+                files.extend(note.file_refs().into_iter().cloned());
+                notes.insert(note.id().clone());
+            }
+        }
+        Ok(Some(MemberScope::new(pages, notes, files)))
+    }
+
+    /// Create checkpoint operations for every live object in a space: the space itself, then its
+    /// pages, notes, files, and file chunks. Applying these in order to an empty state rebuilds
+    /// the space as we currently see it.
+    pub fn space_checkpoint(&self, space_id: &SpaceID) -> Result<Vec<Operation>> {
+        let space = self.spaces().get(space_id).ok_or(Error::SpaceNotFound)?;
+        let mut ops = vec![Operation::space_set(space.clone())];
+        for page in self.pages().values().filter(|p| p.space_id() == space_id) {
+            ops.push(Operation::page_set(space_id.clone(), page.clone()));
+        }
+        for note in self.notes().values().filter(|n| n.space_id() == space_id) {
+            ops.push(Operation::note_set(space_id.clone(), note.clone())?);
+        }
+        for file in self.files().values().filter(|f| f.space_id() == space_id) {
+            ops.push(Operation::file_set(space_id.clone(), file.clone()));
+            for chunk in self.chunks().values().filter(|c| c.file_id() == file.id()) {
+                ops.push(Operation::file_set_chunk(space_id.clone(), file.id().clone(), chunk.clone()));
+            }
+        }
+        Ok(ops)
+    }
+
+    /// Change a space's members, failing (and changing nothing) if the space couldn't meet its
+    /// quorum afterwards.
+    fn update_members<F: FnOnce(&mut Space)>(&mut self, space_id: &SpaceID, update: F) -> Result<()> {
+        let space = match self.spaces.get_mut(space_id) {
+            Some(space) => space,
+            None => return Ok(()),
+        };
+        if space.quorum().is_none() {
+            update(space);
+            return Ok(());
+        }
+        let mut updated = space.clone();
+        update(&mut updated);
+        updated.check_quorum_reachable(*updated.quorum())?;
+        *space = updated;
+        Ok(())
+    }
+
+    /// Remove a space and everything living in it.
+    fn unset_space(&mut self, space_id: &SpaceID) {
+        self.spaces.remove(space_id);
+        self.last_active.remove(space_id);
+        let note_ids = self.notes.values()
+            .filter(|n| n.space_id() == space_id)
+            .map(|n| n.id().clone())
+            .collect::<Vec<_>>();
+        for note_id in note_ids {
+            self.unset_note(&note_id);
+        }
+        self.pages.retain(|_, p| p.space_id() != space_id);
+        let file_ids = self.files.values()
+            .filter(|f| f.space_id() == space_id)
+            .map(|f| f.id().clone())
+            .collect::<HashSet<_>>();
+        self.files.retain(|id, _| !file_ids.contains(id));
+        self.chunks.retain(|_, c| !file_ids.contains(c.file_id()));
+    }
+
+    /// Check whether the note we have matches the given content hash (generally pulled from a
+    /// checkpoint). Returns `false` if we don't have the note at all.
+    pub fn note_matches_hash(&self, note_id: &NoteID, hash: &Hash) -> Result<bool> {
+        match self.notes().get(note_id) {
+            Some(note) => Ok(&note.content_hash()? == hash),
+            None => Ok(false),
+        }
+    }
+
+    /// When a member of a space last did anything in it (ie "last edited 2 days ago"). Only
+    /// operations applied via [`State::apply_operation_by`] count.
+    pub fn member_last_active(&self, space_id: &SpaceID, member_id: &MemberID) -> Option<&Timestamp> {
+        let member = self.spaces().get(space_id)?.member(member_id)?;
+        self.last_active.get(space_id)?.get(member.user_id())
+    }
+
+    /// Make sure the given identity is allowed to run an operation (see [`Space::authorize`]).
+    ///
+    /// The check is made against the space as it stands in this state. Operations are applied in
+    /// DAG order, so that's the space as of the operation's place in the DAG: a member removed
+    /// later can still have made the operation, but one removed earlier can't. A space we don't
+    /// have yet can only be created, and only by a member it names as its owner.
+    pub fn authorize_operation(&self, operation: &Operation, author: &IdentityID) -> Result<()> {
+        let space_id = match operation.context().space() {
+            Some(space_id) => space_id,
+            None => return Ok(()),
+        };
+        match (self.spaces.get(space_id), operation.action()) {
+            (Some(space), _) => space.authorize(author, operation),
+            (None, OperationAction::SpaceSetV1(space)) => {
+                if space.member_by_identity(author).map(|m| m.role() != &Role::Owner).unwrap_or(true) {
+                    Err(Error::PermissionDenied("only an owner can create a space".into()))?;
+                }
+                Ok(())
+            }
+            (None, _) => Err(Error::PermissionDenied("not a member of this space".into())),
+        }
+    }
+
+    /// Apply an operation created by the given identity, keeping track of when each member of a
+    /// space was last active. The operation is [authorized][State::authorize_operation] first,
+    /// and rejected with [`Error::PermissionDenied`] if its author isn't allowed to run it.
+    /// Otherwise the same as [`State::apply_operation_at`].
+    pub fn apply_operation_by(&mut self, operation: Operation, author: &IdentityID, timestamp: &Timestamp) -> Result<()> {
+        self.authorize_operation(&operation, author)?;
+        self.apply_authorized_by(operation, author, timestamp)
+    }
+
+    /// [`State::apply_operation_by`] for an operation that was already authorized, against the
+    /// space as of its place in the DAG (ie, one the inbox held back and checked at the time).
+    pub(crate) fn apply_authorized_by(&mut self, operation: Operation, author: &IdentityID, timestamp: &Timestamp) -> Result<()> {
+        let space_id = operation.context().space().clone();
+        self.apply_operation_at(operation, timestamp)?;
+        if let Some(space_id) = space_id.filter(|id| self.spaces.contains_key(id)) {
+            let last = self.last_active.entry(space_id.clone()).or_default()
+                .entry(author.clone())
+                .or_insert_with(|| timestamp.clone());
+            if &*last < timestamp {
+                *last = timestamp.clone();
+            }
+            self.dirty.activity.insert(space_id);
+        }
+        Ok(())
+    }
+
+    /// Apply an operation to this state object, using the given timestamp (generally the time the
+    /// operation's transaction was created) to track when objects were created/modified.
+    pub fn apply_operation_at(&mut self, operation: Operation, timestamp: &Timestamp) -> Result<()> {
+        let note_id = operation.context().note().clone();
+        self.apply_operation(operation)?;
+        if let Some(note_id) = note_id {
+            if self.notes.contains_key(&note_id) {
+                self.note_dates.entry(note_id)
+                    .or_insert_with(|| NoteDates::new(timestamp.clone(), timestamp.clone()))
+                    .touch(timestamp);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check an operation we're about to create before it's turned into a transaction, ie that
+    /// its data is [valid][Operation::validate], that it fits in its space's
+    /// [quota][Space::check_quota], and that it isn't removing a file live notes still use.
+    ///
+    /// These checks are only for our own operations. Applying an operation (ours or a peer's)
+    /// never runs them, since a peer's operation is already signed and every device has to end
+    /// up with the same state no matter what it thinks of it.
+    pub fn check_local_operation(&self, operation: &Operation) -> Result<()> {
+        operation.validate()?;
+        if let Some(space) = operation.context().space().as_ref().and_then(|id| self.spaces.get(id)) {
+            space.check_quota(self, operation)?;
+        }
+        if let (OperationAction::FileUnsetV1, Some(file_id)) = (operation.action(), operation.context().file().as_ref()) {
+            let referenced = self.file_referenced_by(file_id).len();
+            if referenced > 0 {
+                Err(Error::FileInUse(referenced))?;
+            }
+        }
+        Ok(())
+    }
+
     /// Apply an operation to this state object.
     pub fn apply_operation(&mut self, operation: Operation) -> Result<()> {
+        let approved = match operation.context().space().as_ref().and_then(|id| self.spaces.get(id)) {
+            Some(space) => space.check_quorum(operation.action())?.map(|hash| (space.id().clone(), hash)),
+            None => None,
+        };
+        let note_id = operation.context().note().clone();
+        let page_id = operation.context().page().clone();
+        let file_space = operation.context().file().as_ref().and(operation.context().space().clone());
+        let pages_before = note_id.as_ref().map(|id| self.pages_including(id));
+        let touched = self.touched_by(&operation);
+        let measure = self.measured_by(&operation, &touched);
+        let refs_before = touched.notes.iter()
+            .map(|id| (id.clone(), self.note_file_refs(id)))
+            .collect::<Vec<_>>();
+
+        self.apply_operation_inner(operation)?;
+        if let Some((space_id, objects)) = measure {
+            self.update_usage(&space_id, objects);
+        }
+        self.dirty.merge(touched);
+
+        // approvals are single-use
+        if let Some((space_id, hash)) = approved {
+            if let Some(space) = self.spaces.get_mut(&space_id) {
+                space.approvals_mut().retain(|a| a.action_hash() != &hash);
+            }
+            self.dirty.spaces.insert(space_id);
+        }
+
+        for (note_id, before) in refs_before {
+            self.update_file_refs(&note_id, before);
+        }
+
+        // keep our page counts in sync. if a note changed, only the pages it moved into or out of
+        // need updating. if a page changed, just recount it.
+        if let (Some(note_id), Some(before)) = (note_id, pages_before) {
+            let after = self.pages_including(&note_id);
+            for page_id in before.difference(&after) {
+                if let Some(count) = self.page_counts.get_mut(page_id) {
+                    *count = count.saturating_sub(1);
+                }
+            }
+            for page_id in after.difference(&before) {
+                *self.page_counts.entry(page_id.clone()).or_insert(0) += 1;
+            }
+        }
+        if let Some(page_id) = page_id {
+            self.recount_page(&page_id);
+        }
+        // a file changing can move notes in or out of pages that filter on file type
+        if let Some(space_id) = file_space {
+            let page_ids = self.pages.values()
+                .filter(|p| p.space_id() == &space_id)
+                .filter(|p| matches!(p.slice(), page::Slice::Filtered { filter, .. } if filter.mentions_file_type()))
+                .map(|p| p.id().clone())
+                .collect::<Vec<_>>();
+            for page_id in page_ids {
+                self.recount_page(&page_id);
+            }
+        }
+        let pages = &self.pages;
+        self.page_counts.retain(|id, _| pages.contains_key(id));
+        Ok(())
+    }
+
+    /// How much is in a space. Kept up to date as operations are applied, so this doesn't
+    /// have to re-measure the whole space.
+    pub fn space_usage(&self, space_id: &SpaceID) -> SpaceUsage {
+        self.space_usage.get(space_id).cloned().unwrap_or_default()
+    }
+
+    /// Measure an object for its space's [usage][State::space_usage], returning the space it
+    /// counts against and its size. Chunks count against their file's space, and aren't
+    /// counted until their file shows up.
+    fn measure(&self, object: &ObjectRef) -> Option<(SpaceID, u64)> {
+        fn size<T: rasn::Encode>(obj: &T) -> u64 {
+            rasn::der::encode(obj).map(|x| x.len() as u64).unwrap_or(0)
+        }
+        match object {
+            ObjectRef::Space(id) => self.spaces.get(id).map(|s| (id.clone(), size(s))),
+            ObjectRef::Page(id) => self.pages.get(id).map(|p| (p.space_id().clone(), size(p))),
+            ObjectRef::Note(id) => self.notes.get(id).map(|n| (n.space_id().clone(), size(n))),
+            ObjectRef::File(id) => self.files.get(id).map(|f| (f.space_id().clone(), size(f))),
+            ObjectRef::FileChunk(id) => self.chunks.get(id)
+                .and_then(|c| self.files.get(c.file_id()).map(|f| (f.space_id().clone(), size(c) + *c.len() as u64))),
+        }
+    }
+
+    /// Figure out which objects need re-measuring for [`State::space_usage`] once an operation
+    /// is applied: the ones it touches (see [`State::touched_by`]), plus any chunks it adds or
+    /// removes, or whose file it sets or removes. Call before applying it.
+    fn measured_by(&self, operation: &Operation, touched: &Dirty) -> Option<(SpaceID, Vec<ObjectRef>)> {
+        let space_id = operation.context().space().clone()?;
+        let mut objects = vec![ObjectRef::Space(space_id.clone())];
+        objects.extend(touched.notes.iter().cloned().map(ObjectRef::Note));
+        objects.extend(touched.pages.iter().cloned().map(ObjectRef::Page));
+        objects.extend(touched.files.iter().cloned().map(ObjectRef::File));
+        let file_id = match operation.action() {
+            OperationAction::FileSetChunkV1(chunk) => {
+                objects.push(ObjectRef::FileChunk(chunk.id().clone()));
+                None
+            }
+            OperationAction::FileSetV1(file) => Some(file.id()),
+            OperationAction::FileUnsetV1 => operation.context().file().as_ref(),
+            OperationAction::SpaceSetTombstoneV1(tombstone) => match tombstone.object() {
+                ObjectRef::File(file_id) => Some(file_id),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(file_id) = file_id {
+            objects.extend(self.chunks.values()
+                .filter(|c| c.file_id() == file_id)
+                .map(|c| ObjectRef::FileChunk(c.id().clone())));
+        }
+        Some((space_id, objects))
+    }
+
+    /// Re-measure the given objects, moving the difference into their spaces' usage.
+    fn update_usage(&mut self, space_id: &SpaceID, objects: Vec<ObjectRef>) {
+        for object in objects {
+            let measured = self.measure(&object);
+            if let Some((old_space, old_bytes)) = self.object_sizes.remove(&object) {
+                if let Some(usage) = self.space_usage.get_mut(&old_space) {
+                    usage.remove(&object, old_bytes);
+                }
+            }
+            if let Some((space, bytes)) = measured {
+                self.space_usage.entry(space.clone()).or_default().add(&object, bytes);
+                self.object_sizes.insert(object, (space, bytes));
+            }
+        }
+        // a removed space takes everything in it along
+        if !self.spaces.contains_key(space_id) {
+            self.space_usage.remove(space_id);
+            self.object_sizes.retain(|_, (s, _)| s != space_id);
+        }
+    }
+
+    /// Measure everything from scratch (ie, after loading) for [`State::space_usage`].
+    fn measure_all(&mut self) {
+        let mut by_space: HashMap<SpaceID, Vec<ObjectRef>> = HashMap::new();
+        for space_id in self.spaces.keys() {
+            by_space.entry(space_id.clone()).or_default().push(ObjectRef::Space(space_id.clone()));
+        }
+        for page in self.pages.values() {
+            by_space.entry(page.space_id().clone()).or_default().push(ObjectRef::Page(page.id().clone()));
+        }
+        for note in self.notes.values() {
+            by_space.entry(note.space_id().clone()).or_default().push(ObjectRef::Note(note.id().clone()));
+        }
+        for file in self.files.values() {
+            by_space.entry(file.space_id().clone()).or_default().push(ObjectRef::File(file.id().clone()));
+        }
+        // chunks without a file don't count anywhere, same as in `measure`
+        for chunk in self.chunks.values() {
+            if let Some(file) = self.files.get(chunk.file_id()) {
+                by_space.entry(file.space_id().clone()).or_default().push(ObjectRef::FileChunk(chunk.id().clone()));
+            }
+        }
+        for (space_id, objects) in by_space {
+            self.update_usage(&space_id, objects);
+        }
+    }
+
+    /// Figure out which objects an operation is going to change (or remove), for dirty
+    /// tracking. Call before applying it.
+    fn touched_by(&self, operation: &Operation) -> Dirty {
+        let mut touched = Dirty::default();
+        let context = operation.context();
+        let space_id = match context.space() {
+            Some(space_id) => space_id,
+            None => {
+                touched.user = true;
+                return touched;
+            }
+        };
+        touched.notes.extend(context.note().clone());
+        touched.pages.extend(context.page().clone());
+        touched.files.extend(context.file().clone());
+        match operation.action() {
+            OperationAction::NoteSetV1(note) | OperationAction::NoteSetV2 { note, .. } => {
+                touched.notes.insert(note.id().clone());
+            }
+            OperationAction::PageSetV1(page) => {
+                touched.pages.insert(page.id().clone());
+            }
+            OperationAction::FileSetV1(file) => {
+                touched.files.insert(file.id().clone());
+            }
+            OperationAction::FileSetChunkV1(chunk) => {
+                touched.files.insert(chunk.file_id().clone());
+            }
+            OperationAction::SpaceSetTombstoneV1(tombstone) => {
+                if let ObjectRef::File(file_id) = tombstone.object() {
+                    touched.files.insert(file_id.clone());
+                }
+            }
+            OperationAction::SpaceUnsetV1 => {
+                touched.activity.insert(space_id.clone());
+                touched.notes.extend(self.notes.values().filter(|n| n.space_id() == space_id).map(|n| n.id().clone()));
+                touched.pages.extend(self.pages.values().filter(|p| p.space_id() == space_id).map(|p| p.id().clone()));
+                touched.files.extend(self.files.values().filter(|f| f.space_id() == space_id).map(|f| f.id().clone()));
+            }
+            _ => {}
+        }
+        if context.note().is_none() && context.page().is_none() && context.file().is_none() {
+            touched.spaces.insert(space_id.clone());
+        }
+        touched
+    }
+
+    /// Mark everything as changed, so the next [`State::persist_dirty`] writes the whole state
+    /// (ie, the first time a state is persisted).
+    pub fn mark_all_dirty(&mut self) {
+        self.dirty = Dirty {
+            spaces: self.spaces.keys().cloned().collect(),
+            activity: self.last_active.keys().cloned().collect(),
+            pages: self.pages.keys().cloned().collect(),
+            notes: self.notes.keys().cloned().collect(),
+            files: self.files.keys().cloned().chain(self.chunks.values().map(|c| c.file_id().clone())).collect(),
+            user: true,
+        };
+    }
+
+    /// Write whatever changed since the last call to storage, and remove the records of objects
+    /// that are gone, instead of serializing the whole state. Returns how many records were
+    /// written or removed.
+    ///
+    /// Everything is written in one [storage transaction][Storage::atomic], so a crash never
+    /// leaves half a state on disk. If writing fails, nothing is written and everything stays
+    /// marked so the next call tries again.
+    pub fn persist_dirty(&mut self, storage: &dyn Storage) -> Result<usize> {
+        self.persist_dirty_with(storage, &mut || Ok(()))
+    }
+
+    /// [`State::persist_dirty`], with some other writes (ie, storing the transactions that led
+    /// to the changes) made in the same storage transaction.
+    pub(crate) fn persist_dirty_with(&mut self, storage: &dyn Storage, writes: &mut dyn FnMut() -> Result<()>) -> Result<usize> {
+        let dirty = std::mem::take(&mut self.dirty);
+        let mut count = 0;
+        let result = storage.atomic(&mut || {
+            writes()?;
+            count = self.persist(storage, &dirty)?;
+            Ok(())
+        });
+        match result {
+            Ok(()) => Ok(count),
+            Err(e) => {
+                self.dirty.merge(dirty);
+                Err(e)
+            }
+        }
+    }
+
+    fn space_record(&self, space_id: &SpaceID) -> Result<Option<Vec<u8>>> {
+        self.spaces.get(space_id)
+            .map(|space| to_record(&SpaceRecord { space: space.clone() }))
+            .transpose()
+    }
+
+    fn activity_record(&self, space_id: &SpaceID) -> Result<Option<Vec<u8>>> {
+        self.last_active.get(space_id)
+            .map(|last_active| {
+                let last_active = last_active.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+                to_record(&ActivityRecord { space_id: space_id.clone(), last_active })
+            })
+            .transpose()
+    }
+
+    fn page_record(&self, page_id: &PageID) -> Result<Option<Vec<u8>>> {
+        self.pages.get(page_id).map(to_record).transpose()
+    }
+
+    fn note_record(&self, note_id: &NoteID) -> Result<Option<Vec<u8>>> {
+        self.notes.get(note_id)
+            .map(|note| to_record(&NoteRecord { note: note.clone(), dates: self.note_dates.get(note_id).cloned() }))
+            .transpose()
+    }
+
+    fn file_record(&self, file_id: &FileID) -> Result<Option<Vec<u8>>> {
+        let file = self.files.get(file_id).cloned();
+        let chunks = self.chunks.values().filter(|c| c.file_id() == file_id).cloned().collect::<Vec<_>>();
+        if file.is_none() && chunks.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(to_record(&FileRecord { file_id: file_id.clone(), file, chunks })?))
+    }
+
+    fn user_record(&self) -> Result<Vec<u8>> {
+        to_record(&UserRecord {
+            space_restore_days: self.space_restore_days,
+            user_settings: self.user_settings.clone(),
+            newer_user_settings: self.newer_user_settings.clone(),
+            keychain: self.keychain.clone(),
+        })
+    }
+
+    fn persist(&self, storage: &dyn Storage, dirty: &Dirty) -> Result<usize> {
+        let put_or_delete = |kind: &str, id: &[u8], record: Option<Vec<u8>>| match record {
+            Some(data) => storage.put_record(kind, id, &data[..]),
+            None => storage.delete_record(kind, id),
+        };
+        for space_id in &dirty.spaces {
+            put_or_delete(RECORD_SPACE, &record_id(space_id)?, self.space_record(space_id)?)?;
+        }
+        for space_id in &dirty.activity {
+            put_or_delete(RECORD_ACTIVITY, &record_id(space_id)?, self.activity_record(space_id)?)?;
+        }
+        for page_id in &dirty.pages {
+            put_or_delete(RECORD_PAGE, &record_id(page_id)?, self.page_record(page_id)?)?;
+        }
+        for note_id in &dirty.notes {
+            put_or_delete(RECORD_NOTE, &record_id(note_id)?, self.note_record(note_id)?)?;
+            match self.notes.get(note_id) {
+                Some(note) => storage.put_note_meta(&NoteMeta::new(note, self.note_dates.get(note_id)))?,
+                None => storage.delete_note_meta(note_id)?,
+            }
+        }
+        for file_id in &dirty.files {
+            put_or_delete(RECORD_FILE, &record_id(file_id)?, self.file_record(file_id)?)?;
+        }
+        if dirty.user {
+            storage.put_record(RECORD_USER, &[], &self.user_record()?)?;
+        }
+        Ok(dirty.spaces.len() + dirty.activity.len() + dirty.pages.len() + dirty.notes.len() + dirty.files.len() + usize::from(dirty.user))
+    }
+
+    /// Persist this whole state over whatever state is in storage, ie after restoring one from a
+    /// backup. Records for objects this state doesn't have are removed.
+    pub fn persist_over(&mut self, storage: &dyn Storage) -> Result<usize> {
+        // a state that won't load is usually why we're here: everything this state has gets
+        // written over it regardless
+        let mut stale = Dirty::default();
+        if let Ok(mut existing) = Self::load(storage) {
+            existing.mark_all_dirty();
+            stale = existing.dirty;
+        }
+        self.mark_all_dirty();
+        self.dirty.merge(stale);
+        self.persist_dirty(storage)
+    }
+
+    /// Every record [`State::persist_dirty`] would write for the whole state, as (kind, data),
+    /// ie for putting in a snapshot. [`State::load_records`] turns them back into a state.
+    pub(crate) fn all_records(&self) -> Result<Vec<(&'static str, Vec<u8>)>> {
+        let mut records = Vec::new();
+        for space_id in self.spaces.keys() {
+            records.extend(self.space_record(space_id)?.map(|r| (RECORD_SPACE, r)));
+        }
+        for space_id in self.last_active.keys() {
+            records.extend(self.activity_record(space_id)?.map(|r| (RECORD_ACTIVITY, r)));
+        }
+        for page_id in self.pages.keys() {
+            records.extend(self.page_record(page_id)?.map(|r| (RECORD_PAGE, r)));
+        }
+        for note_id in self.notes.keys() {
+            records.extend(self.note_record(note_id)?.map(|r| (RECORD_NOTE, r)));
+        }
+        let file_ids = self.files.keys()
+            .chain(self.chunks.values().map(|c| c.file_id()))
+            .collect::<HashSet<_>>();
+        for file_id in file_ids {
+            records.extend(self.file_record(file_id)?.map(|r| (RECORD_FILE, r)));
+        }
+        records.push((RECORD_USER, self.user_record()?));
+        Ok(records)
+    }
+
+    /// Load a state written by [`State::persist_dirty`], rebuilding the indexes and counts that
+    /// don't get persisted.
+    pub fn load(storage: &dyn Storage) -> Result<Self> {
+        Self::load_records(|kind| storage.records(kind))
+    }
+
+    /// Build a state from scratch by replaying the transactions we've stored (and checked when
+    /// they came in), ie when the persisted state is gone. Operations are opened in parallel a
+    /// space at a time (see [`operation::open_ordered`]), trying each of a space's keys (as from
+    /// [`Keychain::space_keys`]). Returns the state along with each transaction that couldn't be
+    /// opened or applied.
+    pub fn load_from_transactions(transactions: Vec<Transaction>, user_key: &SecretKey, space_keys: &HashMap<SpaceID, Vec<SecretKey>>) -> Result<(Self, Vec<(TransactionID, Error)>)> {
+        let mut keys = HashMap::new();
+        keys.insert(None, vec![OperationKeys::new(user_key)?]);
+        for (space_id, secret_keys) in space_keys {
+            keys.insert(Some(space_id.clone()), secret_keys.iter().map(OperationKeys::new).collect::<Result<Vec<_>>>()?);
+        }
+        let mut state = Self::new();
+        let mut failed = Vec::new();
+        for (transaction, opened) in operation::open_ordered(transactions, &keys) {
+            let applied = opened.and_then(|op| {
+                let (creator, _) = operation::operation_from_transaction(&transaction)?;
+                state.apply_operation_by(op, creator, transaction.entry().created())
+            });
+            if let Err(e) = applied {
+                failed.push((transaction.id().clone(), e));
+            }
+        }
+        Ok((state, failed))
+    }
+
+    /// Load a state from its records, given a way to grab every record of a kind.
+    pub(crate) fn load_records<F: Fn(&str) -> Result<Vec<Vec<u8>>>>(records: F) -> Result<Self> {
+        let mut state = Self::new();
+        for data in records(RECORD_SPACE)? {
+            let SpaceRecord { space } = from_record(&data[..])?;
+            state.spaces.insert(space.id().clone(), space);
+        }
+        for data in records(RECORD_ACTIVITY)? {
+            let ActivityRecord { space_id, last_active } = from_record(&data[..])?;
+            state.last_active.insert(space_id, last_active.into_iter().collect());
+        }
+        for data in records(RECORD_PAGE)? {
+            let page: Page = from_record(&data[..])?;
+            state.pages.insert(page.id().clone(), page);
+        }
+        for data in records(RECORD_FILE)? {
+            let FileRecord { file, chunks, .. } = from_record(&data[..])?;
+            if let Some(file) = file {
+                state.files.insert(file.id().clone(), file);
+            }
+            state.chunks.extend(chunks.into_iter().map(|c| (c.id().clone(), c)));
+        }
+        for data in records(RECORD_NOTE)? {
+            let NoteRecord { note, dates } = from_record(&data[..])?;
+            let note_id = note.id().clone();
+            if let Some(dates) = dates {
+                state.note_dates.insert(note_id.clone(), dates);
+            }
+            state.set_note(note);
+            state.update_file_refs(&note_id, HashSet::new());
+        }
+        if let Some(data) = records(RECORD_USER)?.pop() {
+            let UserRecord { space_restore_days, user_settings, newer_user_settings, keychain } = from_record(&data[..])?;
+            state.space_restore_days = space_restore_days;
+            state.user_settings = user_settings;
+            state.newer_user_settings = newer_user_settings;
+            state.keychain = keychain;
+        }
+        let page_ids = state.pages.keys().cloned().collect::<Vec<_>>();
+        for page_id in page_ids {
+            state.recount_page(&page_id);
+        }
+        state.measure_all();
+        Ok(state)
+    }
+
+    /// Apply an operation without any of the index/count bookkeeping that [`State::apply_operation`]
+    /// does.
+    fn apply_operation_inner(&mut self, operation: Operation) -> Result<()> {
         let (context, action) = operation.consume();
         macro_rules! get_context {
             ($ty:ident) => {
@@ -43,6 +1086,18 @@ impl State {
             }
         }
         if let Some(space_id) = context.space() {
+            // a purged object stays gone, even if a peer that never purged sends it again
+            let revived = match &action {
+                OperationAction::NoteSetV1(note) | OperationAction::NoteSetV2 { note, .. } => Some(ObjectRef::Note(note.id().clone())),
+                OperationAction::FileSetV1(file) => Some(ObjectRef::File(file.id().clone())),
+                OperationAction::FileSetChunkV1(chunk) => Some(ObjectRef::File(chunk.file_id().clone())),
+                _ => None,
+            };
+            if let Some(object) = revived {
+                if self.spaces.get(space_id).map(|s| s.tombstones().iter().any(|t| t.object() == &object)).unwrap_or(false) {
+                    Err(Error::OperationInvalid(format!("{:?} was purged and can't be set again", object)))?;
+                }
+            }
             match action {
                 OperationAction::FileSetV1(file) => {
                     self.files_mut().insert(file.id().clone(), file);
@@ -56,64 +1111,390 @@ impl State {
                         *file.name_mut() = name;
                     }
                 }
+                OperationAction::FileSetPreviewV1(chunk_id) => {
+                    let file_id = get_context! { file }?;
+                    if let Some(file) = self.files_mut().get_mut(file_id) {
+                        *file.preview_chunk_mut() = chunk_id;
+                    }
+                }
                 OperationAction::FileUnsetV1 => {
                     let file_id = get_context! { file }?;
                     self.files_mut().remove(file_id);
                 }
                 OperationAction::NoteSetV1(note) => {
-                    self.notes_mut().insert(note.id().clone(), note);
+                    self.set_note(note);
+                }
+                OperationAction::NoteSetV2 { note, hash } => {
+                    if note.content_hash()? != hash {
+                        Err(Error::NoteHashMismatch)?;
+                    }
+                    self.set_note(note);
                 }
                 OperationAction::NoteSetBodySectionV1 { section_id, section, after } => {
                     let note_id = get_context! { note }?;
                     if let Some(note) = self.notes_mut().get_mut(note_id) {
+                        let order = note.body_mut().order_mut();
+                        order.retain(|id| id != &section_id);
+                        let idx = after
+                            .and_then(|after| order.iter().position(|id| id == &after))
+                            .map(|idx| idx + 1)
+                            .unwrap_or(0);
+                        order.insert(idx, section_id.clone());
                         note.body_mut().sections_mut().insert(section_id, section);
                     }
                 }
+                OperationAction::NoteSetDeletedV1(deleted) => {
+                    let note_id = get_context! { note }?;
+                    if let Some(note) = self.notes_mut().get_mut(note_id) {
+                        *note.deleted_mut() = deleted;
+                    }
+                }
                 OperationAction::NoteSetTagV1(tag) => {
+                    let note_id = get_context! { note }?;
+                    if let Some(note) = self.notes.get_mut(note_id) {
+                        if !note.tags().contains(&tag) {
+                            note.tags_mut().push(tag.clone());
+                            self.notes_by_tag.entry(tag).or_default().insert(note_id.clone());
+                        }
+                    }
+                }
+                OperationAction::NoteSetColorV1(color) => {
+                    let note_id = get_context! { note }?;
+                    if let Some(note) = self.notes_mut().get_mut(note_id) {
+                        *note.color_mut() = color;
+                    }
+                }
+                OperationAction::NoteSetIconV1(icon) => {
+                    let note_id = get_context! { note }?;
+                    if let Some(note) = self.notes_mut().get_mut(note_id) {
+                        *note.icon_mut() = icon;
+                    }
                 }
                 OperationAction::NoteSetTitleV1(title) => {
+                    let note_id = get_context! { note }?;
+                    if let Some(note) = self.notes_mut().get_mut(note_id) {
+                        *note.title_mut() = title;
+                    }
                 }
                 OperationAction::NoteUnsetV1 => {
+                    let note_id = get_context! { note }?;
+                    self.unset_note(note_id);
                 }
                 OperationAction::NoteUnsetBodySectionV1(section_id) => {
+                    let note_id = get_context! { note }?;
+                    if let Some(note) = self.notes_mut().get_mut(note_id) {
+                        note.body_mut().sections_mut().remove(&section_id);
+                        note.body_mut().order_mut().retain(|id| id != &section_id);
+                    }
                 }
                 OperationAction::NoteUnsetTagV1(tag) => {
+                    let note_id = get_context! { note }?;
+                    if let Some(note) = self.notes.get_mut(note_id) {
+                        note.tags_mut().retain(|t| t != &tag);
+                        self.unindex_tag(&tag, note_id);
+                    }
+                }
+                OperationAction::PageSetV1(mut page) => {
+                    page.migrate_slice_sort();
+                    self.pages_mut().insert(page.id().clone(), page);
+                }
+                OperationAction::PageSetDeletedV1(deleted) => {
+                    let page_id = get_context! { page }?;
+                    if let Some(page) = self.pages_mut().get_mut(page_id) {
+                        *page.deleted_mut() = deleted;
+                    }
                 }
-                OperationAction::PageSetV1(page) => {
+                OperationAction::PageSetDescriptionV1(description) => {
+                    let page_id = get_context! { page }?;
+                    if let Some(page) = self.pages_mut().get_mut(page_id) {
+                        *page.description_mut() = description;
+                    }
                 }
                 OperationAction::PageSetDisplayV1(display) => {
+                    let page_id = get_context! { page }?;
+                    if let Some(page) = self.pages_mut().get_mut(page_id) {
+                        *page.view_mut() = display;
+                    }
+                }
+                OperationAction::PageSetIconV1(icon) => {
+                    let page_id = get_context! { page }?;
+                    if let Some(page) = self.pages_mut().get_mut(page_id) {
+                        *page.icon_mut() = icon;
+                    }
                 }
                 OperationAction::PageSetSliceV1(slice) => {
+                    let page_id = get_context! { page }?;
+                    if let Some(page) = self.pages_mut().get_mut(page_id) {
+                        *page.slice_mut() = slice;
+                        page.migrate_slice_sort();
+                    }
+                }
+                OperationAction::PageSetSortV1(sort) => {
+                    let page_id = get_context! { page }?;
+                    if let Some(page) = self.pages_mut().get_mut(page_id) {
+                        *page.sort_mut() = sort;
+                    }
                 }
                 OperationAction::PageSetTitleV1(title) => {
+                    let page_id = get_context! { page }?;
+                    if let Some(page) = self.pages_mut().get_mut(page_id) {
+                        *page.title_mut() = title;
+                    }
                 }
                 OperationAction::PageUnsetV1 => {
+                    let page_id = get_context! { page }?;
+                    self.pages_mut().remove(page_id);
                 }
                 OperationAction::SpaceSetV1(space) => {
+                    if space.id() != space_id {
+                        Err(Error::OperationInvalid("space does not match the operation's space".into()))?;
+                    }
+                    space.check_quorum_reachable(*space.quorum())?;
+                    self.spaces_mut().insert(space.id().clone(), space);
+                }
+                OperationAction::SpaceSetArchivedV1(archived) => {
+                    if let Some(space) = self.spaces_mut().get_mut(space_id) {
+                        *space.archived_mut() = archived;
+                    }
+                }
+                OperationAction::SpaceSetApprovalV1(approval) => {
+                    if let Some(space) = self.spaces_mut().get_mut(space_id) {
+                        space.approvals_mut().retain(|a| a.member_id() != approval.member_id() || a.action_hash() != approval.action_hash());
+                        space.approvals_mut().push(approval);
+                    }
                 }
                 OperationAction::SpaceSetColorV1(color) => {
+                    if let Some(space) = self.spaces_mut().get_mut(space_id) {
+                        *space.color_mut() = color;
+                    }
+                }
+                OperationAction::SpaceSetConvergentChunksV1(convergent) => {
+                    if let Some(space) = self.spaces_mut().get_mut(space_id) {
+                        *space.convergent_chunks_mut() = convergent;
+                    }
+                }
+                OperationAction::SpaceSetDeletedV1(deleted) => {
+                    if let Some(space) = self.spaces_mut().get_mut(space_id) {
+                        *space.deleted_mut() = deleted;
+                    }
+                }
+                OperationAction::SpaceSetDefaultsV1(defaults) => {
+                    if let Some(space) = self.spaces_mut().get_mut(space_id) {
+                        *space.defaults_mut() = defaults;
+                    }
+                }
+                OperationAction::SpaceSetDefaultPageV1(page_id) => {
+                    if let Some(page_id) = page_id.as_ref() {
+                        if self.pages().get(page_id).map(|p| p.space_id() != space_id).unwrap_or(true) {
+                            Err(Error::OperationInvalid("default page is not in this space".into()))?;
+                        }
+                    }
+                    if let Some(space) = self.spaces_mut().get_mut(space_id) {
+                        *space.default_page_mut() = page_id;
+                    }
                 }
                 OperationAction::SpaceSetMemberV1(member) => {
+                    if member.space_id() != space_id {
+                        Err(Error::OperationInvalid("member does not belong to the operation's space".into()))?;
+                    }
+                    self.update_members(space_id, |space| {
+                        space.members_mut().retain(|m| m.id() != member.id());
+                        space.members_mut().push(member);
+                    })?;
+                }
+                OperationAction::SpaceSetMemberPermissionsV1 { member_id, permissions } => {
+                    self.update_members(space_id, |space| {
+                        if let Some(member) = space.member_mut(&member_id) {
+                            *member.permissions_mut() = permissions;
+                        }
+                    })?;
+                }
+                OperationAction::SpaceSetMemberProfileV1 { member_id, profile } => {
+                    if let Some(member) = self.spaces_mut().get_mut(space_id).and_then(|s| s.member_mut(&member_id)) {
+                        *member.profile_mut() = profile;
+                    }
                 }
                 OperationAction::SpaceSetMemberRoleV1 { member_id, role } => {
+                    self.update_members(space_id, |space| {
+                        if let Some(member) = space.member_mut(&member_id) {
+                            *member.role_mut() = role;
+                        }
+                    })?;
+                }
+                OperationAction::SpaceSetMemberScopeV1 { member_id, scope } => {
+                    if let Some(member) = self.spaces_mut().get_mut(space_id).and_then(|s| s.member_mut(&member_id)) {
+                        *member.scope_mut() = scope;
+                    }
+                }
+                OperationAction::SpaceSetMemberSyncMarkerV1 { member_id, frontier } => {
+                    if let Some(member) = self.spaces_mut().get_mut(space_id).and_then(|s| s.member_mut(&member_id)) {
+                        *member.sync_marker_mut() = frontier;
+                    }
+                }
+                OperationAction::SpaceSetKeyRotatedV1(rotation) => {
+                    if let Some(space) = self.spaces_mut().get_mut(space_id) {
+                        space.add_key_rotation(rotation);
+                    }
+                }
+                OperationAction::SpaceSetQuorumV1(quorum) => {
+                    if let Some(space) = self.spaces_mut().get_mut(space_id) {
+                        space.check_quorum_reachable(quorum)?;
+                        *space.quorum_mut() = quorum;
+                    }
+                }
+                OperationAction::SpaceSetQuotaV1(quota) => {
+                    if let Some(space) = self.spaces_mut().get_mut(space_id) {
+                        *space.quota_mut() = quota;
+                    }
+                }
+                OperationAction::SpaceSetViewerV1(viewer) => {
+                    if let Some(space) = self.spaces_mut().get_mut(space_id) {
+                        space.viewers_mut().retain(|v| v.id() != viewer.id());
+                        space.viewers_mut().push(viewer);
+                    }
                 }
                 OperationAction::SpaceSetTitleV1(title) => {
+                    if let Some(space) = self.spaces_mut().get_mut(space_id) {
+                        *space.title_mut() = title;
+                    }
+                }
+                OperationAction::SpaceSetTombstoneV1(tombstone) => {
+                    // only objects that are actually gone get tombstoned
+                    let gone = match tombstone.object() {
+                        ObjectRef::Note(note_id) => !self.notes.contains_key(note_id),
+                        ObjectRef::File(file_id) => !self.files.contains_key(file_id),
+                        ObjectRef::Space(..) | ObjectRef::Page(..) | ObjectRef::FileChunk(..) => Err(Error::OperationInvalid("only notes and files can be tombstoned".into()))?,
+                    };
+                    if gone {
+                        if let ObjectRef::File(file_id) = tombstone.object() {
+                            self.chunks.retain(|_, chunk| chunk.file_id() != file_id);
+                        }
+                        if let Some(space) = self.spaces_mut().get_mut(space_id) {
+                            if !space.tombstones().iter().any(|t| t.object() == tombstone.object()) {
+                                space.tombstones_mut().push(tombstone);
+                            }
+                        }
+                    }
                 }
                 OperationAction::SpaceUnsetV1 => {
+                    if self.spaces().get(space_id).map(|s| !s.deleted()).unwrap_or(false) {
+                        Err(Error::OperationInvalid("spaces must be deleted before they can be removed".into()))?;
+                    }
+                    self.unset_space(space_id);
                 }
                 OperationAction::SpaceUnsetMemberV1(member_id) => {
+                    self.update_members(space_id, |space| {
+                        space.members_mut().retain(|m| m.id() != &member_id);
+                    })?;
+                }
+                OperationAction::SpaceUnsetViewerV1(viewer_id) => {
+                    if let Some(space) = self.spaces_mut().get_mut(space_id) {
+                        space.viewers_mut().retain(|v| v.id() != &viewer_id);
+                    }
                 }
                 _ => Err(Error::OperationInvalid("User operation in non-user context".into()))?,
             }
         } else {
             // this operation has no space context, therefor it MUST be user-specific.
             match action {
-                OperationAction::UserSetSettingsV1(settings) => {
-                    *self.user_settings_mut() = settings;
+                OperationAction::UserSetSettingsV1(blob) => {
+                    let settings = blob.settings()?;
+                    if settings.schema_version() > USER_SETTINGS_VERSION {
+                        self.newer_user_settings = Some(blob);
+                    }
+                    self.user_settings_mut().merge(settings);
                 }
                 OperationAction::UserSetSettingsDefaultSpaceV1(space) => {
                     *self.user_settings_mut().default_space_mut() = space;
                 }
+                OperationAction::UserSetNoteReadStateV1 { note_id, read_state } => {
+                    let map = self.user_settings_mut().note_read_state_mut();
+                    match read_state {
+                        Some(read_state) => { map.insert(note_id, read_state); }
+                        None => { map.remove(&note_id); }
+                    }
+                }
+                OperationAction::UserSetSettingsThemeV1(theme) => {
+                    *self.user_settings_mut().theme_mut() = theme;
+                }
+                OperationAction::UserSetSettingsLocaleV1(locale) => {
+                    *self.user_settings_mut().locale_mut() = locale;
+                }
+                OperationAction::UserSetSettingsNoteSortV1(sort) => {
+                    *self.user_settings_mut().note_sort_mut() = sort;
+                }
+                OperationAction::UserSetSettingsEditorSpellcheckV1(spellcheck) => {
+                    *self.user_settings_mut().editor_mut().spellcheck_mut() = spellcheck;
+                }
+                OperationAction::UserSetSettingsEditorFontSizeV1(font_size) => {
+                    *self.user_settings_mut().editor_mut().font_size_mut() = font_size;
+                }
+                OperationAction::UserSetSettingsTrashPurgeDaysV1(days) => {
+                    *self.user_settings_mut().trash_purge_days_mut() = days;
+                }
+                OperationAction::UserSetDeviceV1(device) => {
+                    self.user_settings_mut().devices_mut().insert(device.id().clone(), device);
+                }
+                OperationAction::UserSetDeviceNameV1 { device_id, name } => {
+                    if let Some(device) = self.user_settings_mut().devices_mut().get_mut(&device_id) {
+                        *device.name_mut() = name;
+                    }
+                }
+                OperationAction::UserSetDeviceSyncedV1 { device_id, frontier } => {
+                    if let Some(device) = self.user_settings_mut().devices_mut().get_mut(&device_id) {
+                        *device.last_synced_mut() = frontier;
+                    }
+                }
+                OperationAction::UserSetDeviceSyncPolicyV1 { device_id, policy } => {
+                    if let Some(device) = self.user_settings_mut().devices_mut().get_mut(&device_id) {
+                        *device.sync_policy_mut() = policy;
+                    }
+                }
+                OperationAction::UserUnsetDeviceV1(device_id) => {
+                    self.user_settings_mut().devices_mut().remove(&device_id);
+                }
+                OperationAction::UserSetKeychainEntryV1 { space_id, entry } => {
+                    self.keychain_mut().add(space_id, entry);
+                }
+                OperationAction::UserUnsetKeychainEntryV1(space_id) => {
+                    self.keychain_mut().remove(&space_id);
+                }
+                OperationAction::UserSetFavoriteV1 { favorite, index } => {
+                    self.user_settings_mut().set_favorite(favorite, index);
+                }
+                OperationAction::UserUnsetFavoriteV1(favorite) => {
+                    self.user_settings_mut().favorites_mut().retain(|f| f != &favorite);
+                }
+                OperationAction::UserSetRecentViewsV1(views) => {
+                    self.user_settings_mut().add_recent_views(views);
+                }
+                OperationAction::UserSetProfileV1(profile) => {
+                    *self.user_settings_mut().profile_mut() = profile;
+                }
+                OperationAction::UserSetNotificationsDefaultV1(level) => {
+                    *self.user_settings_mut().default_notifications_mut() = level;
+                }
+                OperationAction::UserSetSpaceOverrideV1 { space_id, space_override } => {
+                    let overrides = self.user_settings_mut().space_overrides_mut();
+                    match space_override {
+                        Some(space_override) => { overrides.insert(space_id, space_override); }
+                        None => { overrides.remove(&space_id); }
+                    }
+                }
+                OperationAction::UserSetNotificationsSpaceV1 { space_id, level } => {
+                    let levels = self.user_settings_mut().space_notifications_mut();
+                    match level {
+                        Some(level) => { levels.insert(space_id, level); }
+                        None => { levels.remove(&space_id); }
+                    }
+                }
+                OperationAction::UserSetTagStyleV1 { tag, style } => {
+                    let styles = self.user_settings_mut().tag_styles_mut();
+                    match style {
+                        Some(style) => { styles.insert(tag, style); }
+                        None => { styles.remove(&tag); }
+                    }
+                }
                 _ => Err(Error::OperationInvalid("Non-user operation in user context".into()))?,
             }
         }
@@ -121,3 +1502,241 @@ impl State {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blob::FsBlobStore,
+        models::{
+            file::FileWriter,
+            note::{Section, SectionSpec, Tag, MAX_INDENT},
+            page::{Display, Page, Slice},
+            space::{SpaceDefaults, SpaceQuota, Tombstone},
+        },
+        test_util,
+    };
+
+    fn state_with_space() -> (State, SpaceID) {
+        let space = Space::new("test".into(), test_util::identity_id());
+        let space_id = space.id().clone();
+        let mut state = State::new();
+        state.apply_operation(Operation::space_set(space)).unwrap();
+        (state, space_id)
+    }
+
+    #[test]
+    fn space_defaults_apply_to_new_content() {
+        let (mut state, space_id) = state_with_space();
+        let defaults = SpaceDefaults::new(vec![Tag::new("project")], Some(Display::Grid), Some("🐢".into()), Some("green".into()));
+        state.apply_operation(Operation::space_set_defaults(space_id.clone(), defaults)).unwrap();
+        let space = state.spaces().get(&space_id).unwrap().clone();
+
+        let note = Note::new_in(&space, None, vec![Tag::new("mine")]);
+        assert_eq!(note.tags(), &vec![Tag::new("mine"), Tag::new("project")]);
+        assert_eq!(note.icon().as_deref(), Some("🐢"));
+        assert_eq!(note.color().as_deref(), Some("green"));
+        let page = Page::new_in(&space, "board".into(), Slice::Manual(vec![]));
+        assert!(matches!(page.view(), Display::Grid));
+
+        let note_id = note.id().clone();
+        state.apply_operation(Operation::note_set(space_id.clone(), note).unwrap()).unwrap();
+        state.apply_operation(Operation::note_set_icon(space_id.clone(), note_id.clone(), None)).unwrap();
+        state.apply_operation(Operation::note_set_color(space_id, note_id.clone(), Some("red".into()))).unwrap();
+        let note = state.notes().get(&note_id).unwrap();
+        assert_eq!(note.icon(), &None);
+        assert_eq!(note.color().as_deref(), Some("red"));
+    }
+
+    #[test]
+    fn blank_note_is_valid() {
+        let (mut state, space_id) = state_with_space();
+        let note = Note::new(space_id.clone(), None, vec![]);
+        let note_id = note.id().clone();
+        let op = Operation::note_set(space_id, note).unwrap();
+        state.check_local_operation(&op).unwrap();
+        state.apply_operation(op).unwrap();
+        assert!(state.notes().contains_key(&note_id));
+    }
+
+    #[test]
+    fn invalid_ops_are_only_rejected_locally() {
+        let (mut state, space_id) = state_with_space();
+        let mut note = Note::new(space_id.clone(), Some("deep".into()), vec![]);
+        note.push_section(Section::new(SectionSpec::Paragraph("too far".into()), MAX_INDENT + 1));
+        let note_id = note.id().clone();
+        let op = Operation::note_set(space_id, note).unwrap();
+        assert!(matches!(state.check_local_operation(&op), Err(Error::NoteInvalid(..))));
+        // a peer's operation is applied as-is
+        state.apply_operation(op).unwrap();
+        assert!(state.notes().contains_key(&note_id));
+    }
+
+    #[test]
+    fn usage_tracks_operations() {
+        let (mut state, space_id) = state_with_space();
+        let mut note_ids = Vec::new();
+        for i in 0..3 {
+            let note = Note::new(space_id.clone(), Some(format!("note {}", i)), vec![]);
+            note_ids.push(note.id().clone());
+            state.apply_operation(Operation::note_set(space_id.clone(), note).unwrap()).unwrap();
+        }
+        state.apply_operation(Operation::note_set_title(space_id.clone(), note_ids[0].clone(), Some("a much longer title than before".into()))).unwrap();
+        state.apply_operation(Operation::note_unset(space_id.clone(), note_ids[1].clone())).unwrap();
+
+        let usage = state.space_usage(&space_id);
+        assert_eq!(*usage.notes(), 2);
+        // the running count matches measuring everything from scratch
+        let fresh = State::load_records(|kind| Ok(state.all_records()?.into_iter().filter(|(k, _)| *k == kind).map(|(_, r)| r).collect())).unwrap();
+        let measured = fresh.space_usage(&space_id);
+        assert_eq!(usage.notes(), measured.notes());
+        assert_eq!(usage.bytes(), measured.bytes());
+    }
+
+    #[test]
+    fn measure_all_matches_incremental_usage() {
+        let blobs = FsBlobStore::new(test_util::temp_dir("state-measure")).unwrap();
+        let (mut state, space_id) = state_with_space();
+        let key = SecretKey::new_xchacha20poly1305().unwrap();
+        let data = vec![7u8; 3000];
+        let mut writer = FileWriter::new(&data[..], &key, space_id.clone(), "big.bin".into(), None)
+            .with_chunk_size(1024)
+            .with_inline_threshold(0);
+        let mut chunk_ops = Vec::new();
+        while let Some(chunk) = writer.next_chunk().unwrap() {
+            chunk_ops.push(chunk.store(&blobs).unwrap());
+        }
+        // chunks can show up before or after their file
+        let late = chunk_ops.pop().unwrap();
+        let file_op = writer.finish().unwrap();
+        let mut lost_writer = FileWriter::new(&data[..], &key, space_id.clone(), "lost.bin".into(), None)
+            .with_inline_threshold(0);
+        // and this one never gets its file
+        let lost = lost_writer.next_chunk().unwrap().unwrap().store(&blobs).unwrap();
+        for op in chunk_ops {
+            state.apply_operation(op).unwrap();
+        }
+        state.apply_operation(lost).unwrap();
+        assert_eq!(*state.space_usage(&space_id).chunks(), 0);
+        state.apply_operation(file_op).unwrap();
+        state.apply_operation(late).unwrap();
+        state.apply_operation(Operation::note_set(space_id.clone(), Note::new(space_id.clone(), None, vec![])).unwrap()).unwrap();
+
+        let incremental = state.space_usage(&space_id);
+        assert_eq!(*incremental.files(), 1);
+        assert_eq!(*incremental.chunks(), 3);
+        state.object_sizes.clear();
+        state.space_usage.clear();
+        state.measure_all();
+        assert_eq!(state.space_usage(&space_id), incremental);
+    }
+
+    #[test]
+    fn usage_survives_a_serde_round_trip() {
+        let (mut state, space_id) = state_with_space();
+        state.apply_operation(Operation::note_set(space_id.clone(), Note::new(space_id.clone(), None, vec![])).unwrap()).unwrap();
+        let usage = state.space_usage(&space_id);
+        assert_eq!(*usage.notes(), 1);
+        let restored: State = serde_json::from_slice(&serde_json::to_vec(&state).unwrap()).unwrap();
+        assert_eq!(restored.space_usage(&space_id), usage);
+        assert_eq!(restored.object_sizes, state.object_sizes);
+    }
+
+    #[test]
+    fn quota_counts_each_kind_separately() {
+        let (mut state, space_id) = state_with_space();
+        state.apply_operation(Operation::space_set_quota(space_id.clone(), Some(SpaceQuota::new(Some(1), Some(1), None, None)))).unwrap();
+        state.apply_operation(Operation::note_set(space_id.clone(), Note::new(space_id.clone(), None, vec![])).unwrap()).unwrap();
+        let page = Operation::page_set(space_id.clone(), Page::new(space_id.clone(), "one".into(), Slice::Manual(vec![])));
+        state.check_local_operation(&page).unwrap();
+        state.apply_operation(page).unwrap();
+        let another = Operation::page_set(space_id.clone(), Page::new(space_id.clone(), "two".into(), Slice::Manual(vec![])));
+        match state.check_local_operation(&another) {
+            Err(Error::QuotaExceeded(msg)) => assert_eq!(msg, "space can only have 1 pages"),
+            _ => panic!("expected the page quota to be hit"),
+        }
+    }
+
+    #[test]
+    fn tombstoned_objects_cant_come_back() {
+        let (mut state, space_id) = state_with_space();
+        let note = Note::new(space_id.clone(), Some("gone soon".into()), vec![]);
+        let note_id = note.id().clone();
+        state.apply_operation(Operation::note_set(space_id.clone(), note.clone()).unwrap()).unwrap();
+        state.apply_operation(Operation::note_unset(space_id.clone(), note_id.clone())).unwrap();
+        let tombstone = Tombstone::new(ObjectRef::Note(note_id.clone()), test_util::transaction_id());
+        state.apply_operation(Operation::space_set_tombstone(space_id.clone(), tombstone)).unwrap();
+
+        let again = Operation::note_set(space_id.clone(), note).unwrap();
+        assert!(matches!(state.apply_operation(again), Err(Error::OperationInvalid(..))));
+        assert!(!state.notes().contains_key(&note_id));
+        // anything else in the space is fine
+        state.apply_operation(Operation::note_set(space_id.clone(), Note::new(space_id, None, vec![])).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn quota_only_enforced_locally() {
+        let (mut state, space_id) = state_with_space();
+        state.apply_operation(Operation::space_set_quota(space_id.clone(), Some(SpaceQuota::new(Some(1), None, None, None)))).unwrap();
+        let first = Operation::note_set(space_id.clone(), Note::new(space_id.clone(), None, vec![])).unwrap();
+        state.check_local_operation(&first).unwrap();
+        state.apply_operation(first).unwrap();
+
+        let second = Operation::note_set(space_id.clone(), Note::new(space_id.clone(), None, vec![])).unwrap();
+        assert!(matches!(state.check_local_operation(&second), Err(Error::QuotaExceeded(..))));
+        // a peer that didn't know about the quota yet still gets its note
+        state.apply_operation(second).unwrap();
+        assert_eq!(*state.space_usage(&space_id).notes(), 2);
+    }
+
+    #[test]
+    fn file_in_use_only_checked_locally() {
+        let (mut state, space_id) = state_with_space();
+        let file_id = FileID::new();
+        let mut note = Note::new(space_id.clone(), Some("has a file".into()), vec![]);
+        let note_id = note.id().clone();
+        let section_id = note.push_section(Section::new(SectionSpec::File { id: file_id.clone(), embed: true }, 0));
+        state.apply_operation(Operation::note_set(space_id.clone(), note).unwrap()).unwrap();
+        assert_eq!(state.file_referenced_by(&file_id).len(), 1);
+
+        let unset = Operation::file_unset(space_id.clone(), file_id.clone());
+        assert!(matches!(state.check_local_operation(&unset), Err(Error::FileInUse(1))));
+
+        // dropping the section drops the reference
+        state.apply_operation(Operation::note_unset_body_section(space_id.clone(), note_id, section_id)).unwrap();
+        assert!(state.file_referenced_by(&file_id).is_empty());
+        assert!(state.file_refs().get(&file_id).is_none());
+        state.check_local_operation(&unset).unwrap();
+    }
+
+    #[test]
+    fn peer_file_unset_applies_while_referenced() {
+        let (mut state, space_id) = state_with_space();
+        let file_id = FileID::new();
+        let mut note = Note::new(space_id.clone(), None, vec![]);
+        note.push_section(Section::new(SectionSpec::File { id: file_id.clone(), embed: false }, 0));
+        state.apply_operation(Operation::note_set(space_id.clone(), note).unwrap()).unwrap();
+        state.apply_operation(Operation::file_unset(space_id, file_id.clone())).unwrap();
+        assert!(state.files().get(&file_id).is_none());
+    }
+
+    #[test]
+    fn archived_spaces_left_out_of_queries() {
+        let (mut state, space_id) = state_with_space();
+        let note = Note::new(space_id.clone(), Some("archived soon".into()), vec![]);
+        let note_id = note.id().clone();
+        state.apply_operation(Operation::note_set(space_id.clone(), note).unwrap()).unwrap();
+        let page = Page::new(space_id.clone(), "all".into(), page::Slice::Manual(vec![note_id]));
+        let page_id = page.id().clone();
+        state.apply_operation(Operation::page_set(space_id.clone(), page)).unwrap();
+        assert_eq!(state.page_notes(&page_id).len(), 1);
+
+        state.apply_operation(Operation::space_set_archived(space_id.clone(), true)).unwrap();
+        assert!(state.page_notes(&page_id).is_empty());
+        assert!(state.page_counts(&space_id).is_empty());
+
+        state.apply_operation(Operation::space_set_archived(space_id.clone(), false)).unwrap();
+        assert_eq!(state.page_notes(&page_id).len(), 1);
+        assert_eq!(state.page_counts(&space_id).get(&page_id), Some(&1));
+    }
+}
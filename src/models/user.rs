@@ -2,19 +2,757 @@
 //! effectively just Stamp identities, so there's no real concept of a user outside of a handful of
 //! cross-device settings.
 
-use crate::models::{
-    space::SpaceID,
+use crate::{
+    error::{Error, Result},
+    models::{
+        object_id,
+        file::{File, FileID},
+        note::{NoteID, SectionID, Tag},
+        page::{PageID, SortEntry},
+        space::{MemberProfile, Space, SpaceID},
+    },
 };
 use getset::{Getters, MutGetters};
-use rasn::{AsnType, Decode, Encode};
-use serde::{Deserialize, Serialize};
+use rasn::{types::Any, AsnType, Decode, Encode};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use stamp_core::{
+    dag::TransactionID,
+    util::{HashMapAsn1, Timestamp},
+};
+
+/// The current [`UserSettings`] schema version.
+///
+/// - 1: `default_space` and `note_read_state` (settings with no version at all are version 1)
+/// - 2: theme, locale, editor, devices, favorites, etc
+/// - 3: `space_overrides`
+/// - 4: device sync policies
+///
+/// Bump this whenever a field is added to `UserSettings`, and add the field to
+/// [`UserSettings::merge`] (and bump [`MAX_SETTINGS_TAG`]).
+pub const USER_SETTINGS_VERSION: u32 = 4;
+
+/// The highest field tag [`UserSettings`] knows about. Fields tagged past this come from clients
+/// newer than us (see [`SettingsBlob`]).
+const MAX_SETTINGS_TAG: u32 = 15;
+
+/// How many recently-viewed items we hang onto
+pub const MAX_RECENT_VIEWS: usize = 50;
+
+object_id! {
+    /// A unique id for one of a user's devices
+    DeviceID
+}
+
+/// What kind of system a device runs
+#[derive(Clone, Debug, PartialEq, AsnType, Encode, Decode, Deserialize, Serialize)]
+#[rasn(choice)]
+pub enum Platform {
+    #[rasn(tag(explicit(0)))]
+    Linux,
+    #[rasn(tag(explicit(1)))]
+    MacOs,
+    #[rasn(tag(explicit(2)))]
+    Windows,
+    #[rasn(tag(explicit(3)))]
+    Android,
+    #[rasn(tag(explicit(4)))]
+    Ios,
+    #[rasn(tag(explicit(5)))]
+    Web,
+    /// Anything we don't have a name for
+    #[rasn(tag(explicit(6)))]
+    Other(String),
+}
+
+/// One of the devices a user has signed in on. Devices live in the user's own settings so they
+/// can see everywhere they're signed in and revoke devices they don't recognize (or lost).
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Getters, MutGetters, Serialize)]
+#[getset(get = "pub", get_mut = "pub(crate)")]
+pub struct Device {
+    #[rasn(tag(explicit(0)))]
+    id: DeviceID,
+    /// A name the user recognizes, ie "Work laptop"
+    #[rasn(tag(explicit(1)))]
+    name: String,
+    #[rasn(tag(explicit(2)))]
+    platform: Platform,
+    /// When this device was registered
+    #[rasn(tag(explicit(3)))]
+    added: Timestamp,
+    /// The user DAG's frontier as of this device's last sync
+    #[rasn(tag(explicit(4)))]
+    last_synced: Vec<TransactionID>,
+    /// What this device does and doesn't pull down
+    #[rasn(tag(explicit(5)), default)]
+    #[serde(default)]
+    sync_policy: SyncPolicy,
+}
+
+impl Device {
+    /// Create a new device
+    pub fn new(id: DeviceID, name: String, platform: Platform, added: Timestamp) -> Self {
+        Self { id, name, platform, added, last_synced: Vec::new(), sync_policy: SyncPolicy::default() }
+    }
+}
+
+/// Limits on what a device syncs, so (for instance) a phone doesn't pull down gigabytes of
+/// attachments. The default syncs everything.
+#[derive(Clone, Debug, Default, PartialEq, AsnType, Encode, Decode, Deserialize, Getters, Serialize)]
+#[getset(get = "pub")]
+pub struct SyncPolicy {
+    /// Don't download the data of files bigger than this (in bytes). The file objects themselves
+    /// still sync, so the data can be fetched on demand.
+    #[rasn(tag(explicit(0)))]
+    max_file_size: Option<u64>,
+    /// Only sync the space itself (its title, members, etc) for archived spaces, and hold off on
+    /// their notes, pages, and files until they're unarchived.
+    #[rasn(tag(explicit(1)))]
+    archived_metadata_only: bool,
+}
+
+impl SyncPolicy {
+    /// Create a new sync policy
+    pub fn new(max_file_size: Option<u64>, archived_metadata_only: bool) -> Self {
+        Self { max_file_size, archived_metadata_only }
+    }
+
+    /// Whether to sync anything beyond the space object itself for this space
+    pub fn wants_space_contents(&self, space: &Space) -> bool {
+        !(self.archived_metadata_only && *space.archived())
+    }
+
+    /// Whether to download this file's data (chunks and preview)
+    pub fn wants_file_data(&self, space: &Space, file: &File) -> bool {
+        self.wants_space_contents(space) && self.max_file_size.map(|max| *file.size() <= max).unwrap_or(true)
+    }
+}
+
+/// Where a user left off in a note. This is personal and never shared with the note's space.
+#[derive(Clone, Default, AsnType, Encode, Decode, Deserialize, Getters, MutGetters, Serialize)]
+#[getset(get = "pub", get_mut = "pub(crate)")]
+pub struct NoteReadState {
+    /// The section the user was scrolled to. We track by section instead of by offset because
+    /// every device has a different screen size.
+    #[rasn(tag(explicit(0)))]
+    scroll_section: Option<SectionID>,
+    /// When the user last opened this note
+    #[rasn(tag(explicit(1)))]
+    last_opened: Option<Timestamp>,
+    /// Sections the user has collapsed
+    #[rasn(tag(explicit(2)))]
+    collapsed: Vec<SectionID>,
+}
+
+impl NoteReadState {
+    /// Create a new read state
+    pub fn new(scroll_section: Option<SectionID>, last_opened: Option<Timestamp>, collapsed: Vec<SectionID>) -> Self {
+        Self { scroll_section, last_opened, collapsed }
+    }
+}
+
+/// Which color scheme the app uses
+#[derive(Clone, Debug, PartialEq, AsnType, Encode, Decode, Deserialize, Serialize)]
+#[rasn(choice)]
+pub enum Theme {
+    /// Follow whatever the OS is doing
+    #[rasn(tag(explicit(0)))]
+    System,
+    #[rasn(tag(explicit(1)))]
+    Light,
+    #[rasn(tag(explicit(2)))]
+    Dark,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::System
+    }
+}
+
+/// Preferences for the note editor
+#[derive(Clone, Default, AsnType, Encode, Decode, Deserialize, Getters, MutGetters, Serialize)]
+#[getset(get = "pub", get_mut = "pub(crate)")]
+pub struct EditorSettings {
+    /// Whether spellcheck is on. `None` leaves it up to the platform.
+    #[rasn(tag(explicit(0)))]
+    spellcheck: Option<bool>,
+    /// The editor's font size, in points. `None` uses the app's default.
+    #[rasn(tag(explicit(1)))]
+    font_size: Option<u8>,
+}
+
+/// Something the user has starred
+#[derive(Clone, Debug, PartialEq, AsnType, Encode, Decode, Deserialize, Serialize)]
+#[rasn(choice)]
+pub enum Favorite {
+    #[rasn(tag(explicit(0)))]
+    Note(NoteID),
+    #[rasn(tag(explicit(1)))]
+    Page(PageID),
+    #[rasn(tag(explicit(2)))]
+    Space(SpaceID),
+}
+
+/// Something the user can open and come back to later
+#[derive(Clone, Debug, PartialEq, AsnType, Encode, Decode, Deserialize, Serialize)]
+#[rasn(choice)]
+pub enum ViewTarget {
+    #[rasn(tag(explicit(0)))]
+    Note(NoteID),
+    #[rasn(tag(explicit(1)))]
+    Page(PageID),
+}
+
+/// A note or page the user opened, and when.
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Getters, Serialize)]
+#[getset(get = "pub")]
+pub struct RecentView {
+    #[rasn(tag(explicit(0)))]
+    target: ViewTarget,
+    #[rasn(tag(explicit(1)))]
+    opened: Timestamp,
+}
+
+impl RecentView {
+    /// Create a new recent view
+    pub fn new(target: ViewTarget, opened: Timestamp) -> Self {
+        Self { target, opened }
+    }
+}
+
+/// How the user wants a tag to look. This is personal, so users can style tags in spaces they
+/// can't edit.
+#[derive(Clone, Default, AsnType, Encode, Decode, Deserialize, Getters, MutGetters, Serialize)]
+#[getset(get = "pub", get_mut = "pub(crate)")]
+pub struct TagStyle {
+    /// The tag's color (hex)
+    #[rasn(tag(explicit(0)))]
+    color: Option<String>,
+    /// An emoji to show next to the tag
+    #[rasn(tag(explicit(1)))]
+    emoji: Option<String>,
+    /// A name to show instead of the tag itself
+    #[rasn(tag(explicit(2)))]
+    alias: Option<String>,
+}
+
+impl TagStyle {
+    /// Create a new tag style
+    pub fn new(color: Option<String>, emoji: Option<String>, alias: Option<String>) -> Self {
+        Self { color, emoji, alias }
+    }
+}
+
+/// The profile a user shows to others by default. Spaces are encrypted separately, so this gets
+/// published into each space as a [`MemberProfile`] (which the user can also change per space).
+#[derive(Clone, Default, AsnType, Encode, Decode, Deserialize, Getters, MutGetters, Serialize)]
+#[getset(get = "pub", get_mut = "pub(crate)")]
+pub struct UserProfile {
+    #[rasn(tag(explicit(0)))]
+    display_name: Option<String>,
+    #[rasn(tag(explicit(1)))]
+    pronouns: Option<String>,
+    /// The avatar image. Files belong to a space, so publishing the profile into another space
+    /// means uploading the avatar there too.
+    #[rasn(tag(explicit(2)))]
+    avatar: Option<FileID>,
+}
+
+impl UserProfile {
+    /// Create a new user profile
+    pub fn new(display_name: Option<String>, pronouns: Option<String>, avatar: Option<FileID>) -> Self {
+        Self { display_name, pronouns, avatar }
+    }
+
+    /// Turn this profile into a member profile for a space. `avatar` is the avatar's file in
+    /// that space, if it has one.
+    pub fn to_member_profile(&self, avatar: Option<FileID>, joined: Option<Timestamp>) -> MemberProfile {
+        MemberProfile::new(self.display_name.clone(), self.pronouns.clone(), avatar, joined)
+    }
+}
+
+/// How much a space should bother the user
+#[derive(Clone, Debug, PartialEq, AsnType, Encode, Decode, Deserialize, Serialize)]
+#[rasn(choice)]
+pub enum NotificationLevel {
+    /// Notify on any activity in the space
+    #[rasn(tag(explicit(0)))]
+    All,
+    /// Only notify when the user is mentioned
+    #[rasn(tag(explicit(1)))]
+    Mentions,
+    /// Never notify
+    #[rasn(tag(explicit(2)))]
+    Muted,
+}
+
+impl Default for NotificationLevel {
+    fn default() -> Self {
+        Self::All
+    }
+}
+
+/// A user's own tweaks to a shared space, which nobody else sees.
+#[derive(Clone, Default, AsnType, Encode, Decode, Deserialize, Getters, MutGetters, Serialize)]
+#[getset(get = "pub", get_mut = "pub(crate)")]
+pub struct SpaceOverride {
+    /// Quiet this space down (clients might hide it or sort it last)
+    #[rasn(tag(explicit(0)))]
+    muted: bool,
+    /// A color to use instead of the space's
+    #[rasn(tag(explicit(1)))]
+    color: Option<String>,
+    /// A title to use instead of the space's
+    #[rasn(tag(explicit(2)))]
+    title: Option<String>,
+}
+
+impl SpaceOverride {
+    /// Create a new space override
+    pub fn new(muted: bool, color: Option<String>, title: Option<String>) -> Self {
+        Self { muted, color, title }
+    }
+}
+
+/// A space as the user sees it, with their [`SpaceOverride`] (if any) layered over it.
+#[derive(Getters)]
+#[getset(get = "pub")]
+pub struct SpaceView<'a> {
+    /// The shared space underneath
+    space: &'a Space,
+    title: &'a str,
+    color: Option<&'a str>,
+    muted: bool,
+}
+
+impl<'a> SpaceView<'a> {
+    /// Layer an override over a space.
+    pub fn new(space: &'a Space, space_override: Option<&'a SpaceOverride>) -> Self {
+        let title = space_override.and_then(|o| o.title().as_deref()).unwrap_or(space.title().as_str());
+        let color = space_override.and_then(|o| o.color().as_deref()).or(space.color().as_deref());
+        let muted = space_override.map(|o| *o.muted()).unwrap_or(false);
+        Self { space, title, color, muted }
+    }
+}
 
-/// A user's settings
-#[derive(Default, AsnType, Encode, Decode, Deserialize, Getters, MutGetters, Serialize)]
+/// A user's settings.
+///
+/// Settings get written whole by [`UserSetSettingsV1`][crate::models::operation::OperationAction::UserSetSettingsV1],
+/// so they have to decode no matter which client wrote them. Every field added after the first
+/// version is marked `default`, meaning settings from older clients decode with that field empty,
+/// and the `version` field tells us which fields the writer actually knew about (see
+/// [`UserSettings::merge`]).
+#[derive(Clone, Default, AsnType, Encode, Decode, Deserialize, Getters, MutGetters, Serialize)]
 #[getset(get = "pub", get_mut = "pub(crate)")]
+#[serde(default)]
 pub struct UserSettings {
     /// The space we show when the user logs in
     #[rasn(tag(explicit(0)))]
-    default_space: Option<SpaceID>
+    default_space: Option<SpaceID>,
+    /// Per-note reading state (scroll position, collapsed sections, etc)
+    #[rasn(tag(explicit(1)), default)]
+    note_read_state: HashMapAsn1<NoteID, NoteReadState>,
+    /// The app's color scheme
+    #[rasn(tag(explicit(2)), default)]
+    theme: Theme,
+    /// The user's preferred locale (ie "en-US"). `None` uses the system locale.
+    #[rasn(tag(explicit(3)))]
+    locale: Option<String>,
+    /// How notes are sorted on pages that don't specify their own sort
+    #[rasn(tag(explicit(4)), default)]
+    note_sort: Vec<SortEntry>,
+    /// Note editor preferences
+    #[rasn(tag(explicit(5)), default)]
+    editor: EditorSettings,
+    /// How many days notes stay in the trash before they're purged for good. `None` keeps them
+    /// until they're removed by hand.
+    #[rasn(tag(explicit(6)))]
+    trash_purge_days: Option<u32>,
+    /// The devices this user has registered
+    #[rasn(tag(explicit(7)), default)]
+    devices: HashMapAsn1<DeviceID, Device>,
+    /// The user's starred notes/pages/spaces, in the order they want them shown
+    #[rasn(tag(explicit(8)), default)]
+    favorites: Vec<Favorite>,
+    /// Recently opened notes/pages, most recent first. Capped at [`MAX_RECENT_VIEWS`].
+    #[rasn(tag(explicit(9)), default)]
+    recent_views: Vec<RecentView>,
+    /// The user's own styling for tags
+    #[rasn(tag(explicit(10)), default)]
+    tag_styles: HashMapAsn1<Tag, TagStyle>,
+    /// The profile this user publishes to their spaces by default
+    #[rasn(tag(explicit(11)))]
+    profile: Option<UserProfile>,
+    /// The notification level for spaces that don't have their own
+    #[rasn(tag(explicit(12)), default)]
+    default_notifications: NotificationLevel,
+    /// Per-space notification levels
+    #[rasn(tag(explicit(13)), default)]
+    space_notifications: HashMapAsn1<SpaceID, NotificationLevel>,
+    /// Which schema version these settings were written with. See [`USER_SETTINGS_VERSION`].
+    #[rasn(tag(explicit(14)), default)]
+    version: u32,
+    /// The user's own tweaks to shared spaces
+    #[rasn(tag(explicit(15)), default)]
+    space_overrides: HashMapAsn1<SpaceID, SpaceOverride>,
 }
 
+/// Read a DER header: the first byte, the tag number, how long the header is, and how long the
+/// contents are. Returns `None` if the header is truncated, isn't valid DER (indefinite or
+/// non-minimal lengths), or claims more contents than `data` has.
+fn der_header(data: &[u8]) -> Option<(u8, u32, usize, usize)> {
+    let first = *data.first()?;
+    let mut pos = 1;
+    let mut tag = (first & 0x1f) as u32;
+    if tag == 0x1f {
+        tag = 0;
+        loop {
+            let byte = *data.get(pos)?;
+            pos += 1;
+            tag = tag.checked_mul(128)? | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+    }
+    let len_byte = *data.get(pos)?;
+    pos += 1;
+    let len = if len_byte < 0x80 {
+        len_byte as usize
+    } else {
+        let count = (len_byte & 0x7f) as usize;
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let bytes = data.get(pos..pos + count)?;
+        // DER lengths are minimal: no leading zeros, and no long form for short lengths
+        if bytes[0] == 0 {
+            return None;
+        }
+        let len = bytes.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize);
+        if len < 0x80 {
+            return None;
+        }
+        pos += count;
+        len
+    };
+    if data.len() - pos < len {
+        return None;
+    }
+    Some((first, tag, pos, len))
+}
+
+/// Encode a DER length
+fn der_length(len: usize) -> Vec<u8> {
+    if len < 0x80 {
+        return vec![len as u8];
+    }
+    let bytes = len.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    let mut out = vec![0x80 | (bytes.len() - skip) as u8];
+    out.extend(&bytes[skip..]);
+    out
+}
+
+/// [`UserSettings`] as they're written in an operation, kept encoded.
+///
+/// Settings from a client newer than us can have fields we don't know about. Decoding them
+/// drops those fields, so the encoded settings are kept around (see
+/// [`State::newer_user_settings`][crate::models::state::State::newer_user_settings]) and their
+/// unknown fields are written back out the next time we write settings.
+#[derive(Clone, AsnType, Encode, Decode)]
+#[rasn(delegate)]
+pub struct SettingsBlob(Any);
+
+impl SettingsBlob {
+    /// Encode some settings, along with the fields from `newer` that we don't know about.
+    pub fn new(settings: &UserSettings, newer: Option<&SettingsBlob>) -> Result<Self> {
+        let encoded = rasn::der::encode(settings).map_err(|_| Error::ASNSerialize)?;
+        let unknown = match newer {
+            Some(newer) => newer.unknown_fields()?,
+            None => Vec::new(),
+        };
+        if unknown.is_empty() {
+            return Ok(Self(Any::new(encoded)));
+        }
+        let (first, _, header_len, len) = der_header(&encoded[..]).ok_or(Error::ASNSerialize)?;
+        let mut contents = encoded[header_len..header_len + len].to_vec();
+        // unknown fields are tagged past ours, so they go at the end
+        contents.extend(unknown);
+        let mut out = vec![first];
+        out.extend(der_length(contents.len()));
+        out.extend(contents);
+        Ok(Self(Any::new(out)))
+    }
+
+    /// Decode the settings, dropping any fields we don't know about.
+    pub fn settings(&self) -> Result<UserSettings> {
+        rasn::der::decode(self.0.as_bytes()).map_err(|_| Error::ASNDeserialize)
+    }
+
+    /// The encoded fields tagged past anything [`UserSettings`] knows about.
+    fn unknown_fields(&self) -> Result<Vec<u8>> {
+        let data = self.0.as_bytes();
+        let (_, _, header_len, len) = der_header(data).ok_or(Error::ASNDeserialize)?;
+        let mut rest = &data[header_len..header_len + len];
+        let mut unknown = Vec::new();
+        while !rest.is_empty() {
+            let (first, tag, field_header_len, field_len) = der_header(rest).ok_or(Error::ASNDeserialize)?;
+            let field_end = field_header_len + field_len;
+            // context-specific tags only, which is all the settings fields use
+            if first >> 6 == 2 && tag > MAX_SETTINGS_TAG {
+                unknown.extend(&rest[..field_end]);
+            }
+            rest = &rest[field_end..];
+        }
+        Ok(unknown)
+    }
+}
+
+impl Serialize for SettingsBlob {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        self.0.as_bytes().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SettingsBlob {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        Ok(Self(Any::new(Vec::<u8>::deserialize(deserializer)?)))
+    }
+}
+
+impl UserSettings {
+    /// Which schema version these settings were written with. Settings from before we tracked
+    /// versions are version 1.
+    pub fn schema_version(&self) -> u32 {
+        self.version.max(1)
+    }
+
+    /// Stamp these settings with our schema version, ie before sending them out. We only ever
+    /// claim the fields we know about: fields from newer clients are carried along by
+    /// [`SettingsBlob`], not by the version.
+    pub(crate) fn stamp_version(&mut self) {
+        self.version = USER_SETTINGS_VERSION;
+    }
+
+    /// Take on a full set of settings written by some client.
+    ///
+    /// Only the fields the writer knew about are copied over: if an old client writes its
+    /// settings, it can't know about (for instance) our favorites, so we keep the favorites we
+    /// have instead of wiping them out. Settings from a client newer than us are taken as-is,
+    /// but at our version: the fields we don't know about are kept in the [`SettingsBlob`] they
+    /// came in (see [`State::newer_user_settings`][crate::models::state::State::newer_user_settings]).
+    pub(crate) fn merge(&mut self, incoming: UserSettings) {
+        let version = incoming.schema_version();
+        if version >= USER_SETTINGS_VERSION {
+            *self = incoming;
+            self.version = self.version.min(USER_SETTINGS_VERSION);
+            return;
+        }
+        let Self {
+            default_space, note_read_state,
+            theme, locale, note_sort, editor, trash_purge_days, devices, favorites, recent_views,
+            tag_styles, profile, default_notifications, space_notifications,
+            space_overrides,
+            ..
+        } = incoming;
+        self.default_space = default_space;
+        self.note_read_state = note_read_state;
+        if version >= 2 {
+            self.theme = theme;
+            self.locale = locale;
+            self.note_sort = note_sort;
+            self.editor = editor;
+            self.trash_purge_days = trash_purge_days;
+            // older clients don't know about sync policies, so keep the ones we have
+            let mut devices = devices;
+            if version < 4 {
+                for (device_id, device) in devices.iter_mut() {
+                    if let Some(existing) = self.devices.get(device_id) {
+                        device.sync_policy = existing.sync_policy.clone();
+                    }
+                }
+            }
+            self.devices = devices;
+            self.favorites = favorites;
+            self.recent_views = recent_views;
+            self.tag_styles = tag_styles;
+            self.profile = profile;
+            self.default_notifications = default_notifications;
+            self.space_notifications = space_notifications;
+        }
+        if version >= 3 {
+            self.space_overrides = space_overrides;
+        }
+    }
+
+    /// How the user sees a space, with their override (if any) applied
+    pub fn space_view<'a>(&'a self, space: &'a Space) -> SpaceView<'a> {
+        SpaceView::new(space, self.space_overrides.get(space.id()))
+    }
+    /// How much the given space should notify the user
+    pub fn notification_level(&self, space_id: &SpaceID) -> &NotificationLevel {
+        self.space_notifications.get(space_id).unwrap_or(&self.default_notifications)
+    }
+
+    /// The name the user wants shown for a tag, if they've given it one.
+    pub fn tag_alias(&self, tag: &Tag) -> Option<&str> {
+        self.tag_styles.get(tag).and_then(|s| s.alias().as_deref())
+    }
+
+    /// Whether the given item is in the favorites list
+    pub fn is_favorite(&self, favorite: &Favorite) -> bool {
+        self.favorites.contains(favorite)
+    }
+
+    /// Move a favorite to a new position, adding it if it isn't in the list yet. Indexes past the
+    /// end of the list put it at the end.
+    pub(crate) fn set_favorite(&mut self, favorite: Favorite, index: Option<u32>) {
+        let existing = self.favorites.iter().position(|f| f == &favorite);
+        if let Some(pos) = existing {
+            if index.is_none() {
+                return;
+            }
+            self.favorites.remove(pos);
+        }
+        let index = index.map(|i| (i as usize).min(self.favorites.len())).unwrap_or(self.favorites.len());
+        self.favorites.insert(index, favorite);
+    }
+
+    /// Merge a batch of views into the recent list. Each target only shows up once (at its latest
+    /// open time), so devices syncing the same views in different orders end up agreeing.
+    pub(crate) fn add_recent_views(&mut self, views: Vec<RecentView>) {
+        for view in views {
+            match self.recent_views.iter().position(|v| v.target() == view.target()) {
+                Some(pos) if **self.recent_views[pos].opened() >= **view.opened() => continue,
+                Some(pos) => { self.recent_views.remove(pos); }
+                None => {}
+            }
+            let index = self.recent_views.iter()
+                .position(|v| **v.opened() < **view.opened())
+                .unwrap_or(self.recent_views.len());
+            self.recent_views.insert(index, view);
+        }
+        self.recent_views.truncate(MAX_RECENT_VIEWS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a context-specific, constructed (ie, explicitly tagged) field
+    fn context_field(tag: u32, contents: &[u8]) -> Vec<u8> {
+        let mut out = if tag < 0x1f {
+            vec![0xa0 | tag as u8]
+        } else {
+            let mut tag_bytes = vec![(tag & 0x7f) as u8];
+            let mut rest = tag >> 7;
+            while rest > 0 {
+                tag_bytes.insert(0, 0x80 | (rest & 0x7f) as u8);
+                rest >>= 7;
+            }
+            let mut out = vec![0xbf];
+            out.extend(tag_bytes);
+            out
+        };
+        out.extend(der_length(contents.len()));
+        out.extend(contents);
+        out
+    }
+
+    #[test]
+    fn der_header_reads_short_and_long_forms() {
+        assert_eq!(der_header(&[0xa3, 0x02, 0x05, 0x00]), Some((0xa3, 3, 2, 2)));
+        assert_eq!(der_header(&[0xa3, 0x00]), Some((0xa3, 3, 2, 0)));
+
+        let mut long = vec![0x30, 0x81, 0x80];
+        long.extend(vec![0u8; 0x80]);
+        assert_eq!(der_header(&long[..]), Some((0x30, 16, 3, 0x80)));
+        let mut longer = vec![0x30, 0x82, 0x01, 0x00];
+        longer.extend(vec![0u8; 0x100]);
+        assert_eq!(der_header(&longer[..]), Some((0x30, 16, 4, 0x100)));
+
+        // multi-byte tag numbers
+        assert_eq!(der_header(&[0xbf, 0x1f, 0x00]), Some((0xbf, 31, 3, 0)));
+        assert_eq!(der_header(&[0xbf, 0x81, 0x00, 0x00]), Some((0xbf, 128, 4, 0)));
+    }
+
+    #[test]
+    fn der_header_rejects_bad_input() {
+        let bad: &[&[u8]] = &[
+            // nothing there
+            &[],
+            // no length
+            &[0xa3],
+            // tag number never ends
+            &[0xbf, 0x81],
+            &[0xbf, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f, 0x00],
+            // contents run past the end
+            &[0xa3, 0x05, 0x00],
+            // long-form length cut short
+            &[0x30, 0x82, 0x01],
+            // long-form length longer than the data
+            &[0x30, 0x81, 0x90, 0x00],
+            // indefinite length
+            &[0x30, 0x80, 0x00, 0x00],
+            // more length bytes than we handle
+            &[0x30, 0x85, 0x01, 0x00, 0x00, 0x00, 0x00],
+            // non-minimal lengths
+            &[0x30, 0x81, 0x05, 0, 0, 0, 0, 0],
+            &[0x30, 0x82, 0x00, 0x80],
+        ];
+        for data in bad {
+            assert_eq!(der_header(data), None, "{:x?}", data);
+        }
+    }
+
+    #[test]
+    fn der_length_round_trips() {
+        for len in [0usize, 1, 0x7f, 0x80, 0xff, 0x100, 0xffff, 0x10000] {
+            let mut data = vec![0x04];
+            data.extend(der_length(len));
+            let header_len = data.len();
+            data.extend(vec![0u8; len]);
+            assert_eq!(der_header(&data[..]), Some((0x04, 4, header_len, len)));
+        }
+    }
+
+    #[test]
+    fn unknown_settings_fields_survive_a_rewrite() {
+        let settings = UserSettings::default();
+        let encoded = rasn::der::encode(&settings).unwrap();
+        let (first, _, header_len, len) = der_header(&encoded[..]).unwrap();
+        let known = &encoded[header_len..header_len + len];
+        let newer_field = context_field(MAX_SETTINGS_TAG + 1, &[0x02, 0x01, 0x2a]);
+        let far_field = context_field(200, &[0x0c, 0x02, b'h', b'i']);
+        let mut contents = known.to_vec();
+        contents.extend(&newer_field);
+        contents.extend(&far_field);
+        let mut newer = vec![first];
+        newer.extend(der_length(contents.len()));
+        newer.extend(contents);
+        let newer = SettingsBlob(Any::new(newer));
+
+        let mut expected = newer_field.clone();
+        expected.extend(&far_field);
+        assert_eq!(newer.unknown_fields().unwrap(), expected);
+        assert_eq!(newer.settings().unwrap().schema_version(), settings.schema_version());
+
+        let rewritten = SettingsBlob::new(&settings, Some(&newer)).unwrap();
+        assert_eq!(rewritten.unknown_fields().unwrap(), expected);
+        assert_eq!(rewritten.0.as_bytes(), newer.0.as_bytes());
+
+        let plain = SettingsBlob::new(&settings, None).unwrap();
+        assert!(plain.unknown_fields().unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_settings_blobs_error() {
+        let truncated = SettingsBlob(Any::new(vec![0x30, 0x05, 0xa0, 0x03]));
+        assert!(truncated.unknown_fields().is_err());
+        let bad_field = SettingsBlob(Any::new(vec![0x30, 0x02, 0xa0, 0x05]));
+        assert!(bad_field.unknown_fields().is_err());
+        assert!(SettingsBlob::new(&UserSettings::default(), Some(&bad_field)).is_err());
+    }
+}
@@ -0,0 +1,693 @@
+//! Pairing a new device with an existing one, so the new device gets the user's keys without
+//! the user having to type in a recovery code or passphrase.
+//!
+//! The flow is the same on every platform:
+//!
+//! 1. The existing device (the *host*) starts a [`PairingHost`] and shows its [`PairingCode`],
+//!    either as a short code to type in or as a QR code.
+//! 2. The new device (the *guest*) enters the code, starts a [`PairingGuest`], and sends the
+//!    host a [`PairingHello`] describing itself, along with a commitment to its nonce.
+//! 3. The host checks the hello and answers with a [`PairingAccept`] naming the identity being
+//!    paired and carrying the host's nonce.
+//! 4. The guest answers with a [`PairingReveal`] opening its commitment. Both devices now show
+//!    the same [verification code][PairingHost::verification_code], and the user confirms on
+//!    both that they match (and, on the guest, that the identity is theirs).
+//! 5. Once confirmed, the host sends a [`KeychainTransfer`] holding the master key and keychain,
+//!    and registers the new device in the user's settings.
+//!
+//! Every message is authenticated with a key derived from the pairing code, so only a device
+//! that saw the code can take part, and the transfer is sealed under a key that also depends on
+//! both devices' nonces. How the messages get from one device to the other (local network, a
+//! relay) is up to the client: they're safe to send over an untrusted channel.
+//!
+//! The verification code is what protects against someone in the middle who also saw the
+//! pairing code. The guest commits to its nonce before it sees the host's, and the host reveals
+//! its nonce before it sees the guest's, so nobody in the middle can pick a nonce after seeing
+//! both real ones and steer the two devices to the same code: they get one guess in a million.
+
+use crate::{
+    error::{Error, Result},
+    models::{
+        keychain::Keychain,
+        operation::Operation,
+        user::Device,
+    },
+};
+use getset::Getters;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rasn::{AsnType, Decode, Encode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use stamp_core::{
+    crypto::{
+        base::{Sealed, SecretKey},
+        seal,
+    },
+    identity::IdentityID,
+    util::Timestamp,
+};
+use std::fmt;
+use std::str::FromStr;
+use zeroize::Zeroizing;
+
+/// Which pairing code format we produce. Bumped if the layout of a code ever changes.
+const PAIRING_CODE_VERSION: u8 = 1;
+
+/// How much randomness is in a pairing code
+const SECRET_LEN: usize = 20;
+
+/// How much randomness each device contributes to the session
+const NONCE_LEN: usize = 16;
+
+/// How long a commitment to a nonce is (a SHA-256 hash)
+const COMMITMENT_LEN: usize = 32;
+
+/// How many characters go between the dashes in a printed code
+const GROUP_SIZE: usize = 4;
+
+/// What goes in front of a code when it's shown as a QR code
+const QR_PREFIX: &str = "turtl:pair:";
+
+/// How long a pairing code is good for
+pub const PAIRING_TIMEOUT_SECS: i64 = 300;
+
+const AUTH_LABEL: &[u8] = b"turtl/pairing/auth/v1";
+const TRANSFER_LABEL: &[u8] = b"turtl/pairing/transfer/v1";
+const VERIFY_LABEL: &[u8] = b"turtl/pairing/verify/v1";
+const COMMIT_LABEL: &[u8] = b"turtl/pairing/commit/v1";
+
+/// Grab some random bytes
+fn random_bytes(len: usize) -> Result<Zeroizing<Vec<u8>>> {
+    let mut bytes = Zeroizing::new(vec![0u8; len]);
+    getrandom::getrandom(&mut bytes[..]).map_err(|e| Error::PairingFailed(format!("no randomness: {}", e)))?;
+    Ok(bytes)
+}
+
+/// Derive a key from the pairing secret. `salt` ties the key to a particular session (ie, both
+/// devices' nonces).
+fn derive(secret: &[u8], salt: Option<&[u8]>, label: &[u8]) -> Result<Zeroizing<[u8; 32]>> {
+    let mut okm = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(salt, secret)
+        .expand(label, &mut okm[..])
+        .map_err(|_| Error::PairingFailed("key derivation failed".into()))?;
+    Ok(okm)
+}
+
+/// MAC a list of fields with the pairing auth key
+fn authenticate(secret: &[u8], parts: &[&[u8]]) -> Result<Hmac<Sha256>> {
+    let key = derive(secret, None, AUTH_LABEL)?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key[..])
+        .map_err(|_| Error::PairingFailed("bad auth key".into()))?;
+    for part in parts {
+        mac.update(&(part.len() as u64).to_be_bytes());
+        mac.update(part);
+    }
+    Ok(mac)
+}
+
+/// The salt that ties session keys to both devices' nonces
+fn session_salt(guest_nonce: &[u8], host_nonce: &[u8]) -> Vec<u8> {
+    let mut salt = Vec::with_capacity(guest_nonce.len() + host_nonce.len());
+    salt.extend_from_slice(guest_nonce);
+    salt.extend_from_slice(host_nonce);
+    salt
+}
+
+/// Commit to the guest's nonce without giving it away
+fn commitment(nonce: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(COMMIT_LABEL);
+    hasher.update(nonce);
+    hasher.finalize().to_vec()
+}
+
+/// The six-digit code both devices show so the user can check they're talking to each other.
+fn verification_code(secret: &[u8], guest_nonce: &[u8], host_nonce: &[u8]) -> Result<String> {
+    let okm = derive(secret, Some(&session_salt(guest_nonce, host_nonce)[..]), VERIFY_LABEL)?;
+    let num = u32::from_be_bytes([okm[0], okm[1], okm[2], okm[3]]) % 1_000_000;
+    Ok(format!("{:06}", num))
+}
+
+/// The key the host shows the guest, ie `AEBA-GBAF-...`, or as a QR code.
+///
+/// Like a [`RecoveryCode`][crate::recovery::RecoveryCode], a pairing code carries a version and a
+/// short checksum so typos are caught when the code is entered.
+#[derive(Clone, PartialEq)]
+pub struct PairingCode {
+    secret: Zeroizing<Vec<u8>>,
+}
+
+impl PairingCode {
+    fn checksum(body: &[u8]) -> [u8; 2] {
+        let digest = Sha256::digest(body);
+        [digest[0], digest[1]]
+    }
+
+    /// The text to put in a QR code. [`PairingCode::from_str`] reads this as well as the typed
+    /// version.
+    pub fn to_qr_payload(&self) -> String {
+        format!("{}{}", QR_PREFIX, self)
+    }
+}
+
+impl fmt::Display for PairingCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut body = Zeroizing::new(vec![PAIRING_CODE_VERSION]);
+        body.extend_from_slice(&self.secret[..]);
+        let checksum = Self::checksum(&body[..]);
+        body.extend_from_slice(&checksum[..]);
+        let encoded = Zeroizing::new(data_encoding::BASE32_NOPAD.encode(&body[..]));
+        let grouped = encoded.as_bytes()
+            .chunks(GROUP_SIZE)
+            .map(|c| std::str::from_utf8(c).unwrap_or_default())
+            .collect::<Vec<_>>()
+            .join("-");
+        f.write_str(&grouped)
+    }
+}
+
+impl fmt::Debug for PairingCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PairingCode(..)")
+    }
+}
+
+impl FromStr for PairingCode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let s = s.strip_prefix(QR_PREFIX).unwrap_or(s);
+        let normalized = Zeroizing::new(s.chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_uppercase())
+            .collect::<String>());
+        let body = Zeroizing::new(data_encoding::BASE32_NOPAD.decode(normalized.as_bytes())
+            .map_err(|_| Error::PairingFailed("code has invalid characters".into()))?);
+        if body.len() != SECRET_LEN + 3 {
+            Err(Error::PairingFailed("code is the wrong length".into()))?;
+        }
+        let (payload, checksum) = body.split_at(body.len() - 2);
+        if Self::checksum(payload) != checksum {
+            Err(Error::PairingFailed("code has a typo (checksum mismatch)".into()))?;
+        }
+        if payload[0] != PAIRING_CODE_VERSION {
+            Err(Error::PairingFailed(format!("unknown code version {}", payload[0])))?;
+        }
+        Ok(Self { secret: Zeroizing::new(payload[1..].to_vec()) })
+    }
+}
+
+/// Where a pairing session is at. Both sides go through the same states.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PairingState {
+    /// The host is showing its code and waiting for a guest to say hello (on the guest, the hello
+    /// is out and we're waiting to hear back)
+    Offered,
+    /// The host has answered a hello and is waiting for the guest to reveal its nonce
+    Answered,
+    /// The devices have found each other and the user needs to check the verification code
+    Verifying,
+    /// The user confirmed the verification code on this device
+    Confirmed,
+    /// The keys have been handed over
+    Complete,
+    /// Pairing was cancelled, timed out, or a message didn't check out. Start over.
+    Aborted,
+}
+
+/// Sent by the guest once the user has entered the host's code.
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct PairingHello {
+    /// The device that wants to be paired, as it'll show up in the user's device list
+    #[rasn(tag(explicit(0)))]
+    device: Device,
+    /// A hash of the guest's share of the session randomness, which is only revealed (in a
+    /// [`PairingReveal`]) once the host has sent its own
+    #[rasn(tag(explicit(1)))]
+    commitment: Vec<u8>,
+    /// Proves the guest knows the pairing code
+    #[rasn(tag(explicit(2)))]
+    mac: Vec<u8>,
+}
+
+impl PairingHello {
+    fn mac_for(secret: &[u8], device: &Device, commitment: &[u8]) -> Result<Hmac<Sha256>> {
+        let device_ser = rasn::der::encode(device).map_err(|_| Error::ASNSerialize)?;
+        authenticate(secret, &[b"hello", &device_ser[..], commitment])
+    }
+
+    /// Serialize this hello for sending
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        rasn::der::encode(self).map_err(|_| Error::ASNSerialize)
+    }
+
+    /// Read a hello we received
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        rasn::der::decode(bytes).map_err(|_| Error::ASNDeserialize)
+    }
+}
+
+/// The host's answer to a [`PairingHello`].
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct PairingAccept {
+    /// The identity the guest is being paired with, so the user can check it's theirs
+    #[rasn(tag(explicit(0)))]
+    identity_id: IdentityID,
+    /// The host's share of the session randomness
+    #[rasn(tag(explicit(1)))]
+    nonce: Vec<u8>,
+    /// Proves the host knows the pairing code, and ties this answer to the guest's hello
+    #[rasn(tag(explicit(2)))]
+    mac: Vec<u8>,
+}
+
+impl PairingAccept {
+    fn mac_for(secret: &[u8], identity_id: &IdentityID, nonce: &[u8], guest_commitment: &[u8]) -> Result<Hmac<Sha256>> {
+        let identity_ser = rasn::der::encode(identity_id).map_err(|_| Error::ASNSerialize)?;
+        authenticate(secret, &[b"accept", &identity_ser[..], nonce, guest_commitment])
+    }
+
+    /// Serialize this answer for sending
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        rasn::der::encode(self).map_err(|_| Error::ASNSerialize)
+    }
+
+    /// Read an answer we received
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        rasn::der::decode(bytes).map_err(|_| Error::ASNDeserialize)
+    }
+}
+
+/// Sent by the guest once it has the host's [`PairingAccept`], opening the commitment from its
+/// [`PairingHello`].
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct PairingReveal {
+    /// The guest's share of the session randomness
+    #[rasn(tag(explicit(0)))]
+    nonce: Vec<u8>,
+    /// Proves the guest knows the pairing code, and ties this to the host's answer
+    #[rasn(tag(explicit(1)))]
+    mac: Vec<u8>,
+}
+
+impl PairingReveal {
+    fn mac_for(secret: &[u8], nonce: &[u8], host_nonce: &[u8]) -> Result<Hmac<Sha256>> {
+        authenticate(secret, &[b"reveal", nonce, host_nonce])
+    }
+
+    /// Serialize this reveal for sending
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        rasn::der::encode(self).map_err(|_| Error::ASNSerialize)
+    }
+
+    /// Read a reveal we received
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        rasn::der::decode(bytes).map_err(|_| Error::ASNDeserialize)
+    }
+}
+
+/// What's inside a [`KeychainTransfer`]
+#[derive(AsnType, Encode, Decode)]
+struct TransferPayload {
+    #[rasn(tag(explicit(0)))]
+    identity_id: IdentityID,
+    #[rasn(tag(explicit(1)))]
+    master_key: SecretKey,
+    #[rasn(tag(explicit(2)))]
+    keychain: Keychain,
+}
+
+/// The user's keys, sealed for the guest under the session key.
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize)]
+pub struct KeychainTransfer {
+    /// A sealed [`TransferPayload`]
+    #[rasn(tag(explicit(0)))]
+    sealed: Sealed,
+}
+
+impl KeychainTransfer {
+    fn session_key(secret: &[u8], guest_nonce: &[u8], host_nonce: &[u8]) -> Result<SecretKey> {
+        let key = derive(secret, Some(&session_salt(guest_nonce, host_nonce)[..]), TRANSFER_LABEL)?;
+        Ok(SecretKey::new_xchacha20poly1305_from_slice(&key[..])?)
+    }
+
+    /// Serialize this transfer for sending
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        rasn::der::encode(self).map_err(|_| Error::ASNSerialize)
+    }
+
+    /// Read a transfer we received
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        rasn::der::decode(bytes).map_err(|_| Error::ASNDeserialize)
+    }
+}
+
+/// The existing device's side of a pairing.
+pub struct PairingHost {
+    identity_id: IdentityID,
+    secret: Zeroizing<Vec<u8>>,
+    nonce: Zeroizing<Vec<u8>>,
+    expires: Timestamp,
+    /// The guest's device and nonce commitment, once it's said hello
+    hello: Option<(Device, Vec<u8>)>,
+    /// The guest's nonce, once it's been revealed
+    guest_nonce: Option<Vec<u8>>,
+    state: PairingState,
+}
+
+impl PairingHost {
+    /// Start pairing a new device with `identity_id`. The code is good for
+    /// [`PAIRING_TIMEOUT_SECS`] from `now`.
+    pub fn new(identity_id: IdentityID, now: &Timestamp) -> Result<Self> {
+        Ok(Self {
+            identity_id,
+            secret: random_bytes(SECRET_LEN)?,
+            nonce: random_bytes(NONCE_LEN)?,
+            expires: Timestamp::from(**now + chrono::Duration::seconds(PAIRING_TIMEOUT_SECS)),
+            hello: None,
+            guest_nonce: None,
+            state: PairingState::Offered,
+        })
+    }
+
+    /// The code to show the guest
+    pub fn code(&self) -> PairingCode {
+        PairingCode { secret: self.secret.clone() }
+    }
+
+    /// Where this pairing is at
+    pub fn state(&self) -> &PairingState {
+        &self.state
+    }
+
+    /// The device asking to be paired, once it's said hello
+    pub fn guest_device(&self) -> Option<&Device> {
+        self.hello.as_ref().map(|(device, _)| device)
+    }
+
+    /// The code to show the user so they can check it matches the guest's. Only available once
+    /// the guest has revealed its nonce.
+    pub fn verification_code(&self) -> Option<String> {
+        let guest_nonce = self.guest_nonce.as_ref()?;
+        verification_code(&self.secret[..], guest_nonce, &self.nonce[..]).ok()
+    }
+
+    /// Check a guest's hello and build our answer.
+    ///
+    /// A hello that doesn't check out aborts the pairing: the code may have been seen by
+    /// someone it shouldn't have, so it's better to start over with a new one than let them
+    /// keep guessing.
+    pub fn receive_hello(&mut self, hello: &PairingHello, now: &Timestamp) -> Result<PairingAccept> {
+        if self.state != PairingState::Offered {
+            Err(Error::PairingFailed(format!("not expecting a hello ({:?})", self.state)))?;
+        }
+        if now > &self.expires {
+            self.state = PairingState::Aborted;
+            Err(Error::PairingFailed("pairing code expired".into()))?;
+        }
+        let verified = PairingHello::mac_for(&self.secret[..], hello.device(), hello.commitment())?
+            .verify_slice(hello.mac())
+            .is_ok();
+        if !verified || hello.commitment().len() != COMMITMENT_LEN {
+            self.state = PairingState::Aborted;
+            Err(Error::PairingFailed("hello didn't check out".into()))?;
+        }
+        let mac = PairingAccept::mac_for(&self.secret[..], &self.identity_id, &self.nonce[..], hello.commitment())?
+            .finalize()
+            .into_bytes()
+            .to_vec();
+        self.hello = Some((hello.device().clone(), hello.commitment().clone()));
+        self.state = PairingState::Answered;
+        Ok(PairingAccept { identity_id: self.identity_id.clone(), nonce: self.nonce.to_vec(), mac })
+    }
+
+    /// Check the guest's nonce against the commitment in its hello. After this, both devices
+    /// can show their verification codes.
+    pub fn receive_reveal(&mut self, reveal: &PairingReveal, now: &Timestamp) -> Result<()> {
+        let committed = match (&self.state, self.hello.as_ref()) {
+            (PairingState::Answered, Some((_, committed))) => committed,
+            _ => Err(Error::PairingFailed(format!("not expecting a reveal ({:?})", self.state)))?,
+        };
+        if now > &self.expires {
+            self.state = PairingState::Aborted;
+            Err(Error::PairingFailed("pairing code expired".into()))?;
+        }
+        let verified = PairingReveal::mac_for(&self.secret[..], reveal.nonce(), &self.nonce[..])?
+            .verify_slice(reveal.mac())
+            .is_ok();
+        if !verified || reveal.nonce().len() != NONCE_LEN || &commitment(reveal.nonce()) != committed {
+            self.state = PairingState::Aborted;
+            Err(Error::PairingFailed("reveal didn't check out".into()))?;
+        }
+        self.guest_nonce = Some(reveal.nonce().clone());
+        self.state = PairingState::Verifying;
+        Ok(())
+    }
+
+    /// The user checked the verification code and it matches. Seals the user's keys for the
+    /// guest, and returns the operation that adds the guest to the user's devices.
+    pub fn confirm(&mut self, master_key: &SecretKey, keychain: &Keychain) -> Result<(KeychainTransfer, Operation)> {
+        let (device, guest_nonce) = match (&self.state, self.hello.as_ref(), self.guest_nonce.as_ref()) {
+            (PairingState::Verifying, Some((device, _)), Some(guest_nonce)) => (device, guest_nonce),
+            _ => Err(Error::PairingFailed(format!("nothing to confirm ({:?})", self.state)))?,
+        };
+        let payload = TransferPayload {
+            identity_id: self.identity_id.clone(),
+            master_key: master_key.clone(),
+            keychain: keychain.clone(),
+        };
+        let serialized = Zeroizing::new(rasn::der::encode(&payload).map_err(|_| Error::ASNSerialize)?);
+        let key = KeychainTransfer::session_key(&self.secret[..], guest_nonce, &self.nonce[..])?;
+        let transfer = KeychainTransfer { sealed: seal::seal(&key, &serialized[..])? };
+        let op = Operation::user_set_device(device.clone());
+        self.state = PairingState::Complete;
+        Ok((transfer, op))
+    }
+
+    /// Call the whole thing off (the user said the codes don't match, or hit cancel)
+    pub fn abort(&mut self) {
+        self.state = PairingState::Aborted;
+    }
+}
+
+/// The new device's side of a pairing.
+pub struct PairingGuest {
+    secret: Zeroizing<Vec<u8>>,
+    nonce: Zeroizing<Vec<u8>>,
+    host: Option<(IdentityID, Vec<u8>)>,
+    state: PairingState,
+}
+
+impl PairingGuest {
+    /// Start pairing with the host that showed us `code`, returning the hello to send it.
+    pub fn new(code: PairingCode, device: Device) -> Result<(Self, PairingHello)> {
+        let nonce = random_bytes(NONCE_LEN)?;
+        let commitment = commitment(&nonce[..]);
+        let mac = PairingHello::mac_for(&code.secret[..], &device, &commitment[..])?
+            .finalize()
+            .into_bytes()
+            .to_vec();
+        let hello = PairingHello { device, commitment, mac };
+        let guest = Self { secret: code.secret, nonce, host: None, state: PairingState::Offered };
+        Ok((guest, hello))
+    }
+
+    /// Where this pairing is at
+    pub fn state(&self) -> &PairingState {
+        &self.state
+    }
+
+    /// The identity the host says we're being paired with. Show this to the user along with the
+    /// verification code.
+    pub fn identity_id(&self) -> Option<&IdentityID> {
+        self.host.as_ref().map(|(identity_id, _)| identity_id)
+    }
+
+    /// The code to show the user so they can check it matches the host's. Only available once
+    /// the host has answered.
+    pub fn verification_code(&self) -> Option<String> {
+        let (_, host_nonce) = self.host.as_ref()?;
+        verification_code(&self.secret[..], &self.nonce[..], host_nonce).ok()
+    }
+
+    /// Check the host's answer to our hello, returning the reveal to send back.
+    pub fn receive_accept(&mut self, accept: &PairingAccept) -> Result<PairingReveal> {
+        if self.state != PairingState::Offered {
+            Err(Error::PairingFailed(format!("not expecting an answer ({:?})", self.state)))?;
+        }
+        let verified = PairingAccept::mac_for(&self.secret[..], accept.identity_id(), accept.nonce(), &commitment(&self.nonce[..]))?
+            .verify_slice(accept.mac())
+            .is_ok();
+        if !verified || accept.nonce().len() != NONCE_LEN {
+            self.state = PairingState::Aborted;
+            Err(Error::PairingFailed("answer didn't check out".into()))?;
+        }
+        let mac = PairingReveal::mac_for(&self.secret[..], &self.nonce[..], accept.nonce())?
+            .finalize()
+            .into_bytes()
+            .to_vec();
+        self.host = Some((accept.identity_id().clone(), accept.nonce().clone()));
+        self.state = PairingState::Verifying;
+        Ok(PairingReveal { nonce: self.nonce.to_vec(), mac })
+    }
+
+    /// The user checked the verification code (and identity) and they match.
+    pub fn confirm(&mut self) -> Result<()> {
+        if self.state != PairingState::Verifying {
+            Err(Error::PairingFailed(format!("nothing to confirm ({:?})", self.state)))?;
+        }
+        self.state = PairingState::Confirmed;
+        Ok(())
+    }
+
+    /// Open the host's keychain transfer, returning the master key and keychain. The keychain's
+    /// entries are already in the user's DAG, so there's nothing to write back.
+    pub fn receive_transfer(&mut self, transfer: &KeychainTransfer) -> Result<(SecretKey, Keychain)> {
+        let (identity_id, host_nonce) = match (&self.state, self.host.as_ref()) {
+            (PairingState::Confirmed, Some(host)) => host,
+            _ => Err(Error::PairingFailed(format!("not ready for the keys ({:?})", self.state)))?,
+        };
+        let key = KeychainTransfer::session_key(&self.secret[..], &self.nonce[..], host_nonce)?;
+        let serialized = match seal::open(&key, &transfer.sealed) {
+            Ok(serialized) => Zeroizing::new(serialized),
+            Err(_) => {
+                self.state = PairingState::Aborted;
+                Err(Error::PairingFailed("transfer didn't check out".into()))?
+            }
+        };
+        let TransferPayload { identity_id: payload_identity, master_key, keychain } = rasn::der::decode(&serialized[..])
+            .map_err(|_| Error::ASNDeserialize)?;
+        if &payload_identity != identity_id {
+            self.state = PairingState::Aborted;
+            Err(Error::PairingFailed("transfer is for a different identity".into()))?;
+        }
+        self.state = PairingState::Complete;
+        Ok((master_key, keychain))
+    }
+
+    /// Call the whole thing off
+    pub fn abort(&mut self) {
+        self.state = PairingState::Aborted;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::user::{DeviceID, Platform},
+        test_util,
+    };
+
+    fn device() -> Device {
+        Device::new(DeviceID::new(), "new laptop".into(), Platform::Linux, Timestamp::now())
+    }
+
+    fn start() -> (PairingHost, PairingGuest, PairingHello, Timestamp) {
+        let now = Timestamp::now();
+        let host = PairingHost::new(test_util::identity_id(), &now).unwrap();
+        let code: PairingCode = host.code().to_string().parse().unwrap();
+        let (guest, hello) = PairingGuest::new(code, device()).unwrap();
+        (host, guest, hello, now)
+    }
+
+    #[test]
+    fn matching_codes_hand_over_the_keys() {
+        let (mut host, mut guest, hello, now) = start();
+        let hello = PairingHello::deserialize(&hello.serialize().unwrap()).unwrap();
+        let accept = host.receive_hello(&hello, &now).unwrap();
+        // nothing to compare until the guest's nonce is out
+        assert!(host.verification_code().is_none());
+        let reveal = guest.receive_accept(&PairingAccept::deserialize(&accept.serialize().unwrap()).unwrap()).unwrap();
+        host.receive_reveal(&PairingReveal::deserialize(&reveal.serialize().unwrap()).unwrap(), &now).unwrap();
+        assert_eq!(host.verification_code().unwrap(), guest.verification_code().unwrap());
+        assert_eq!(host.verification_code().unwrap().len(), 6);
+
+        let master_key = SecretKey::new_xchacha20poly1305().unwrap();
+        let (transfer, _) = host.confirm(&master_key, &Keychain::new()).unwrap();
+        guest.confirm().unwrap();
+        let (received, _) = guest.receive_transfer(&transfer).unwrap();
+        assert_eq!(rasn::der::encode(&received).unwrap(), rasn::der::encode(&master_key).unwrap());
+        assert_eq!(host.state(), &PairingState::Complete);
+        assert_eq!(guest.state(), &PairingState::Complete);
+    }
+
+    #[test]
+    fn tampered_hello_aborts() {
+        let (mut host, _, hello, now) = start();
+        let mut tampered = hello.clone();
+        tampered.device = device();
+        assert!(host.receive_hello(&tampered, &now).is_err());
+        assert_eq!(host.state(), &PairingState::Aborted);
+        // and the real one doesn't get a second chance
+        assert!(host.receive_hello(&hello, &now).is_err());
+
+        let (mut host, _, mut hello, now) = start();
+        hello.commitment[0] ^= 1;
+        assert!(host.receive_hello(&hello, &now).is_err());
+        assert_eq!(host.state(), &PairingState::Aborted);
+    }
+
+    #[test]
+    fn tampered_accept_aborts() {
+        let (mut host, mut guest, hello, now) = start();
+        let mut accept = host.receive_hello(&hello, &now).unwrap();
+        accept.nonce[0] ^= 1;
+        assert!(guest.receive_accept(&accept).is_err());
+        assert_eq!(guest.state(), &PairingState::Aborted);
+        assert!(guest.verification_code().is_none());
+    }
+
+    #[test]
+    fn reveal_has_to_match_the_commitment() {
+        let (mut host, mut guest, hello, now) = start();
+        let accept = host.receive_hello(&hello, &now).unwrap();
+        let reveal = guest.receive_accept(&accept).unwrap();
+
+        // a different nonce with a good MAC (ie, from someone who saw the code) is still refused
+        let nonce = random_bytes(NONCE_LEN).unwrap().to_vec();
+        let mac = PairingReveal::mac_for(&host.secret[..], &nonce[..], &host.nonce[..]).unwrap().finalize().into_bytes().to_vec();
+        assert!(host.receive_reveal(&PairingReveal { nonce, mac }, &now).is_err());
+        assert_eq!(host.state(), &PairingState::Aborted);
+        assert!(host.receive_reveal(&reveal, &now).is_err());
+        assert!(host.verification_code().is_none());
+    }
+
+    #[test]
+    fn steps_out_of_order_are_refused() {
+        let (mut host, mut guest, hello, now) = start();
+        let master_key = SecretKey::new_xchacha20poly1305().unwrap();
+        // nothing to confirm or reveal before the hello
+        assert!(host.confirm(&master_key, &Keychain::new()).is_err());
+        assert!(guest.confirm().is_err());
+
+        let accept = host.receive_hello(&hello, &now).unwrap();
+        // the host can't hand over keys before the guest reveals
+        assert!(host.confirm(&master_key, &Keychain::new()).is_err());
+        assert!(host.receive_hello(&hello, &now).is_err());
+
+        let reveal = guest.receive_accept(&accept).unwrap();
+        assert!(guest.receive_accept(&accept).is_err());
+        host.receive_reveal(&reveal, &now).unwrap();
+        assert!(host.receive_reveal(&reveal, &now).is_err());
+
+        let (transfer, _) = host.confirm(&master_key, &Keychain::new()).unwrap();
+        // the guest has to confirm the code before taking the keys
+        assert!(guest.receive_transfer(&transfer).is_err());
+        guest.confirm().unwrap();
+        guest.receive_transfer(&transfer).unwrap();
+    }
+
+    #[test]
+    fn expired_codes_are_refused() {
+        let (mut host, _, hello, now) = start();
+        let later = Timestamp::from(*now + chrono::Duration::seconds(PAIRING_TIMEOUT_SECS + 1));
+        assert!(host.receive_hello(&hello, &later).is_err());
+        assert_eq!(host.state(), &PairingState::Aborted);
+    }
+}
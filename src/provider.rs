@@ -0,0 +1,78 @@
+//! Abstracts the operations that need a user's private keys, so those keys don't have to live
+//! in this process as raw [`SecretKey`]s.
+//!
+//! Clients that keep identity keys in a platform keystore (Secure Enclave, Android Keystore, a
+//! YubiKey) implement [`CryptoProvider`] on top of it and pass that in wherever a master key
+//! would otherwise be needed. [`LocalProvider`] is the in-process version for clients that do
+//! hold the keys themselves.
+
+use crate::error::{Error, Result};
+use stamp_core::crypto::{
+    base::{CryptoKeypair, CryptoKeypairMessage, Sealed, SecretKey, SignKeypair, SignKeypairSignature},
+    seal,
+};
+
+/// Something that can use a user's private keys on our behalf.
+pub trait CryptoProvider {
+    /// Sign some data with the user's identity signing key.
+    fn sign(&self, data: &[u8]) -> Result<SignKeypairSignature>;
+
+    /// Open a message that was sealed to the user's identity crypto key (ie, an operation
+    /// envelope, see [`crypto::seal_for_members`][crate::crypto::seal_for_members]).
+    fn open_anonymous(&self, message: &CryptoKeypairMessage) -> Result<Vec<u8>>;
+
+    /// Seal data under the user's master key (ie, wrapping a space key for the keychain).
+    fn wrap(&self, data: &[u8]) -> Result<Sealed>;
+
+    /// Open data sealed by [`CryptoProvider::wrap`].
+    fn unwrap(&self, sealed: &Sealed) -> Result<Vec<u8>>;
+}
+
+/// A [`CryptoProvider`] that holds the keys in memory.
+///
+/// The identity keypairs are optional: a provider that's only used for the keychain doesn't need
+/// them, and asking it to sign or open an envelope fails.
+pub struct LocalProvider<'k> {
+    master_key: &'k SecretKey,
+    sign_keypair: Option<&'k SignKeypair>,
+    crypto_keypair: Option<&'k CryptoKeypair>,
+}
+
+impl<'k> LocalProvider<'k> {
+    /// Create a provider around a master key
+    pub fn new(master_key: &'k SecretKey) -> Self {
+        Self { master_key, sign_keypair: None, crypto_keypair: None }
+    }
+
+    /// Give this provider the identity's signing keypair (unlocked by the master key)
+    pub fn with_sign_keypair(mut self, sign_keypair: &'k SignKeypair) -> Self {
+        self.sign_keypair = Some(sign_keypair);
+        self
+    }
+
+    /// Give this provider the identity's crypto keypair (unlocked by the master key)
+    pub fn with_crypto_keypair(mut self, crypto_keypair: &'k CryptoKeypair) -> Self {
+        self.crypto_keypair = Some(crypto_keypair);
+        self
+    }
+}
+
+impl<'k> CryptoProvider for LocalProvider<'k> {
+    fn sign(&self, data: &[u8]) -> Result<SignKeypairSignature> {
+        let keypair = self.sign_keypair.ok_or_else(|| Error::ProviderMissingKey("sign".into()))?;
+        Ok(keypair.sign(self.master_key, data)?)
+    }
+
+    fn open_anonymous(&self, message: &CryptoKeypairMessage) -> Result<Vec<u8>> {
+        let keypair = self.crypto_keypair.ok_or_else(|| Error::ProviderMissingKey("crypto".into()))?;
+        Ok(keypair.open_anonymous(self.master_key, message)?)
+    }
+
+    fn wrap(&self, data: &[u8]) -> Result<Sealed> {
+        Ok(seal::seal(self.master_key, data)?)
+    }
+
+    fn unwrap(&self, sealed: &Sealed) -> Result<Vec<u8>> {
+        Ok(seal::open(self.master_key, sealed)?)
+    }
+}
@@ -0,0 +1,423 @@
+//! Recovery codes: a way back in when every device (and every copy of the master key) is gone.
+//!
+//! The user's master key is split with Shamir's secret sharing into a handful of printable
+//! codes, any `threshold` of which can put the key back together. Fewer than `threshold` codes
+//! say nothing about the key, so the codes can be stored in different places (a drawer, a
+//! password manager, a trusted friend) without any one of them being a liability.
+//!
+//! Space keys can be split the same way among a space's admins (see [`split_space_key`]), so a
+//! space whose owner disappears can still be recovered, but only by enough admins working
+//! together.
+
+use crate::{
+    error::{Error, Result},
+    models::space::{MemberID, Role, Space, SpaceID, SpaceKeyID},
+    provider::{CryptoProvider, LocalProvider},
+};
+use getset::Getters;
+use rasn::{AsnType, Decode, Encode};
+use serde::{Deserialize, Serialize};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use sharks::{Share, Sharks};
+use stamp_core::{
+    crypto::base::{CryptoKeypair, CryptoKeypairMessage, SecretKey},
+    identity::IdentityID,
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use zeroize::Zeroizing;
+
+/// Which recovery code format we produce. Bumped if the layout of a code ever changes.
+const RECOVERY_CODE_VERSION: u8 = 2;
+
+/// The first recovery code format, which didn't carry a [check value](check_value)
+const RECOVERY_CODE_VERSION_UNCHECKED: u8 = 1;
+
+/// How long a [check value](check_value) is
+const CHECK_LEN: usize = 8;
+
+/// HMAC label for [check values](check_value)
+const CHECK_LABEL: &[u8] = b"turtl/recovery-check/v1";
+
+/// How many characters go between the dashes in a printed code
+const GROUP_SIZE: usize = 4;
+
+/// Split a secret into `count` shares, any `threshold` of which can recover it.
+pub(crate) fn split_secret(secret: &[u8], threshold: u8, count: u8) -> Result<Vec<Vec<u8>>> {
+    if threshold == 0 || threshold > count {
+        Err(Error::RecoveryInvalid(format!("can't make {} shares with a threshold of {}", count, threshold)))?;
+    }
+    Ok(Sharks(threshold)
+        .dealer(secret)
+        .take(count as usize)
+        .map(|share| Vec::from(&share))
+        .collect())
+}
+
+/// A short keyed hash of a secret that gets stored with each of its shares, so a secret put back
+/// together from the wrong shares (ie, from two different sets) is caught instead of turning into
+/// a garbage key. It's keyed by the secret itself, so it says nothing about the secret to
+/// anyone who doesn't already have it.
+pub(crate) fn check_value(secret: &[u8]) -> Result<Vec<u8>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret)
+        .map_err(|_| Error::RecoveryInvalid("can't make a check value".into()))?;
+    mac.update(CHECK_LABEL);
+    Ok(mac.finalize().into_bytes()[..CHECK_LEN].to_vec())
+}
+
+/// Make sure a recombined secret matches the check value its shares carried (if they carried
+/// one).
+fn verify_check(secret: &[u8], check: Option<&[u8]>) -> Result<()> {
+    if let Some(check) = check {
+        if check_value(secret)?[..] != check[..] {
+            Err(Error::RecoveryInvalid("codes are from different sets".into()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Put a secret back together from (at least `threshold`) shares. The same share given twice
+/// only counts once, and two different shares with the same index are an error.
+pub(crate) fn combine_shares(shares: &[Vec<u8>], threshold: u8) -> Result<Zeroizing<Vec<u8>>> {
+    let mut by_index: HashMap<u8, &Vec<u8>> = HashMap::new();
+    for share in shares {
+        let index = *share.first().ok_or_else(|| Error::RecoveryInvalid("share is empty".into()))?;
+        match by_index.get(&index) {
+            Some(existing) if existing != &share => Err(Error::RecoveryInvalid(format!("two different shares have index {}", index)))?,
+            Some(_) => {}
+            None => { by_index.insert(index, share); }
+        }
+    }
+    if by_index.len() < threshold as usize {
+        Err(Error::RecoveryInvalid(format!("need {} different shares, got {}", threshold, by_index.len())))?;
+    }
+    let shares = by_index.values()
+        .map(|s| Share::try_from(s.as_slice()).map_err(|e| Error::RecoveryInvalid(e.into())))
+        .collect::<Result<Vec<_>>>()?;
+    let secret = Sharks(threshold)
+        .recover(shares.iter())
+        .map_err(|e| Error::RecoveryInvalid(e.into()))?;
+    Ok(Zeroizing::new(secret))
+}
+
+/// One printable piece of the master key, ie `AEBA-GBAF-...`.
+///
+/// A code holds the format version, how many codes are needed to recover, a
+/// [check value](check_value) of the master key, the share itself, and a short checksum so that
+/// typos get caught when the code is entered instead of producing a garbage key. The check value
+/// catches what the checksum can't: valid codes from two different sets.
+#[derive(Clone, PartialEq)]
+pub struct RecoveryCode {
+    threshold: u8,
+    /// Missing from codes made before check values existed
+    check: Option<Vec<u8>>,
+    share: Zeroizing<Vec<u8>>,
+}
+
+impl RecoveryCode {
+    /// How many codes it takes to recover the key
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    fn checksum(body: &[u8]) -> [u8; 2] {
+        let digest = Sha256::digest(body);
+        [digest[0], digest[1]]
+    }
+}
+
+impl fmt::Display for RecoveryCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut body = match self.check.as_ref() {
+            Some(check) => {
+                let mut body = Zeroizing::new(vec![RECOVERY_CODE_VERSION, self.threshold]);
+                body.extend_from_slice(&check[..]);
+                body
+            }
+            None => Zeroizing::new(vec![RECOVERY_CODE_VERSION_UNCHECKED, self.threshold]),
+        };
+        body.extend_from_slice(&self.share[..]);
+        let checksum = Self::checksum(&body[..]);
+        body.extend_from_slice(&checksum[..]);
+        let encoded = Zeroizing::new(data_encoding::BASE32_NOPAD.encode(&body[..]));
+        let grouped = encoded.as_bytes()
+            .chunks(GROUP_SIZE)
+            .map(|c| std::str::from_utf8(c).unwrap_or_default())
+            .collect::<Vec<_>>()
+            .join("-");
+        f.write_str(&grouped)
+    }
+}
+
+impl fmt::Debug for RecoveryCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RecoveryCode(threshold: {}, ..)", self.threshold)
+    }
+}
+
+impl FromStr for RecoveryCode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let normalized = Zeroizing::new(s.chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_uppercase())
+            .collect::<String>());
+        let body = Zeroizing::new(data_encoding::BASE32_NOPAD.decode(normalized.as_bytes())
+            .map_err(|_| Error::RecoveryInvalid("code has invalid characters".into()))?);
+        if body.len() < 5 {
+            Err(Error::RecoveryInvalid("code is too short".into()))?;
+        }
+        let (payload, checksum) = body.split_at(body.len() - 2);
+        if Self::checksum(payload) != checksum {
+            Err(Error::RecoveryInvalid("code has a typo (checksum mismatch)".into()))?;
+        }
+        match payload[0] {
+            RECOVERY_CODE_VERSION => {
+                if payload.len() < 3 + CHECK_LEN {
+                    Err(Error::RecoveryInvalid("code is too short".into()))?;
+                }
+                let (check, share) = payload[2..].split_at(CHECK_LEN);
+                Ok(Self { threshold: payload[1], check: Some(check.to_vec()), share: Zeroizing::new(share.to_vec()) })
+            }
+            RECOVERY_CODE_VERSION_UNCHECKED => {
+                Ok(Self { threshold: payload[1], check: None, share: Zeroizing::new(payload[2..].to_vec()) })
+            }
+            version => Err(Error::RecoveryInvalid(format!("unknown code version {}", version)))?,
+        }
+    }
+}
+
+/// Split the master key into `count` recovery codes, any `threshold` of which can bring it back.
+pub fn recovery_codes(master_key: &SecretKey, threshold: u8, count: u8) -> Result<Vec<RecoveryCode>> {
+    let serialized = Zeroizing::new(rasn::der::encode(master_key).map_err(|_| Error::ASNSerialize)?);
+    let check = check_value(&serialized[..])?;
+    Ok(split_secret(&serialized[..], threshold, count)?
+        .into_iter()
+        .map(|share| RecoveryCode { threshold, check: Some(check.clone()), share: Zeroizing::new(share) })
+        .collect())
+}
+
+/// Rebuild the master key from recovery codes.
+pub fn recover_master_key(codes: &[RecoveryCode]) -> Result<SecretKey> {
+    let threshold = codes.first()
+        .map(|c| c.threshold)
+        .ok_or_else(|| Error::RecoveryInvalid("no codes given".into()))?;
+    let check = codes[0].check.as_ref();
+    if codes.iter().any(|c| c.threshold != threshold || c.check.as_ref() != check) {
+        Err(Error::RecoveryInvalid("codes are from different sets".into()))?;
+    }
+    if codes.len() < threshold as usize {
+        Err(Error::RecoveryInvalid(format!("need {} codes, got {}", threshold, codes.len())))?;
+    }
+    let shares = Zeroizing::new(codes.iter().map(|c| c.share.to_vec()).collect::<Vec<_>>());
+    let serialized = combine_shares(&shares[..], threshold)?;
+    verify_check(&serialized[..], check.map(|c| &c[..]))?;
+    rasn::der::decode(&serialized[..]).map_err(|_| Error::ASNDeserialize)
+}
+
+/// One admin's share of a space key, sealed to that admin's identity crypto key so that only they
+/// can open it. Shares are handed to each admin out of band (or through their own DAG): they
+/// can't live in the space itself, since the space's operations are encrypted with the very key
+/// they protect.
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct SpaceKeyShare {
+    /// The space this is a share of
+    #[rasn(tag(explicit(0)))]
+    space_id: SpaceID,
+    /// Which of the space's keys this is a share of (`None` for the key the space was created
+    /// with)
+    #[rasn(tag(explicit(1)))]
+    key_id: Option<SpaceKeyID>,
+    /// The admin holding this share
+    #[rasn(tag(explicit(2)))]
+    member_id: MemberID,
+    /// How many shares it takes to rebuild the key
+    #[rasn(tag(explicit(3)))]
+    threshold: u8,
+    /// The share, sealed to the admin's identity crypto key
+    #[rasn(tag(explicit(4)))]
+    sealed_share: CryptoKeypairMessage,
+    /// A [check value](check_value) of the key, so a key rebuilt from the wrong shares is caught
+    #[rasn(tag(explicit(5)), default)]
+    #[serde(default)]
+    check: Option<Vec<u8>>,
+}
+
+impl SpaceKeyShare {
+    /// Open this share with the admin's identity crypto key (and the master key that unlocks it).
+    pub fn open(&self, keypair: &CryptoKeypair, master_key: &SecretKey) -> Result<OpenedKeyShare> {
+        self.open_with(&LocalProvider::new(master_key).with_crypto_keypair(keypair))
+    }
+
+    /// Like [`SpaceKeyShare::open`], but lets a [`CryptoProvider`] do the unsealing.
+    pub fn open_with(&self, provider: &dyn CryptoProvider) -> Result<OpenedKeyShare> {
+        let share = Zeroizing::new(provider.open_anonymous(&self.sealed_share)?);
+        Ok(OpenedKeyShare {
+            space_id: self.space_id.clone(),
+            key_id: self.key_id.clone(),
+            threshold: self.threshold,
+            check: self.check.clone(),
+            share,
+        })
+    }
+}
+
+/// A space key share that an admin has opened and is contributing towards recovering the key.
+/// This is what admins send (over a secure channel) to whoever is putting the key back together.
+#[derive(Clone)]
+pub struct OpenedKeyShare {
+    space_id: SpaceID,
+    key_id: Option<SpaceKeyID>,
+    threshold: u8,
+    check: Option<Vec<u8>>,
+    share: Zeroizing<Vec<u8>>,
+}
+
+impl fmt::Debug for OpenedKeyShare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OpenedKeyShare({:?}, threshold: {}, ..)", self.space_id, self.threshold)
+    }
+}
+
+/// Split a space key among the space's admins, any `threshold` of whom can rebuild it with
+/// [`combine_space_key`].
+///
+/// `identity_keys` holds the (public) crypto keys of the identities we know about. Every admin
+/// needs one: a share we can't seal to anybody would just lower the number of admins who could
+/// ever recover the key, so this errors instead.
+pub fn split_space_key(space: &Space, key_id: Option<SpaceKeyID>, space_key: &SecretKey, threshold: u8, identity_keys: &HashMap<IdentityID, CryptoKeypair>) -> Result<Vec<SpaceKeyShare>> {
+    let admins = space.members().iter()
+        .filter(|m| matches!(m.role(), Role::Admin))
+        .collect::<Vec<_>>();
+    let count = u8::try_from(admins.len())
+        .map_err(|_| Error::RecoveryInvalid("too many admins to split a key among".into()))?;
+    let keys = admins.iter()
+        .map(|m| identity_keys.get(m.user_id()).ok_or_else(|| Error::RecoveryInvalid(format!("no crypto key for admin {:?}", m.id()))))
+        .collect::<Result<Vec<_>>>()?;
+    let serialized = Zeroizing::new(rasn::der::encode(space_key).map_err(|_| Error::ASNSerialize)?);
+    let check = check_value(&serialized[..])?;
+    let shares = Zeroizing::new(split_secret(&serialized[..], threshold, count)?);
+    admins.iter()
+        .zip(keys)
+        .zip(shares.iter())
+        .map(|((admin, keypair), share)| {
+            Ok(SpaceKeyShare {
+                space_id: space.id().clone(),
+                key_id: key_id.clone(),
+                member_id: admin.id().clone(),
+                threshold,
+                sealed_share: keypair.seal_anonymous(&share[..])?,
+                check: Some(check.clone()),
+            })
+        })
+        .collect()
+}
+
+/// Rebuild a space key from (at least `threshold`) admins' opened shares. Returns the space and
+/// key ID the shares were for along with the key, ready to go into the keychain.
+pub fn combine_space_key(shares: &[OpenedKeyShare]) -> Result<(SpaceID, Option<SpaceKeyID>, SecretKey)> {
+    let first = shares.first().ok_or_else(|| Error::RecoveryInvalid("no shares given".into()))?;
+    if shares.iter().any(|s| s.space_id != first.space_id || s.key_id != first.key_id || s.threshold != first.threshold || s.check != first.check) {
+        Err(Error::RecoveryInvalid("shares are for different keys".into()))?;
+    }
+    if shares.len() < first.threshold as usize {
+        Err(Error::RecoveryInvalid(format!("need {} shares, got {}", first.threshold, shares.len())))?;
+    }
+    let raw = Zeroizing::new(shares.iter().map(|s| s.share.to_vec()).collect::<Vec<_>>());
+    let serialized = combine_shares(&raw[..], first.threshold)?;
+    verify_check(&serialized[..], first.check.as_deref())?;
+    let secret_key = rasn::der::decode(&serialized[..]).map_err(|_| Error::ASNDeserialize)?;
+    Ok((first.space_id.clone(), first.key_id.clone(), secret_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_der(key: &SecretKey) -> Vec<u8> {
+        rasn::der::encode(key).unwrap()
+    }
+
+    fn reparse(code: &RecoveryCode) -> RecoveryCode {
+        code.to_string().to_lowercase().parse().unwrap()
+    }
+
+    #[test]
+    fn any_threshold_of_codes_recovers() {
+        let master_key = SecretKey::new_xchacha20poly1305().unwrap();
+        let codes = recovery_codes(&master_key, 3, 5).unwrap();
+        for skip in 0..5 {
+            let subset = codes.iter()
+                .enumerate()
+                .filter(|(i, _)| *i != skip && *i != (skip + 1) % 5)
+                .map(|(_, c)| reparse(c))
+                .collect::<Vec<_>>();
+            assert_eq!(key_der(&recover_master_key(&subset[..]).unwrap()), key_der(&master_key));
+        }
+        assert!(recover_master_key(&codes[..2]).is_err());
+        assert!(recovery_codes(&master_key, 0, 5).is_err());
+        assert!(recovery_codes(&master_key, 6, 5).is_err());
+    }
+
+    #[test]
+    fn typos_are_caught() {
+        let master_key = SecretKey::new_xchacha20poly1305().unwrap();
+        let code = recovery_codes(&master_key, 2, 3).unwrap().remove(0).to_string();
+        let flipped = code.chars()
+            .enumerate()
+            .map(|(i, c)| if i == 5 { if c == 'A' { 'B' } else { 'A' } } else { c })
+            .collect::<String>();
+        assert!(matches!(flipped.parse::<RecoveryCode>(), Err(Error::RecoveryInvalid(..))));
+    }
+
+    #[test]
+    fn codes_from_different_sets_are_rejected() {
+        let key1 = SecretKey::new_xchacha20poly1305().unwrap();
+        let key2 = SecretKey::new_xchacha20poly1305().unwrap();
+        let codes1 = recovery_codes(&key1, 2, 3).unwrap();
+        let codes2 = recovery_codes(&key2, 2, 3).unwrap();
+        let mixed = vec![codes1[0].clone(), codes2[1].clone()];
+        assert!(matches!(recover_master_key(&mixed[..]), Err(Error::RecoveryInvalid(..))));
+
+        // even with the check values stripped off, the recombined key doesn't pass a check
+        let serialized = rasn::der::encode(&key1).unwrap();
+        let check = check_value(&serialized[..]).unwrap();
+        let shares = vec![codes1[0].share.to_vec(), codes2[1].share.to_vec()];
+        let combined = combine_shares(&shares[..], 2).unwrap();
+        assert!(verify_check(&combined[..], Some(&check[..])).is_err());
+    }
+
+    #[test]
+    fn duplicate_codes_count_once() {
+        let master_key = SecretKey::new_xchacha20poly1305().unwrap();
+        let codes = recovery_codes(&master_key, 2, 3).unwrap();
+        let dupes = vec![codes[0].clone(), codes[0].clone()];
+        assert!(matches!(recover_master_key(&dupes[..]), Err(Error::RecoveryInvalid(..))));
+        let with_dupe = vec![codes[0].clone(), codes[0].clone(), codes[2].clone()];
+        assert_eq!(key_der(&recover_master_key(&with_dupe[..]).unwrap()), key_der(&master_key));
+
+        // same index, different share
+        let mut forged = codes[1].clone();
+        forged.share[0] = codes[0].share[0];
+        let conflicting = vec![codes[0].share.to_vec(), forged.share.to_vec()];
+        assert!(matches!(combine_shares(&conflicting[..], 2), Err(Error::RecoveryInvalid(..))));
+    }
+
+    #[test]
+    fn unchecked_codes_still_recover() {
+        let master_key = SecretKey::new_xchacha20poly1305().unwrap();
+        let codes = recovery_codes(&master_key, 2, 3).unwrap()
+            .into_iter()
+            .map(|mut c| {
+                c.check = None;
+                reparse(&c)
+            })
+            .collect::<Vec<_>>();
+        assert!(codes.iter().all(|c| c.check.is_none()));
+        assert_eq!(key_der(&recover_master_key(&codes[1..]).unwrap()), key_der(&master_key));
+    }
+}
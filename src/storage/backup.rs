@@ -0,0 +1,312 @@
+//! Rotating, encrypted snapshots of the state.
+//!
+//! The operation log is the source of truth, but rebuilding a big account's state from it takes
+//! a while, and a state that rotted on disk is otherwise gone. A [`BackupManager`] writes the
+//! whole state, along with the frontier of every space it was built from, into a snapshot file
+//! sealed with a key derived from the master key.
+//!
+//! The core doesn't keep time, so "scheduled" means calling [`BackupManager::run`] every so
+//! often (ie, on startup and once an hour): it takes a snapshot if there isn't one from today
+//! yet and then thins out old ones, keeping the newest snapshot from each of the last few days
+//! and the last few weeks (see [`Retention`]).
+//!
+//! [Restoring][BackupManager::restore] checks the snapshot against the operation log first: a
+//! snapshot built on transactions the log has never seen belongs to some other account (or some
+//! other device's log) and is refused. Anything the log has beyond the snapshot's frontier is
+//! handed back so it can be applied on top.
+
+use crate::{
+    blob,
+    crypto::{self, AssociatedData, KeyPurpose},
+    error::{Error, Result},
+    models::{operation, space::SpaceID, state::State},
+    storage::{compact, Storage},
+};
+use chrono::{Datelike, NaiveDateTime, TimeZone, Utc};
+use getset::Getters;
+use rasn::{AsnType, Decode, Encode};
+use stamp_core::{
+    crypto::base::{Sealed, SecretKey},
+    dag::{Transaction, TransactionID},
+    util::Timestamp,
+};
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Marks a snapshot file
+const SNAPSHOT_MAGIC: &[u8] = b"TBAK1";
+
+/// Snapshot files are named `snapshot-<time>.tbak`, with the time in this format
+const SNAPSHOT_TIME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+const SNAPSHOT_EXTENSION: &str = "tbak";
+
+/// The tips of one space's DAG when a snapshot was taken
+#[derive(AsnType, Encode, Decode)]
+struct SnapshotFrontier {
+    #[rasn(tag(explicit(0)))]
+    space_id: Option<SpaceID>,
+    #[rasn(tag(explicit(1)))]
+    tips: Vec<TransactionID>,
+}
+
+/// One persisted state record (see [`State::persist_dirty`])
+#[derive(AsnType, Encode, Decode)]
+struct SnapshotRecord {
+    #[rasn(tag(explicit(0)))]
+    kind: String,
+    #[rasn(tag(explicit(1)))]
+    data: Vec<u8>,
+}
+
+/// What goes in a snapshot file (compressed, then sealed)
+#[derive(AsnType, Encode, Decode)]
+struct Snapshot {
+    #[rasn(tag(explicit(0)))]
+    taken: Timestamp,
+    #[rasn(tag(explicit(1)))]
+    frontier: Vec<SnapshotFrontier>,
+    #[rasn(tag(explicit(2)))]
+    records: Vec<SnapshotRecord>,
+}
+
+/// How many old snapshots to keep. The newest snapshot from each of the last `daily` days that
+/// have one is kept, and so is the newest from each of the last `weekly` weeks, so with the
+/// defaults there's a snapshot for each of the past week's days and for each of about the past
+/// month's weeks. The newest snapshot is always kept.
+#[derive(Clone, Debug, Getters)]
+#[getset(get = "pub")]
+pub struct Retention {
+    daily: usize,
+    weekly: usize,
+}
+
+impl Retention {
+    /// Create a new retention rule
+    pub fn new(daily: usize, weekly: usize) -> Self {
+        Self { daily, weekly }
+    }
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self::new(7, 4)
+    }
+}
+
+/// A snapshot file in the backup directory
+#[derive(Clone, Debug, Getters)]
+#[getset(get = "pub")]
+pub struct BackupInfo {
+    /// When the snapshot was taken
+    taken: Timestamp,
+    /// Where the snapshot lives
+    path: PathBuf,
+}
+
+/// A state brought back from a snapshot by [`BackupManager::restore`].
+#[derive(Getters)]
+#[getset(get = "pub")]
+pub struct Restored {
+    /// When the snapshot was taken
+    taken: Timestamp,
+    /// The state as of the snapshot
+    state: State,
+    /// Every transaction the snapshot already covers. An [`Inbox`][crate::sync::Inbox] created
+    /// with these will apply `newer` instead of seeing them as duplicates.
+    known: Vec<TransactionID>,
+    /// Transactions in the operation log from after the snapshot, parents first. Apply these to
+    /// the state to bring it up to date.
+    newer: Vec<Transaction>,
+}
+
+impl Restored {
+    /// Consume this restore, returning the state, the known transaction IDs, and the newer
+    /// transactions.
+    pub fn consume(self) -> (State, Vec<TransactionID>, Vec<Transaction>) {
+        let Self { state, known, newer, .. } = self;
+        (state, known, newer)
+    }
+}
+
+/// Writes, rotates, and restores state snapshots. See the [module docs][self].
+pub struct BackupManager {
+    dir: PathBuf,
+    key: SecretKey,
+    retention: Retention,
+}
+
+impl BackupManager {
+    /// Create a backup manager writing snapshots to `dir` (created if needed), sealed with a key
+    /// derived from the master key.
+    pub fn new<P: AsRef<Path>>(dir: P, master_key: &SecretKey, retention: Retention) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let key = crypto::derive_subkey(master_key, KeyPurpose::Backup)?;
+        Ok(Self { dir, key, retention })
+    }
+
+    /// The directory snapshots are written to
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The retention rule
+    pub fn retention(&self) -> &Retention {
+        &self.retention
+    }
+
+    /// Every snapshot in the backup directory, newest first. Files that don't look like
+    /// snapshots are ignored.
+    pub fn list(&self) -> Result<Vec<BackupInfo>> {
+        let mut backups = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SNAPSHOT_EXTENSION) {
+                continue;
+            }
+            let taken = path.file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.strip_prefix(SNAPSHOT_PREFIX))
+                .and_then(|s| NaiveDateTime::parse_from_str(s, SNAPSHOT_TIME_FORMAT).ok())
+                .map(|t| Timestamp::from(Utc.from_utc_datetime(&t)));
+            if let Some(taken) = taken {
+                backups.push(BackupInfo { taken, path });
+            }
+        }
+        backups.sort_by(|a, b| (*b.taken).cmp(&*a.taken));
+        Ok(backups)
+    }
+
+    /// Whether a snapshot is due: there's no snapshot from the same (UTC) day as `now`.
+    pub fn due(&self, now: &Timestamp) -> Result<bool> {
+        let today = now.date_naive();
+        Ok(!self.list()?.iter().any(|b| b.taken.date_naive() == today))
+    }
+
+    /// Take a snapshot if one is [due][BackupManager::due], then [prune][BackupManager::prune].
+    /// Returns the new snapshot, if one was taken.
+    pub fn run(&self, storage: &dyn Storage, state: &State, now: &Timestamp) -> Result<Option<BackupInfo>> {
+        let taken = if self.due(now)? { Some(self.snapshot(storage, state, now)?) } else { None };
+        self.prune()?;
+        Ok(taken)
+    }
+
+    /// Write a snapshot of the state, along with the frontier of every space in storage.
+    ///
+    /// The state should match what's in storage, so take snapshots after the state is
+    /// [settled][crate::storage::Wal::settle], not in the middle of applying.
+    pub fn snapshot(&self, storage: &dyn Storage, state: &State, now: &Timestamp) -> Result<BackupInfo> {
+        let frontier = storage.spaces()?
+            .into_iter()
+            .map(|space_id| {
+                let tips = storage.frontier(space_id.as_ref())?;
+                Ok(SnapshotFrontier { space_id, tips })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let records = state.all_records()?
+            .into_iter()
+            .map(|(kind, data)| SnapshotRecord { kind: kind.into(), data })
+            .collect();
+        let snapshot = Snapshot { taken: now.clone(), frontier, records };
+        let serialized = rasn::der::encode(&snapshot).map_err(|_| Error::ASNSerialize)?;
+        let compressed = zstd::encode_all(&serialized[..], 0)?;
+        let sealed = crypto::seal_bound(&self.key, &Self::associated(), &compressed[..])?;
+        let sealed = rasn::der::encode(&sealed).map_err(|_| Error::ASNSerialize)?;
+
+        let name = format!("{}{}.{}", SNAPSHOT_PREFIX, now.format(SNAPSHOT_TIME_FORMAT), SNAPSHOT_EXTENSION);
+        let path = self.dir.join(name);
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(SNAPSHOT_MAGIC)?;
+            file.write_all(&sealed[..])?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &path)?;
+        blob::sync_dir(&self.dir);
+        Ok(BackupInfo { taken: now.clone(), path })
+    }
+
+    /// Delete the snapshots the [retention rule][Retention] doesn't keep. Returns how many were
+    /// deleted.
+    pub fn prune(&self) -> Result<usize> {
+        let mut days = HashSet::new();
+        let mut weeks = HashSet::new();
+        let mut deleted = 0;
+        for (idx, backup) in self.list()?.into_iter().enumerate() {
+            let day = backup.taken.date_naive();
+            let week = (backup.taken.iso_week().year(), backup.taken.iso_week().week());
+            // newest first, so the first snapshot seen for a day/week is the one to keep
+            let keep_daily = days.len() < self.retention.daily && days.insert(day);
+            let keep_weekly = weeks.len() < self.retention.weekly && weeks.insert(week);
+            if idx == 0 || keep_daily || keep_weekly {
+                continue;
+            }
+            fs::remove_file(&backup.path)?;
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+
+    fn open(&self, backup: &BackupInfo) -> Result<Snapshot> {
+        let contents = fs::read(&backup.path)?;
+        if contents.len() < SNAPSHOT_MAGIC.len() || &contents[..SNAPSHOT_MAGIC.len()] != SNAPSHOT_MAGIC {
+            Err(Error::SnapshotInvalid(format!("{} isn't a snapshot", backup.path.display())))?;
+        }
+        let sealed: Sealed = rasn::der::decode(&contents[SNAPSHOT_MAGIC.len()..])
+            .map_err(|_| Error::SnapshotInvalid(format!("{} is damaged", backup.path.display())))?;
+        let compressed = crypto::open_bound(&self.key, &Self::associated(), &sealed)
+            .map_err(|_| Error::SnapshotInvalid(format!("{} is damaged or was made with another key", backup.path.display())))?;
+        let serialized = zstd::decode_all(&compressed[..])?;
+        rasn::der::decode(&serialized[..]).map_err(|_| Error::ASNDeserialize)
+    }
+
+    /// Bring a state back from a snapshot, checking it against the operation log in storage.
+    ///
+    /// Fails if any transaction the snapshot was built on isn't in the log (or compacted out of
+    /// it). The restored state isn't written anywhere: [persist it over][State::persist_over]
+    /// the old one and apply the newer transactions through an inbox to finish up.
+    pub fn restore(&self, backup: &BackupInfo, storage: &dyn Storage) -> Result<Restored> {
+        let Snapshot { taken, frontier, records } = self.open(backup)?;
+        let logged = compact::known_ids(storage)?.into_iter().collect::<HashSet<_>>();
+        for tip in frontier.iter().flat_map(|f| f.tips.iter()) {
+            if !logged.contains(tip) {
+                Err(Error::SnapshotInvalid(format!("snapshot builds on {}, which isn't in the operation log", tip)))?;
+            }
+        }
+
+        let state = State::load_records(|kind| {
+            Ok(records.iter().filter(|r| r.kind == kind).map(|r| r.data.clone()).collect())
+        })?;
+
+        // compacted transactions are behind a checkpoint, which either made it into the
+        // snapshot or is still in the log to be applied, so they count as covered either way
+        let stored = storage.transaction_ids()?.into_iter().collect::<HashSet<_>>();
+        let mut covered = logged.difference(&stored).cloned().collect::<HashSet<_>>();
+        let mut newer = Vec::new();
+        for space_id in storage.spaces()? {
+            let transactions = storage.transactions(space_id.as_ref())?;
+            let parents = operation::transaction_parents(&transactions[..]);
+            let tips = frontier.iter()
+                .find(|f| f.space_id == space_id)
+                .map(|f| &f.tips[..])
+                .unwrap_or(&[]);
+            let before = compact::ancestors(tips, &parents);
+            for transaction in transactions {
+                if before.contains(transaction.id()) {
+                    covered.insert(transaction.id().clone());
+                } else {
+                    newer.push(transaction);
+                }
+            }
+        }
+        Ok(Restored { taken, state, known: covered.into_iter().collect(), newer })
+    }
+
+    fn associated() -> AssociatedData {
+        AssociatedData::new(None, "turtl/backup/snapshot")
+    }
+}
@@ -0,0 +1,202 @@
+//! A cache of decrypted objects, so scrolling back and forth over a big page doesn't open the
+//! same operations and notes over and over.
+//!
+//! The cache works within a byte budget, throwing out whatever was used least recently once it
+//! goes over. Sizes are estimates (the encoded size of whatever the object was opened from), which
+//! is close enough for keeping memory in check.
+//!
+//! Operations never change once written, so they only leave the cache when they're evicted or
+//! [purged][DecryptCache::forget_transactions]. Notes do change: feed the cache the
+//! [events][crate::events::Event] coming out of the state and it drops whatever they touch.
+
+use crate::{
+    crypto::OperationKeys,
+    error::{Error, Result},
+    events::{Event, EventKind},
+    models::{
+        note::{Note, NoteID},
+        operation::{self, ObjectRef, Operation},
+    },
+};
+use stamp_core::dag::{Transaction, TransactionID};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// How big a cache gets by default: 32 MiB
+pub const DEFAULT_CACHE_BUDGET: usize = 32 * 1024 * 1024;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum CacheKey {
+    Operation(TransactionID),
+    Note(NoteID),
+}
+
+enum Cached {
+    Operation(Arc<Operation>),
+    Note(Arc<Note>),
+}
+
+struct Entry {
+    value: Cached,
+    size: usize,
+    /// When this entry was last used, for finding the least recently used one
+    used_at: u64,
+}
+
+/// An LRU cache of decrypted operations and notes. See the [module docs][self].
+pub struct DecryptCache {
+    budget: usize,
+    used: usize,
+    clock: u64,
+    entries: HashMap<CacheKey, Entry>,
+    /// Entry keys by when they were last used, oldest first
+    recency: BTreeMap<u64, CacheKey>,
+}
+
+impl Default for DecryptCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_BUDGET)
+    }
+}
+
+impl DecryptCache {
+    /// Create a cache that holds about `budget` bytes of decrypted objects.
+    pub fn new(budget: usize) -> Self {
+        Self { budget, used: 0, clock: 0, entries: HashMap::new(), recency: BTreeMap::new() }
+    }
+
+    /// The byte budget
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// About how many bytes are cached right now
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// How many objects are cached
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the cache is empty
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Change the byte budget, evicting as needed to fit.
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.evict();
+    }
+
+    /// Throw out everything.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+        self.used = 0;
+    }
+
+    fn touch(&mut self, key: &CacheKey) -> Option<&Cached> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        self.recency.remove(&entry.used_at);
+        entry.used_at = self.clock;
+        self.recency.insert(self.clock, key.clone());
+        Some(&entry.value)
+    }
+
+    fn insert(&mut self, key: CacheKey, value: Cached, size: usize) {
+        self.remove(&key);
+        // something bigger than the whole budget would just push everything else out
+        if size > self.budget {
+            return;
+        }
+        self.clock += 1;
+        self.recency.insert(self.clock, key.clone());
+        self.entries.insert(key, Entry { value, size, used_at: self.clock });
+        self.used += size;
+        self.evict();
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.used_at);
+            self.used -= entry.size;
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.used > self.budget {
+            let key = match self.recency.values().next() {
+                Some(key) => key.clone(),
+                None => break,
+            };
+            self.remove(&key);
+        }
+    }
+
+    /// Grab a cached operation by the ID of the transaction it came in.
+    pub fn operation(&mut self, id: &TransactionID) -> Option<Arc<Operation>> {
+        match self.touch(&CacheKey::Operation(id.clone()))? {
+            Cached::Operation(op) => Some(op.clone()),
+            Cached::Note(..) => None,
+        }
+    }
+
+    /// Open the operation in a transaction, or grab it from the cache if it's been opened
+    /// before.
+    pub fn open_operation(&mut self, transaction: &Transaction, keys: &OperationKeys) -> Result<Arc<Operation>> {
+        if let Some(op) = self.operation(transaction.id()) {
+            return Ok(op);
+        }
+        let (_, encrypted) = operation::operation_from_transaction(transaction)?;
+        let op = Arc::new(encrypted.decrypt_with(keys)?);
+        let size = rasn::der::encode(transaction).map_err(|_| Error::ASNSerialize)?.len();
+        self.insert(CacheKey::Operation(transaction.id().clone()), Cached::Operation(op.clone()), size);
+        Ok(op)
+    }
+
+    /// Drop operations from the cache, ie ones a tombstone purged.
+    pub fn forget_transactions(&mut self, ids: &[TransactionID]) {
+        for id in ids {
+            self.remove(&CacheKey::Operation(id.clone()));
+        }
+    }
+
+    /// Grab a cached note.
+    pub fn note(&mut self, note_id: &NoteID) -> Option<Arc<Note>> {
+        match self.touch(&CacheKey::Note(note_id.clone()))? {
+            Cached::Note(note) => Some(note.clone()),
+            Cached::Operation(..) => None,
+        }
+    }
+
+    /// Cache a decrypted note, replacing whatever was cached for it.
+    pub fn put_note(&mut self, note: Note) -> Result<Arc<Note>> {
+        let size = rasn::der::encode(&note).map_err(|_| Error::ASNSerialize)?.len();
+        let note = Arc::new(note);
+        self.insert(CacheKey::Note(note.id().clone()), Cached::Note(note.clone()), size);
+        Ok(note)
+    }
+
+    /// Drop whatever an event makes stale: the note it's about, or every note in a space that
+    /// was deleted.
+    pub fn invalidate(&mut self, event: &Event) {
+        if let Some(ObjectRef::Note(note_id)) = event.object() {
+            self.remove(&CacheKey::Note(note_id.clone()));
+        }
+        if let (EventKind::SpaceDeleted, Some(space_id)) = (event.kind(), event.space_id()) {
+            let stale = self.entries.iter()
+                .filter_map(|(key, entry)| match &entry.value {
+                    Cached::Note(note) if note.space_id() == space_id => Some(key.clone()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            for key in stale {
+                self.remove(&key);
+            }
+        }
+    }
+}
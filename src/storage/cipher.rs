@@ -0,0 +1,112 @@
+//! Encryption at rest for local storage.
+//!
+//! Transactions are mostly ciphertext already, but the parts around the ciphertext (who wrote
+//! what, when, in which space, and what it builds on) are not, and neither are settings blobs
+//! like a serialized outbox. A [`StorageCipher`] keyed from the user's master key seals every row
+//! a backend writes, and swaps the columns a backend looks things up by for keyed hashes, so a
+//! stolen device's database is just noise without the master key.
+//!
+//! What's still visible: how many transactions there are in how many spaces, roughly how big
+//! they are, the shape of each space's DAG (which hash builds on which), and the names of the
+//! settings blobs.
+
+use crate::{
+    crypto::{self, AssociatedData, KeyPurpose},
+    error::{Error, Result},
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use stamp_core::crypto::base::{Sealed, SecretKey};
+use zeroize::Zeroizing;
+
+/// Sealed into the storage so opening it with the wrong key fails up front instead of on the
+/// first read.
+const CHECK_VALUE: &[u8] = b"turtl/storage/check/v1";
+
+/// How a storage backend protects what it writes.
+pub enum StorageCipher {
+    /// Rows are stored as-is. Only for storage that's protected some other way (or for tests).
+    Plain,
+    /// Rows are sealed with a key derived from the master key, and lookup columns are HMACed.
+    Sealed {
+        key: SecretKey,
+        index_key: Zeroizing<Vec<u8>>,
+    },
+}
+
+impl StorageCipher {
+    /// A cipher that stores rows as-is
+    pub fn plain() -> Self {
+        Self::Plain
+    }
+
+    /// A cipher keyed from the user's master key.
+    pub fn new(master_key: &SecretKey) -> Result<Self> {
+        let key = crypto::derive_subkey(master_key, KeyPurpose::Storage)?;
+        let index_subkey = crypto::derive_subkey(master_key, KeyPurpose::StorageIndex)?;
+        let index_key = Zeroizing::new(rasn::der::encode(&index_subkey).map_err(|_| Error::ASNSerialize)?);
+        Ok(Self::Sealed { key, index_key })
+    }
+
+    /// Whether this cipher actually encrypts anything
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self, Self::Sealed { .. })
+    }
+
+    /// Turn a value into the form it's looked up by in a backend's `table`. Equal values (in the
+    /// same table) always give the same result.
+    pub fn index(&self, table: &str, value: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Plain => value.to_vec(),
+            Self::Sealed { index_key, .. } => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(&index_key[..])
+                    .expect("HMAC takes keys of any size");
+                mac.update(table.as_bytes());
+                mac.update(b"\0");
+                mac.update(value);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    /// Seal a row for a backend's `table`.
+    pub fn seal(&self, table: &str, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::Plain => Ok(data.to_vec()),
+            Self::Sealed { key, .. } => {
+                let sealed = crypto::seal_bound(key, &Self::associated(table), data)?;
+                rasn::der::encode(&sealed).map_err(|_| Error::ASNSerialize)
+            }
+        }
+    }
+
+    /// Open a row sealed with [`StorageCipher::seal`] for the same `table`.
+    pub fn open(&self, table: &str, data: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        match self {
+            Self::Plain => Ok(Zeroizing::new(data.to_vec())),
+            Self::Sealed { key, .. } => {
+                let sealed: Sealed = rasn::der::decode(data).map_err(|_| Error::Storage("row isn't sealed".into()))?;
+                let opened = crypto::open_bound(key, &Self::associated(table), &sealed)
+                    .map_err(|_| Error::Storage(format!("couldn't open a row in {}", table)))?;
+                Ok(Zeroizing::new(opened))
+            }
+        }
+    }
+
+    /// Make the value a backend stores to check it's being opened with the right cipher later
+    pub fn check_value(&self) -> Result<Vec<u8>> {
+        self.seal("check", CHECK_VALUE)
+    }
+
+    /// Make sure a value from [`StorageCipher::check_value`] was made by this cipher.
+    pub fn verify(&self, check: &[u8]) -> Result<()> {
+        match self.open("check", check) {
+            Ok(opened) if &opened[..] == CHECK_VALUE => Ok(()),
+            _ => Err(Error::Storage("storage is locked with a different key".into())),
+        }
+    }
+
+    fn associated(table: &str) -> AssociatedData {
+        AssociatedData::new(None, &format!("turtl/storage/{}", table))
+    }
+}
@@ -0,0 +1,181 @@
+//! Reclaiming space from operations that checkpoints have made redundant.
+//!
+//! A [key rotation][crate::crypto::RevocationPlan] writes a rotation marker followed by
+//! checkpoints of every live object in the space, so everything at or before the rotation's
+//! `pre_rotation` frontier is replaced by the checkpoints. Once every member of the space has
+//! acknowledged the checkpoints (their [sync marker][crate::models::space::Member::sync_marker]
+//! covers the last one), [`compact`] deletes the replaced transactions, deletes the blobs of
+//! the chunks they wrote that nothing points at anymore, and vacuums the storage.
+//!
+//! Compacted transactions are remembered (see [`known_ids`]) so that a peer sending one of them
+//! again is seen as a duplicate instead of being applied on top of the checkpoints.
+
+use crate::{
+    blob::FsBlobStore,
+    crypto::OperationKeys,
+    error::{Error, Result},
+    models::{
+        operation::{self, Operation, OperationAction},
+        space::{KeyRotation, SpaceID},
+        state::State,
+    },
+    storage::{Storage, Wal},
+    sync::purge,
+};
+use getset::Getters;
+use stamp_core::{
+    crypto::base::{Hash, SecretKey},
+    dag::{Transaction, TransactionID},
+};
+use std::collections::{HashMap, HashSet};
+
+/// The record kind compacted transaction IDs are remembered under
+const RECORD_COMPACTED: &str = "compacted";
+
+/// What a [`compact`] run got rid of.
+#[derive(Debug, Default, Getters)]
+#[getset(get = "pub")]
+pub struct Compaction {
+    /// How many transactions were deleted
+    transactions: usize,
+    /// How many blobs were deleted
+    blobs: usize,
+    /// About how many bytes were freed, across transactions, blobs, and the vacuum
+    bytes_reclaimed: u64,
+}
+
+/// Every transaction ID storage knows about: the ones it has, plus the ones compacted away and
+/// the ones [purged][purge::purged_ids] for a tombstone. This is what an
+/// [`Inbox`][crate::sync::Inbox] should be created with.
+pub fn known_ids(storage: &dyn Storage) -> Result<Vec<TransactionID>> {
+    let mut ids = storage.transaction_ids()?;
+    for data in storage.records(RECORD_COMPACTED)? {
+        ids.push(rasn::der::decode(&data[..]).map_err(|_| Error::ASNDeserialize)?);
+    }
+    ids.extend(purge::purged_ids(storage)?);
+    Ok(ids)
+}
+
+/// Decrypt a transaction with whichever of a space's keys opens it
+fn decrypt(trans: &Transaction, keys: &[OperationKeys]) -> Option<Operation> {
+    let (_, encrypted) = operation::operation_from_transaction(trans).ok()?;
+    keys.iter().find_map(|k| encrypted.decrypt_with(k).ok())
+}
+
+/// Everything at or before a set of transactions
+pub(crate) fn ancestors<'a>(tips: &'a [TransactionID], parents: &'a HashMap<TransactionID, Vec<TransactionID>>) -> HashSet<&'a TransactionID> {
+    let mut seen = HashSet::new();
+    let mut queue = tips.iter().collect::<Vec<_>>();
+    while let Some(id) = queue.pop() {
+        if seen.insert(id) {
+            if let Some(prev) = parents.get(id) {
+                queue.extend(prev.iter());
+            }
+        }
+    }
+    seen
+}
+
+/// Find the last transaction of the batch a rotation was written in: the rotation marker, then
+/// each checkpoint built directly on the one before it by the same author.
+fn checkpoint_tail<'a>(transactions: &'a [Transaction], keys: &[OperationKeys], rotation: &KeyRotation) -> Option<&'a TransactionID> {
+    let mut children: HashMap<&TransactionID, Vec<&Transaction>> = HashMap::new();
+    for trans in transactions {
+        for parent in trans.entry().previous_transactions() {
+            children.entry(parent).or_default().push(trans);
+        }
+    }
+    let marker = transactions.iter().find(|trans| {
+        decrypt(trans, keys)
+            .map(|op| matches!(op.action(), OperationAction::SpaceSetKeyRotatedV1(r) if r.key_id() == rotation.key_id()))
+            .unwrap_or(false)
+    })?;
+    let author = operation::operation_from_transaction(marker).ok()?.0;
+    let mut tail = marker;
+    while let Some([next]) = children.get(tail.id()).map(|c| &c[..]) {
+        let same_author = operation::operation_from_transaction(next).map(|(a, _)| a == author).unwrap_or(false);
+        if !same_author || next.entry().previous_transactions().len() != 1 {
+            break;
+        }
+        tail = *next;
+    }
+    Some(tail.id())
+}
+
+/// Delete every transaction that an acknowledged checkpoint replaced, delete the blobs of the
+/// chunks those transactions wrote that nothing points to anymore, and vacuum the storage.
+///
+/// `space_keys` are every key we have for each space (as from
+/// [`Keychain::space_keys`][crate::models::keychain::Keychain::space_keys], any order): they're
+/// needed to find the rotation markers and to see which chunks the replaced transactions wrote.
+/// Spaces we don't have a key for are left alone.
+///
+/// Blobs are only deleted if a compacted transaction we could decrypt wrote them, and no chunk
+/// in the state or in the [write-ahead log][Wal] points to them. Everything else in the blob
+/// store (ie, chunks that were uploaded ahead of their operations) is left alone: use
+/// [`FsBlobStore::sweep`] for a full sweep.
+pub fn compact(storage: &dyn Storage, blobs: &FsBlobStore, state: &State, space_keys: &HashMap<SpaceID, Vec<SecretKey>>) -> Result<Compaction> {
+    let mut compaction = Compaction::default();
+    let mut orphaned: Vec<Hash> = Vec::new();
+    for (space_id, space) in state.spaces() {
+        let rotation = match space.key_rotations().last() {
+            Some(rotation) => rotation,
+            None => continue,
+        };
+        let keys = match space_keys.get(space_id) {
+            Some(keys) if !keys.is_empty() => keys.iter().map(OperationKeys::new).collect::<Result<Vec<_>>>()?,
+            _ => continue,
+        };
+        let transactions = storage.transactions(Some(space_id))?;
+        let parents = operation::transaction_parents(&transactions[..]);
+        let tail = match checkpoint_tail(&transactions[..], &keys[..], rotation) {
+            Some(tail) => tail,
+            None => continue,
+        };
+        if !space.received_by_all(&parents).contains(tail) {
+            continue;
+        }
+        let replaced = ancestors(rotation.pre_rotation(), &parents);
+        let mut deleted = Vec::new();
+        for trans in transactions.iter().filter(|t| replaced.contains(t.id())) {
+            let encoded = rasn::der::encode(trans.id()).map_err(|_| Error::ASNSerialize)?;
+            storage.put_record(RECORD_COMPACTED, &encoded[..], &encoded[..])?;
+            compaction.bytes_reclaimed += rasn::der::encode(trans).map(|x| x.len() as u64).unwrap_or(0);
+            if let Some(OperationAction::FileSetChunkV1(chunk)) = decrypt(trans, &keys[..]).as_ref().map(|op| op.action()) {
+                if !orphaned.contains(chunk.blob_hash()) {
+                    orphaned.push(chunk.blob_hash().clone());
+                }
+            }
+            deleted.push(trans.id().clone());
+        }
+        storage.delete_transactions(&deleted[..])?;
+        compaction.transactions += deleted.len();
+    }
+
+    // chunks in the state, or still waiting in the log to be applied, keep their blobs
+    let mut live = state.chunks().values()
+        .map(|c| c.blob_hash().clone())
+        .collect::<Vec<Hash>>();
+    for trans in Wal::open(storage)?.pending(storage)? {
+        let keys = match operation::operation_from_transaction(&trans) {
+            Ok((_, encrypted)) => encrypted.context().as_ref().and_then(|space_id| space_keys.get(space_id)),
+            Err(_) => None,
+        };
+        let keys = match keys {
+            Some(keys) => keys.iter().map(OperationKeys::new).collect::<Result<Vec<_>>>()?,
+            None => continue,
+        };
+        if let Some(OperationAction::FileSetChunkV1(chunk)) = decrypt(&trans, &keys[..]).as_ref().map(|op| op.action()) {
+            live.push(chunk.blob_hash().clone());
+        }
+    }
+    for hash in orphaned.iter().filter(|h| !live.contains(h)) {
+        let freed = blobs.remove(hash)?;
+        if freed > 0 {
+            compaction.blobs += 1;
+            compaction.bytes_reclaimed += freed;
+        }
+    }
+    compaction.bytes_reclaimed += storage.vacuum()?;
+    Ok(compaction)
+}
@@ -0,0 +1,270 @@
+//! Searchable note metadata, so page queries can be answered by storage instead of by walking
+//! every note in memory.
+//!
+//! For each note, storage keeps which space it's in, its tags, the words in its title, its
+//! created/modified times, and whether it has a file or is deleted, in indexed tables. Tags and
+//! title words go through the backend's [cipher][crate::storage::StorageCipher] like any other
+//! lookup column, so an encrypted database only holds keyed hashes of them: good for "which
+//! notes have this exact tag," useless for reading tags back out. Timestamps and the two flags
+//! are kept as-is so they can be sorted and filtered on.
+//!
+//! [`NoteQuery::from_slice`] turns a page's slice into a query when it can. Slices that look at
+//! note text (searches, links) or anything else the metadata doesn't have can't be, and still
+//! need the notes themselves.
+//!
+//! Metadata is written as part of [persisting][crate::models::state::State::persist_dirty] the
+//! state. Storage created before the metadata tables existed fills in as notes change, or all at
+//! once after [`State::mark_all_dirty`][crate::models::state::State::mark_all_dirty].
+
+use crate::models::{
+    note::{Note, NoteDates, NoteID, SectionSpec, Tag},
+    page::{AscDesc, DisplayWindow, Slice, SliceFilter, Sort, SortEntry},
+    space::SpaceID,
+};
+use getset::Getters;
+use rasn::{AsnType, Decode, Encode};
+use stamp_core::util::Timestamp;
+
+/// The searchable parts of a note.
+#[derive(Clone, AsnType, Encode, Decode, Getters)]
+#[getset(get = "pub")]
+pub struct NoteMeta {
+    #[rasn(tag(explicit(0)))]
+    note_id: NoteID,
+    #[rasn(tag(explicit(1)))]
+    space_id: SpaceID,
+    #[rasn(tag(explicit(2)))]
+    tags: Vec<Tag>,
+    /// The (lowercased, deduplicated) words in the note's title
+    #[rasn(tag(explicit(3)))]
+    title_words: Vec<String>,
+    #[rasn(tag(explicit(4)))]
+    created: Option<Timestamp>,
+    #[rasn(tag(explicit(5)))]
+    modified: Option<Timestamp>,
+    #[rasn(tag(explicit(6)))]
+    has_file: bool,
+    #[rasn(tag(explicit(7)))]
+    deleted: bool,
+}
+
+impl NoteMeta {
+    /// Pull the metadata out of a note.
+    pub fn new(note: &Note, dates: Option<&NoteDates>) -> Self {
+        Self {
+            note_id: note.id().clone(),
+            space_id: note.space_id().clone(),
+            tags: note.tags().clone(),
+            title_words: note.title().as_deref().map(title_words).unwrap_or_default(),
+            created: dates.map(|d| d.created().clone()),
+            modified: dates.map(|d| d.modified().clone()),
+            has_file: note.body().sections().values().any(|s| matches!(s.spec(), SectionSpec::File { .. })),
+            deleted: *note.deleted(),
+        }
+    }
+
+    /// The lookup terms for this note's tags and title words, before the backend's cipher gets
+    /// to them
+    pub fn terms(&self) -> Vec<Vec<u8>> {
+        self.tags.iter()
+            .map(tag_term)
+            .chain(self.title_words.iter().map(|w| title_term(w)))
+            .collect()
+    }
+}
+
+/// Split a title into the words it's looked up by: lowercased and split on anything that isn't
+/// a letter or number, without repeats.
+pub fn title_words(title: &str) -> Vec<String> {
+    let mut words: Vec<String> = Vec::new();
+    for word in title.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()) {
+        let word = word.to_lowercase();
+        if !words.contains(&word) {
+            words.push(word);
+        }
+    }
+    words
+}
+
+/// How a tag is looked up. Tags and title words share a table, so each gets a prefix to keep
+/// the tag "work" and the title word "work" apart.
+pub fn tag_term(tag: &Tag) -> Vec<u8> {
+    [b"tag\0", tag.as_str().as_bytes()].concat()
+}
+
+/// How a title word is looked up
+pub fn title_term(word: &str) -> Vec<u8> {
+    [b"title\0", word.as_bytes()].concat()
+}
+
+/// Something notes can be sorted by
+#[derive(Clone, Debug, PartialEq)]
+pub enum MetaSort {
+    Created,
+    Modified,
+    HasFile,
+}
+
+/// A query against the note metadata in storage. Every condition has to hold for a note to
+/// match.
+#[derive(Clone, Getters)]
+#[getset(get = "pub")]
+pub struct NoteQuery {
+    space_id: SpaceID,
+    /// Notes must have every one of these tags
+    tags_all: Vec<Tag>,
+    /// Notes must have at least one tag from each of these groups
+    tags_any: Vec<Vec<Tag>>,
+    /// Notes must have every one of these words in their title
+    title_words: Vec<String>,
+    /// Only notes that are (or aren't) deleted, or either if `None`
+    deleted: Option<bool>,
+    /// Only notes that do (or don't) have a file, or either if `None`
+    has_file: Option<bool>,
+    /// The order to return notes in, as (what to sort by, ascending). Like a filtered slice,
+    /// ties (and everything, if this is empty) come back in no particular order.
+    sort: Vec<(MetaSort, bool)>,
+    /// Only return this window of the results
+    window: Option<DisplayWindow>,
+}
+
+impl NoteQuery {
+    /// A query for every note in a space that isn't deleted
+    pub fn new(space_id: SpaceID) -> Self {
+        Self {
+            space_id,
+            tags_all: Vec::new(),
+            tags_any: Vec::new(),
+            title_words: Vec::new(),
+            deleted: Some(false),
+            has_file: None,
+            sort: Vec::new(),
+            window: None,
+        }
+    }
+
+    /// Only match notes with this tag
+    pub fn with_tag(mut self, tag: Tag) -> Self {
+        self.tags_all.push(tag);
+        self
+    }
+
+    /// Only match notes with at least one of these tags
+    pub fn with_any_tag(mut self, tags: Vec<Tag>) -> Self {
+        self.tags_any.push(tags);
+        self
+    }
+
+    /// Only match notes with every word of `title` in their title
+    pub fn with_title(mut self, title: &str) -> Self {
+        self.title_words.extend(title_words(title));
+        self
+    }
+
+    /// Only match notes that are (or aren't) deleted, or either with `None`
+    pub fn with_deleted(mut self, deleted: Option<bool>) -> Self {
+        self.deleted = deleted;
+        self
+    }
+
+    /// Only match notes that do (or don't) have a file
+    pub fn with_has_file(mut self, has_file: bool) -> Self {
+        self.has_file = Some(has_file);
+        self
+    }
+
+    /// Add a sort, after whatever sorts are already there
+    pub fn with_sort(mut self, sort: MetaSort, ascending: bool) -> Self {
+        self.sort.push((sort, ascending));
+        self
+    }
+
+    /// Only return one window of the results
+    pub fn with_window(mut self, window: DisplayWindow) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    /// Whether this query can't match anything (ie, it wants one of an empty set of tags), so a
+    /// backend doesn't need to bother.
+    pub fn matches_nothing(&self) -> bool {
+        self.tags_any.iter().any(|tags| tags.is_empty())
+    }
+
+    /// Turn a page's slice and sort into a query, if the metadata is enough to answer it. Returns
+    /// `None` for manual slices (which already know their notes) and for filters or sorts that
+    /// need more than the metadata has.
+    pub fn from_slice(space_id: SpaceID, slice: &Slice, sort: &[SortEntry]) -> Option<Self> {
+        let filter = match slice {
+            Slice::Filtered { filter, .. } => filter,
+            Slice::Manual(..) => return None,
+        };
+        let mut query = Self::new(space_id).with_deleted(None);
+        if !query.add_filter(filter) {
+            return None;
+        }
+        // same as a slice: deleted notes only show up when asked for
+        if !filter.mentions_deleted() {
+            query.deleted = Some(false);
+        }
+        for entry in sort {
+            let by = match entry.sort() {
+                Sort::Created => MetaSort::Created,
+                Sort::Modified => MetaSort::Modified,
+                Sort::HasFile => MetaSort::HasFile,
+                Sort::Title => return None,
+            };
+            query.sort.push((by, matches!(entry.asc(), AscDesc::Ascending)));
+        }
+        Some(query)
+    }
+
+    /// Fold a filter into this query, returning false if it can't be expressed.
+    fn add_filter(&mut self, filter: &SliceFilter) -> bool {
+        // two different answers for the same flag can't be expressed (and never matches anyway)
+        fn set_flag(flag: &mut Option<bool>, val: bool) -> bool {
+            match flag {
+                Some(existing) => *existing == val,
+                None => {
+                    *flag = Some(val);
+                    true
+                }
+            }
+        }
+        match filter {
+            SliceFilter::And(filters) => filters.iter().all(|f| self.add_filter(f)),
+            SliceFilter::Tag(tag) => {
+                self.tags_all.push(tag.clone());
+                true
+            }
+            SliceFilter::TagAllOf(tags) => {
+                self.tags_all.extend(tags.iter().cloned());
+                true
+            }
+            SliceFilter::TagAnyOf(tags) => {
+                self.tags_any.push(tags.clone());
+                true
+            }
+            // an "or" of tags is just a bigger "any of"
+            SliceFilter::Or(filters) => {
+                let tags = filters.iter()
+                    .map(|f| match f {
+                        SliceFilter::Tag(tag) => Some(vec![tag.clone()]),
+                        SliceFilter::TagAnyOf(tags) => Some(tags.clone()),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>();
+                match tags {
+                    Some(tags) => {
+                        self.tags_any.push(tags.into_iter().flatten().collect());
+                        true
+                    }
+                    None => false,
+                }
+            }
+            SliceFilter::HasFile(has_file) => set_flag(&mut self.has_file, *has_file),
+            SliceFilter::Deleted(deleted) => set_flag(&mut self.deleted, *deleted),
+            SliceFilter::Search(..) | SliceFilter::LinksTo(..) | SliceFilter::Not(..) | SliceFilter::HasFileOfType(..) => false,
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! Durable local storage for the operation log: every transaction we have, grouped by space,
+//! plus a handful of opaque settings blobs (a serialized [`Outbox`][crate::sync::Outbox], the
+//! device's ID, and so on).
+//!
+//! The core doesn't care where things are kept, so [`Storage`] is a trait. A SQLite backend
+//! ships behind the `sqlite` feature (see [`sqlite::SqliteStorage`]).
+//!
+//! Backends encrypt what they write with a [`StorageCipher`] keyed from the master key (see
+//! [`cipher`]), so the database on a lost device doesn't give away the operation log.
+//!
+//! Incoming transactions go through a [write-ahead log][wal] on their way into the state, so a
+//! crash mid-apply doesn't lose them.
+//!
+//! Backends also keep [searchable note metadata][meta], so big pages can be queried without
+//! every note in memory.
+//!
+//! A [`BackupManager`] keeps a rotating set of encrypted state snapshots, to restore from when
+//! the state on disk goes bad.
+//!
+//! One device can hold several accounts side by side, each with its own storage: see
+//! [`Profiles`].
+
+use crate::{
+    error::Result,
+    models::{note::NoteID, operation, space::SpaceID},
+};
+use stamp_core::dag::{Transaction, TransactionID};
+
+pub mod backup;
+pub mod cache;
+pub mod cipher;
+pub mod compact;
+pub mod meta;
+pub mod profile;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod wal;
+
+pub use backup::BackupManager;
+pub use cache::DecryptCache;
+pub use cipher::StorageCipher;
+pub use compact::compact;
+pub use meta::{NoteMeta, NoteQuery};
+pub use profile::{Profile, ProfileID, Profiles};
+pub use wal::Wal;
+
+/// Somewhere to keep transactions between runs.
+///
+/// Space IDs are `None` for the user's own transactions. Transactions come back in the order
+/// they were stored, which (since the [inbox][crate::sync::Inbox] only lets a transaction through
+/// once its parents are in) is always parents first.
+pub trait Storage {
+    /// Store a transaction under a space. Storing a transaction we already have does nothing.
+    fn put_transaction(&self, space_id: Option<&SpaceID>, transaction: &Transaction) -> Result<()>;
+
+    /// Grab a transaction by ID, if we have it.
+    fn get_transaction(&self, id: &TransactionID) -> Result<Option<Transaction>>;
+
+    /// Whether we have a transaction.
+    fn has_transaction(&self, id: &TransactionID) -> Result<bool>;
+
+    /// Every transaction we have for a space, in the order they were stored.
+    fn transactions(&self, space_id: Option<&SpaceID>) -> Result<Vec<Transaction>>;
+
+    /// The IDs of every transaction we have, in any space. To set up an
+    /// [`Inbox`][crate::sync::Inbox], use [`compact::known_ids`] instead, which also counts
+    /// compacted transactions.
+    fn transaction_ids(&self) -> Result<Vec<TransactionID>>;
+
+    /// The tips of a space's DAG: transactions nothing else we have builds on.
+    fn frontier(&self, space_id: Option<&SpaceID>) -> Result<Vec<TransactionID>>;
+
+    /// The spaces we have transactions for (`None` being the user's own).
+    fn spaces(&self) -> Result<Vec<Option<SpaceID>>>;
+
+    /// Remove transactions (ie, ones a [tombstone][crate::models::space::Tombstone] says can go).
+    /// Anything that built on a removed transaction still counts as building on it, so removing a
+    /// transaction never moves the frontier backwards.
+    fn delete_transactions(&self, ids: &[TransactionID]) -> Result<()>;
+
+    /// Store a settings blob, replacing whatever was under the key.
+    fn put_setting(&self, key: &str, value: &[u8]) -> Result<()>;
+
+    /// Grab a settings blob, if there's one under the key.
+    fn get_setting(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Store a record (ie, one note of a [persisted state][crate::models::state::State::persist_dirty])
+    /// under a kind and ID, replacing whatever was there.
+    fn put_record(&self, kind: &str, id: &[u8], data: &[u8]) -> Result<()>;
+
+    /// Remove a record. Removing a record we don't have does nothing.
+    fn delete_record(&self, kind: &str, id: &[u8]) -> Result<()>;
+
+    /// Every record of a kind, in no particular order.
+    fn records(&self, kind: &str) -> Result<Vec<Vec<u8>>>;
+
+    /// Store a note's [searchable metadata][meta], replacing whatever was there for the note.
+    fn put_note_meta(&self, meta: &NoteMeta) -> Result<()>;
+
+    /// Remove a note's metadata. Removing metadata we don't have does nothing.
+    fn delete_note_meta(&self, note_id: &NoteID) -> Result<()>;
+
+    /// The notes whose metadata matches a query, in the query's order.
+    fn query_notes(&self, query: &NoteQuery) -> Result<Vec<NoteID>>;
+
+    /// Make a group of writes all-or-nothing: either every write `writes` makes lands, or (if it
+    /// fails, or we crash partway) none do. Calls nested inside `writes` are part of the same
+    /// group.
+    fn atomic(&self, writes: &mut dyn FnMut() -> Result<()>) -> Result<()>;
+
+    /// Give space freed by deletes back to the filesystem, if the backend holds on to it.
+    /// Returns about how many bytes were given back.
+    fn vacuum(&self) -> Result<u64>;
+}
+
+/// Store a transaction under whatever space its operation says it belongs to.
+pub fn store_transaction(storage: &dyn Storage, transaction: &Transaction) -> Result<()> {
+    let (_, encrypted) = operation::operation_from_transaction(transaction)?;
+    storage.put_transaction(encrypted.context().as_ref(), transaction)
+}
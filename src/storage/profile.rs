@@ -0,0 +1,188 @@
+//! Keeping more than one account on a device.
+//!
+//! A storage root holds any number of profiles, each one an independent account with its own
+//! directory: its own database (encrypted under that account's master key), its own blobs, its
+//! own settings and operation log. Nothing is shared between profiles, so one account being
+//! unlocked says nothing about the others.
+//!
+//! The list of profiles lives in a small index file at the root. It's kept in the clear (someone
+//! has to be able to pick a profile before unlocking it), so profile names shouldn't be
+//! anything more private than "Mom" or "work".
+
+use crate::{
+    blob::{self, FsBlobStore},
+    error::{Error, Result},
+    models::object_id,
+};
+use data_encoding::HEXLOWER;
+use getset::Getters;
+use serde::{Deserialize, Serialize};
+use stamp_core::util::Timestamp;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "sqlite")]
+use {crate::storage::sqlite::SqliteStorage, stamp_core::crypto::base::SecretKey};
+
+/// The name of the profile index file in a storage root
+const PROFILES_FILE: &str = "profiles.json";
+
+/// The name of a profile's database within its directory
+pub const DATABASE_FILE: &str = "storage.db";
+
+/// The name of a profile's blob directory within its directory
+pub const BLOBS_DIR: &str = "blobs";
+
+object_id! {
+    /// A unique ID for a profile
+    ProfileID
+}
+
+/// One account kept in a [`Profiles`] root.
+#[derive(Clone, Debug, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct Profile {
+    /// The profile's ID
+    id: ProfileID,
+    /// What the profile is called on the profile picker
+    name: String,
+    /// When the profile was created
+    created: Timestamp,
+}
+
+/// The profiles in a storage root. See the [module docs][self].
+pub struct Profiles {
+    root: PathBuf,
+    profiles: Vec<Profile>,
+}
+
+impl Profiles {
+    /// Open the storage root at the given path, creating it if needed.
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+        let profiles = match fs::read(root.join(PROFILES_FILE)) {
+            Ok(data) => serde_json::from_slice(&data[..])
+                .map_err(|e| Error::Storage(format!("profile index is unreadable: {}", e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => Err(e)?,
+        };
+        Ok(Self { root, profiles })
+    }
+
+    /// The storage root
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Every profile, in the order they were created
+    pub fn list(&self) -> &[Profile] {
+        &self.profiles[..]
+    }
+
+    /// Grab a profile by ID
+    pub fn get(&self, profile_id: &ProfileID) -> Option<&Profile> {
+        self.profiles.iter().find(|p| p.id() == profile_id)
+    }
+
+    /// Add a new, empty profile, created as of `now`.
+    pub fn create(&mut self, name: String, now: &Timestamp) -> Result<Profile> {
+        let profile = Profile { id: ProfileID::new(), name, created: now.clone() };
+        fs::create_dir_all(self.dir_for(profile.id())?)?;
+        self.profiles.push(profile.clone());
+        self.save()?;
+        Ok(profile)
+    }
+
+    /// Change what a profile is called.
+    pub fn rename(&mut self, profile_id: &ProfileID, name: String) -> Result<()> {
+        let profile = self.profiles.iter_mut()
+            .find(|p| p.id() == profile_id)
+            .ok_or(Error::ProfileNotFound)?;
+        profile.name = name;
+        self.save()
+    }
+
+    /// Remove a profile and everything stored for it. There's no getting it back (short of
+    /// syncing the account down again), so ask first.
+    pub fn delete(&mut self, profile_id: &ProfileID) -> Result<()> {
+        let idx = self.profiles.iter()
+            .position(|p| p.id() == profile_id)
+            .ok_or(Error::ProfileNotFound)?;
+        // drop the profile from the index first: a crash halfway through deleting leaves a stray
+        // directory instead of a profile that's half there
+        let profile = self.profiles.remove(idx);
+        self.save()?;
+        match fs::remove_dir_all(self.dir_for(profile.id())?) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e)?,
+        }
+    }
+
+    /// The directory a profile's data lives in. Fails if there's no such profile.
+    pub fn dir(&self, profile_id: &ProfileID) -> Result<PathBuf> {
+        self.get(profile_id).ok_or(Error::ProfileNotFound)?;
+        self.dir_for(profile_id)
+    }
+
+    /// Where a profile's database lives
+    pub fn database_path(&self, profile_id: &ProfileID) -> Result<PathBuf> {
+        Ok(self.dir(profile_id)?.join(DATABASE_FILE))
+    }
+
+    /// Open a profile's blob store.
+    pub fn blobs(&self, profile_id: &ProfileID) -> Result<FsBlobStore> {
+        FsBlobStore::new(self.dir(profile_id)?.join(BLOBS_DIR))
+    }
+
+    /// Open a profile's database, encrypted under the account's master key. Opening a profile
+    /// with another account's key fails.
+    #[cfg(feature = "sqlite")]
+    pub fn open_storage(&self, profile_id: &ProfileID, master_key: &SecretKey) -> Result<SqliteStorage> {
+        SqliteStorage::open_encrypted(self.database_path(profile_id)?, master_key)
+    }
+
+    fn dir_for(&self, profile_id: &ProfileID) -> Result<PathBuf> {
+        let serialized = rasn::der::encode(profile_id).map_err(|_| Error::ASNSerialize)?;
+        Ok(self.root.join(HEXLOWER.encode(&serialized[..])))
+    }
+
+    /// Write the index out, in a way that a crash never leaves it half-written.
+    fn save(&self) -> Result<()> {
+        let data = serde_json::to_vec_pretty(&self.profiles)
+            .map_err(|e| Error::Storage(format!("couldn't serialize profile index: {}", e)))?;
+        let path = self.root.join(PROFILES_FILE);
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(&data[..])?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &path)?;
+        blob::sync_dir(&self.root);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn created_profiles_are_kept_in_the_index() {
+        let root = test_util::temp_dir("profiles");
+        let now = Timestamp::from(Utc.timestamp_opt(1_700_000_000, 0).unwrap());
+        let mut profiles = Profiles::open(&root).unwrap();
+        let profile = profiles.create("work".into(), &now).unwrap();
+        assert_eq!(**profile.created(), *now);
+        assert!(profiles.dir(profile.id()).unwrap().is_dir());
+
+        let reopened = Profiles::open(&root).unwrap();
+        let loaded = reopened.get(profile.id()).unwrap();
+        assert_eq!(loaded.name(), "work");
+        assert_eq!(**loaded.created(), *now);
+    }
+}
@@ -0,0 +1,693 @@
+//! A [`Storage`] backed by a SQLite database.
+//!
+//! Every row goes through a [`StorageCipher`]: lookup columns (transaction IDs, space IDs,
+//! parent links) hold its [index][StorageCipher::index] of the DER-encoded value, and the data
+//! we actually read back is [sealed][StorageCipher::seal]. Parent links get a table of their own
+//! so frontier queries don't have to load every transaction.
+//!
+//! [Note metadata][crate::storage::meta] goes in two tables: `note_meta`, one row per note with
+//! the columns queries sort and filter on, and `note_terms`, one row per tag or title word. Both
+//! lookup columns are cipher indexes, and the whole [`NoteMeta`] is sealed alongside so rekeying
+//! can rebuild them.
+
+use crate::{
+    error::{Error, Result},
+    models::{note::NoteID, space::SpaceID},
+    storage::{
+        meta::{self, MetaSort, NoteMeta, NoteQuery},
+        Storage, StorageCipher,
+    },
+};
+use rasn::{AsnType, Decode, Encode};
+use rusqlite::{params, params_from_iter, types::Value, Connection, OptionalExtension};
+use stamp_core::{
+    crypto::base::SecretKey,
+    dag::{Transaction, TransactionID},
+};
+use std::path::Path;
+
+/// The schema version we create. Bumped (with a migration) whenever the tables change.
+const SCHEMA_VERSION: i64 = 4;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS transactions (
+        id BLOB PRIMARY KEY,
+        space BLOB,
+        space_data BLOB,
+        data BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS transactions_space ON transactions (space);
+    CREATE TABLE IF NOT EXISTS parents (
+        child BLOB NOT NULL,
+        parent BLOB NOT NULL,
+        link BLOB NOT NULL,
+        PRIMARY KEY (child, parent)
+    );
+    CREATE INDEX IF NOT EXISTS parents_parent ON parents (parent);
+    CREATE TABLE IF NOT EXISTS settings (
+        key TEXT PRIMARY KEY,
+        value BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS records (
+        kind TEXT NOT NULL,
+        id BLOB NOT NULL,
+        id_data BLOB NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (kind, id)
+    );
+    CREATE TABLE IF NOT EXISTS note_meta (
+        note BLOB PRIMARY KEY,
+        space BLOB NOT NULL,
+        created INTEGER,
+        modified INTEGER,
+        has_file INTEGER NOT NULL,
+        deleted INTEGER NOT NULL,
+        data BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS note_meta_space ON note_meta (space, deleted);
+    CREATE INDEX IF NOT EXISTS note_meta_created ON note_meta (space, created);
+    CREATE INDEX IF NOT EXISTS note_meta_modified ON note_meta (space, modified);
+    CREATE TABLE IF NOT EXISTS note_terms (
+        term BLOB NOT NULL,
+        note BLOB NOT NULL,
+        PRIMARY KEY (term, note)
+    );
+    CREATE INDEX IF NOT EXISTS note_terms_note ON note_terms (note);
+";
+
+/// A parent link, sealed into the `parents` table so the lookup columns can be rebuilt when
+/// the database is rekeyed (the child might be long gone by then).
+#[derive(AsnType, Encode, Decode)]
+struct ParentLink {
+    #[rasn(tag(explicit(0)))]
+    child: TransactionID,
+    #[rasn(tag(explicit(1)))]
+    parent: TransactionID,
+}
+
+fn db_err(err: rusqlite::Error) -> Error {
+    Error::Storage(format!("{}", err))
+}
+
+fn encode<T: rasn::Encode>(val: &T) -> Result<Vec<u8>> {
+    rasn::der::encode(val).map_err(|_| Error::ASNSerialize)
+}
+
+fn decode<T: rasn::Decode>(bytes: &[u8]) -> Result<T> {
+    rasn::der::decode(bytes).map_err(|_| Error::ASNDeserialize)
+}
+
+/// The columns a transaction is stored under
+struct TransactionRow {
+    id: Vec<u8>,
+    space: Option<Vec<u8>>,
+    space_data: Option<Vec<u8>>,
+    data: Vec<u8>,
+}
+
+impl TransactionRow {
+    fn new(cipher: &StorageCipher, space_id: Option<&SpaceID>, transaction: &Transaction) -> Result<Self> {
+        let space = space_id.map(encode).transpose()?;
+        Ok(Self {
+            id: cipher.index("transactions", &encode(transaction.id())?[..]),
+            space: space.as_ref().map(|s| cipher.index("spaces", &s[..])),
+            space_data: space.map(|s| cipher.seal("spaces", &s[..])).transpose()?,
+            data: cipher.seal("transactions", &encode(transaction)?[..])?,
+        })
+    }
+}
+
+/// The columns a parent link is stored under
+fn link_row(cipher: &StorageCipher, link: &ParentLink) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>)> {
+    Ok((
+        cipher.index("transactions", &encode(&link.child)?[..]),
+        cipher.index("transactions", &encode(&link.parent)?[..]),
+        cipher.seal("parents", &encode(link)?[..])?,
+    ))
+}
+
+/// Local storage in a SQLite database.
+pub struct SqliteStorage {
+    conn: Connection,
+    cipher: StorageCipher,
+}
+
+impl SqliteStorage {
+    /// Open (or create) an unencrypted database at the given path.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with(path, StorageCipher::plain())
+    }
+
+    /// Open (or create) a database at the given path, encrypted under the master key.
+    pub fn open_encrypted<P: AsRef<Path>>(path: P, master_key: &SecretKey) -> Result<Self> {
+        Self::open_with(path, StorageCipher::new(master_key)?)
+    }
+
+    /// Open (or create) a database at the given path with the given cipher. Fails if the
+    /// database was written with a different one.
+    pub fn open_with<P: AsRef<Path>>(path: P, cipher: StorageCipher) -> Result<Self> {
+        Self::init(Connection::open(path).map_err(db_err)?, cipher)
+    }
+
+    /// Open a database that only lives in memory, ie for tests or a throwaway session.
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory().map_err(db_err)?, StorageCipher::plain())
+    }
+
+    fn init(mut conn: Connection, cipher: StorageCipher) -> Result<Self> {
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0)).map_err(db_err)?;
+        if version > SCHEMA_VERSION {
+            Err(Error::Storage(format!("database schema {} is newer than we understand", version)))?;
+        }
+        let has_meta: bool = conn
+            .query_row("SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'meta')", [], |row| row.get(0))
+            .map_err(db_err)?;
+        let check: Option<Vec<u8>> = if has_meta {
+            conn.query_row("SELECT value FROM meta WHERE key = 'check'", [], |row| row.get(0))
+                .optional()
+                .map_err(db_err)?
+        } else {
+            None
+        };
+        // check the key before writing anything, so opening with the wrong one leaves the
+        // database as it was
+        let new_check = match check {
+            Some(check) => {
+                cipher.verify(&check[..])?;
+                None
+            }
+            None => {
+                // version 1 databases are always plain: open them plain and rekey to encrypt
+                let check = if version == 1 { StorageCipher::plain().check_value()? } else { cipher.check_value()? };
+                cipher.verify(&check[..])?;
+                Some(check)
+            }
+        };
+
+        // all or nothing, so a crash partway through leaves the old version to migrate again
+        let tx = conn.transaction().map_err(db_err)?;
+        if version == 1 {
+            migrate_v1(&tx)?;
+        }
+        tx.execute_batch(SCHEMA).map_err(db_err)?;
+        tx.pragma_update(None, "user_version", SCHEMA_VERSION).map_err(db_err)?;
+        if let Some(check) = new_check {
+            tx.execute("INSERT INTO meta (key, value) VALUES ('check', ?1)", params![check])
+                .map_err(db_err)?;
+        }
+        tx.commit().map_err(db_err)?;
+        Ok(Self { conn, cipher })
+    }
+
+    /// Whether this database is encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_encrypted()
+    }
+
+    /// Re-encrypt everything under a new cipher, ie after the master key changes (or to encrypt
+    /// a database that was plain until now). Either every row moves over or none do.
+    pub fn rekey(&mut self, cipher: StorageCipher) -> Result<()> {
+        let tx = self.conn.transaction().map_err(db_err)?;
+        let old = &self.cipher;
+
+        let rows = {
+            let mut stmt = tx.prepare("SELECT rowid, space_data, data FROM transactions").map_err(db_err)?;
+            let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Option<Vec<u8>>>(1)?, row.get::<_, Vec<u8>>(2)?)))
+                .map_err(db_err)?;
+            rows.collect::<std::result::Result<Vec<_>, _>>().map_err(db_err)?
+        };
+        for (rowid, space_data, data) in rows {
+            let transaction: Transaction = decode(&old.open("transactions", &data[..])?[..])?;
+            let space_id: Option<SpaceID> = space_data
+                .map(|s| old.open("spaces", &s[..]).and_then(|s| decode(&s[..])))
+                .transpose()?;
+            let row = TransactionRow::new(&cipher, space_id.as_ref(), &transaction)?;
+            tx.execute(
+                "UPDATE transactions SET id = ?1, space = ?2, space_data = ?3, data = ?4 WHERE rowid = ?5",
+                params![row.id, row.space, row.space_data, row.data, rowid],
+            ).map_err(db_err)?;
+        }
+
+        let links = {
+            let mut stmt = tx.prepare("SELECT rowid, link FROM parents").map_err(db_err)?;
+            let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))).map_err(db_err)?;
+            rows.collect::<std::result::Result<Vec<_>, _>>().map_err(db_err)?
+        };
+        for (rowid, link) in links {
+            let link: ParentLink = decode(&old.open("parents", &link[..])?[..])?;
+            let (child, parent, link) = link_row(&cipher, &link)?;
+            tx.execute("UPDATE parents SET child = ?1, parent = ?2, link = ?3 WHERE rowid = ?4", params![child, parent, link, rowid])
+                .map_err(db_err)?;
+        }
+
+        let settings = {
+            let mut stmt = tx.prepare("SELECT key, value FROM settings").map_err(db_err)?;
+            let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))).map_err(db_err)?;
+            rows.collect::<std::result::Result<Vec<_>, _>>().map_err(db_err)?
+        };
+        for (key, value) in settings {
+            let value = cipher.seal("settings", &old.open("settings", &value[..])?[..])?;
+            tx.execute("UPDATE settings SET value = ?1 WHERE key = ?2", params![value, key]).map_err(db_err)?;
+        }
+
+        let records = {
+            let mut stmt = tx.prepare("SELECT rowid, kind, id_data, data FROM records").map_err(db_err)?;
+            let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, Vec<u8>>(2)?, row.get::<_, Vec<u8>>(3)?)))
+                .map_err(db_err)?;
+            rows.collect::<std::result::Result<Vec<_>, _>>().map_err(db_err)?
+        };
+        for (rowid, kind, id_data, data) in records {
+            let table = record_table(&kind);
+            let id = old.open(&table, &id_data[..])?;
+            let data = old.open(&table, &data[..])?;
+            tx.execute(
+                "UPDATE records SET id = ?1, id_data = ?2, data = ?3 WHERE rowid = ?4",
+                params![cipher.index(&table, &id[..]), cipher.seal(&table, &id[..])?, cipher.seal(&table, &data[..])?, rowid],
+            ).map_err(db_err)?;
+        }
+
+        let metas = {
+            let mut stmt = tx.prepare("SELECT data FROM note_meta ORDER BY rowid").map_err(db_err)?;
+            let rows = stmt.query_map([], |row| row.get::<_, Vec<u8>>(0)).map_err(db_err)?;
+            rows.collect::<std::result::Result<Vec<_>, _>>().map_err(db_err)?
+        };
+        tx.execute_batch("DELETE FROM note_meta; DELETE FROM note_terms;").map_err(db_err)?;
+        for data in metas {
+            let meta: NoteMeta = decode(&old.open("note-meta", &data[..])?[..])?;
+            write_note_meta(&tx, &cipher, &meta)?;
+        }
+
+        tx.execute("INSERT OR REPLACE INTO meta (key, value) VALUES ('check', ?1)", params![cipher.check_value()?])
+            .map_err(db_err)?;
+        tx.commit().map_err(db_err)?;
+        self.cipher = cipher;
+        Ok(())
+    }
+
+    /// Open a column of sealed transactions
+    fn collect_transactions(&self, sql: &str, space: Option<Vec<u8>>) -> Result<Vec<Transaction>> {
+        let mut stmt = self.conn.prepare(sql).map_err(db_err)?;
+        let rows = stmt.query_map(params![space], |row| row.get::<_, Vec<u8>>(0)).map_err(db_err)?;
+        rows.map(|data| decode(&self.cipher.open("transactions", &data.map_err(db_err)?[..])?[..])).collect()
+    }
+
+    /// Run some writes in a transaction, unless they're already part of one (see
+    /// [`Storage::atomic`]), in which case they just run.
+    fn write<T, F: FnOnce(&Connection) -> Result<T>>(&self, writes: F) -> Result<T> {
+        if !self.conn.is_autocommit() {
+            return writes(&self.conn);
+        }
+        let tx = self.conn.unchecked_transaction().map_err(db_err)?;
+        let out = writes(&tx)?;
+        tx.commit().map_err(db_err)?;
+        Ok(out)
+    }
+
+    fn space_index(&self, space_id: Option<&SpaceID>) -> Result<Option<Vec<u8>>> {
+        Ok(space_id.map(encode).transpose()?.map(|s| self.cipher.index("spaces", &s[..])))
+    }
+
+    fn transaction_index(&self, id: &TransactionID) -> Result<Vec<u8>> {
+        Ok(self.cipher.index("transactions", &encode(id)?[..]))
+    }
+}
+
+/// Write a note's metadata rows, replacing whatever was there.
+fn write_note_meta(conn: &Connection, cipher: &StorageCipher, meta: &NoteMeta) -> Result<()> {
+    let note = cipher.index("notes", &encode(meta.note_id())?[..]);
+    let space = cipher.index("spaces", &encode(meta.space_id())?[..]);
+    conn.execute(
+        "INSERT OR REPLACE INTO note_meta (note, space, created, modified, has_file, deleted, data) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            note,
+            space,
+            meta.created().as_ref().map(|t| t.timestamp_millis()),
+            meta.modified().as_ref().map(|t| t.timestamp_millis()),
+            meta.has_file(),
+            meta.deleted(),
+            cipher.seal("note-meta", &encode(meta)?[..])?,
+        ],
+    ).map_err(db_err)?;
+    conn.execute("DELETE FROM note_terms WHERE note = ?1", params![note]).map_err(db_err)?;
+    for term in meta.terms() {
+        conn.execute("INSERT OR IGNORE INTO note_terms (term, note) VALUES (?1, ?2)", params![cipher.index("note-terms", &term[..]), note])
+            .map_err(db_err)?;
+    }
+    Ok(())
+}
+
+/// What the cipher calls a kind of record
+fn record_table(kind: &str) -> String {
+    format!("records/{}", kind)
+}
+
+/// Bring a version 1 database (plain, no sealed columns) up to version 2.
+fn migrate_v1(conn: &Connection) -> Result<()> {
+    conn.execute_batch("
+        ALTER TABLE transactions ADD COLUMN space_data BLOB;
+        UPDATE transactions SET space_data = space;
+        ALTER TABLE parents ADD COLUMN link BLOB NOT NULL DEFAULT x'';
+    ").map_err(db_err)?;
+    let links = {
+        let mut stmt = conn.prepare("SELECT rowid, child, parent FROM parents").map_err(db_err)?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?, row.get::<_, Vec<u8>>(2)?))).map_err(db_err)?;
+        rows.collect::<std::result::Result<Vec<_>, _>>().map_err(db_err)?
+    };
+    for (rowid, child, parent) in links {
+        let link = ParentLink { child: decode(&child[..])?, parent: decode(&parent[..])? };
+        conn.execute("UPDATE parents SET link = ?1 WHERE rowid = ?2", params![encode(&link)?, rowid]).map_err(db_err)?;
+    }
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn put_transaction(&self, space_id: Option<&SpaceID>, transaction: &Transaction) -> Result<()> {
+        let row = TransactionRow::new(&self.cipher, space_id, transaction)?;
+        self.write(|conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO transactions (id, space, space_data, data) VALUES (?1, ?2, ?3, ?4)",
+                params![row.id, row.space, row.space_data, row.data],
+            ).map_err(db_err)?;
+            for parent in transaction.entry().previous_transactions() {
+                let link = ParentLink { child: transaction.id().clone(), parent: parent.clone() };
+                let (child, parent, link) = link_row(&self.cipher, &link)?;
+                conn.execute("INSERT OR IGNORE INTO parents (child, parent, link) VALUES (?1, ?2, ?3)", params![child, parent, link])
+                    .map_err(db_err)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn get_transaction(&self, id: &TransactionID) -> Result<Option<Transaction>> {
+        let data: Option<Vec<u8>> = self.conn
+            .query_row("SELECT data FROM transactions WHERE id = ?1", params![self.transaction_index(id)?], |row| row.get(0))
+            .optional()
+            .map_err(db_err)?;
+        let transaction: Option<Transaction> = data
+            .map(|d| decode(&self.cipher.open("transactions", &d[..])?[..]))
+            .transpose()?;
+        // rows are sealed one at a time, so make sure this one wasn't moved under another's ID
+        if transaction.as_ref().map(|t| t.id() != id).unwrap_or(false) {
+            Err(Error::Storage("transaction is stored under the wrong ID".into()))?;
+        }
+        Ok(transaction)
+    }
+
+    fn has_transaction(&self, id: &TransactionID) -> Result<bool> {
+        let count: i64 = self.conn
+            .query_row("SELECT COUNT(*) FROM transactions WHERE id = ?1", params![self.transaction_index(id)?], |row| row.get(0))
+            .map_err(db_err)?;
+        Ok(count > 0)
+    }
+
+    fn transactions(&self, space_id: Option<&SpaceID>) -> Result<Vec<Transaction>> {
+        self.collect_transactions("SELECT data FROM transactions WHERE space IS ?1 ORDER BY rowid", self.space_index(space_id)?)
+    }
+
+    fn transaction_ids(&self) -> Result<Vec<TransactionID>> {
+        let mut stmt = self.conn.prepare("SELECT data FROM transactions ORDER BY rowid").map_err(db_err)?;
+        let rows = stmt.query_map([], |row| row.get::<_, Vec<u8>>(0)).map_err(db_err)?;
+        rows.map(|data| {
+            let transaction: Transaction = decode(&self.cipher.open("transactions", &data.map_err(db_err)?[..])?[..])?;
+            Ok(transaction.id().clone())
+        }).collect()
+    }
+
+    fn frontier(&self, space_id: Option<&SpaceID>) -> Result<Vec<TransactionID>> {
+        let tips = self.collect_transactions(
+            "SELECT t.data FROM transactions t
+                WHERE t.space IS ?1
+                AND NOT EXISTS (SELECT 1 FROM parents p WHERE p.parent = t.id)
+                ORDER BY t.rowid",
+            self.space_index(space_id)?,
+        )?;
+        Ok(tips.iter().map(|t| t.id().clone()).collect())
+    }
+
+    fn spaces(&self) -> Result<Vec<Option<SpaceID>>> {
+        let mut stmt = self.conn.prepare("SELECT MIN(space_data) FROM transactions GROUP BY space").map_err(db_err)?;
+        let rows = stmt.query_map([], |row| row.get::<_, Option<Vec<u8>>>(0)).map_err(db_err)?;
+        rows.map(|space| {
+            space.map_err(db_err)?
+                .map(|s| decode(&self.cipher.open("spaces", &s[..])?[..]))
+                .transpose()
+        }).collect()
+    }
+
+    fn delete_transactions(&self, ids: &[TransactionID]) -> Result<()> {
+        // parent links are kept on purpose, see the trait docs
+        self.write(|conn| {
+            for id in ids {
+                conn.execute("DELETE FROM transactions WHERE id = ?1", params![self.transaction_index(id)?]).map_err(db_err)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn put_setting(&self, key: &str, value: &[u8]) -> Result<()> {
+        let value = self.cipher.seal("settings", value)?;
+        self.conn
+            .execute("INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)", params![key, value])
+            .map_err(db_err)?;
+        Ok(())
+    }
+
+    fn get_setting(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let value: Option<Vec<u8>> = self.conn
+            .query_row("SELECT value FROM settings WHERE key = ?1", params![key], |row| row.get(0))
+            .optional()
+            .map_err(db_err)?;
+        value.map(|v| Ok(self.cipher.open("settings", &v[..])?.to_vec())).transpose()
+    }
+
+    fn put_record(&self, kind: &str, id: &[u8], data: &[u8]) -> Result<()> {
+        let table = record_table(kind);
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO records (kind, id, id_data, data) VALUES (?1, ?2, ?3, ?4)",
+                params![kind, self.cipher.index(&table, id), self.cipher.seal(&table, id)?, self.cipher.seal(&table, data)?],
+            )
+            .map_err(db_err)?;
+        Ok(())
+    }
+
+    fn delete_record(&self, kind: &str, id: &[u8]) -> Result<()> {
+        let table = record_table(kind);
+        self.conn
+            .execute("DELETE FROM records WHERE kind = ?1 AND id = ?2", params![kind, self.cipher.index(&table, id)])
+            .map_err(db_err)?;
+        Ok(())
+    }
+
+    fn put_note_meta(&self, meta: &NoteMeta) -> Result<()> {
+        self.write(|conn| write_note_meta(conn, &self.cipher, meta))
+    }
+
+    fn delete_note_meta(&self, note_id: &NoteID) -> Result<()> {
+        let note = self.cipher.index("notes", &encode(note_id)?[..]);
+        self.write(|conn| {
+            conn.execute("DELETE FROM note_meta WHERE note = ?1", params![note]).map_err(db_err)?;
+            conn.execute("DELETE FROM note_terms WHERE note = ?1", params![note]).map_err(db_err)?;
+            Ok(())
+        })
+    }
+
+    fn query_notes(&self, query: &NoteQuery) -> Result<Vec<NoteID>> {
+        if query.matches_nothing() {
+            return Ok(Vec::new());
+        }
+        let term = |term: Vec<u8>| Value::Blob(self.cipher.index("note-terms", &term[..]));
+        let mut sql = String::from("SELECT data FROM note_meta WHERE space = ?");
+        let mut args = vec![Value::Blob(self.cipher.index("spaces", &encode(query.space_id())?[..]))];
+        if let Some(deleted) = query.deleted() {
+            sql.push_str(" AND deleted = ?");
+            args.push(Value::Integer(i64::from(*deleted)));
+        }
+        if let Some(has_file) = query.has_file() {
+            sql.push_str(" AND has_file = ?");
+            args.push(Value::Integer(i64::from(*has_file)));
+        }
+        let all_terms = query.tags_all().iter()
+            .map(meta::tag_term)
+            .chain(query.title_words().iter().map(|w| meta::title_term(w)));
+        for t in all_terms {
+            sql.push_str(" AND note IN (SELECT note FROM note_terms WHERE term = ?)");
+            args.push(term(t));
+        }
+        for tags in query.tags_any() {
+            let marks = vec!["?"; tags.len()].join(", ");
+            sql.push_str(&format!(" AND note IN (SELECT note FROM note_terms WHERE term IN ({}))", marks));
+            args.extend(tags.iter().map(|t| term(meta::tag_term(t))));
+        }
+        sql.push_str(" ORDER BY ");
+        for (by, ascending) in query.sort() {
+            let column = match by {
+                MetaSort::Created => "created",
+                MetaSort::Modified => "modified",
+                MetaSort::HasFile => "has_file",
+            };
+            sql.push_str(&format!("{} {}, ", column, if *ascending { "ASC" } else { "DESC" }));
+        }
+        sql.push_str("rowid");
+        if let Some(window) = query.window() {
+            sql.push_str(" LIMIT ? OFFSET ?");
+            args.push(Value::Integer(*window.limit() as i64));
+            args.push(Value::Integer(*window.offset() as i64));
+        }
+
+        let mut stmt = self.conn.prepare(&sql).map_err(db_err)?;
+        let rows = stmt.query_map(params_from_iter(args), |row| row.get::<_, Vec<u8>>(0)).map_err(db_err)?;
+        rows.map(|data| {
+            let meta: NoteMeta = decode(&self.cipher.open("note-meta", &data.map_err(db_err)?[..])?[..])?;
+            Ok(meta.note_id().clone())
+        }).collect()
+    }
+
+    fn atomic(&self, writes: &mut dyn FnMut() -> Result<()>) -> Result<()> {
+        self.write(|_| writes())
+    }
+
+    fn vacuum(&self) -> Result<u64> {
+        let size = |conn: &Connection| -> Result<u64> {
+            let pages: i64 = conn.query_row("PRAGMA page_count", [], |row| row.get(0)).map_err(db_err)?;
+            let page_size: i64 = conn.query_row("PRAGMA page_size", [], |row| row.get(0)).map_err(db_err)?;
+            Ok((pages * page_size) as u64)
+        };
+        let before = size(&self.conn)?;
+        self.conn.execute_batch("VACUUM").map_err(db_err)?;
+        Ok(before.saturating_sub(size(&self.conn)?))
+    }
+
+    fn records(&self, kind: &str) -> Result<Vec<Vec<u8>>> {
+        let table = record_table(kind);
+        let mut stmt = self.conn.prepare("SELECT data FROM records WHERE kind = ?1").map_err(db_err)?;
+        let rows = stmt.query_map(params![kind], |row| row.get::<_, Vec<u8>>(0)).map_err(db_err)?;
+        rows.map(|data| Ok(self.cipher.open(&table, &data.map_err(db_err)?[..])?.to_vec())).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    fn db_path(name: &str) -> std::path::PathBuf {
+        test_util::temp_dir(name).join("turtl.db")
+    }
+
+    fn user_version(path: &Path) -> i64 {
+        Connection::open(path).unwrap().query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap()
+    }
+
+    /// Write a database the way the first version of the schema did
+    fn write_v1(path: &Path, child: &TransactionID, parent: &TransactionID) {
+        let conn = Connection::open(path).unwrap();
+        conn.execute_batch("
+            CREATE TABLE transactions (id BLOB PRIMARY KEY, space BLOB, data BLOB NOT NULL);
+            CREATE TABLE parents (child BLOB NOT NULL, parent BLOB NOT NULL, PRIMARY KEY (child, parent));
+            CREATE TABLE settings (key TEXT PRIMARY KEY, value BLOB NOT NULL);
+            PRAGMA user_version = 1;
+        ").unwrap();
+        conn.execute("INSERT INTO parents (child, parent) VALUES (?1, ?2)", params![encode(child).unwrap(), encode(parent).unwrap()]).unwrap();
+        conn.execute("INSERT INTO settings (key, value) VALUES ('outbox', ?1)", params![b"queued".to_vec()]).unwrap();
+    }
+
+    #[test]
+    fn encrypted_storage_needs_its_key() {
+        let path = db_path("sqlite-cipher");
+        let master_key = SecretKey::new_xchacha20poly1305().unwrap();
+        {
+            let storage = SqliteStorage::open_encrypted(&path, &master_key).unwrap();
+            assert!(storage.is_encrypted());
+            storage.put_setting("outbox", b"queued").unwrap();
+            storage.put_record("state/page", b"page-1", b"a page").unwrap();
+        }
+        let raw = Connection::open(&path).unwrap()
+            .query_row("SELECT value FROM settings WHERE key = 'outbox'", [], |row| row.get::<_, Vec<u8>>(0))
+            .unwrap();
+        assert!(!raw.windows(6).any(|w| w == b"queued"));
+
+        assert!(SqliteStorage::open_encrypted(&path, &SecretKey::new_xchacha20poly1305().unwrap()).is_err());
+        assert!(SqliteStorage::open(&path).is_err());
+        let storage = SqliteStorage::open_encrypted(&path, &master_key).unwrap();
+        assert_eq!(storage.get_setting("outbox").unwrap().unwrap(), b"queued");
+        assert_eq!(storage.records("state/page").unwrap(), vec![b"a page".to_vec()]);
+    }
+
+    #[test]
+    fn rekey_moves_everything_to_the_new_key() {
+        let path = db_path("sqlite-rekey");
+        let master_key = SecretKey::new_xchacha20poly1305().unwrap();
+        {
+            let mut storage = SqliteStorage::open(&path).unwrap();
+            storage.put_setting("outbox", b"queued").unwrap();
+            storage.put_record("state/page", b"page-1", b"a page").unwrap();
+            storage.rekey(StorageCipher::new(&master_key).unwrap()).unwrap();
+            assert!(storage.is_encrypted());
+            assert_eq!(storage.get_setting("outbox").unwrap().unwrap(), b"queued");
+        }
+        assert!(SqliteStorage::open(&path).is_err());
+        let mut storage = SqliteStorage::open_encrypted(&path, &master_key).unwrap();
+        assert_eq!(storage.records("state/page").unwrap(), vec![b"a page".to_vec()]);
+
+        let new_key = SecretKey::new_xchacha20poly1305().unwrap();
+        storage.rekey(StorageCipher::new(&new_key).unwrap()).unwrap();
+        drop(storage);
+        assert!(SqliteStorage::open_encrypted(&path, &master_key).is_err());
+        let storage = SqliteStorage::open_encrypted(&path, &new_key).unwrap();
+        assert_eq!(storage.get_setting("outbox").unwrap().unwrap(), b"queued");
+    }
+
+    #[test]
+    fn v1_databases_migrate_once() {
+        let path = db_path("sqlite-migrate");
+        let (child, parent) = (test_util::transaction_id(), test_util::transaction_id());
+        write_v1(&path, &child, &parent);
+
+        // the wrong cipher is turned away without touching anything
+        let master_key = SecretKey::new_xchacha20poly1305().unwrap();
+        assert!(SqliteStorage::open_encrypted(&path, &master_key).is_err());
+        assert_eq!(user_version(&path), 1);
+        let has_meta: bool = Connection::open(&path).unwrap()
+            .query_row("SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE name = 'meta')", [], |row| row.get(0))
+            .unwrap();
+        assert!(!has_meta);
+
+        let storage = SqliteStorage::open(&path).unwrap();
+        assert_eq!(user_version(&path), SCHEMA_VERSION);
+        assert_eq!(storage.get_setting("outbox").unwrap().unwrap(), b"queued");
+        let link: Vec<u8> = storage.conn.query_row("SELECT link FROM parents", [], |row| row.get(0)).unwrap();
+        let link: ParentLink = decode(&link[..]).unwrap();
+        assert_eq!((link.child, link.parent), (child, parent));
+        drop(storage);
+
+        // opening again doesn't try to migrate again
+        let storage = SqliteStorage::open(&path).unwrap();
+        assert_eq!(storage.get_setting("outbox").unwrap().unwrap(), b"queued");
+        drop(storage);
+        SqliteStorage::open(&path).unwrap();
+        assert_eq!(user_version(&path), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn failed_migration_leaves_v1_to_retry() {
+        let path = db_path("sqlite-migrate-crash");
+        let (child, parent) = (test_util::transaction_id(), test_util::transaction_id());
+        write_v1(&path, &child, &parent);
+        // a parent link that won't decode stops the migration partway, after the columns went in
+        Connection::open(&path).unwrap()
+            .execute("INSERT INTO parents (child, parent) VALUES (?1, ?2)", params![b"junk".to_vec(), b"junk".to_vec()])
+            .unwrap();
+        assert!(SqliteStorage::open(&path).is_err());
+        assert_eq!(user_version(&path), 1);
+
+        Connection::open(&path).unwrap().execute("DELETE FROM parents WHERE child = ?1", params![b"junk".to_vec()]).unwrap();
+        let storage = SqliteStorage::open(&path).unwrap();
+        assert_eq!(user_version(&path), SCHEMA_VERSION);
+        assert_eq!(storage.get_setting("outbox").unwrap().unwrap(), b"queued");
+    }
+}
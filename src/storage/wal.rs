@@ -0,0 +1,156 @@
+//! A write-ahead log, so a crash between receiving a transaction and persisting what it did
+//! never loses it.
+//!
+//! Incoming transactions go into the log before anything else happens to them. Once a
+//! transaction is in the log, it's safe to tell the peer we have it. The usual flow is:
+//!
+//! 1. [`Wal::append`] each transaction as it arrives, then hand it to the
+//!    [`Inbox`][crate::sync::Inbox].
+//! 2. [`Inbox::apply`][crate::sync::Inbox::apply] the ready transactions to the state.
+//! 3. [`Wal::settle`] with the inbox's events. This stores the transactions that were applied,
+//!    [persists][crate::models::state::State::persist_dirty] the state, and drops the log
+//!    entries that are now safe, all in one storage transaction.
+//!
+//! If the process dies anywhere in there, [`Wal::recover`] on the next start replays whatever is
+//! still in the log. Since settling is all-or-nothing, a transaction is only ever in storage
+//! along with its effect on the state, so any logged transaction storage already has really is
+//! a duplicate.
+
+use crate::{
+    error::{Error, Result},
+    models::{space::SpaceID, state::State},
+    storage::{self, Storage},
+    sync::{inbox::SyncEvent, Inbox},
+};
+use rasn::{AsnType, Decode, Encode};
+use stamp_core::{
+    crypto::base::SecretKey,
+    dag::{Transaction, TransactionID},
+    identity::{Identity, IdentityID},
+    util::Timestamp,
+};
+use std::collections::HashMap;
+
+/// The record kind log entries are stored under
+const RECORD_WAL: &str = "wal";
+
+/// One logged transaction
+#[derive(AsnType, Encode, Decode)]
+struct WalEntry {
+    #[rasn(tag(explicit(0)))]
+    seq: u64,
+    #[rasn(tag(explicit(1)))]
+    transaction: Transaction,
+}
+
+/// The write-ahead log. See the [module docs][self] for how it fits in.
+pub struct Wal {
+    next_seq: u64,
+}
+
+impl Wal {
+    /// Open the log kept in a storage.
+    pub fn open(storage: &dyn Storage) -> Result<Self> {
+        let next_seq = Self::entries(storage)?.last().map(|e| e.seq + 1).unwrap_or(0);
+        Ok(Self { next_seq })
+    }
+
+    fn entries(storage: &dyn Storage) -> Result<Vec<WalEntry>> {
+        let mut entries = storage.records(RECORD_WAL)?
+            .iter()
+            .map(|data| rasn::der::decode::<WalEntry>(&data[..]).map_err(|_| Error::ASNDeserialize))
+            .collect::<Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.seq);
+        Ok(entries)
+    }
+
+    /// Log a transaction before doing anything else with it. Once this returns, the transaction
+    /// survives a crash.
+    pub fn append(&mut self, storage: &dyn Storage, transaction: &Transaction) -> Result<()> {
+        let entry = WalEntry { seq: self.next_seq, transaction: transaction.clone() };
+        let data = rasn::der::encode(&entry).map_err(|_| Error::ASNSerialize)?;
+        storage.put_record(RECORD_WAL, &entry.seq.to_be_bytes()[..], &data[..])?;
+        self.next_seq += 1;
+        Ok(())
+    }
+
+    /// The transactions still in the log, oldest first.
+    pub fn pending(&self, storage: &dyn Storage) -> Result<Vec<Transaction>> {
+        Ok(Self::entries(storage)?.into_iter().map(|e| e.transaction).collect())
+    }
+
+    /// Finish up after an [`Inbox::apply`]: store the logged transactions the inbox applied,
+    /// persist the state's changes, and drop every log entry whose transaction is now in
+    /// storage, all in one [storage transaction][Storage::atomic]. Entries for transactions that
+    /// are still buffered, held, or quarantined stay in the log. Returns how many entries were
+    /// dropped.
+    ///
+    /// `events` are the inbox's events since the last settle (see
+    /// [`Inbox::drain_events`][crate::sync::Inbox::drain_events]).
+    pub fn settle(&self, storage: &dyn Storage, state: &mut State, events: &[SyncEvent]) -> Result<usize> {
+        let entries = Self::entries(storage)?;
+        let logged = entries.iter()
+            .map(|e| (e.transaction.id(), &e.transaction))
+            .collect::<HashMap<_, _>>();
+        // storing the transactions, persisting what they did, and forgetting their log entries
+        // all happen in one storage transaction. otherwise a crash in between could leave a
+        // transaction in storage without its effect on the state, and recovering would see it as
+        // a duplicate and drop it.
+        let mut dropped = 0;
+        state.persist_dirty_with(storage, &mut || {
+            for event in events {
+                if let SyncEvent::Applied(id) = event {
+                    if let Some(transaction) = logged.get(id) {
+                        storage::store_transaction(storage, transaction)?;
+                    }
+                }
+            }
+            dropped = 0;
+            for entry in &entries {
+                if storage.has_transaction(entry.transaction.id())? {
+                    storage.delete_record(RECORD_WAL, &entry.seq.to_be_bytes()[..])?;
+                    dropped += 1;
+                }
+            }
+            Ok(())
+        })?;
+        Ok(dropped)
+    }
+
+    /// Drop a transaction from the log without applying it, ie one the inbox rejected that is
+    /// never going to apply.
+    pub fn discard(&self, storage: &dyn Storage, id: &TransactionID) -> Result<()> {
+        for entry in Self::entries(storage)? {
+            if entry.transaction.id() == id {
+                storage.delete_record(RECORD_WAL, &entry.seq.to_be_bytes()[..])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replay whatever is left in the log after a crash, on startup. `state` should be the
+    /// [loaded state][State::load], and `inbox` should know every transaction in storage.
+    ///
+    /// The logged transactions go back through the inbox and get applied and
+    /// [settled][Wal::settle]. Returns the inbox's events, so they can be handled like the
+    /// events of any other sync.
+    #[allow(clippy::too_many_arguments)]
+    pub fn recover(
+        &self,
+        storage: &dyn Storage,
+        state: &mut State,
+        inbox: &mut Inbox,
+        identities: &HashMap<IdentityID, Identity>,
+        user_key: &SecretKey,
+        space_keys: &HashMap<SpaceID, SecretKey>,
+        now: &Timestamp,
+    ) -> Result<Vec<SyncEvent>> {
+        for transaction in self.pending(storage)? {
+            inbox.receive(transaction, identities, now);
+        }
+        inbox.apply(state, user_key, space_keys);
+        let events = inbox.drain_events();
+        self.settle(storage, state, &events[..])?;
+        Ok(events)
+    }
+}
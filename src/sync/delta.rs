@@ -0,0 +1,169 @@
+//! Delta sync: instead of sending whole histories every time two peers connect, each side says
+//! where its DAG ends for each space (its frontier) and the other side works out exactly which
+//! transactions are missing.
+//!
+//! This works because nobody holds a transaction without also holding everything before it (the
+//! [inbox][crate::sync::inbox::Inbox] sees to that), so a frontier stands in for a whole history.
+
+use crate::{
+    error::{Error, Result},
+    models::{operation, space::SpaceID},
+    sync::handshake::Capabilities,
+};
+use getset::Getters;
+use rasn::{AsnType, Decode, Encode};
+use stamp_core::dag::{Transaction, TransactionID};
+use std::collections::{HashMap, HashSet};
+
+/// The transactions in a set that nothing else in the set builds on: the tips of the DAG.
+pub fn frontier(transactions: &[Transaction]) -> Vec<TransactionID> {
+    let referenced = transactions.iter()
+        .flat_map(|t| t.entry().previous_transactions().iter())
+        .collect::<HashSet<_>>();
+    transactions.iter()
+        .filter(|t| !referenced.contains(t.id()))
+        .map(|t| t.id().clone())
+        .collect()
+}
+
+/// One space's frontier, as sent to a peer.
+#[derive(Clone, AsnType, Encode, Decode, Getters)]
+#[getset(get = "pub")]
+pub struct SpaceFrontier {
+    /// `None` for the user's own transactions
+    #[rasn(tag(explicit(0)))]
+    space_id: Option<SpaceID>,
+    #[rasn(tag(explicit(1)))]
+    frontier: Vec<TransactionID>,
+}
+
+/// The opening message of a sync: our frontier for every space we're syncing, and what we can
+/// handle.
+#[derive(Clone, Default, AsnType, Encode, Decode, Getters)]
+#[getset(get = "pub")]
+pub struct SyncHello {
+    #[rasn(tag(explicit(0)))]
+    spaces: Vec<SpaceFrontier>,
+    /// Hellos from clients that predate negotiation decode with the legacy default
+    #[rasn(tag(explicit(1)), default)]
+    capabilities: Capabilities,
+}
+
+impl SyncHello {
+    /// Build a hello from our transactions, grouping them by space.
+    pub fn new(transactions: &[Transaction]) -> Self {
+        let mut spaces = Vec::new();
+        for (space_id, transactions) in group_by_space(transactions) {
+            spaces.push(SpaceFrontier { space_id, frontier: frontier(&transactions) });
+        }
+        Self { spaces, capabilities: Capabilities::current() }
+    }
+
+    /// Ask about a space we have nothing from yet (ie, one we just joined), so the peer sends
+    /// all of it. Does nothing if the space is already in the hello.
+    pub fn add_space(&mut self, space_id: Option<SpaceID>) {
+        if !self.spaces.iter().any(|s| s.space_id == space_id) {
+            self.spaces.push(SpaceFrontier { space_id, frontier: Vec::new() });
+        }
+    }
+
+    /// Serialize this hello to send it
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        rasn::der::encode(self).map_err(|_| Error::ASNSerialize)
+    }
+
+    /// Read a hello from a peer
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        rasn::der::decode(bytes).map_err(|_| Error::ASNDeserialize)
+    }
+
+    /// What we and the peer who sent this hello have in common
+    pub fn negotiate(&self) -> Capabilities {
+        Capabilities::current().negotiate(&self.capabilities)
+    }
+
+    /// Compare a peer's hello against our transactions and figure out what each side is missing.
+    /// Spaces the peer didn't mention aren't synced.
+    pub fn plan(&self, ours: &[Transaction]) -> HashMap<Option<SpaceID>, DeltaPlan> {
+        let mut grouped = group_by_space(ours);
+        self.spaces.iter()
+            .map(|space| {
+                let transactions = grouped.remove(&space.space_id).unwrap_or_default();
+                (space.space_id.clone(), DeltaPlan::new(&transactions, &space.frontier))
+            })
+            .collect()
+    }
+}
+
+fn group_by_space(transactions: &[Transaction]) -> HashMap<Option<SpaceID>, Vec<Transaction>> {
+    let mut grouped: HashMap<Option<SpaceID>, Vec<Transaction>> = HashMap::new();
+    for trans in transactions {
+        if let Ok((_, encrypted)) = operation::operation_from_transaction(trans) {
+            grouped.entry(encrypted.context().clone()).or_default().push(trans.clone());
+        }
+    }
+    grouped
+}
+
+/// What needs to move, in each direction, to bring one space in sync with a peer.
+#[derive(Clone, Debug, Default, Getters)]
+#[getset(get = "pub")]
+pub struct DeltaPlan {
+    /// Transactions we have that the peer doesn't, parents first
+    send: Vec<TransactionID>,
+    /// Tips of the peer's DAG that we don't have. Pull these; anything they build on that we're
+    /// also missing shows up in [`Inbox::wanted`][crate::sync::inbox::Inbox::wanted] once they
+    /// arrive.
+    request: Vec<TransactionID>,
+}
+
+impl DeltaPlan {
+    /// Work out the delta between our transactions (for one space) and a peer's frontier.
+    pub fn new(ours: &[Transaction], their_frontier: &[TransactionID]) -> Self {
+        let parents = operation::transaction_parents(ours);
+        let request = their_frontier.iter()
+            .filter(|id| !parents.contains_key(id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // everything at or before their frontier, as far as we can see it, they already have
+        let mut theirs = HashSet::new();
+        let mut stack = their_frontier.iter().collect::<Vec<_>>();
+        while let Some(id) = stack.pop() {
+            if let Some(prev) = parents.get(id) {
+                if theirs.insert(id) {
+                    stack.extend(prev.iter());
+                }
+            }
+        }
+
+        // emit what's left, parents first
+        let mut send = Vec::new();
+        let mut done = HashSet::new();
+        for trans in ours {
+            let mut stack = vec![(trans.id(), false)];
+            while let Some((id, expanded)) = stack.pop() {
+                if theirs.contains(id) || done.contains(id) {
+                    continue;
+                }
+                let prev = match parents.get(id) {
+                    Some(prev) => prev,
+                    None => continue,
+                };
+                if expanded {
+                    done.insert(id);
+                    send.push(id.clone());
+                } else {
+                    stack.push((id, true));
+                    stack.extend(prev.iter().map(|p| (p, false)));
+                }
+            }
+        }
+        Self { send, request }
+    }
+
+    /// Whether the two sides are already in sync
+    pub fn is_empty(&self) -> bool {
+        self.send.is_empty() && self.request.is_empty()
+    }
+}
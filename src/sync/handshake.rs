@@ -0,0 +1,131 @@
+//! Protocol version negotiation. Each side of a sync says what it can handle in its
+//! [`SyncHello`][crate::sync::delta::SyncHello], and both sides settle on what they have in
+//! common, so an old client and a new one in the same space can keep working together instead of
+//! the old one choking on operations it can't decode.
+//!
+//! Transactions are always sent whole (leaving one out would leave a hole in the peer's DAG), so
+//! negotiation is about what we *write*: before using a newer operation, a client compares its
+//! [tag][action_tag] against the negotiated `max_action_tag`, and falls back to an older
+//! equivalent (or waits) if the peer can't decode it.
+
+use crate::{
+    error::{Error, Result},
+    models::{
+        file::CompressionAlgo,
+        operation::{MAX_ACTION_TAG, OperationAction},
+    },
+};
+use getset::Getters;
+use rasn::{AsnType, Decode, Encode};
+
+/// The sync protocol version this build speaks. Bump this when the shape of the sync messages
+/// themselves changes.
+pub const SYNC_PROTOCOL_VERSION: u32 = 1;
+
+/// The highest operation tag that clients from before negotiation existed understand. A hello
+/// without capabilities is assumed to come from one of these.
+const LEGACY_MAX_ACTION_TAG: u32 = 26;
+
+/// What a client can handle.
+#[derive(Clone, Debug, PartialEq, AsnType, Encode, Decode, Getters)]
+#[getset(get = "pub")]
+pub struct Capabilities {
+    /// The sync protocol version (`0` for clients from before negotiation)
+    #[rasn(tag(explicit(0)))]
+    protocol_version: u32,
+    /// The highest [`OperationAction`] tag the client can decode
+    #[rasn(tag(explicit(1)))]
+    max_action_tag: u32,
+    /// Whether the client can apply space checkpoints
+    /// ([`State::space_checkpoint`][crate::models::state::State::space_checkpoint])
+    #[rasn(tag(explicit(2)))]
+    checkpoints: bool,
+    /// Compression the client can decompress, in order of preference
+    #[rasn(tag(explicit(3)))]
+    compression: Vec<CompressionAlgo>,
+}
+
+impl Capabilities {
+    /// What this build can handle
+    pub fn current() -> Self {
+        Self {
+            protocol_version: SYNC_PROTOCOL_VERSION,
+            max_action_tag: MAX_ACTION_TAG,
+            checkpoints: true,
+            compression: vec![CompressionAlgo::Zstd],
+        }
+    }
+
+    /// What two clients have in common. Compression comes out in our order of preference.
+    pub fn negotiate(&self, theirs: &Capabilities) -> Self {
+        Self {
+            protocol_version: self.protocol_version.min(theirs.protocol_version),
+            max_action_tag: self.max_action_tag.min(theirs.max_action_tag),
+            checkpoints: self.checkpoints && theirs.checkpoints,
+            compression: self.compression.iter()
+                .filter(|c| theirs.compression.contains(c))
+                .cloned()
+                .collect(),
+        }
+    }
+
+}
+
+impl Default for Capabilities {
+    /// What a client from before negotiation existed can handle
+    fn default() -> Self {
+        Self {
+            protocol_version: 0,
+            max_action_tag: LEGACY_MAX_ACTION_TAG,
+            checkpoints: true,
+            compression: Vec::new(),
+        }
+    }
+}
+
+/// Grab the tag an action is encoded with. `OperationAction` is an explicitly-tagged choice, so
+/// this is the (context-specific) tag on the outside of its DER encoding.
+pub fn action_tag(action: &OperationAction) -> Result<u32> {
+    let encoded = rasn::der::encode(action).map_err(|_| Error::ASNSerialize)?;
+    let (first, rest) = encoded.split_first().ok_or(Error::ASNSerialize)?;
+    if first & 0xc0 != 0x80 {
+        Err(Error::OperationInvalid("action isn't context-tagged".into()))?;
+    }
+    if first & 0x1f != 0x1f {
+        return Ok((first & 0x1f) as u32);
+    }
+    // long form: base-128, high bit set on every byte but the last
+    let mut tag: u32 = 0;
+    for byte in rest {
+        tag = tag.checked_mul(128).ok_or(Error::ASNSerialize)? | (byte & 0x7f) as u32;
+        if byte & 0x80 == 0 {
+            return Ok(tag);
+        }
+    }
+    Err(Error::ASNSerialize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn action_tag_reads_short_and_long_form_tags() {
+        assert_eq!(action_tag(&OperationAction::FileUnsetV1).unwrap(), 3);
+        assert_eq!(action_tag(&OperationAction::PageSetDescriptionV1(None)).unwrap(), 30);
+        // 31 and up no longer fit in the identifier octet
+        assert_eq!(action_tag(&OperationAction::SpaceSetDefaultPageV1(None)).unwrap(), 31);
+        assert_eq!(action_tag(&OperationAction::NoteSetColorV1(None)).unwrap(), MAX_ACTION_TAG);
+    }
+
+    #[test]
+    fn negotiating_with_a_legacy_client() {
+        let legacy = Capabilities::default();
+        assert!(legacy.compression().is_empty());
+        let agreed = Capabilities::current().negotiate(&legacy);
+        assert_eq!(agreed.protocol_version(), &0);
+        assert_eq!(agreed.max_action_tag(), &LEGACY_MAX_ACTION_TAG);
+        assert!(agreed.compression().is_empty());
+        assert_eq!(Capabilities::current().negotiate(&Capabilities::current()), Capabilities::current());
+    }
+}
@@ -0,0 +1,849 @@
+//! The inbox takes in transactions from wherever they come from (a peer, a relay server, a
+//! bundle) and gets them into [`State`] in an order that makes sense.
+//!
+//! Transactions are deduplicated, checked, and held back until everything they build on has
+//! arrived, so the state never sees an operation before the ones it depends on.
+
+use crate::{
+    crypto::OperationKeys,
+    error::{Error, Result},
+    models::{
+        operation::{self, ObjectRef, Operation, OperationAction, Verdict},
+        space::{Space, SpaceID},
+        state::State,
+        user::SyncPolicy,
+    },
+};
+use getset::Getters;
+use stamp_core::{
+    crypto::base::SecretKey,
+    dag::{Transaction, TransactionID},
+    identity::{Identity, IdentityID},
+    util::Timestamp,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// How many transactions the inbox holds onto while they wait for their parents, unless told
+/// otherwise (see [`Inbox::with_buffer_limit`]).
+pub const DEFAULT_BUFFER_LIMIT: usize = 10_000;
+
+/// Something that happened to an incoming transaction, for showing sync progress (and telling
+/// the user when someone else's change landed on top of theirs).
+#[derive(Clone, Debug, PartialEq)]
+pub enum SyncEvent {
+    /// The transaction passed its checks and is ready to be applied
+    Accepted(TransactionID),
+    /// We already had this one
+    Duplicate(TransactionID),
+    /// The transaction failed its checks and was dropped
+    Rejected(TransactionID, String),
+    /// The transaction is waiting on these parents to arrive
+    Buffered(TransactionID, Vec<TransactionID>),
+    /// The transaction's operation made it into the state
+    Applied(TransactionID),
+    /// The transaction was in order but its operation couldn't be opened or applied
+    Failed(TransactionID, String),
+    /// The transaction's author is over their [`RateLimit`] for its space. It was dropped
+    /// without being stored, so a peer offering it again later (once the author is back under
+    /// their limit) isn't a duplicate.
+    RateLimited {
+        transaction: TransactionID,
+        author: IdentityID,
+    },
+    /// The transaction is waiting on parents, but the inbox is already holding as many
+    /// transactions as it's allowed to. It was dropped without being stored, and is
+    /// [asked for again][Inbox::wanted] once there's room.
+    BufferFull(TransactionID),
+    /// A remote operation changed an object that we also changed, without having seen our
+    /// change (the two were made concurrently). Both are applied and merged as usual, but the
+    /// user probably wants to know their edit may have been overridden.
+    Conflict {
+        object: ObjectRef,
+        local_tx: TransactionID,
+        remote_tx: TransactionID,
+    },
+}
+
+/// Caps on how much one author can send us in one space (or in the user's own DAG), so a
+/// misbehaving or compromised device can't flood everyone else's storage. `None` means no limit,
+/// which is the default.
+#[derive(Clone, Debug, Default, Getters)]
+#[getset(get = "pub")]
+pub struct RateLimit {
+    /// How many operations an author can send per minute
+    ops_per_minute: Option<u32>,
+    /// How many bytes of transactions an author can send per hour
+    bytes_per_hour: Option<u64>,
+}
+
+impl RateLimit {
+    /// Create a new rate limit
+    pub fn new(ops_per_minute: Option<u32>, bytes_per_hour: Option<u64>) -> Self {
+        Self { ops_per_minute, bytes_per_hour }
+    }
+}
+
+/// What one author has sent us in one space over the last hour
+#[derive(Default)]
+struct AuthorUsage {
+    /// When each transaction came in, and how big it was, oldest first
+    recent: VecDeque<(Timestamp, u64)>,
+}
+
+impl AuthorUsage {
+    /// Count a transaction against the limit, if it fits. Returns `false` (and counts nothing)
+    /// if it doesn't.
+    fn admit(&mut self, limit: &RateLimit, now: &Timestamp, size: u64) -> bool {
+        let hour_ago = Timestamp::from(**now - chrono::Duration::hours(1));
+        let minute_ago = Timestamp::from(**now - chrono::Duration::minutes(1));
+        while self.recent.front().map(|(at, _)| at <= &hour_ago).unwrap_or(false) {
+            self.recent.pop_front();
+        }
+        if let Some(max_ops) = limit.ops_per_minute {
+            let ops = self.recent.iter().filter(|(at, _)| at > &minute_ago).count();
+            if ops >= max_ops as usize {
+                return false;
+            }
+        }
+        if let Some(max_bytes) = limit.bytes_per_hour {
+            let bytes = self.recent.iter().map(|(_, size)| size).sum::<u64>();
+            if bytes.saturating_add(size) > max_bytes {
+                return false;
+            }
+        }
+        self.recent.push_back((now.clone(), size));
+        true
+    }
+}
+
+/// Where an identity stands in a space, as far as [`operation::verify_operation`] is concerned
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Standing {
+    Member,
+    Viewer,
+    Outsider,
+}
+
+impl Standing {
+    /// Everyone with a standing in a space. Anyone not in here is an outsider.
+    fn all(space: Option<&Space>) -> HashMap<IdentityID, Standing> {
+        let mut standings = HashMap::new();
+        if let Some(space) = space {
+            standings.extend(space.viewers().iter().map(|v| (v.user_id().clone(), Standing::Viewer)));
+            standings.extend(space.members().iter().map(|m| (m.user_id().clone(), Standing::Member)));
+        }
+        standings
+    }
+
+    fn verdict(&self) -> Verdict {
+        match self {
+            Standing::Member => Verdict::Valid,
+            Standing::Viewer => Verdict::Viewer,
+            Standing::Outsider => Verdict::NotAMember,
+        }
+    }
+}
+
+/// A transaction that changed where someone stands in a space
+struct StandingChange {
+    transaction: TransactionID,
+    before: Standing,
+    after: Standing,
+}
+
+/// Whether an operation can change who's in a space
+fn changes_membership(action: &OperationAction) -> bool {
+    matches!(action,
+        OperationAction::SpaceSetV1(_) |
+        OperationAction::SpaceUnsetV1 |
+        OperationAction::SpaceSetMemberV1(_) |
+        OperationAction::SpaceUnsetMemberV1(_) |
+        OperationAction::SpaceSetViewerV1(_) |
+        OperationAction::SpaceUnsetViewerV1(_)
+    )
+}
+
+/// A transaction that was dropped (failed its checks, or couldn't be applied), kept around so it
+/// can be inspected or retried.
+#[derive(Clone, Getters)]
+#[getset(get = "pub")]
+pub struct Quarantined {
+    transaction: Transaction,
+    /// The transaction's space, if we could tell
+    space_id: Option<SpaceID>,
+    reason: String,
+}
+
+/// Our queue of incoming transactions.
+pub struct Inbox {
+    /// Everything we have (or have accepted), and won't take again
+    known: HashSet<TransactionID>,
+    /// Transactions waiting on parents we don't have yet
+    buffered: HashMap<TransactionID, Transaction>,
+    /// The buffered transactions waiting on each missing parent, so an arrival only looks at
+    /// the transactions it can release
+    waiting: HashMap<TransactionID, HashSet<TransactionID>>,
+    /// How many parents each buffered transaction is still missing
+    unmet: HashMap<TransactionID, usize>,
+    /// The most transactions `buffered` can hold
+    buffer_limit: usize,
+    /// Transactions we turned away (rate limited, or no room to buffer them) that we still want
+    /// a peer to offer again
+    dropped: HashSet<TransactionID>,
+    /// Transactions whose parents we have, waiting to be applied
+    ready: Vec<Transaction>,
+    /// Transactions that were rejected or failed to apply. Capped at `buffer_limit`; past that,
+    /// only their events record them.
+    quarantine: Vec<Quarantined>,
+    /// Transactions held back by the sync policy (ie, notes in an archived space)
+    held: Vec<Transaction>,
+    /// Held transactions that were released. These were verified and authorized when they were
+    /// held, against the space as of their place in the DAG, and aren't checked again.
+    released: HashSet<TransactionID>,
+    policy: SyncPolicy,
+    rate_limit: RateLimit,
+    /// How much each author has sent in each space lately
+    usage: HashMap<(IdentityID, Option<SpaceID>), AuthorUsage>,
+    /// Each accepted (or local) transaction's parents, for telling whether two transactions
+    /// were concurrent
+    parents: HashMap<TransactionID, Vec<TransactionID>>,
+    /// The latest local transaction to touch each object
+    local_edits: HashMap<ObjectRef, TransactionID>,
+    /// The creators of the transactions we've accepted, for checking them again when applied
+    creators: HashMap<IdentityID, Identity>,
+    /// Every change to each identity's standing in each space, in the order they were applied,
+    /// for working out who was a member as of a transaction's parents
+    standings: HashMap<(SpaceID, IdentityID), Vec<StandingChange>>,
+    events: Vec<SyncEvent>,
+}
+
+impl Inbox {
+    /// Create an inbox, given the transactions we already have (and that incoming transactions
+    /// might build on).
+    pub fn new<I: IntoIterator<Item = TransactionID>>(known: I) -> Self {
+        Self {
+            known: known.into_iter().collect(),
+            buffered: HashMap::new(),
+            waiting: HashMap::new(),
+            unmet: HashMap::new(),
+            buffer_limit: DEFAULT_BUFFER_LIMIT,
+            dropped: HashSet::new(),
+            ready: Vec::new(),
+            quarantine: Vec::new(),
+            held: Vec::new(),
+            released: HashSet::new(),
+            policy: SyncPolicy::default(),
+            rate_limit: RateLimit::default(),
+            usage: HashMap::new(),
+            parents: HashMap::new(),
+            local_edits: HashMap::new(),
+            creators: HashMap::new(),
+            standings: HashMap::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Use this device's sync policy when applying transactions
+    pub fn with_policy(mut self, policy: SyncPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Limit how much each author can send us
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    /// Cap how many transactions can wait on their parents at once. This also caps how many
+    /// turned-away and quarantined transactions are kept around.
+    pub fn with_buffer_limit(mut self, buffer_limit: usize) -> Self {
+        self.buffer_limit = buffer_limit;
+        self
+    }
+
+    /// Change the rate limit. What authors have already sent still counts against the new one.
+    pub fn set_rate_limit(&mut self, rate_limit: RateLimit) {
+        self.rate_limit = rate_limit;
+    }
+
+    /// Change the sync policy. Anything held back by the old policy gets another look on the
+    /// next [`Inbox::apply`].
+    pub fn set_policy(&mut self, policy: SyncPolicy) {
+        self.policy = policy;
+        self.release_held();
+    }
+
+    /// Put everything the sync policy held back into the ready queue (ie, after a space is
+    /// unarchived). Whatever the policy still doesn't want is held again on the next apply.
+    ///
+    /// Until then, anything below the space level that comes in for a space with held
+    /// transactions is held along with them, so the held ones never land after later changes to
+    /// the same objects.
+    pub fn release_held(&mut self) {
+        let held = std::mem::take(&mut self.held);
+        self.released.extend(held.iter().map(|t| t.id().clone()));
+        self.ready.extend(held);
+    }
+
+    /// How many transactions the sync policy is holding back
+    pub fn held_count(&self) -> usize {
+        self.held.len()
+    }
+
+    /// Take in a serialized transaction.
+    pub fn receive_raw(&mut self, bytes: &[u8], identities: &HashMap<IdentityID, Identity>, now: &Timestamp) -> Result<()> {
+        let transaction: Transaction = rasn::der::decode(bytes).map_err(|_| Error::ASNDeserialize)?;
+        self.receive(transaction, identities, now);
+        Ok(())
+    }
+
+    /// Take in a transaction. It's checked to be a Turtl operation signed by its creator (whose
+    /// identity needs to be in `identities`) and counted against the creator's [`RateLimit`],
+    /// then either queued to be applied or held until its parents show up.
+    pub fn receive(&mut self, transaction: Transaction, identities: &HashMap<IdentityID, Identity>, now: &Timestamp) {
+        let id = transaction.id().clone();
+        if self.known.contains(&id) || self.buffered.contains_key(&id) {
+            self.events.push(SyncEvent::Duplicate(id));
+            return;
+        }
+        let (creator, space_id) = match operation::operation_from_transaction(&transaction) {
+            Ok((creator, encrypted)) => (creator.clone(), encrypted.context().clone()),
+            Err(e) => {
+                self.reject(transaction, None, format!("{}", e));
+                return;
+            }
+        };
+        let identity = match identities.get(&creator) {
+            Some(identity) => identity,
+            None => {
+                self.reject(transaction, space_id, "unknown creator".into());
+                return;
+            }
+        };
+        if let Err(e) = transaction.verify(Some(identity)) {
+            self.reject(transaction, space_id, format!("bad signature: {}", e));
+            return;
+        }
+        if !self.creators.contains_key(&creator) {
+            self.creators.insert(creator.clone(), identity.clone());
+        }
+        // only checked once the signature is, so nobody can burn through someone else's limit
+        if !self.admit(&creator, &space_id, &transaction, now) {
+            self.drop_for_now(id.clone());
+            self.events.push(SyncEvent::RateLimited { transaction: id, author: creator });
+            return;
+        }
+        let missing = self.missing_parents(&transaction);
+        if missing.is_empty() {
+            self.dropped.remove(&id);
+            self.accept(transaction);
+            self.release_buffered(&id);
+        } else if self.buffered.len() >= self.buffer_limit {
+            self.drop_for_now(id.clone());
+            self.events.push(SyncEvent::BufferFull(id));
+        } else {
+            self.dropped.remove(&id);
+            self.wait_on(&id, &missing);
+            self.events.push(SyncEvent::Buffered(id.clone(), missing));
+            self.buffered.insert(id, transaction);
+        }
+    }
+
+    /// Remember a transaction we turned away, so it's [asked for again][Inbox::wanted]. This is
+    /// capped like the buffer is; past that, we rely on the peer offering it again.
+    fn drop_for_now(&mut self, id: TransactionID) {
+        if self.dropped.len() < self.buffer_limit {
+            self.dropped.insert(id);
+        }
+    }
+
+    /// Count a transaction against its author's rate limit, returning whether it's allowed in.
+    fn admit(&mut self, creator: &IdentityID, space_id: &Option<SpaceID>, transaction: &Transaction, now: &Timestamp) -> bool {
+        if self.rate_limit.ops_per_minute.is_none() && self.rate_limit.bytes_per_hour.is_none() {
+            return true;
+        }
+        let size = rasn::der::encode(transaction).map(|bytes| bytes.len() as u64).unwrap_or(0);
+        self.usage.entry((creator.clone(), space_id.clone()))
+            .or_default()
+            .admit(&self.rate_limit, now, size)
+    }
+
+    fn reject(&mut self, transaction: Transaction, space_id: Option<SpaceID>, reason: String) {
+        self.events.push(SyncEvent::Rejected(transaction.id().clone(), reason.clone()));
+        self.quarantine(Quarantined { transaction, space_id, reason });
+    }
+
+    /// Keep a transaction that was rejected or failed to apply, if there's still room. Anyone
+    /// can send us junk, so this is capped like the buffer is.
+    fn quarantine(&mut self, quarantined: Quarantined) {
+        if self.quarantine.len() < self.buffer_limit {
+            self.quarantine.push(quarantined);
+        }
+    }
+
+    fn missing_parents(&self, transaction: &Transaction) -> Vec<TransactionID> {
+        transaction.entry().previous_transactions().iter()
+            .filter(|p| !self.known.contains(p))
+            .cloned()
+            .collect()
+    }
+
+    /// Record a transaction we made ourselves (and committed locally), so that remote changes
+    /// made without seeing it can be flagged as [conflicts][SyncEvent::Conflict].
+    pub fn record_local(&mut self, transaction: &Transaction, operation: &Operation) {
+        let id = transaction.id().clone();
+        self.known.insert(id.clone());
+        self.parents.insert(id.clone(), transaction.entry().previous_transactions().clone());
+        self.release_buffered(&id);
+        if let Some(object) = operation.context().object() {
+            self.local_edits.insert(object, id);
+        }
+    }
+
+    /// Apply an operation we made ourselves and [record it][Inbox::record_local]. Operations
+    /// that change a space's membership should go through here rather than straight to the
+    /// state, so remote transactions made without seeing the change are still checked against
+    /// the membership they were made under.
+    pub fn apply_local(&mut self, transaction: &Transaction, operation: Operation, state: &mut State) -> Result<()> {
+        let (creator, _) = operation::operation_from_transaction(transaction)?;
+        let space_id = operation.context().space().clone();
+        let before = space_id.as_ref()
+            .filter(|_| changes_membership(operation.action()))
+            .map(|id| Standing::all(state.spaces().get(id)));
+        self.record_local(transaction, &operation);
+        state.apply_operation_by(operation, creator, transaction.entry().created())?;
+        if let (Some(space_id), Some(before)) = (space_id, before) {
+            self.record_standings(space_id, transaction.id(), before, state);
+        }
+        Ok(())
+    }
+
+    /// Note whose standing in a space a transaction changed, given everyone's standing from
+    /// before it was applied.
+    fn record_standings(&mut self, space_id: SpaceID, transaction_id: &TransactionID, mut before: HashMap<IdentityID, Standing>, state: &State) {
+        let after = Standing::all(state.spaces().get(&space_id));
+        for identity_id in after.keys() {
+            before.entry(identity_id.clone()).or_insert(Standing::Outsider);
+        }
+        for (identity_id, before) in before {
+            let after = after.get(&identity_id).copied().unwrap_or(Standing::Outsider);
+            if before != after {
+                self.standings.entry((space_id.clone(), identity_id))
+                    .or_default()
+                    .push(StandingChange { transaction: transaction_id.clone(), before, after });
+            }
+        }
+    }
+
+    /// Where an identity stood in a space as of a transaction's parents, replaying only the
+    /// membership changes the transaction had seen. `None` if their standing has never changed
+    /// since we started keeping track, in which case it's whatever it is in the space now.
+    fn standing_at(&self, space_id: &SpaceID, identity_id: &IdentityID, transaction_id: &TransactionID) -> Option<Standing> {
+        let changes = self.standings.get(&(space_id.clone(), identity_id.clone()))?;
+        changes.iter()
+            .rev()
+            .find(|c| self.is_ancestor(&c.transaction, transaction_id))
+            .map(|c| c.after)
+            .or_else(|| changes.first().map(|c| c.before))
+    }
+
+    /// [Verify][operation::verify_operation] a space transaction, checking its creator's
+    /// membership as of the transaction's parents. A membership change applied before this
+    /// transaction (ie, a concurrent removal that sorted first) but that the transaction hadn't
+    /// seen doesn't count against it, so every device comes to the same verdict no matter what
+    /// order it got things in. User transactions, and ones for a space we don't have yet (ie,
+    /// the one creating it), have nothing to check against and are left to
+    /// [`State::apply_operation_by`].
+    fn verify(&self, transaction: &Transaction, state: &State) -> Verdict {
+        let (creator, encrypted) = match operation::operation_from_transaction(transaction) {
+            Ok(x) => x,
+            Err(_) => return Verdict::NotAnOperation,
+        };
+        let space = match encrypted.context().as_ref().and_then(|id| state.spaces().get(id)) {
+            Some(space) => space,
+            None => return Verdict::Valid,
+        };
+        let identity = match self.creators.get(creator) {
+            Some(identity) => identity,
+            None => return Verdict::BadSignature("unknown creator".into()),
+        };
+        match operation::verify_operation(transaction, space, identity) {
+            Verdict::Valid | Verdict::Viewer | Verdict::NotAMember => self.membership_verdict(space, creator, transaction.id()),
+            verdict => verdict,
+        }
+    }
+
+    /// Whether an identity was a member of a space (or only a viewer) as of a transaction's
+    /// parents
+    fn membership_verdict(&self, space: &Space, identity_id: &IdentityID, transaction_id: &TransactionID) -> Verdict {
+        self.standing_at(space.id(), identity_id, transaction_id)
+            .unwrap_or_else(|| Standing::all(Some(space)).get(identity_id).copied().unwrap_or(Standing::Outsider))
+            .verdict()
+    }
+
+    /// Whether `ancestor` comes before `transaction` in the DAG
+    fn is_ancestor(&self, ancestor: &TransactionID, transaction: &TransactionID) -> bool {
+        let mut seen = HashSet::new();
+        let mut stack = vec![transaction];
+        while let Some(id) = stack.pop() {
+            if id == ancestor {
+                return true;
+            }
+            if seen.insert(id) {
+                if let Some(prev) = self.parents.get(id) {
+                    stack.extend(prev.iter());
+                }
+            }
+        }
+        false
+    }
+
+    fn accept(&mut self, transaction: Transaction) {
+        self.known.insert(transaction.id().clone());
+        self.parents.insert(transaction.id().clone(), transaction.entry().previous_transactions().clone());
+        self.events.push(SyncEvent::Accepted(transaction.id().clone()));
+        self.ready.push(transaction);
+    }
+
+    /// Index a buffered transaction by the parents it's missing.
+    fn wait_on(&mut self, id: &TransactionID, missing: &[TransactionID]) {
+        let missing = missing.iter().collect::<HashSet<_>>();
+        for parent in &missing {
+            self.waiting.entry((*parent).clone()).or_default().insert(id.clone());
+        }
+        self.unmet.insert(id.clone(), missing.len());
+    }
+
+    /// Note that a parent has arrived, returning the buffered transactions that aren't missing
+    /// anything anymore.
+    fn unblock(&mut self, parent: &TransactionID) -> Vec<TransactionID> {
+        let mut unblocked = Vec::new();
+        for id in self.waiting.remove(parent).unwrap_or_default() {
+            if let Some(unmet) = self.unmet.get_mut(&id) {
+                *unmet -= 1;
+                if *unmet == 0 {
+                    self.unmet.remove(&id);
+                    unblocked.push(id);
+                }
+            }
+        }
+        unblocked
+    }
+
+    /// Move any buffered transactions that were only waiting on a transaction that just arrived
+    /// into the ready queue. Each one released can release others, so keep going until nothing
+    /// moves.
+    fn release_buffered(&mut self, arrived: &TransactionID) {
+        let mut arrivals = vec![arrived.clone()];
+        while let Some(parent) = arrivals.pop() {
+            for id in self.unblock(&parent) {
+                if let Some(transaction) = self.buffered.remove(&id) {
+                    self.accept(transaction);
+                    arrivals.push(id);
+                }
+            }
+        }
+    }
+
+    /// How many transactions are ready to apply
+    pub fn ready_count(&self) -> usize {
+        self.ready.len()
+    }
+
+    /// How many transactions are waiting on parents
+    pub fn buffered_count(&self) -> usize {
+        self.buffered.len()
+    }
+
+    /// Transactions that were rejected or couldn't be applied
+    pub fn quarantined(&self) -> &[Quarantined] {
+        &self.quarantine
+    }
+
+    /// Buffered transactions (waiting on parents) for a space
+    pub fn buffered_for(&self, space_id: &Option<SpaceID>) -> usize {
+        self.buffered.values()
+            .filter(|t| operation::operation_from_transaction(t).map(|(_, e)| e.context() == space_id).unwrap_or(false))
+            .count()
+    }
+
+    /// Empty the quarantine, ie to feed its transactions back through [`Inbox::receive`] once
+    /// whatever was wrong (a missing identity or key) is fixed. Transactions that had made it
+    /// past their checks are forgotten, so receiving them again doesn't count as a duplicate.
+    pub fn take_quarantined(&mut self) -> Vec<Quarantined> {
+        let quarantined = std::mem::take(&mut self.quarantine);
+        for q in &quarantined {
+            self.known.remove(q.transaction.id());
+        }
+        quarantined
+    }
+
+    /// What to ask peers for next: the parents that buffered transactions are waiting on, and
+    /// anything we turned away (rate limited, or with no room to buffer it). Transactions that
+    /// were turned away are only asked for while there's room to buffer them again.
+    pub fn wanted(&self) -> Vec<TransactionID> {
+        let has_room = self.buffered.len() < self.buffer_limit;
+        self.waiting.keys()
+            .cloned()
+            .chain(self.dropped.iter().filter(|_| has_room).cloned())
+            .filter(|id| !self.buffered.contains_key(id) && !self.known.contains(id))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Apply every ready transaction to the state, in [DAG order][operation::order_transactions],
+    /// so devices that get the same transactions in a different order end up with the same
+    /// state. User operations are opened with `user_key` and space operations with that space's
+    /// key from `space_keys`.
+    ///
+    /// Operations the sync policy doesn't want (anything below the space level, for an archived
+    /// space with [`archived_metadata_only`][SyncPolicy::archived_metadata_only] set) are held
+    /// rather than applied, see [`Inbox::release_held`].
+    ///
+    /// Each space transaction is [verified][operation::verify_operation] against the space's
+    /// membership as of the transaction's parents, and rejected into the quarantine if its
+    /// creator wasn't a member at that point (or was only a viewer).
+    ///
+    /// A transaction that can't be opened or applied doesn't stop the rest; it's reported as
+    /// [`SyncEvent::Failed`]. Returns how many were applied.
+    pub fn apply(&mut self, state: &mut State, user_key: &SecretKey, space_keys: &HashMap<SpaceID, SecretKey>) -> usize {
+        let mut applied = 0;
+        let mut holding = self.held.iter()
+            .filter_map(|t| operation::operation_from_transaction(t).ok().and_then(|(_, e)| e.context().clone()))
+            .collect::<HashSet<_>>();
+        let ready = std::mem::take(&mut self.ready);
+        let keys = Self::operation_keys(&ready, user_key, space_keys);
+        for (transaction, opened) in operation::open_ordered(ready, &keys) {
+            let checked = self.released.remove(transaction.id());
+            let verdict = if checked { Verdict::Valid } else { self.verify(&transaction, state) };
+            if !verdict.is_valid() {
+                let space_id = operation::operation_from_transaction(&transaction).ok().and_then(|(_, e)| e.context().clone());
+                self.reject(transaction, space_id, format!("failed verification: {:?}", verdict));
+                continue;
+            }
+            let id = transaction.id().clone();
+            let mut space_id = None;
+            let mut hold = false;
+            let mut object = None;
+            let mut before = None;
+            let result = operation::operation_from_transaction(&transaction)
+                .and_then(|(creator, encrypted)| {
+                    space_id = encrypted.context().clone();
+                    let operation = opened?;
+                    let context = operation.context();
+                    let below_space = context.note().is_some() || context.page().is_some() || context.file().is_some() || context.chunk().is_some();
+                    let wanted = space_id.as_ref()
+                        .and_then(|id| state.spaces().get(id))
+                        .map(|space| self.policy.wants_space_contents(space) && !holding.contains(space.id()))
+                        .unwrap_or(true);
+                    if below_space && !wanted {
+                        // check it now, while the space is as it was when this was made
+                        if !checked {
+                            state.authorize_operation(&operation, creator)?;
+                        }
+                        hold = true;
+                        return Ok(());
+                    }
+                    object = context.object();
+                    if changes_membership(operation.action()) {
+                        before = space_id.as_ref().map(|id| Standing::all(state.spaces().get(id)));
+                    }
+                    if checked {
+                        state.apply_authorized_by(operation, creator, transaction.entry().created())
+                    } else {
+                        state.apply_operation_by(operation, creator, transaction.entry().created())
+                    }
+                });
+            if hold {
+                holding.extend(space_id);
+                self.held.push(transaction);
+                continue;
+            }
+            match result {
+                Ok(()) => {
+                    applied += 1;
+                    if let (Some(space_id), Some(before)) = (space_id, before) {
+                        self.record_standings(space_id, &id, before, state);
+                    }
+                    let local_tx = object.as_ref()
+                        .and_then(|o| self.local_edits.get(o))
+                        .filter(|local_tx| !self.is_ancestor(local_tx, &id))
+                        .cloned();
+                    self.events.push(SyncEvent::Applied(id.clone()));
+                    if let (Some(object), Some(local_tx)) = (object, local_tx) {
+                        self.events.push(SyncEvent::Conflict { object, local_tx, remote_tx: id });
+                    }
+                }
+                Err(e) => {
+                    let reason = format!("{}", e);
+                    self.events.push(SyncEvent::Failed(id, reason.clone()));
+                    self.quarantine(Quarantined { transaction, space_id, reason });
+                }
+            }
+        }
+        applied
+    }
+
+    /// Derive the keys for every space (and the user's own operations) with a transaction in a
+    /// batch, once for the whole batch.
+    fn operation_keys<'k>(transactions: &[Transaction], user_key: &'k SecretKey, space_keys: &'k HashMap<SpaceID, SecretKey>) -> HashMap<Option<SpaceID>, Vec<OperationKeys<'k>>> {
+        let mut keys = HashMap::new();
+        for transaction in transactions {
+            let space_id = match operation::operation_from_transaction(transaction) {
+                Ok((_, encrypted)) => encrypted.context().clone(),
+                Err(_) => continue,
+            };
+            if keys.contains_key(&space_id) {
+                continue;
+            }
+            let key = match space_id.as_ref() {
+                Some(id) => space_keys.get(id),
+                None => Some(user_key),
+            };
+            if let Some(op_keys) = key.and_then(|k| OperationKeys::new(k).ok()) {
+                keys.insert(space_id, vec![op_keys]);
+            }
+        }
+        keys
+    }
+
+    /// Grab the events that have happened since the last call.
+    pub fn drain_events(&mut self) -> Vec<SyncEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::space::{Member, Role},
+        test_util,
+    };
+
+    #[test]
+    fn turned_away_transactions_are_asked_for_again() {
+        let known = test_util::transaction_id();
+        let mut inbox = Inbox::new(vec![known.clone()]).with_buffer_limit(2);
+        let (parent1, parent2, parent3) = (test_util::transaction_id(), test_util::transaction_id(), test_util::transaction_id());
+        inbox.drop_for_now(parent1.clone());
+        inbox.drop_for_now(parent2.clone());
+        // past the cap, we stop keeping track
+        inbox.drop_for_now(parent3.clone());
+        let wanted = inbox.wanted();
+        assert_eq!(wanted.len(), 2);
+        assert!(wanted.contains(&parent1) && wanted.contains(&parent2));
+
+        // anything that's since arrived isn't asked for
+        inbox.known.insert(parent1.clone());
+        assert_eq!(inbox.wanted(), vec![parent2.clone()]);
+    }
+
+    #[test]
+    fn full_buffer_stops_asking_for_dropped() {
+        let mut inbox = Inbox::new(Vec::new()).with_buffer_limit(0);
+        inbox.dropped.insert(test_util::transaction_id());
+        assert!(inbox.wanted().is_empty());
+        inbox.buffer_limit = 1;
+        assert_eq!(inbox.wanted().len(), 1);
+    }
+
+    #[test]
+    fn arrivals_unblock_only_what_waits_on_them() {
+        let mut inbox = Inbox::new(Vec::new());
+        let (parent, child, grandchild, other) = (test_util::transaction_id(), test_util::transaction_id(), test_util::transaction_id(), test_util::transaction_id());
+        inbox.wait_on(&child, &[parent.clone()]);
+        inbox.wait_on(&grandchild, &[child.clone(), parent.clone(), parent.clone()]);
+        inbox.wait_on(&other, &[test_util::transaction_id()]);
+        let mut wanted = inbox.wanted();
+        wanted.retain(|id| id == &parent || id == &child);
+        assert_eq!(wanted.len(), 2);
+
+        assert_eq!(inbox.unblock(&parent), vec![child.clone()]);
+        assert!(inbox.unblock(&parent).is_empty());
+        assert_eq!(inbox.unblock(&child), vec![grandchild.clone()]);
+        assert_eq!(inbox.unmet.len(), 1);
+        assert_eq!(inbox.waiting.len(), 1);
+        assert!(!inbox.wanted().contains(&parent));
+    }
+
+    fn verdict_at(inbox: &Inbox, state: &State, space_id: &SpaceID, identity_id: &IdentityID, transaction_id: &TransactionID) -> Verdict {
+        inbox.membership_verdict(state.spaces().get(space_id).unwrap(), identity_id, transaction_id)
+    }
+
+    /// Apply a membership change as the inbox would, recording who it moved
+    fn apply_membership(inbox: &mut Inbox, state: &mut State, space_id: &SpaceID, transaction_id: &TransactionID, operation: Operation) {
+        let before = Standing::all(state.spaces().get(space_id));
+        state.apply_operation(operation).unwrap();
+        inbox.record_standings(space_id.clone(), transaction_id, before, state);
+    }
+
+    #[test]
+    fn membership_is_checked_as_of_the_transactions_parents() {
+        let space = Space::new("shared".into(), test_util::identity_id());
+        let space_id = space.id().clone();
+        let bob = test_util::identity_id();
+        let member = Member::new(space_id.clone(), bob.clone(), Role::Member);
+        let member_id = member.id().clone();
+        let (base, removal, concurrent_edit, later_edit) = (test_util::transaction_id(), test_util::transaction_id(), test_util::transaction_id(), test_util::transaction_id());
+        let parents = [
+            (removal.clone(), vec![base.clone()]),
+            (concurrent_edit.clone(), vec![base.clone()]),
+            (later_edit.clone(), vec![removal.clone()]),
+        ];
+
+        // one device gets the removal first, the other gets bob's concurrent edit first
+        let mut verdicts = Vec::new();
+        for removal_first in [true, false] {
+            let mut state = State::new();
+            state.apply_operation(Operation::space_set(space.clone())).unwrap();
+            state.apply_operation(Operation::space_set_member(member.clone())).unwrap();
+            let mut inbox = Inbox::new(vec![base.clone()]);
+            inbox.parents.extend(parents.iter().cloned());
+            let edit_verdict = if removal_first {
+                apply_membership(&mut inbox, &mut state, &space_id, &removal, Operation::space_unset_member(space_id.clone(), member_id.clone()));
+                verdict_at(&inbox, &state, &space_id, &bob, &concurrent_edit)
+            } else {
+                let verdict = verdict_at(&inbox, &state, &space_id, &bob, &concurrent_edit);
+                apply_membership(&mut inbox, &mut state, &space_id, &removal, Operation::space_unset_member(space_id.clone(), member_id.clone()));
+                verdict
+            };
+            verdicts.push((edit_verdict, verdict_at(&inbox, &state, &space_id, &bob, &later_edit)));
+        }
+        assert_eq!(verdicts[0], (Verdict::Valid, Verdict::NotAMember));
+        assert_eq!(verdicts[0], verdicts[1]);
+    }
+
+    #[test]
+    fn membership_replays_removal_and_re_adding() {
+        let space = Space::new("shared".into(), test_util::identity_id());
+        let space_id = space.id().clone();
+        let bob = test_util::identity_id();
+        let member = Member::new(space_id.clone(), bob.clone(), Role::Member);
+        let member_id = member.id().clone();
+        let mut state = State::new();
+        state.apply_operation(Operation::space_set(space)).unwrap();
+        state.apply_operation(Operation::space_set_member(member.clone())).unwrap();
+
+        let (base, removal, re_add) = (test_util::transaction_id(), test_util::transaction_id(), test_util::transaction_id());
+        let (before_removal, between, after_re_add) = (test_util::transaction_id(), test_util::transaction_id(), test_util::transaction_id());
+        let mut inbox = Inbox::new(vec![base.clone()]);
+        inbox.parents.extend([
+            (removal.clone(), vec![base.clone()]),
+            (re_add.clone(), vec![removal.clone()]),
+            (before_removal.clone(), vec![base.clone()]),
+            (between.clone(), vec![removal.clone()]),
+            (after_re_add.clone(), vec![re_add.clone()]),
+        ]);
+        apply_membership(&mut inbox, &mut state, &space_id, &removal, Operation::space_unset_member(space_id.clone(), member_id));
+        apply_membership(&mut inbox, &mut state, &space_id, &re_add, Operation::space_set_member(member));
+
+        assert_eq!(verdict_at(&inbox, &state, &space_id, &bob, &before_removal), Verdict::Valid);
+        assert_eq!(verdict_at(&inbox, &state, &space_id, &bob, &between), Verdict::NotAMember);
+        assert_eq!(verdict_at(&inbox, &state, &space_id, &bob, &after_re_add), Verdict::Valid);
+        // someone whose standing never changed is checked against the space as it is
+        assert_eq!(verdict_at(&inbox, &state, &space_id, &test_util::identity_id(), &after_re_add), Verdict::NotAMember);
+    }
+}
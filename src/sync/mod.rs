@@ -0,0 +1,24 @@
+//! Sync plumbing that doesn't depend on any particular transport: queueing up our own operations
+//! to send out, and taking in operations from elsewhere.
+//!
+//! Everything here works in terms of Stamp [transactions][stamp_core::dag::Transaction], since
+//! that's what actually moves between devices.
+
+pub mod delta;
+pub mod handshake;
+pub mod inbox;
+pub mod outbox;
+pub mod purge;
+pub mod push;
+#[cfg(feature = "relay")]
+pub mod relay;
+pub mod routing;
+pub mod scheduler;
+pub mod status;
+pub mod transport;
+
+pub use inbox::Inbox;
+pub use outbox::Outbox;
+pub use push::push_payload;
+pub use status::status;
+pub use transport::SyncTransport;
@@ -0,0 +1,398 @@
+//! The outbox holds operations we've committed (saved as transactions) until the other side has
+//! confirmed it has them.
+//!
+//! Each entry moves from pending to sent to acked. Sent entries that aren't acked in time, and
+//! entries whose send failed, go back into rotation with an exponential backoff so a flaky
+//! connection doesn't get hammered.
+//!
+//! Everything we commit goes out eventually, whatever this device's [`SyncPolicy`] says, since
+//! nobody else has it. The policy only decides what goes first: spaces this device only keeps the
+//! metadata for (see [`SyncPolicy::archived_metadata_only`]) drain after the rest.
+
+use crate::{
+    error::{Error, Result},
+    models::{operation, space::SpaceID, state::State, user::SyncPolicy},
+};
+use getset::Getters;
+use rasn::{AsnType, Decode, Encode};
+use serde::{Deserialize, Serialize};
+use stamp_core::{
+    dag::{Transaction, TransactionID},
+    util::Timestamp,
+};
+use std::collections::{HashMap, HashSet};
+
+/// How long to wait before the first retry, in seconds. Each retry after that waits twice as
+/// long as the last.
+pub const RETRY_BASE_SECS: i64 = 5;
+
+/// The longest we'll ever wait between retries, in seconds.
+pub const RETRY_MAX_SECS: i64 = 60 * 60;
+
+/// Where an outgoing transaction is at.
+#[derive(Clone, Debug, PartialEq, AsnType, Encode, Decode, Deserialize, Serialize)]
+#[rasn(choice)]
+pub enum OutboxStatus {
+    /// Waiting to be sent (or re-sent)
+    #[rasn(tag(explicit(0)))]
+    #[serde(rename = "pending")]
+    Pending,
+    /// Sent, waiting on the other side to confirm
+    #[rasn(tag(explicit(1)))]
+    #[serde(rename = "sent")]
+    Sent,
+    /// The other side has it
+    #[rasn(tag(explicit(2)))]
+    #[serde(rename = "acked")]
+    Acked,
+}
+
+/// One transaction in the outbox.
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct OutboxEntry {
+    #[rasn(tag(explicit(0)))]
+    transaction: Transaction,
+    /// The space the transaction's operation belongs to (`None` for user operations)
+    #[rasn(tag(explicit(1)))]
+    space_id: Option<SpaceID>,
+    #[rasn(tag(explicit(2)))]
+    status: OutboxStatus,
+    /// How many times we've tried to send this
+    #[rasn(tag(explicit(3)))]
+    attempts: u32,
+    /// When this is next due to go out: for pending entries, the end of their backoff, and for
+    /// sent entries, when we give up waiting for an ack and send again.
+    #[rasn(tag(explicit(4)))]
+    next_attempt: Timestamp,
+}
+
+impl OutboxEntry {
+    /// Whether this entry should go out in the next batch
+    fn is_due(&self, now: &Timestamp) -> bool {
+        match self.status {
+            OutboxStatus::Pending | OutboxStatus::Sent => &self.next_attempt <= now,
+            OutboxStatus::Acked => false,
+        }
+    }
+
+    /// Push `next_attempt` out according to how many times we've tried so far
+    fn back_off(&mut self, now: &Timestamp) {
+        let shift = self.attempts.saturating_sub(1).min(30);
+        let secs = RETRY_BASE_SECS.saturating_mul(1 << shift).min(RETRY_MAX_SECS);
+        self.next_attempt = Timestamp::from(**now + chrono::Duration::seconds(secs));
+    }
+}
+
+/// When a space (or `None` for user transactions) last had a send acked.
+#[derive(Clone, AsnType, Encode, Decode, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct LastSynced {
+    #[rasn(tag(explicit(0)))]
+    space_id: Option<SpaceID>,
+    #[rasn(tag(explicit(1)))]
+    at: Timestamp,
+}
+
+/// What of the outbox gets saved. The index over it is rebuilt on load.
+#[derive(Clone, Default, AsnType, Encode, Decode, Deserialize, Serialize)]
+struct SavedOutbox {
+    #[rasn(tag(explicit(0)))]
+    entries: Vec<OutboxEntry>,
+    /// The last time each space had something acked
+    #[rasn(tag(explicit(1)), default)]
+    #[serde(default)]
+    last_synced: Vec<LastSynced>,
+}
+
+/// Our queue of outgoing transactions, in the order they were committed.
+///
+/// Clients are expected to save this (see [`Outbox::serialize`]) whenever it changes, so nothing
+/// gets lost if the app closes before a send goes through.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(from = "SavedOutbox", into = "SavedOutbox")]
+pub struct Outbox {
+    saved: SavedOutbox,
+    /// Where each transaction sits in `saved.entries`
+    index: HashMap<TransactionID, usize>,
+    /// This device's sync policy. A setting, not part of the queue, so it isn't saved.
+    policy: SyncPolicy,
+}
+
+impl From<SavedOutbox> for Outbox {
+    fn from(saved: SavedOutbox) -> Self {
+        let mut outbox = Self { saved, index: HashMap::new(), policy: SyncPolicy::default() };
+        outbox.reindex();
+        outbox
+    }
+}
+
+impl From<Outbox> for SavedOutbox {
+    fn from(outbox: Outbox) -> Self {
+        outbox.saved
+    }
+}
+
+/// Pick up to `limit` transactions to send from `candidates` (due entries, oldest first), given
+/// as each transaction's ID and parents. A transaction whose parent `unsent` says hasn't gone out
+/// yet is held back, unless that parent was picked earlier in the same batch.
+fn pick_sendable<'a, T>(candidates: impl IntoIterator<Item = (&'a TransactionID, &'a [TransactionID], T)>, unsent: impl Fn(&TransactionID) -> bool, limit: usize) -> Vec<T> {
+    let mut picked_ids = HashSet::new();
+    let mut picked = Vec::new();
+    for (id, parents, item) in candidates {
+        if picked.len() >= limit {
+            break;
+        }
+        if parents.iter().any(|p| unsent(p) && !picked_ids.contains(p)) {
+            continue;
+        }
+        picked_ids.insert(id);
+        picked.push(item);
+    }
+    picked
+}
+
+impl Outbox {
+    /// Create an empty outbox
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use this device's sync policy when deciding what to send first
+    pub fn with_policy(mut self, policy: SyncPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Change the sync policy
+    pub fn set_policy(&mut self, policy: SyncPolicy) {
+        self.policy = policy;
+    }
+
+    /// Everything in the outbox, in the order it was committed
+    pub fn entries(&self) -> &Vec<OutboxEntry> {
+        &self.saved.entries
+    }
+
+    /// The last time each space had something acked
+    pub fn last_synced(&self) -> &Vec<LastSynced> {
+        &self.saved.last_synced
+    }
+
+    fn reindex(&mut self) {
+        self.index = self.saved.entries.iter()
+            .enumerate()
+            .map(|(idx, e)| (e.transaction.id().clone(), idx))
+            .collect();
+    }
+
+    /// Queue up a committed transaction. Queueing a transaction that's already in the outbox
+    /// does nothing.
+    pub fn push(&mut self, transaction: Transaction, now: &Timestamp) -> Result<()> {
+        if self.index.contains_key(transaction.id()) {
+            return Ok(());
+        }
+        let (_, encrypted) = operation::operation_from_transaction(&transaction)?;
+        self.index.insert(transaction.id().clone(), self.saved.entries.len());
+        self.saved.entries.push(OutboxEntry {
+            space_id: encrypted.context().clone(),
+            transaction,
+            status: OutboxStatus::Pending,
+            attempts: 0,
+            next_attempt: now.clone(),
+        });
+        Ok(())
+    }
+
+    /// Find an entry by its transaction ID
+    pub fn get(&self, transaction_id: &TransactionID) -> Option<&OutboxEntry> {
+        self.index.get(transaction_id).map(|idx| &self.saved.entries[*idx])
+    }
+
+    fn get_mut(&mut self, transaction_id: &TransactionID) -> Option<&mut OutboxEntry> {
+        self.index.get(transaction_id).map(|idx| &mut self.saved.entries[*idx])
+    }
+
+    /// The spaces (and `None` for user operations) that have something due to send, in the
+    /// order to drain them: the spaces the sync policy wants the contents of come first.
+    pub fn due_spaces(&self, now: &Timestamp, state: &State) -> Vec<Option<SpaceID>> {
+        let mut spaces: Vec<Option<SpaceID>> = Vec::new();
+        for entry in self.saved.entries.iter().filter(|e| e.is_due(now)) {
+            if !spaces.contains(&entry.space_id) {
+                spaces.push(entry.space_id.clone());
+            }
+        }
+        self.order_for_policy(&mut spaces, state);
+        spaces
+    }
+
+    /// Move the spaces the policy only wants metadata for to the back, keeping the order
+    /// otherwise.
+    fn order_for_policy(&self, spaces: &mut [Option<SpaceID>], state: &State) {
+        spaces.sort_by_key(|space_id| {
+            let wanted = space_id.as_ref()
+                .and_then(|id| state.spaces().get(id))
+                .map(|space| self.policy.wants_space_contents(space))
+                .unwrap_or(true);
+            !wanted
+        });
+    }
+
+    /// The next (up to `limit`) transactions to send for a space, oldest first. Once they're
+    /// handed to the transport, call [`Outbox::mark_sent`].
+    ///
+    /// A transaction whose parent is still pending (ie, backing off after a failed send) is held
+    /// back until the parent has gone out, so the other side never gets a child before its parent.
+    pub fn next_batch(&self, space_id: &Option<SpaceID>, now: &Timestamp, limit: usize) -> Vec<&Transaction> {
+        let candidates = self.saved.entries.iter()
+            .filter(|e| &e.space_id == space_id && e.is_due(now))
+            .map(|e| (e.transaction.id(), &e.transaction.entry().previous_transactions()[..], &e.transaction));
+        let unsent = |id: &TransactionID| self.get(id).map(|e| e.status == OutboxStatus::Pending).unwrap_or(false);
+        pick_sendable(candidates, unsent, limit)
+    }
+
+    /// Record that transactions went out. If they aren't acked before their backoff runs out,
+    /// they'll show up in [`Outbox::next_batch`] again.
+    pub fn mark_sent(&mut self, transaction_ids: &[TransactionID], now: &Timestamp) {
+        for id in transaction_ids {
+            if let Some(entry) = self.get_mut(id) {
+                if entry.status == OutboxStatus::Acked {
+                    continue;
+                }
+                entry.status = OutboxStatus::Sent;
+                entry.attempts += 1;
+                entry.back_off(now);
+            }
+        }
+    }
+
+    /// Record that sending transactions failed outright. They go back to pending, to be retried
+    /// after their backoff.
+    pub fn mark_failed(&mut self, transaction_ids: &[TransactionID], now: &Timestamp) {
+        for id in transaction_ids {
+            if let Some(entry) = self.get_mut(id) {
+                if entry.status == OutboxStatus::Acked {
+                    continue;
+                }
+                entry.status = OutboxStatus::Pending;
+                entry.attempts = entry.attempts.max(1);
+                entry.back_off(now);
+            }
+        }
+    }
+
+    /// Record that the other side confirmed it has transactions.
+    pub fn mark_acked(&mut self, transaction_ids: &[TransactionID], now: &Timestamp) {
+        for id in transaction_ids {
+            let space_id = match self.get_mut(id) {
+                Some(entry) => {
+                    entry.status = OutboxStatus::Acked;
+                    entry.space_id.clone()
+                }
+                None => continue,
+            };
+            match self.saved.last_synced.iter_mut().find(|l| l.space_id == space_id) {
+                Some(last) => last.at = now.clone(),
+                None => self.saved.last_synced.push(LastSynced { space_id, at: now.clone() }),
+            }
+        }
+    }
+
+    /// The last time a space had something acked
+    pub fn last_synced_at(&self, space_id: &Option<SpaceID>) -> Option<&Timestamp> {
+        self.saved.last_synced.iter().find(|l| &l.space_id == space_id).map(|l| &l.at)
+    }
+
+    /// Drop every acked entry, returning how many were removed.
+    pub fn prune_acked(&mut self) -> usize {
+        let before = self.saved.entries.len();
+        self.saved.entries.retain(|e| e.status != OutboxStatus::Acked);
+        self.reindex();
+        before - self.saved.entries.len()
+    }
+
+    /// How many entries for a space haven't been acked yet
+    pub fn unacked_count(&self, space_id: &Option<SpaceID>) -> usize {
+        self.saved.entries.iter()
+            .filter(|e| &e.space_id == space_id && e.status != OutboxStatus::Acked)
+            .count()
+    }
+
+    /// Serialize the outbox so it can be saved between runs.
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        rasn::der::encode(&self.saved).map_err(|_| Error::ASNSerialize)
+    }
+
+    /// Read an outbox saved with [`Outbox::serialize`].
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        let saved: SavedOutbox = rasn::der::decode(bytes).map_err(|_| Error::ASNDeserialize)?;
+        Ok(Self::from(saved))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{operation::Operation, space::Space},
+        test_util,
+    };
+
+    #[test]
+    fn children_wait_for_unsent_parents() {
+        let (parent, child, grandchild, other) = (test_util::transaction_id(), test_util::transaction_id(), test_util::transaction_id(), test_util::transaction_id());
+        let none: Vec<TransactionID> = vec![];
+        let (to_parent, to_child) = (vec![parent.clone()], vec![child.clone()]);
+
+        // the parent is backing off, so it isn't a candidate, and its descendants stay put
+        let candidates = vec![(&child, &to_parent[..], 1), (&grandchild, &to_child[..], 2), (&other, &none[..], 3)];
+        let unsent = |id: &TransactionID| id == &parent || id == &child || id == &grandchild;
+        assert_eq!(pick_sendable(candidates, unsent, 10), vec![3]);
+
+        // once it's due, the whole chain can go out together
+        let candidates = vec![(&parent, &none[..], 0), (&child, &to_parent[..], 1), (&grandchild, &to_child[..], 2)];
+        assert_eq!(pick_sendable(candidates, unsent, 10), vec![0, 1, 2]);
+
+        // but not if the parent gets cut off by the limit
+        let candidates = vec![(&other, &none[..], 3), (&parent, &none[..], 0), (&child, &to_parent[..], 1)];
+        assert_eq!(pick_sendable(candidates, unsent, 1), vec![3]);
+
+        // a parent that already went out (and is waiting on its ack) doesn't hold anything up
+        let candidates = vec![(&child, &to_parent[..], 1)];
+        assert_eq!(pick_sendable(candidates, |id: &TransactionID| id == &child, 10), vec![1]);
+    }
+
+    #[test]
+    fn metadata_only_spaces_drain_last() {
+        let mut state = State::new();
+        let mut space_ids = Vec::new();
+        for title in ["archived", "active"] {
+            let space = Space::new(title.into(), test_util::identity_id());
+            space_ids.push(Some(space.id().clone()));
+            state.apply_operation(Operation::space_set(space)).unwrap();
+        }
+        state.apply_operation(Operation::space_set_archived(space_ids[0].clone().unwrap(), true)).unwrap();
+        let due = vec![space_ids[0].clone(), None, space_ids[1].clone()];
+
+        let mut spaces = due.clone();
+        Outbox::new().order_for_policy(&mut spaces, &state);
+        assert_eq!(spaces, due);
+
+        let outbox = Outbox::new().with_policy(SyncPolicy::new(None, true));
+        let mut spaces = due.clone();
+        outbox.order_for_policy(&mut spaces, &state);
+        assert_eq!(spaces, vec![None, space_ids[1].clone(), space_ids[0].clone()]);
+    }
+
+    #[test]
+    fn saved_outbox_round_trips() {
+        let mut outbox = Outbox::new();
+        outbox.mark_acked(&[test_util::transaction_id()], &Timestamp::now());
+        assert!(outbox.last_synced_at(&None).is_none());
+        let bytes = outbox.serialize().unwrap();
+        let loaded = Outbox::deserialize(&bytes).unwrap();
+        assert_eq!(loaded.entries().len(), 0);
+        assert_eq!(loaded.serialize().unwrap(), bytes);
+        assert!(loaded.get(&test_util::transaction_id()).is_none());
+    }
+}
@@ -0,0 +1,264 @@
+//! Reclaiming storage from notes and files that are gone for good.
+//!
+//! Unsetting a note or file removes it from the state, but every device still holds the
+//! operations that built it (and, for files, the chunk blobs). Those can't just be deleted
+//! locally: a peer that hasn't seen the unset yet could send them right back. Instead:
+//!
+//! 1. Once every member of the space has acknowledged the unset (their
+//!    [sync marker][crate::models::space::Member::sync_marker] covers it), any device can run
+//!    [`plan_tombstones`] and commit the [`Tombstone`] operations it returns.
+//! 2. Every device that applies a tombstone calls [`purge`], which works out from its own log
+//!    what the tombstone lets it throw away (see [`purge_set`]), deletes those transactions from
+//!    storage, and deletes their chunk blobs. The unset transaction itself is kept.
+//! 3. Purged transactions are treated as received from then on (see [`purged_ids`]), so nothing
+//!    waits on them.
+//!
+//! A tombstone only names the object and its unset. Nothing a peer sends ever decides which of
+//! our transactions or blobs get deleted.
+//!
+//! Deleted spaces work a bit differently: they sit in the trash for a restore window, and once
+//! that's passed any device can create the removal with [`plan_space_unset`].
+
+use crate::{
+    blob::BlobStore,
+    crypto::OperationKeys,
+    error::{Error, Result},
+    models::{
+        operation::{self, ObjectRef, Operation, OperationAction},
+        space::{Space, SpaceID, Tombstone},
+        state::State,
+    },
+    storage::Storage,
+};
+use getset::Getters;
+use stamp_core::{
+    crypto::base::{Hash, SecretKey},
+    dag::{Transaction, TransactionID},
+    util::Timestamp,
+};
+use std::collections::HashMap;
+
+/// The record kind purged transaction IDs are remembered under
+const RECORD_PURGED: &str = "purged";
+
+/// What a tombstone lets a device throw away.
+#[derive(Debug, Default, Getters)]
+#[getset(get = "pub")]
+pub struct PurgeSet {
+    /// The transactions that built the object
+    transactions: Vec<TransactionID>,
+    /// The object's chunk blobs
+    blobs: Vec<Hash>,
+}
+
+/// Decrypt the transactions in a space that every member has acknowledged. Transactions we
+/// can't decrypt are left out.
+fn acked_operations<'a>(space: &Space, space_id: &SpaceID, transactions: &'a [Transaction], keys: &[SecretKey]) -> Result<Vec<(&'a TransactionID, Operation)>> {
+    let parents = operation::transaction_parents(transactions);
+    let acked = space.received_by_all(&parents);
+    let op_keys = keys.iter().map(OperationKeys::new).collect::<Result<Vec<_>>>()?;
+
+    let mut ops = Vec::new();
+    for trans in transactions {
+        if !acked.contains(trans.id()) {
+            continue;
+        }
+        let encrypted = match operation::operation_from_transaction(trans) {
+            Ok((_, encrypted)) if encrypted.context().as_ref() == Some(space_id) => encrypted,
+            _ => continue,
+        };
+        if let Some(op) = op_keys.iter().find_map(|k| encrypted.decrypt_with(k).ok()) {
+            ops.push((trans.id(), op));
+        }
+    }
+    Ok(ops)
+}
+
+/// Find the notes and files in a space that every member has seen unset but that don't have a
+/// tombstone yet, and build the operations that tombstone them.
+///
+/// `keys` are the space keys we have (any order). Unsets we can't decrypt, or that some member
+/// hasn't acknowledged, don't get a tombstone yet.
+pub fn plan_tombstones(state: &State, space_id: &SpaceID, transactions: &[Transaction], keys: &[SecretKey]) -> Result<Vec<Operation>> {
+    let space = state.spaces().get(space_id).ok_or(Error::SpaceNotFound)?;
+    let ops = acked_operations(space, space_id, transactions, keys)?;
+
+    // the unsets everyone has seen, for objects that are really gone and not tombstoned yet
+    let mut unsets: HashMap<ObjectRef, &TransactionID> = HashMap::new();
+    for (id, op) in &ops {
+        let object = match (op.action(), op.context().note(), op.context().file()) {
+            (OperationAction::NoteUnsetV1, Some(note_id), _) if !state.notes().contains_key(note_id) => ObjectRef::Note(note_id.clone()),
+            (OperationAction::FileUnsetV1, _, Some(file_id)) if !state.files().contains_key(file_id) => ObjectRef::File(file_id.clone()),
+            _ => continue,
+        };
+        if !space.tombstones().iter().any(|t| t.object() == &object) {
+            unsets.insert(object, *id);
+        }
+    }
+
+    Ok(unsets.into_iter()
+        .map(|(object, unset)| Operation::space_set_tombstone(space_id.clone(), Tombstone::new(object, unset.clone())))
+        .collect())
+}
+
+/// When a space was last moved to the trash, according to its log: the time of the last
+/// [`SpaceSetDeletedV1`][OperationAction::SpaceSetDeletedV1] in DAG order, if that one deleted
+/// it. Every device with the same log comes up with the same time.
+///
+/// Returns `None` if the space isn't in the trash as far as the log goes, or if we can't read
+/// the operation that put it there.
+pub fn space_deleted_at(space_id: &SpaceID, transactions: &[Transaction], keys: &[SecretKey]) -> Result<Option<Timestamp>> {
+    let op_keys = keys.iter().map(OperationKeys::new).collect::<Result<Vec<_>>>()?;
+    let in_space = transactions.iter()
+        .filter(|t| operation::operation_from_transaction(t).map(|(_, e)| e.context().as_ref() == Some(space_id)).unwrap_or(false))
+        .cloned()
+        .collect::<Vec<_>>();
+    let keys = HashMap::from([(Some(space_id.clone()), op_keys)]);
+    let mut deleted_at = None;
+    for (trans, opened) in operation::open_ordered(in_space, &keys) {
+        if let Ok(OperationAction::SpaceSetDeletedV1(deleted)) = opened.as_ref().map(|op| op.action()) {
+            deleted_at = if *deleted { Some(trans.entry().created().clone()) } else { None };
+        }
+    }
+    Ok(deleted_at)
+}
+
+/// Create the operation that removes a deleted space for good, once its restore window (this
+/// device's [`State::space_restore_window`]) has passed as of `now`.
+///
+/// The window is only checked here, when the removal is created: applying a removal never looks
+/// at it, or devices with different windows would disagree about whether the space is gone. If
+/// we can't tell when the space was deleted (see [`space_deleted_at`]), its window hasn't passed.
+pub fn plan_space_unset(state: &State, space_id: &SpaceID, transactions: &[Transaction], keys: &[SecretKey], now: &Timestamp) -> Result<Operation> {
+    let space = state.spaces().get(space_id).ok_or(Error::SpaceNotFound)?;
+    if !space.deleted() {
+        Err(Error::OperationInvalid("spaces must be deleted before they can be removed".into()))?;
+    }
+    match space_deleted_at(space_id, transactions, keys)? {
+        Some(deleted_at) if (**now - *deleted_at) >= state.space_restore_window() => {}
+        _ => Err(Error::SpaceInRestoreWindow)?,
+    }
+    Ok(Operation::space_unset(space_id.clone()))
+}
+
+/// Work out what a tombstone lets us throw away, from our own log: every transaction in
+/// `transactions` that touched the tombstoned object and that every member has acknowledged,
+/// other than the unset itself, plus the chunk blobs those transactions wrote.
+///
+/// `keys` are the space keys we have (any order). Transactions we can't decrypt are kept.
+pub fn purge_set(state: &State, space_id: &SpaceID, transactions: &[Transaction], keys: &[SecretKey], tombstone: &Tombstone) -> Result<PurgeSet> {
+    let space = state.spaces().get(space_id).ok_or(Error::SpaceNotFound)?;
+    let mut set = PurgeSet::default();
+    for (id, op) in acked_operations(space, space_id, transactions, keys)? {
+        if id == tombstone.unset() {
+            continue;
+        }
+        let touches = match tombstone.object() {
+            ObjectRef::Note(note_id) => op.context().note().as_ref() == Some(note_id),
+            ObjectRef::File(file_id) => op.context().file().as_ref() == Some(file_id),
+            ObjectRef::Space(..) | ObjectRef::Page(..) | ObjectRef::FileChunk(..) => false,
+        };
+        if !touches {
+            continue;
+        }
+        set.transactions.push(id.clone());
+        if let OperationAction::FileSetChunkV1(chunk) = op.action() {
+            set.blobs.push(chunk.blob_hash().clone());
+        }
+    }
+    Ok(set)
+}
+
+/// Throw away everything the tombstones in a space let us: the purged transactions are
+/// remembered (see [`purged_ids`]) and deleted from storage, then their chunk blobs are deleted
+/// with [`purge_blobs`]. Returns what was thrown away.
+///
+/// Safe to run again after applying more tombstones: anything already purged is gone from the
+/// log, so it isn't found a second time.
+pub fn purge(storage: &dyn Storage, store: &dyn BlobStore, state: &State, space_id: &SpaceID, keys: &[SecretKey]) -> Result<PurgeSet> {
+    let space = state.spaces().get(space_id).ok_or(Error::SpaceNotFound)?;
+    let transactions = storage.transactions(Some(space_id))?;
+    let mut purged = PurgeSet::default();
+    for tombstone in space.tombstones() {
+        let set = purge_set(state, space_id, &transactions[..], keys, tombstone)?;
+        purged.transactions.extend(set.transactions);
+        purged.blobs.extend(set.blobs);
+    }
+    storage.atomic(&mut || {
+        for id in &purged.transactions {
+            let encoded = rasn::der::encode(id).map_err(|_| Error::ASNSerialize)?;
+            storage.put_record(RECORD_PURGED, &encoded[..], &encoded[..])?;
+        }
+        storage.delete_transactions(&purged.transactions[..])
+    })?;
+    purge_blobs(store, state, &purged.blobs[..])?;
+    Ok(purged)
+}
+
+/// Every transaction [`purge`] has thrown away. Anything in here should be treated as already
+/// received (ie, passed to [`Inbox::new`][crate::sync::Inbox::new]) since nobody's going to send
+/// it to us again.
+pub fn purged_ids(storage: &dyn Storage) -> Result<Vec<TransactionID>> {
+    storage.records(RECORD_PURGED)?
+        .iter()
+        .map(|data| rasn::der::decode(&data[..]).map_err(|_| Error::ASNDeserialize))
+        .collect()
+}
+
+/// Delete a purge's chunk blobs from a blob store. Blobs that a live chunk still points to
+/// (convergent chunks can be shared between files) are kept. Returns how many were deleted.
+pub fn purge_blobs(store: &dyn BlobStore, state: &State, blobs: &[Hash]) -> Result<usize> {
+    let live = state.chunks().values()
+        .map(|c| c.blob_hash())
+        .collect::<Vec<&Hash>>();
+    let mut purged = 0;
+    for hash in blobs {
+        if live.contains(&hash) {
+            continue;
+        }
+        store.delete(hash)?;
+        purged += 1;
+    }
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::space::Space, test_util};
+
+    fn state_with_space(deleted: bool) -> (State, SpaceID) {
+        let space = Space::new("trash me".into(), test_util::identity_id());
+        let space_id = space.id().clone();
+        let mut state = State::new();
+        state.apply_operation(Operation::space_set(space)).unwrap();
+        if deleted {
+            state.apply_operation(Operation::space_set_deleted(space_id.clone(), true)).unwrap();
+        }
+        (state, space_id)
+    }
+
+    #[test]
+    fn space_unset_needs_deleted_space() {
+        let (state, space_id) = state_with_space(false);
+        let res = plan_space_unset(&state, &space_id, &[], &[], &Timestamp::now());
+        assert!(matches!(res, Err(Error::OperationInvalid(..))));
+    }
+
+    #[test]
+    fn space_unset_waits_when_deletion_time_unknown() {
+        let (state, space_id) = state_with_space(true);
+        let far_future = Timestamp::from(*Timestamp::now() + chrono::Duration::days(3650));
+        let res = plan_space_unset(&state, &space_id, &[], &[], &far_future);
+        assert!(matches!(res, Err(Error::SpaceInRestoreWindow)));
+        assert!(space_deleted_at(&space_id, &[], &[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn applying_space_unset_ignores_restore_window() {
+        let (mut state, space_id) = state_with_space(true);
+        state.set_space_restore_days(10_000);
+        state.apply_operation(Operation::space_unset(space_id.clone())).unwrap();
+        assert!(state.spaces().get(&space_id).is_none());
+    }
+}
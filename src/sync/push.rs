@@ -0,0 +1,144 @@
+//! Push notification payloads. These go through APNs/FCM, so everything but the space id is
+//! sealed under a subkey of the space key: the push provider can see that *something* happened
+//! in a space, but not what or who did it.
+//!
+//! The sender builds a payload with [`push_payload`], the push gets delivered, and the device's
+//! notification handler turns it into something to show with [`PushPayload::open`].
+
+use crate::{
+    activity::ActivityKind,
+    crypto::{self, AssociatedData, KeyPurpose},
+    error::{Error, Result},
+    models::{
+        operation::Operation,
+        space::SpaceID,
+        state::State,
+        user::NotificationLevel,
+    },
+};
+use getset::Getters;
+use rasn::{AsnType, Decode, Encode};
+use serde::Serialize;
+use stamp_core::{
+    crypto::base::{Sealed, SecretKey},
+    identity::IdentityID,
+};
+use zeroize::Zeroizing;
+
+/// What push payloads are bound to (see [`AssociatedData`])
+const PURPOSE_PUSH: &str = "turtl/push";
+
+/// What's sealed inside a [`PushPayload`]
+#[derive(AsnType, Encode, Decode)]
+struct PushContents {
+    #[rasn(tag(explicit(0)))]
+    kind: ActivityKind,
+    #[rasn(tag(explicit(1)))]
+    author: IdentityID,
+}
+
+/// A push notification as it's handed to the push provider.
+#[derive(Clone, AsnType, Encode, Decode, Getters)]
+#[getset(get = "pub")]
+pub struct PushPayload {
+    /// The space this is about, left in the clear so the device knows which key to open it with
+    #[rasn(tag(explicit(0)))]
+    space_id: SpaceID,
+    /// A sealed `PushContents`
+    #[rasn(tag(explicit(1)))]
+    sealed: Sealed,
+}
+
+impl PushPayload {
+    /// Encode this payload as text, for putting in a push provider's JSON body.
+    pub fn serialize(&self) -> Result<String> {
+        let bytes = rasn::der::encode(self).map_err(|_| Error::ASNSerialize)?;
+        Ok(data_encoding::BASE64.encode(&bytes[..]))
+    }
+
+    /// Decode a payload made by [`PushPayload::serialize`].
+    pub fn deserialize(text: &str) -> Result<Self> {
+        let bytes = data_encoding::BASE64.decode(text.trim().as_bytes()).map_err(|_| Error::ASNDeserialize)?;
+        rasn::der::decode(&bytes[..]).map_err(|_| Error::ASNDeserialize)
+    }
+
+    /// Open this payload into a notification to show, using the space keys in `state`'s keychain.
+    ///
+    /// Returns `None` if the user doesn't want to hear about this space: it's muted, or only
+    /// notifies on mentions (which a push payload doesn't carry, so the client finds those once
+    /// it syncs).
+    pub fn open(&self, state: &State, master_key: &SecretKey) -> Result<Option<PushNotification>> {
+        let space_view = state.space_view(&self.space_id).ok_or(Error::SpaceNotFound)?;
+        let level = state.user_settings().notification_level(&self.space_id);
+        if *space_view.muted() || level != &NotificationLevel::All {
+            return Ok(None);
+        }
+        let associated = AssociatedData::new(Some(self.space_id.clone()), PURPOSE_PUSH);
+        // the push may have been sealed before or after a key rotation we know about, so try the
+        // newest key first and work backwards
+        let keys = state.keychain().space_keys(master_key, &self.space_id)?;
+        let opened = keys.iter()
+            .rev()
+            .find_map(|(_, space_key)| {
+                let push_key = crypto::derive_subkey(space_key, KeyPurpose::Push).ok()?;
+                crypto::open_bound(&push_key, &associated, &self.sealed).ok()
+            })
+            .map(Zeroizing::new)
+            .ok_or(Error::SpaceKeyMissing)?;
+        let PushContents { kind, author } = rasn::der::decode(&opened[..]).map_err(|_| Error::ASNDeserialize)?;
+        let author_name = space_view.space()
+            .member_by_identity(&author)
+            .and_then(|m| m.profile().display_name().clone());
+        Ok(Some(PushNotification {
+            space_id: self.space_id.clone(),
+            space_title: space_view.title().to_string(),
+            kind,
+            author,
+            author_name,
+        }))
+    }
+}
+
+/// A decrypted push, ready to show as a local notification.
+#[derive(Getters, Serialize)]
+#[getset(get = "pub")]
+pub struct PushNotification {
+    space_id: SpaceID,
+    /// The space's title, as the user sees it
+    space_title: String,
+    kind: ActivityKind,
+    author: IdentityID,
+    /// The author's display name in this space, if they set one
+    author_name: Option<String>,
+}
+
+impl PushNotification {
+    /// The notification's title
+    pub fn title(&self) -> &str {
+        &self.space_title
+    }
+
+    /// The notification's body, ie "Bob added a note"
+    pub fn body(&self) -> String {
+        let name = self.author_name.as_deref().unwrap_or("Someone");
+        let (verb, single, _) = self.kind.describe();
+        format!("{} {} {}", name, verb, single)
+    }
+}
+
+/// Build the push payload for an operation `author` just made, sealed under the space's key.
+///
+/// Returns `None` for operations that aren't worth a notification (the same ones the
+/// [activity feed][crate::activity] leaves out) and for operations that aren't in a space.
+pub fn push_payload(operation: &Operation, author: &IdentityID, space_key: &SecretKey) -> Result<Option<PushPayload>> {
+    let (space_id, kind) = match (operation.context().space(), ActivityKind::from_action(operation.action())) {
+        (Some(space_id), Some(kind)) => (space_id.clone(), kind),
+        _ => return Ok(None),
+    };
+    let contents = PushContents { kind, author: author.clone() };
+    let serialized = Zeroizing::new(rasn::der::encode(&contents).map_err(|_| Error::ASNSerialize)?);
+    let push_key = crypto::derive_subkey(space_key, KeyPurpose::Push)?;
+    let associated = AssociatedData::new(Some(space_id.clone()), PURPOSE_PUSH);
+    let sealed = crypto::seal_bound(&push_key, &associated, &serialized[..])?;
+    Ok(Some(PushPayload { space_id, sealed }))
+}
@@ -0,0 +1,203 @@
+//! A [`SyncTransport`] for a simple relay server: a dumb store of encrypted transactions that
+//! self-hosters can run while p2p sync matures. The server never sees anything but ciphertext
+//! and IDs.
+//!
+//! The protocol is deliberately small:
+//!
+//! - `POST {base}/spaces/{space}/transactions` with a [`RelayBatch`] body stores transactions and
+//!   responds with a [`RelayAck`].
+//! - `GET {base}/spaces/{space}/transactions?since={id},{id}` responds with a [`RelayBatch`] of
+//!   everything after the given frontier.
+//! - `{ws_base}/subscribe?spaces={space},{space}` is a WebSocket where each binary message is one
+//!   serialized transaction.
+//!
+//! `{space}` is the hex of the DER-encoded space ID, or `user` for the user's own transactions.
+//! Transaction IDs are hex-encoded DER the same way.
+
+use crate::{
+    error::{Error, Result},
+    models::space::SpaceID,
+    sync::transport::{Subscription, SyncTransport},
+};
+use data_encoding::HEXLOWER;
+use rasn::{AsnType, Decode, Encode};
+use stamp_core::dag::{Transaction, TransactionID};
+use std::io::Read;
+use tungstenite::{client::IntoClientRequest, http::HeaderValue, Message};
+
+/// The most we'll read from a relay response. A batch bigger than this is more likely a
+/// misbehaving server than real data.
+pub const MAX_BODY_SIZE: u64 = 64 * 1024 * 1024;
+
+/// A batch of serialized transactions, in either direction.
+#[derive(AsnType, Encode, Decode)]
+pub struct RelayBatch {
+    #[rasn(tag(explicit(0)))]
+    transactions: Vec<Vec<u8>>,
+}
+
+/// What the relay says back after a push.
+#[derive(AsnType, Encode, Decode)]
+pub struct RelayAck {
+    /// The transactions it stored (or already had)
+    #[rasn(tag(explicit(0)))]
+    accepted: Vec<TransactionID>,
+}
+
+/// Talks to a relay server over HTTP(S) and WebSockets.
+pub struct RelayClient {
+    base_url: String,
+    ws_url: String,
+    token: Option<String>,
+}
+
+impl RelayClient {
+    /// Create a relay client. `base_url` is the HTTP(S) endpoint, ie `https://relay.example.com/v1`,
+    /// and `ws_url` the WebSocket one, ie `wss://relay.example.com/v1`.
+    pub fn new<S: Into<String>>(base_url: S, ws_url: S) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').into(),
+            ws_url: ws_url.into().trim_end_matches('/').into(),
+            token: None,
+        }
+    }
+
+    /// Send a bearer token with every request
+    pub fn with_token<S: Into<String>>(mut self, token: S) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    fn space_segment(space_id: &Option<SpaceID>) -> Result<String> {
+        match space_id {
+            Some(space_id) => Ok(HEXLOWER.encode(&rasn::der::encode(space_id).map_err(|_| Error::ASNSerialize)?)),
+            None => Ok("user".into()),
+        }
+    }
+
+    fn transactions_url(&self, space_id: &Option<SpaceID>) -> Result<String> {
+        Ok(format!("{}/spaces/{}/transactions", self.base_url, Self::space_segment(space_id)?))
+    }
+
+    fn authorize(&self, request: ureq::Request) -> ureq::Request {
+        match self.token.as_ref() {
+            Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+
+    fn read_body(response: ureq::Response) -> Result<Vec<u8>> {
+        read_limited(response.into_reader(), MAX_BODY_SIZE)
+    }
+}
+
+/// Read everything from `reader`, failing if there's more than `limit` bytes of it.
+fn read_limited<R: Read>(reader: R, limit: u64) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    reader.take(limit + 1).read_to_end(&mut body)?;
+    if body.len() as u64 > limit {
+        Err(Error::Transport(format!("response is over {} bytes", limit)))?;
+    }
+    Ok(body)
+}
+
+/// Turn WebSocket reads into a stream of transactions. The stream ends when the socket closes,
+/// or right after the first error it reports.
+fn messages<F: FnMut() -> tungstenite::Result<Message>>(mut read: F) -> impl Iterator<Item = Result<Vec<u8>>> {
+    let mut done = false;
+    std::iter::from_fn(move || loop {
+        if done {
+            return None;
+        }
+        match read() {
+            Ok(Message::Binary(data)) => return Some(Ok(data)),
+            Ok(Message::Close(_)) => done = true,
+            // pings are answered by tungstenite itself, and we don't speak text
+            Ok(_) => continue,
+            Err(tungstenite::Error::ConnectionClosed) | Err(tungstenite::Error::AlreadyClosed) => done = true,
+            Err(e) => {
+                done = true;
+                return Some(Err(Error::Transport(format!("subscription failed: {}", e))));
+            }
+        }
+    })
+}
+
+impl SyncTransport for RelayClient {
+    fn push(&self, space_id: &Option<SpaceID>, transactions: &[&Transaction]) -> Result<Vec<TransactionID>> {
+        let batch = RelayBatch {
+            transactions: transactions.iter()
+                .map(|t| rasn::der::encode(*t).map_err(|_| Error::ASNSerialize))
+                .collect::<Result<Vec<_>>>()?,
+        };
+        let body = rasn::der::encode(&batch).map_err(|_| Error::ASNSerialize)?;
+        let response = self.authorize(ureq::post(&self.transactions_url(space_id)?))
+            .set("Content-Type", "application/octet-stream")
+            .send_bytes(&body[..])
+            .map_err(|e| Error::Transport(format!("push failed: {}", e)))?;
+        let ack: RelayAck = rasn::der::decode(&Self::read_body(response)?[..]).map_err(|_| Error::ASNDeserialize)?;
+        Ok(ack.accepted)
+    }
+
+    fn pull(&self, space_id: &Option<SpaceID>, frontier: &[TransactionID]) -> Result<Vec<Vec<u8>>> {
+        let since = frontier.iter()
+            .map(|id| Ok(HEXLOWER.encode(&rasn::der::encode(id).map_err(|_| Error::ASNSerialize)?)))
+            .collect::<Result<Vec<_>>>()?
+            .join(",");
+        let response = self.authorize(ureq::get(&self.transactions_url(space_id)?))
+            .query("since", &since)
+            .call()
+            .map_err(|e| Error::Transport(format!("pull failed: {}", e)))?;
+        let batch: RelayBatch = rasn::der::decode(&Self::read_body(response)?[..]).map_err(|_| Error::ASNDeserialize)?;
+        Ok(batch.transactions)
+    }
+
+    fn subscribe(&self, space_ids: &[Option<SpaceID>]) -> Result<Subscription> {
+        let spaces = space_ids.iter()
+            .map(Self::space_segment)
+            .collect::<Result<Vec<_>>>()?
+            .join(",");
+        let mut request = format!("{}/subscribe?spaces={}", self.ws_url, spaces)
+            .into_client_request()
+            .map_err(|e| Error::Transport(format!("bad subscribe url: {}", e)))?;
+        if let Some(token) = self.token.as_ref() {
+            let header = HeaderValue::from_str(&format!("Bearer {}", token))
+                .map_err(|e| Error::Transport(format!("bad token: {}", e)))?;
+            request.headers_mut().insert("Authorization", header);
+        }
+        let (mut socket, _) = tungstenite::connect(request)
+            .map_err(|e| Error::Transport(format!("subscribe failed: {}", e)))?;
+        Ok(Box::new(messages(move || socket.read())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bodies_over_the_limit_fail() {
+        assert_eq!(read_limited(&[1u8, 2, 3][..], 3).unwrap(), vec![1, 2, 3]);
+        assert!(matches!(read_limited(&[1u8, 2, 3, 4][..], 3), Err(Error::Transport(..))));
+    }
+
+    #[test]
+    fn subscription_ends_after_an_error() {
+        let mut reads = vec![
+            Ok(Message::Binary(vec![1])),
+            Ok(Message::Ping(vec![])),
+            Err(tungstenite::Error::Io(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset"))),
+            Ok(Message::Binary(vec![2])),
+        ].into_iter();
+        let results = messages(move || reads.next().unwrap_or(Err(tungstenite::Error::AlreadyClosed))).collect::<Vec<_>>();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].as_ref().unwrap(), &vec![1]);
+        assert!(matches!(results[1], Err(Error::Transport(..))));
+    }
+
+    #[test]
+    fn subscription_ends_on_close() {
+        let mut reads = vec![Ok(Message::Close(None)), Ok(Message::Binary(vec![1]))].into_iter();
+        assert_eq!(messages(move || reads.next().unwrap()).count(), 0);
+    }
+}
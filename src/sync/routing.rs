@@ -0,0 +1,91 @@
+//! Works out who should get each operation, so a transport (ie tp2p) knows where to send what.
+//!
+//! Routing only looks at what's visible without the space key: the space an operation belongs to
+//! (its unencrypted context) and that space's membership. User operations only ever go to the
+//! user's own devices.
+
+use crate::{
+    error::{Error, Result},
+    models::{
+        operation::{self, OperationEncrypted},
+        space::Space,
+    },
+};
+use stamp_core::{
+    dag::{Transaction, TransactionID},
+    identity::IdentityID,
+};
+use std::collections::HashMap;
+
+/// The identities that should receive an operation.
+///
+/// `space` is the operation's space as it stood at the operation's point in the DAG, so someone
+/// who was removed before the operation was made doesn't get it. `owner` is the identity doing
+/// the routing, which always gets its own operations (that's how they reach the user's other
+/// devices).
+///
+/// Scoped members are left out: whether an operation is in their scope depends on its encrypted
+/// context, so send to them separately using
+/// [`filter_transactions_for_scope`][crate::models::operation::filter_transactions_for_scope].
+pub fn recipients(encrypted: &OperationEncrypted, space: Option<&Space>, owner: &IdentityID) -> Result<Vec<IdentityID>> {
+    let mut recipients = vec![owner.clone()];
+    let space_id = match encrypted.context() {
+        Some(space_id) => space_id,
+        None => return Ok(recipients),
+    };
+    let space = space
+        .filter(|s| s.id() == space_id)
+        .ok_or(Error::SpaceNotFound)?;
+    let members = space.members().iter()
+        .filter(|m| m.scope().is_none())
+        .map(|m| m.user_id());
+    let viewers = space.viewers().iter().map(|v| v.user_id());
+    for identity_id in members.chain(viewers) {
+        if !recipients.contains(identity_id) {
+            recipients.push(identity_id.clone());
+        }
+    }
+    Ok(recipients)
+}
+
+/// Per-peer lists of transactions to send, built up one transaction at a time.
+#[derive(Default)]
+pub struct SendLists {
+    lists: HashMap<IdentityID, Vec<TransactionID>>,
+}
+
+impl SendLists {
+    /// Create empty send lists
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Route a transaction (see [`recipients`]), adding it to the list of everyone who should
+    /// get it. `space` is the transaction's space as of that point in the DAG (`None` for user
+    /// operations). The owner gets a list too, for its other devices.
+    pub fn add(&mut self, transaction: &Transaction, space: Option<&Space>, owner: &IdentityID) -> Result<()> {
+        let (_, encrypted) = operation::operation_from_transaction(transaction)?;
+        for identity_id in recipients(&encrypted, space, owner)? {
+            let list = self.lists.entry(identity_id).or_insert_with(Vec::new);
+            if !list.contains(transaction.id()) {
+                list.push(transaction.id().clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// The transactions to send a peer, in the order they were added
+    pub fn for_peer(&self, identity_id: &IdentityID) -> &[TransactionID] {
+        self.lists.get(identity_id).map(|l| l.as_slice()).unwrap_or(&[])
+    }
+
+    /// Every peer with something to send
+    pub fn peers(&self) -> Vec<&IdentityID> {
+        self.lists.keys().collect()
+    }
+
+    /// Consume these lists, returning the transactions to send each peer.
+    pub fn consume(self) -> HashMap<IdentityID, Vec<TransactionID>> {
+        self.lists
+    }
+}
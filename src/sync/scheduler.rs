@@ -0,0 +1,317 @@
+//! Decides what goes over the wire next when there's more to move than bandwidth to move it.
+//!
+//! Operations (which are small and make everything else make sense) always go first. File
+//! chunks come after, ordered by what the user is looking at right now: the chunks covering the
+//! part of a file that's on screen, then the chunks just past it, then everything else. On top
+//! of that, an optional [`BandwidthCap`] keeps transfers from eating a metered connection.
+//!
+//! The device's [`SyncPolicy`] decides which files get downloaded in the background at all. A file
+//! the user opens is fetched regardless, but once they look away, whatever the policy doesn't
+//! want is dropped instead of spending the cap on it.
+//!
+//! A scheduler handles one direction, so clients that want separate upload and download caps run
+//! two.
+
+use crate::models::{
+    file::{File, FileChunkID, FileID, FileTransfer},
+    state::State,
+    user::SyncPolicy,
+};
+use getset::Getters;
+use stamp_core::{
+    dag::TransactionID,
+    util::Timestamp,
+};
+use std::ops::Range;
+
+/// How many bytes past the viewed range to prefetch, by default
+pub const DEFAULT_PREFETCH_BYTES: u64 = 1024 * 1024;
+
+/// How urgent a transfer is. Earlier variants go first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Operation transactions
+    Metadata,
+    /// Chunks covering what the user is looking at
+    Viewing,
+    /// Chunks just past what the user is looking at
+    Prefetch,
+    /// Everything else
+    Background,
+}
+
+/// Something waiting to be transferred.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransferItem {
+    Transaction(TransactionID),
+    Chunk {
+        file_id: FileID,
+        chunk_id: FileChunkID,
+        index: u32,
+    },
+}
+
+/// A limit on how fast we send (or receive), as a token bucket: up to `burst` bytes can go at
+/// once, refilling at `bytes_per_sec`.
+#[derive(Clone, Debug, Getters)]
+#[getset(get = "pub")]
+pub struct BandwidthCap {
+    bytes_per_sec: u64,
+    burst: u64,
+}
+
+impl BandwidthCap {
+    /// Create a new cap. The burst is never less than one second's worth.
+    pub fn new(bytes_per_sec: u64, burst: u64) -> Self {
+        Self { bytes_per_sec, burst: burst.max(bytes_per_sec) }
+    }
+}
+
+/// One item in the queue
+#[derive(Clone, Debug)]
+struct Queued {
+    item: TransferItem,
+    priority: Priority,
+    /// Roughly how many bytes this is
+    size: u64,
+    /// For chunks, the size of the whole file, so the sync policy can be checked again later
+    file_size: Option<u64>,
+    /// Keeps items of the same priority in the order they were queued
+    seq: u64,
+}
+
+/// Orders pending transfers and hands them out within the bandwidth cap.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    queue: Vec<Queued>,
+    seq: u64,
+    cap: Option<BandwidthCap>,
+    /// Bytes we're allowed to move right now, and when we last topped it up
+    tokens: u64,
+    refilled: Option<Timestamp>,
+    prefetch_bytes: u64,
+    policy: SyncPolicy,
+}
+
+impl Scheduler {
+    /// Create a scheduler with an optional bandwidth cap.
+    pub fn new(cap: Option<BandwidthCap>) -> Self {
+        let tokens = cap.as_ref().map(|c| c.burst).unwrap_or(0);
+        Self { cap, tokens, prefetch_bytes: DEFAULT_PREFETCH_BYTES, ..Self::default() }
+    }
+
+    /// Set how far past the viewed range to prefetch.
+    pub fn with_prefetch_bytes(mut self, prefetch_bytes: u64) -> Self {
+        self.prefetch_bytes = prefetch_bytes;
+        self
+    }
+
+    /// Use this device's sync policy to decide which files to download in the background.
+    pub fn with_policy(mut self, policy: SyncPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Change the sync policy. Queued background chunks it doesn't want are dropped the next
+    /// time something is taken from the queue.
+    pub fn set_policy(&mut self, policy: SyncPolicy) {
+        self.policy = policy;
+    }
+
+    /// Change (or remove) the bandwidth cap, ie when moving from wifi to cellular.
+    pub fn set_cap(&mut self, cap: Option<BandwidthCap>) {
+        self.tokens = cap.as_ref().map(|c| c.burst.min(self.tokens)).unwrap_or(0);
+        self.cap = cap;
+    }
+
+    fn enqueue(&mut self, item: TransferItem, priority: Priority, size: u64, file_size: Option<u64>) {
+        if let Some(queued) = self.queue.iter_mut().find(|q| q.item == item) {
+            queued.priority = queued.priority.min(priority);
+            return;
+        }
+        self.seq += 1;
+        self.queue.push(Queued { item, priority, size, file_size, seq: self.seq });
+    }
+
+    /// Queue a transaction (of roughly `size` bytes) to go out ahead of any file data.
+    pub fn queue_transaction(&mut self, transaction_id: TransactionID, size: u64) {
+        self.enqueue(TransferItem::Transaction(transaction_id), Priority::Metadata, size, None);
+    }
+
+    /// Queue the chunks a file transfer still needs to download, in the background. Files the
+    /// sync policy doesn't want the data for are skipped.
+    pub fn queue_file(&mut self, file: &File, state: &State, transfer: &FileTransfer) {
+        let wanted = state.spaces().get(file.space_id())
+            .map(|space| self.policy.wants_file_data(space, file))
+            .unwrap_or(true);
+        if !wanted {
+            return;
+        }
+        let pending = transfer.pending_download();
+        for chunk in file.chunks(state) {
+            if pending.contains(chunk.index()) {
+                let item = TransferItem::Chunk { file_id: file.id().clone(), chunk_id: chunk.id().clone(), index: *chunk.index() };
+                self.enqueue(item, Priority::Background, *chunk.len() as u64, Some(*file.size()));
+            }
+        }
+    }
+
+    /// The user is looking at `range` of a file: bump the chunks covering it (and the ones just
+    /// after it) to the front of the file queue. Whatever was being viewed before drops back to
+    /// the background.
+    ///
+    /// Chunks the transfer already has locally are skipped. Errors building the chunk plan (ie,
+    /// the file's chunk metadata hasn't all synced yet) leave the queue as it was.
+    pub fn set_viewing(&mut self, file: &File, state: &State, transfer: &FileTransfer, range: Range<u64>) {
+        let viewing = match file.chunk_plan(state, range.clone()) {
+            Ok(plan) => plan,
+            Err(_) => return,
+        };
+        let prefetch = file.chunk_plan(state, range.end..range.end.saturating_add(self.prefetch_bytes)).unwrap_or_default();
+        for queued in self.queue.iter_mut() {
+            if matches!(queued.priority, Priority::Viewing | Priority::Prefetch) {
+                queued.priority = Priority::Background;
+            }
+        }
+        let pending = transfer.pending_download();
+        let planned = viewing.iter().map(|p| (p, Priority::Viewing))
+            .chain(prefetch.iter().map(|p| (p, Priority::Prefetch)));
+        for (planned, priority) in planned {
+            if !pending.contains(planned.index()) {
+                continue;
+            }
+            let size = state.chunks().get(planned.chunk_id()).map(|c| *c.len() as u64).unwrap_or(0);
+            let item = TransferItem::Chunk { file_id: file.id().clone(), chunk_id: planned.chunk_id().clone(), index: *planned.index() };
+            self.enqueue(item, priority, size, Some(*file.size()));
+        }
+    }
+
+    /// Drop something from the queue (it finished some other way, or isn't wanted anymore)
+    pub fn cancel(&mut self, item: &TransferItem) {
+        self.queue.retain(|q| &q.item != item);
+    }
+
+    /// Top up the token bucket for the time that's passed
+    fn refill(&mut self, now: &Timestamp) {
+        let cap = match self.cap.as_ref() {
+            Some(cap) => cap,
+            None => return,
+        };
+        if let Some(last) = self.refilled.as_ref() {
+            let elapsed_ms = (**now - **last).num_milliseconds().max(0) as u64;
+            let earned = elapsed_ms.saturating_mul(cap.bytes_per_sec) / 1000;
+            self.tokens = self.tokens.saturating_add(earned).min(cap.burst);
+        }
+        self.refilled = Some(now.clone());
+    }
+
+    /// Drop the background chunks of files bigger than the sync policy allows, so they never
+    /// count against the cap.
+    fn drop_unwanted(&mut self) {
+        let max = match self.policy.max_file_size() {
+            Some(max) => *max,
+            None => return,
+        };
+        self.queue.retain(|q| q.priority != Priority::Background || q.file_size.map(|size| size <= max).unwrap_or(true));
+    }
+
+    /// Index of the most urgent item in the queue
+    fn peek(&self) -> Option<usize> {
+        self.queue.iter()
+            .enumerate()
+            .min_by_key(|(_, q)| (q.priority, q.seq))
+            .map(|(idx, _)| idx)
+    }
+
+    /// Take the next thing to transfer, if the bandwidth cap allows it. It's up to the caller to
+    /// queue the item again if the transfer fails.
+    ///
+    /// An item bigger than the cap's burst goes out once the bucket is full, so a large chunk
+    /// can't get stuck forever.
+    pub fn next(&mut self, now: &Timestamp) -> Option<TransferItem> {
+        self.refill(now);
+        self.drop_unwanted();
+        let idx = self.peek()?;
+        if let Some(cap) = self.cap.as_ref() {
+            let needed = self.queue[idx].size.min(cap.burst);
+            if self.tokens < needed {
+                return None;
+            }
+            self.tokens -= needed;
+        }
+        Some(self.queue.remove(idx).item)
+    }
+
+    /// How long until [`Scheduler::next`] will hand something out, in milliseconds: `None` if
+    /// there's nothing queued (or the cap is zero, so nothing ever will be), `Some(0)` if
+    /// something can go now.
+    pub fn wait_ms(&mut self, now: &Timestamp) -> Option<u64> {
+        self.refill(now);
+        self.drop_unwanted();
+        let idx = self.peek()?;
+        let cap = match self.cap.as_ref() {
+            Some(cap) => cap,
+            None => return Some(0),
+        };
+        let needed = self.queue[idx].size.min(cap.burst);
+        let short = needed.saturating_sub(self.tokens);
+        if short == 0 {
+            return Some(0);
+        }
+        if cap.bytes_per_sec == 0 {
+            return None;
+        }
+        Some((short.saturating_mul(1000) + cap.bytes_per_sec - 1) / cap.bytes_per_sec)
+    }
+
+    /// How many items are waiting
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Whether there's nothing waiting
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    fn chunk(index: u32) -> TransferItem {
+        TransferItem::Chunk { file_id: FileID::new(), chunk_id: FileChunkID::new(), index }
+    }
+
+    #[test]
+    fn policy_drops_big_background_chunks_before_spending_tokens() {
+        let now = Timestamp::now();
+        let mut scheduler = Scheduler::new(Some(BandwidthCap::new(100, 100)))
+            .with_policy(SyncPolicy::new(Some(1000), false));
+        let (big, small, viewing) = (chunk(0), chunk(0), chunk(1));
+        scheduler.enqueue(big.clone(), Priority::Background, 100, Some(5000));
+        scheduler.enqueue(viewing.clone(), Priority::Viewing, 100, Some(5000));
+        scheduler.enqueue(small.clone(), Priority::Background, 100, Some(500));
+
+        // the file the user is looking at still comes down
+        assert_eq!(scheduler.next(&now), Some(viewing));
+        let later = Timestamp::from(*now + chrono::Duration::seconds(1));
+        assert_eq!(scheduler.next(&later), Some(small));
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn changing_the_policy_drops_what_it_no_longer_wants() {
+        let now = Timestamp::now();
+        let mut scheduler = Scheduler::new(None);
+        let transaction = TransferItem::Transaction(test_util::transaction_id());
+        scheduler.enqueue(chunk(0), Priority::Background, 10, Some(5000));
+        scheduler.enqueue(transaction.clone(), Priority::Metadata, 10, None);
+        assert_eq!(scheduler.len(), 2);
+        scheduler.set_policy(SyncPolicy::new(Some(1000), false));
+        assert_eq!(scheduler.wait_ms(&now), Some(0));
+        assert_eq!(scheduler.len(), 1);
+        assert_eq!(scheduler.next(&now), Some(transaction));
+    }
+}
@@ -0,0 +1,96 @@
+//! A summary of where sync is at for each space, for showing sync indicators in the UI.
+
+use crate::{
+    models::{
+        operation,
+        space::{MemberID, SpaceID},
+        state::State,
+    },
+    sync::{inbox::Inbox, outbox::Outbox},
+};
+use getset::Getters;
+use stamp_core::{
+    dag::{Transaction, TransactionID},
+    util::Timestamp,
+};
+
+/// A member who hasn't acknowledged everything in a space yet.
+#[derive(Clone, Debug, Getters)]
+#[getset(get = "pub")]
+pub struct MemberBehind {
+    member_id: MemberID,
+    /// How many of the space's transactions they haven't acknowledged
+    behind: usize,
+}
+
+/// Where sync is at for one space.
+#[derive(Clone, Debug, Getters)]
+#[getset(get = "pub")]
+pub struct SpaceSyncStatus {
+    /// `None` for the user's own (non-space) data
+    space_id: Option<SpaceID>,
+    /// Outgoing transactions that haven't been acked yet
+    pending_outgoing: usize,
+    /// The last time something we sent was acked
+    last_synced: Option<Timestamp>,
+    /// Incoming transactions waiting on parents that haven't arrived
+    buffered_incoming: usize,
+    /// Incoming transactions that were rejected or couldn't be applied
+    quarantined: Vec<TransactionID>,
+    /// Members who are behind, going by their sync markers
+    members_behind: Vec<MemberBehind>,
+}
+
+impl SpaceSyncStatus {
+    /// Whether everything is caught up in both directions
+    pub fn is_synced(&self) -> bool {
+        self.pending_outgoing == 0 && self.buffered_incoming == 0 && self.quarantined.is_empty() && self.members_behind.is_empty()
+    }
+}
+
+/// Sum up sync for the user's own data and every space in the state. `transactions` is
+/// everything we have, which is used to work out how far behind each member is.
+pub fn status(outbox: &Outbox, inbox: &Inbox, state: &State, transactions: &[Transaction]) -> Vec<SpaceSyncStatus> {
+    let parents = operation::transaction_parents(transactions);
+    let mut space_ids = vec![None];
+    space_ids.extend(state.spaces().keys().cloned().map(Some));
+    space_ids.into_iter()
+        .map(|space_id| {
+            let quarantined = inbox.quarantined().iter()
+                .filter(|q| q.space_id() == &space_id)
+                .map(|q| q.transaction().id().clone())
+                .collect();
+            let members_behind = match space_id.as_ref().and_then(|id| state.spaces().get(id)) {
+                Some(space) => {
+                    let space_transactions = transactions.iter()
+                        .filter(|t| {
+                            operation::operation_from_transaction(t)
+                                .map(|(_, e)| e.context().as_ref() == Some(space.id()))
+                                .unwrap_or(false)
+                        })
+                        .collect::<Vec<_>>();
+                    space.members().iter()
+                        .filter_map(|member| {
+                            let received = member.received(&parents);
+                            let behind = space_transactions.iter().filter(|t| !received.contains(t.id())).count();
+                            if behind > 0 {
+                                Some(MemberBehind { member_id: member.id().clone(), behind })
+                            } else {
+                                None
+                            }
+                        })
+                        .collect()
+                }
+                None => Vec::new(),
+            };
+            SpaceSyncStatus {
+                pending_outgoing: outbox.unacked_count(&space_id),
+                last_synced: outbox.last_synced_at(&space_id).cloned(),
+                buffered_incoming: inbox.buffered_for(&space_id),
+                quarantined,
+                members_behind,
+                space_id,
+            }
+        })
+        .collect()
+}
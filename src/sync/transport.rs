@@ -0,0 +1,31 @@
+//! The interface between the sync machinery and whatever actually moves transactions around
+//! (a relay server, tp2p, a USB stick).
+//!
+//! Transports deal in serialized transactions: what comes out of a transport goes straight into
+//! [`Inbox::receive_raw`][crate::sync::inbox::Inbox::receive_raw], and they never need to
+//! understand (or be able to open) what they carry.
+
+use crate::{
+    error::Result,
+    models::space::SpaceID,
+};
+use stamp_core::dag::{Transaction, TransactionID};
+
+/// A stream of serialized transactions pushed to us as they arrive. Ends when the other side
+/// closes it.
+pub type Subscription = Box<dyn Iterator<Item = Result<Vec<u8>>> + Send>;
+
+/// Moves transactions between us and somewhere else. `space_id` is `None` for the user's own
+/// (non-space) transactions.
+pub trait SyncTransport {
+    /// Send transactions, returning the IDs of the ones the other side accepted (which can then
+    /// be [acked in the outbox][crate::sync::outbox::Outbox::mark_acked]).
+    fn push(&self, space_id: &Option<SpaceID>, transactions: &[&Transaction]) -> Result<Vec<TransactionID>>;
+
+    /// Grab every transaction the other side has that comes after `frontier` (our latest
+    /// transactions for the space). An empty frontier means everything.
+    fn pull(&self, space_id: &Option<SpaceID>, frontier: &[TransactionID]) -> Result<Vec<Vec<u8>>>;
+
+    /// Get new transactions for the given spaces as they arrive.
+    fn subscribe(&self, space_ids: &[Option<SpaceID>]) -> Result<Subscription>;
+}
@@ -0,0 +1,24 @@
+//! Helpers shared between unit tests.
+
+use stamp_core::{
+    crypto::base::Hash,
+    dag::TransactionID,
+    identity::IdentityID,
+};
+
+/// A made-up transaction ID, different every call.
+pub(crate) fn transaction_id() -> TransactionID {
+    TransactionID::from(Hash::new_blake3(uuid::Uuid::new_v4().as_bytes()).unwrap())
+}
+
+/// A made-up identity ID, different every call.
+pub(crate) fn identity_id() -> IdentityID {
+    IdentityID::from(transaction_id())
+}
+
+/// A fresh, empty directory under the system temp dir.
+pub(crate) fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("turtl-test-{}-{}", name, uuid::Uuid::new_v4().simple()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}